@@ -7,7 +7,7 @@ fn main() {
 	let target_str = env::var("CARGO_TARGET_DIR").unwrap_or_else( |_| {
 		let mut tgt = "./target/".to_string();
 		tgt.push_str(&profile);
-		tgt.push_str("/");
+		tgt.push('/');
 
 		tgt
 	});
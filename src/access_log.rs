@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use mlua::prelude::LuaResult;
+use mlua::{Table, Value};
+use crate::http::http_message::HttpMessage;
+use crate::http::http_request::HttpRequest;
+use crate::log::{format_timestamp, open_log_file, reopen_generation};
+use crate::lua_api::json::escape_json_string;
+use crate::utils::{civil_from_days, MONTHS};
+
+/// `%h %l %u %t "%r" %>s %b`
+const COMMON_FORMAT: &str = "%h %l %u %t \"%r\" %>s %b";
+/// Common plus the referer and user agent
+const COMBINED_FORMAT: &str = "%h %l %u %t \"%r\" %>s %b \"%{Referer}i\" \"%{User-Agent}i\"";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AccessLogFormat {
+	/// A template of Apache-style directives, see `format_template`
+	Template(String),
+	/// One JSON object per line
+	Json
+}
+
+/// Access log settings, from `config_set_access_log`
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessLogConfig {
+	pub enabled: bool,
+	pub format: AccessLogFormat,
+	/// Log file, opened for appending; stdout if not set
+	pub file: Option<String>
+}
+
+impl Default for AccessLogConfig {
+	fn default() -> Self {
+		AccessLogConfig {
+			enabled: true,
+			format: AccessLogFormat::Template(COMMON_FORMAT.to_string()),
+			file: None
+		}
+	}
+}
+
+impl AccessLogConfig {
+	/// Reads the value of the `access_log` option: `false` to disable it, or a table
+	/// `{ format = "common" | "combined" | "json" | template, file }`
+	pub fn from_option(value: Value) -> LuaResult<AccessLogConfig> {
+		let table: Table = match value {
+			Value::Nil => return Ok(AccessLogConfig::default()),
+			Value::Boolean(enabled) => return Ok(AccessLogConfig { enabled, ..AccessLogConfig::default() }),
+			Value::Table(t) => t,
+			other => return Err(mlua::Error::RuntimeError(format!("access_log must be a table or a boolean, got {}", other.type_name())))
+		};
+
+		let format = match table.get::<Option<String>>("format")?.as_deref() {
+			None | Some("common") => AccessLogFormat::Template(COMMON_FORMAT.to_string()),
+			Some("combined") => AccessLogFormat::Template(COMBINED_FORMAT.to_string()),
+			Some("json") => AccessLogFormat::Json,
+			Some(template) if template.contains('%') => AccessLogFormat::Template(template.to_string()),
+			Some(other) => return Err(mlua::Error::RuntimeError(format!("Unknown access log format '{}', expected common, combined, json or a template", other)))
+		};
+
+		Ok(AccessLogConfig {
+			enabled: table.get::<Option<bool>>("enabled")?.unwrap_or(true),
+			format,
+			file: table.get("file")?
+		})
+	}
+}
+
+/// What is known about a request once its response was sent
+pub struct AccessEntry<'a> {
+	pub address: SocketAddr,
+	pub request: &'a HttpRequest,
+	pub status: u16,
+	/// Size of the response body
+	pub bytes: usize,
+	pub time: SystemTime,
+	pub duration: Duration
+}
+
+struct AccessLogger {
+	config: AccessLogConfig,
+	file: Option<File>,
+	generation: usize
+}
+
+static ACCESS_LOG: Mutex<Option<AccessLogger>> = Mutex::new(None);
+
+/// Applies `config` in the listener, which writes the access log
+pub fn init(config: &AccessLogConfig) -> Result<(), std::io::Error> {
+	let file = match &config.file {
+		Some(path) if config.enabled => Some(open_log_file(path)?),
+		_ => None
+	};
+
+	*ACCESS_LOG.lock().unwrap_or_else(|e| e.into_inner()) = Some(AccessLogger {
+		config: config.clone(),
+		file,
+		generation: reopen_generation()
+	});
+
+	Ok(())
+}
+
+/// `[10/Oct/2000:13:55:36 +0000]`
+fn clf_time(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+	let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+	let time = secs.rem_euclid(86400);
+
+	format!("[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]", day, MONTHS[(month - 1) as usize], year, time / 3600, (time % 3600) / 60, time % 60)
+}
+
+/// Values written to the log must stay on one line and inside their quotes
+fn escape(value: &str) -> String {
+	let mut res = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'"' => res.push_str("\\\""),
+			'\\' => res.push_str("\\\\"),
+			c if c.is_control() => res.push_str(&format!("\\x{:02x}", c as u32)),
+			c => res.push(c)
+		}
+	}
+
+	res
+}
+
+fn header_or_dash(request: &HttpRequest, name: &str) -> String {
+	match request.find_header(name) {
+		Some(v) => escape(v),
+		None => "-".to_string()
+	}
+}
+
+/// Expands the directives of `template`: `%h` client address, `%l` and `%u` (always `-`),
+/// `%t` time, `%r` request line, `%s` or `%>s` status, `%b` body size (`-` if empty), `%B`
+/// body size, `%D` response time in microseconds, `%T` in seconds, `%m` method, `%U` path,
+/// `%q` query string, `%H` protocol, `%{Name}i` request header and `%%`.
+fn format_template(template: &str, entry: &AccessEntry) -> String {
+	let request = entry.request;
+	let mut res = String::new();
+	let mut chars = template.chars().peekable();
+
+	while let Some(c) = chars.next() {
+		if c != '%' {
+			res.push(c);
+			continue;
+		}
+
+		if chars.peek() == Some(&'>') {
+			chars.next();
+		}
+
+		match chars.next() {
+			Some('h') => res.push_str(&entry.address.ip().to_string()),
+			Some('l') | Some('u') => res.push('-'),
+			Some('t') => res.push_str(&clf_time(entry.time)),
+			Some('r') => res.push_str(&escape(&request.request_line)),
+			Some('s') => res.push_str(&entry.status.to_string()),
+			Some('b') if entry.bytes == 0 => res.push('-'),
+			Some('b') | Some('B') => res.push_str(&entry.bytes.to_string()),
+			Some('D') => res.push_str(&entry.duration.as_micros().to_string()),
+			Some('T') => res.push_str(&entry.duration.as_secs().to_string()),
+			Some('m') => res.push_str(request.method.to_str()),
+			Some('U') => res.push_str(&escape(&request.url.uri)),
+			Some('q') => res.push_str(&escape(request.raw_query())),
+			Some('H') => res.push_str(request.version.to_str()),
+			Some('%') => res.push('%'),
+			Some('{') => {
+				let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+				if chars.next() == Some('i') {
+					res.push_str(&header_or_dash(request, &name));
+				}
+			},
+			Some(other) => {
+				res.push('%');
+				res.push(other);
+			},
+			None => res.push('%')
+		}
+	}
+
+	res
+}
+
+fn format_json(entry: &AccessEntry) -> String {
+	let request = entry.request;
+	let optional = |name: &str| match request.find_header(name) {
+		Some(v) => format!("\"{}\"", escape_json_string(v)),
+		None => "null".to_string()
+	};
+
+	format!(
+		"{{\"time\":\"{}\",\"remote_addr\":\"{}\",\"method\":\"{}\",\"uri\":\"{}\",\"query\":\"{}\",\"protocol\":\"{}\",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"referer\":{},\"user_agent\":{}}}",
+		format_timestamp(entry.time), entry.address.ip(), request.method.to_str(), escape_json_string(&request.url.uri),
+		escape_json_string(request.raw_query().trim_start_matches('?')), request.version.to_str(), entry.status, entry.bytes,
+		entry.duration.as_secs_f64() * 1000.0, optional("Referer"), optional("User-Agent")
+	)
+}
+
+/// Writes the entry of a request whose response was sent
+pub fn write(entry: &AccessEntry) {
+	let mut guard = ACCESS_LOG.lock().unwrap_or_else(|e| e.into_inner());
+	let logger = match guard.as_mut() {
+		Some(l) if l.config.enabled => l,
+		_ => return
+	};
+
+	let generation = reopen_generation();
+	if logger.generation != generation {
+		logger.generation = generation;
+		if let Some(path) = &logger.config.file {
+			match open_log_file(path) {
+				Ok(file) => logger.file = Some(file),
+				Err(e) => log_error!("AccessLog", "Can't reopen {}: {:?}", path, e)
+			}
+		}
+	}
+
+	let line = match &logger.config.format {
+		AccessLogFormat::Template(template) => format_template(template, entry),
+		AccessLogFormat::Json => format_json(entry)
+	} + "\n";
+
+	_ = match &mut logger.file {
+		Some(file) => file.write_all(line.as_bytes()),
+		None => std::io::stdout().write_all(line.as_bytes())
+	};
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, UNIX_EPOCH};
+	use crate::access_log::{format_json, format_template, AccessEntry, COMBINED_FORMAT, COMMON_FORMAT};
+	use crate::http::http_request::HttpRequest;
+
+	#[test]
+	pub fn test_access_log_formats() {
+		let request = HttpRequest::parse(b"GET /docs/index.html?page=2 HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/8.0 \"test\"\r\n\r\n").unwrap();
+		let entry = AccessEntry {
+			address: "127.0.0.1:50000".parse().unwrap(),
+			request: &request,
+			status: 200,
+			bytes: 1234,
+			time: UNIX_EPOCH + Duration::from_secs(971186136),
+			duration: Duration::from_micros(2500)
+		};
+
+		assert_eq!(format_template(COMMON_FORMAT, &entry), "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /docs/index.html?page=2 HTTP/1.1\" 200 1234");
+		assert!(format_template(COMBINED_FORMAT, &entry).ends_with(" 200 1234 \"-\" \"curl/8.0 \\\"test\\\"\""));
+		assert_eq!(format_template("%m %U%q %D %{Host}i 100%%", &entry), "GET /docs/index.html?page=2 2500 localhost 100%");
+
+		let json = format_json(&entry);
+		assert!(json.starts_with("{\"time\":\"2000-10-10T13:55:36.000Z\""));
+		assert!(json.contains("\"query\":\"page=2\""));
+		assert!(json.contains("\"status\":200,\"bytes\":1234,\"duration_ms\":2.500,\"referer\":null"));
+
+		// Parameters are logged in the order and encoding they were sent in
+		let request = HttpRequest::parse(b"GET /search?q=a%20b&sort=desc&page=3&tag=x&tag=y HTTP/1.1\r\n\r\n").unwrap();
+		let entry = AccessEntry { request: &request, ..entry };
+		assert_eq!(format_template("\"%r\" %U %q", &entry), "\"GET /search?q=a%20b&sort=desc&page=3&tag=x&tag=y HTTP/1.1\" /search ?q=a%20b&sort=desc&page=3&tag=x&tag=y");
+		assert!(format_json(&entry).contains("\"query\":\"q=a%20b&sort=desc&page=3&tag=x&tag=y\""));
+	}
+}
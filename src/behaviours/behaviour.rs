@@ -1,8 +1,133 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::{Error};
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+use crate::behaviours::behaviour_router::BehaviourRouter;
 use crate::http::http_request::HttpRequest;
 use crate::http::http_response::HttpResponse;
+use crate::http::response_stream::ResponseStream;
+
+/// Everything a behaviour can reach besides the request itself
+pub struct RequestContext<'a> {
+    pub router: &'a BehaviourRouter,
+    /// Lets a behaviour stream its response instead of returning it whole. Once the stream
+    /// has started, the returned response is ignored.
+    pub stream: &'a RefCell<ResponseStream>,
+    /// Messages relayed by the listener once the connection has been upgraded to a WebSocket
+    pub input: &'a RefCell<Box<dyn Read>>
+}
+
+/// How much state a behaviour keeps between requests. Set per endpoint with the `isolation`
+/// option of `config_set_endpoint`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum IsolationMode {
+    /// Every request gets a brand new state: the script is loaded again for each request
+    Fresh,
+    /// Every request starts from the state the script left when it was loaded at startup;
+    /// whatever a request changes is thrown away (default)
+    Snapshot,
+    /// Requests are handled one after the other by a long-lived worker, so globals (caches,
+    /// connections, ...) survive from one request to the next
+    Persistent
+}
+
+impl IsolationMode {
+    pub fn from_str(string: &str) -> Option<IsolationMode> {
+        match string {
+            "fresh" => Some(IsolationMode::Fresh),
+            "snapshot" => Some(IsolationMode::Snapshot),
+            "persistent" => Some(IsolationMode::Persistent),
+            _ => None
+        }
+    }
+}
+
+/// Resources a single request may use, set per endpoint. A request over its time limit is
+/// answered with a 504, one over any other limit with a 503. Scripts of endpoints with any
+/// of the first four limits run with the JIT compiler off, so the limits can be checked.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ExecutionLimits {
+    /// Wall-clock time, in seconds
+    pub timeout: Option<f64>,
+    /// Lua VM instructions
+    pub max_instructions: Option<u64>,
+    /// Lua heap size, in bytes
+    pub max_memory: Option<usize>,
+    /// CPU time, in seconds
+    pub max_cpu_time: Option<f64>,
+    /// Address space of the worker process (`RLIMIT_AS`), in bytes
+    pub max_address_space: Option<u64>
+}
+
+impl ExecutionLimits {
+    /// True if some of the limits are checked from inside the VM
+    pub fn needs_hook(&self) -> bool {
+        self.timeout.is_some() || self.max_instructions.is_some() || self.max_memory.is_some() || self.max_cpu_time.is_some()
+    }
+}
+
+/// Why a behaviour couldn't produce a response
+#[derive(Debug)]
+pub struct BehaviourError {
+    pub message: String,
+    /// Stack traceback, for script errors
+    pub traceback: Option<String>,
+    /// Script and line the error was raised at, when known
+    pub location: Option<(String, u32)>
+}
+
+impl Display for BehaviourError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(traceback) = &self.traceback {
+            write!(f, "\n{}", traceback)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<std::io::Error> for BehaviourError {
+    fn from(e: std::io::Error) -> Self {
+        BehaviourError {
+            message: e.to_string(),
+            traceback: None,
+            location: None
+        }
+    }
+}
 
 pub trait Behaviour {
-    fn run(&self,request: &HttpRequest, params: HashMap<String, String>) -> Result<HttpResponse, Error>;
+    fn isolation(&self) -> IsolationMode {
+        IsolationMode::Snapshot
+    }
+
+    fn limits(&self) -> ExecutionLimits {
+        ExecutionLimits::default()
+    }
+
+    fn run(&self,request: &HttpRequest, params: HashMap<String, String>, context: &RequestContext) -> Result<HttpResponse, BehaviourError>;
+
+    /// Puts the behaviour back in the state it was loaded in after a request, so a pool worker
+    /// can serve the next one. False if it can't, and the worker has to exit.
+    fn restore(&self) -> bool {
+        false
+    }
+}
+
+/// Code run around the behaviours of every route under a prefix, registered with
+/// `config_add_middleware`
+pub trait Middleware {
+    /// Runs before the behaviour and may change the request. Returning a response answers the
+    /// request without running the behaviour or the remaining middlewares.
+    fn before(&self, request: &mut HttpRequest, params: &HashMap<String, String>, context: &RequestContext) -> Result<Option<HttpResponse>, BehaviourError>;
+
+    /// Runs after the behaviour (or the middleware that answered in its place) and may change
+    /// the response, unless it has already been streamed to the client.
+    fn after(&self, request: &HttpRequest, params: &HashMap<String, String>, response: &mut HttpResponse, context: &RequestContext) -> Result<(), BehaviourError>;
+
+    /// Same as `Behaviour::restore`
+    fn restore(&self) -> bool {
+        false
+    }
 }
\ No newline at end of file
@@ -1,12 +1,20 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Display;
-use crate::behaviours::behaviour::Behaviour;
+use std::io::Read;
+use crate::behaviours::behaviour::{Behaviour, BehaviourError, IsolationMode, Middleware, RequestContext};
 use crate::behaviours::behaviour_router::RoutePartType::PARAMETER;
 use crate::http::http_message::HttpVersion;
 use crate::http::http_request::HttpRequest;
 use crate::http::http_response::HttpResponse;
+use crate::http::response_stream::ResponseStream;
+use crate::behaviours::error_page::error_response;
+use crate::url::{build_query, percent_decode, percent_encode};
+use crate::log;
+use crate::utils::new_request_id;
 
 #[derive(PartialEq, Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum RoutePartType {
     PLAIN,
     PARAMETER,
@@ -44,7 +52,7 @@ impl Route {
             idx = rt.find('/');
         }
 
-        if rt.len() != 0 {
+        if !rt.is_empty() {
             sections.push(rt.to_string());
         }
 
@@ -53,7 +61,7 @@ impl Route {
         for s in sections {
             let mut tp = RoutePartType::PLAIN;
             let mut sx = s.clone();
-            if s.len() > 0 && s.chars().nth(0) == Some('{') && s.chars().nth(s.len() - 1) == Some('}') {
+            if !s.is_empty() && s.chars().nth(0) == Some('{') && s.chars().nth(s.len() - 1) == Some('}') {
                 tp = if s.len() > 2 { RoutePartType::PARAMETER } else { RoutePartType::IGNORE };
                 sx = s[1..s.len() - 1].to_string();
             }
@@ -68,6 +76,36 @@ impl Route {
             parts
         }
     }
+
+    /// Builds a concrete URI for this route, substituting every `{name}` part with the
+    /// percent-encoded value from `params`.
+    pub fn build(&self, params: &HashMap<String, String>) -> Result<String, String> {
+        let mut s = String::new();
+
+        for part in &self.parts {
+            s.push('/');
+            match part.part_type {
+                RoutePartType::PLAIN => {
+                    s.push_str(&part.name);
+                },
+                RoutePartType::PARAMETER => {
+                    match params.get(&part.name) {
+                        Some(v) => s.push_str(&percent_encode(v)),
+                        None => return Err(format!("Missing required parameter '{}' for route {}", part.name, self))
+                    }
+                },
+                RoutePartType::IGNORE => {
+                    return Err(format!("Route {} contains an unnamed segment and cannot be built", self));
+                }
+            }
+        }
+
+        if s.is_empty() {
+            s.push('/');
+        }
+
+        Ok(s)
+    }
 }
 
 impl Display for Route {
@@ -75,15 +113,15 @@ impl Display for Route {
         let mut s = String::new();
 
         for part in &self.parts {
-            s.push_str("/");
+            s.push('/');
             match part.part_type {
                 RoutePartType::PLAIN => {
                     s.push_str(&part.name);
                 },
                 RoutePartType::PARAMETER => {
-                    s.push_str("{");
+                    s.push('{');
                     s.push_str(&part.name);
-                    s.push_str("}");
+                    s.push('}');
                 },
                 RoutePartType::IGNORE => {
                     s.push_str("{}");
@@ -112,11 +150,16 @@ struct RouteTreeLeaf {
 }
 
 pub struct BehaviourRouter {
-    tree: RouteTreeLeaf
+    tree: RouteTreeLeaf,
+    names: HashMap<String, Route>,
+    /// Middlewares in registration order, with the URI segments they apply under
+    middlewares: Vec<(Vec<String>, Box<dyn Middleware>)>,
+    dev_mode: bool
 }
 
 impl BehaviourRouter {
-    pub fn new(mut behaviours: HashMap<String, Box<dyn Behaviour>>) -> BehaviourRouter {
+    /// `names` maps route names to the endpoint they were registered on.
+    pub fn new(mut behaviours: HashMap<String, Box<dyn Behaviour>>, names: HashMap<String, String>) -> BehaviourRouter {
         let mut tree = RouteTreeLeaf {
             leaves: HashMap::new(),
             behaviour: None,
@@ -157,11 +200,63 @@ impl BehaviourRouter {
             current.route = route;
         }
 
+        let names = names.iter()
+            .map(|(name, endpoint)| (name.clone(), Route::parse(endpoint)))
+            .collect();
+
         BehaviourRouter {
-            tree
+            tree,
+            names,
+            middlewares: Vec::new(),
+            dev_mode: false
+        }
+    }
+
+    /// In development mode, failing requests get a page with the error details instead of a
+    /// generic one
+    pub fn set_dev_mode(&mut self, dev_mode: bool) {
+        self.dev_mode = dev_mode;
+    }
+
+    /// Runs `middleware` around the behaviours of every route under `prefix` (matched by whole
+    /// segments: `/admin` covers `/admin/users` but not `/administrator`)
+    pub fn add_middleware(&mut self, prefix: &str, middleware: Box<dyn Middleware>) {
+        self.middlewares.push((BehaviourRouter::get_uri_parts(prefix), middleware));
+    }
+
+    /// Undoes what serving `uri` left behind in the process: restores the state of its
+    /// behaviour, unless it loads a new one for every request (`fresh`), and of the middlewares
+    /// around it. False if one of them can't be restored.
+    pub fn restore(&self, uri: &str) -> bool {
+        let parts = BehaviourRouter::get_uri_parts(uri);
+        let behaviour = match self.get(uri) {
+            Some((_, b)) => b.isolation() == IsolationMode::Fresh || b.restore(),
+            None => true
+        };
+
+        // Every middleware is restored, even after one failed, so none is left half done
+        self.middlewares.iter()
+            .filter(|(prefix, _)| parts.starts_with(prefix))
+            .fold(behaviour, |ok, (_, m)| m.restore() && ok)
+    }
+
+    /// Reverse routing: builds the URL of the route registered as `name`.
+    pub fn url_for(&self, name: &str, params: &HashMap<String, String>, query: &[(String, String)]) -> Result<String, String> {
+        let route = match self.names.get(name) {
+            Some(r) => r,
+            None => return Err(format!("No route named '{}'", name))
+        };
+
+        let mut url = route.build(params)?;
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&build_query(query));
         }
+
+        Ok(url)
     }
 
+    #[allow(clippy::borrowed_box)]
     fn parse_request_level<'a>(&'a self, current: &'a RouteTreeLeaf, sections: &Vec<String>, current_section: usize) -> Vec<(&'a Route, &'a Box<dyn Behaviour>)> {
         let mut res = Vec::<(&Route, & Box<dyn Behaviour>)>::new();
 
@@ -177,7 +272,7 @@ impl BehaviourRouter {
                 res.append(&mut val);
             }
 
-            if let Some(next) = current.leaves.get("*") {
+            if let Some(next) = current.leaves.get("/") {
                 let mut val = self.parse_request_level(next, sections, current_section + 1);
                 res.append(&mut val);
             }
@@ -187,33 +282,27 @@ impl BehaviourRouter {
     }
 
     fn get_uri_parts(uri: &str) -> Vec<String> {
-        let mut parts = Vec::new();
+        uri.split('/')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect()
+    }
 
-        let mut i: usize = 0;
-        while i < uri.len() {
-            match uri[(i + 1)..].find('/') {
-                None => {
-                    if uri.len() - 1 > i {
-                        parts.push(uri[(i + 1)..].to_string());
-                    }
-                    break;
-                },
-                Some(idx) => {
-                    parts.push(uri[(i + 1)..(i + 1 + idx)].to_string());
-                    i += 1;
-                }
-            }
-        }
+    /// Handles a request, tagging everything logged meanwhile with a new request ID
+    pub fn run(&self, req: HttpRequest, stream: &RefCell<ResponseStream>, input: &RefCell<Box<dyn Read>>) -> HttpResponse {
+        let request_id = new_request_id();
+        log::set_request_id(Some(request_id.clone()));
+        let response = self.handle(req, stream, input, &request_id);
+        log::set_request_id(None);
 
-        parts
+        response
     }
 
-    pub fn run(&self, req: &HttpRequest) -> HttpResponse {
+    fn handle(&self, mut req: HttpRequest, stream: &RefCell<ResponseStream>, input: &RefCell<Box<dyn Read>>, request_id: &str) -> HttpResponse {
 
-        let uri = &req.url.uri;
-        let parts = BehaviourRouter::get_uri_parts(uri);
+        let parts = BehaviourRouter::get_uri_parts(&req.url.uri);
 
-        let result = self.get(uri);
+        let result = self.get(&req.url.uri);
 
         let (route, behaviour) = match result {
             Some((r, b)) => (r, b),
@@ -224,28 +313,62 @@ impl BehaviourRouter {
 
         let mut parameters: HashMap<String, String> = HashMap::new();
 
-        for i in 0..parts.len() {
-            if i >= route.parts.len() {
-                //TODO: Print warning for more URI segments that Route parts
-                break;
-            }
-
-            let p = &route.parts[i];
+        //TODO: Print warning for more URI segments that Route parts
+        for (part, p) in parts.iter().zip(route.parts.iter()) {
             if p.part_type == PARAMETER {
-                parameters.insert(p.name.clone(), parts[i].clone());
+                parameters.insert(p.name.clone(), percent_decode(part));
             }
         }
 
-        match behaviour.run(req, parameters) {
+        let context = RequestContext {
+            router: self,
+            stream,
+            input
+        };
+
+        let middlewares: Vec<&dyn Middleware> = self.middlewares.iter()
+            .filter(|(prefix, _)| parts.starts_with(prefix))
+            .map(|(_, m)| m.as_ref())
+            .collect();
+
+        match BehaviourRouter::run_chain(&mut req, behaviour.as_ref(), parameters, &middlewares, &context) {
             Ok(resp) => resp,
             Err(e) => {
-                let content = format!("500: Internal server error: {:?}", e);
-                let content_bytes = content.as_bytes();
-                HttpResponse::new(500, HashMap::from([("Content-Length".to_string(), content_bytes.len().to_string())]), content_bytes.to_vec(), req.version.clone())
+                log_error!("BehaviourRouter", "{} {} failed: {}", req.method.to_str(), req.url.uri, e);
+
+                error_response(&req, &e, request_id, self.dev_mode)
             }
         }
     }
 
+    /// Runs the `before` hooks of `middlewares` in order, then the behaviour, then the `after`
+    /// hooks in reverse order. A middleware answering in `before` skips the rest of the way
+    /// in, but the `after` hooks of the middlewares that ran so far still run.
+    fn run_chain(req: &mut HttpRequest, behaviour: &dyn Behaviour, parameters: HashMap<String, String>, middlewares: &[&dyn Middleware], context: &RequestContext) -> Result<HttpResponse, BehaviourError> {
+        let mut ran = 0;
+        let mut response: Option<HttpResponse> = None;
+
+        for m in middlewares {
+            ran += 1;
+            response = m.before(req, &parameters, context)?;
+            if response.is_some() {
+                break;
+            }
+        }
+
+        let mut response = match response {
+            Some(r) => r,
+            None => behaviour.run(req, parameters.clone(), context)?
+        };
+
+        for m in middlewares[..ran].iter().rev() {
+            m.after(req, &parameters, &mut response, context)?;
+        }
+
+        Ok(response)
+    }
+
+    #[allow(clippy::borrowed_box)]
     pub fn get(&self, uri: &str) -> Option<(&Route, &Box<dyn Behaviour>)> {
         let parts = BehaviourRouter::get_uri_parts(uri);
 
@@ -276,4 +399,106 @@ impl BehaviourRouter {
             _ => None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::Read;
+    use crate::behaviours::behaviour::{Behaviour, BehaviourError, Middleware, RequestContext};
+    use crate::behaviours::behaviour_router::{BehaviourRouter, Route};
+    use crate::http::http_message::{HttpMessage, HttpVersion};
+    use crate::http::http_request::HttpRequest;
+    use crate::http::http_response::HttpResponse;
+    use crate::http::response_stream::ResponseStream;
+
+    struct Echo;
+
+    impl Behaviour for Echo {
+        fn run(&self, request: &HttpRequest, _: HashMap<String, String>, _: &RequestContext) -> Result<HttpResponse, BehaviourError> {
+            let user = request.find_header("X-User").cloned().unwrap_or_default();
+            Ok(HttpResponse::new(200, HashMap::new(), user.into_bytes(), request.version.clone()))
+        }
+    }
+
+    /// Denies requests without a token, tags the others and every response
+    struct Auth(&'static str);
+
+    impl Middleware for Auth {
+        fn before(&self, request: &mut HttpRequest, _: &HashMap<String, String>, _: &RequestContext) -> Result<Option<HttpResponse>, BehaviourError> {
+            if request.find_header("Token").is_none() {
+                return Ok(Some(HttpResponse::new(401, HashMap::new(), Vec::new(), request.version.clone())));
+            }
+
+            request.headers.insert("X-User".to_string(), "alice".to_string());
+            Ok(None)
+        }
+
+        fn after(&self, _: &HttpRequest, _: &HashMap<String, String>, response: &mut HttpResponse, _: &RequestContext) -> Result<(), BehaviourError> {
+            response.set_header("X-Tag", self.0);
+            Ok(())
+        }
+    }
+
+    fn run(router: &BehaviourRouter, data: &[u8]) -> HttpResponse {
+        let stream = RefCell::new(ResponseStream::new(Box::new(Vec::new()), HttpVersion::Http1_1));
+        let input: RefCell<Box<dyn Read>> = RefCell::new(Box::new(std::io::empty()));
+        router.run(HttpRequest::parse(data).unwrap(), &stream, &input)
+    }
+
+    #[test]
+    pub fn test_middleware_chain() {
+        let behaviours: HashMap<String, Box<dyn Behaviour>> = HashMap::from([
+            ("/admin".to_string(), Box::new(Echo) as Box<dyn Behaviour>),
+            ("/administrator".to_string(), Box::new(Echo) as Box<dyn Behaviour>)
+        ]);
+        let mut router = BehaviourRouter::new(behaviours, HashMap::new());
+        router.add_middleware("/admin", Box::new(Auth("auth")));
+
+        let resp = run(&router, b"GET /admin HTTP/1.1\r\nToken: x\r\n\r\n");
+        assert_eq!(resp.get_code(), 200);
+        assert_eq!(resp.get_content(), b"alice");
+        assert_eq!(resp.find_header("X-Tag").unwrap(), "auth");
+
+        let resp = run(&router, b"GET /admin HTTP/1.1\r\n\r\n");
+        assert_eq!(resp.get_code(), 401);
+        assert_eq!(resp.find_header("X-Tag").unwrap(), "auth");
+
+        let resp = run(&router, b"GET /administrator HTTP/1.1\r\n\r\n");
+        assert_eq!(resp.get_code(), 200);
+        assert!(resp.find_header("X-Tag").is_none());
+    }
+
+    #[test]
+    pub fn test_route_build() {
+        let route = Route::parse("/users/{id}/posts/{post}");
+
+        let params = HashMap::from([
+            ("id".to_string(), "john doe".to_string()),
+            ("post".to_string(), "12".to_string())
+        ]);
+        assert_eq!(route.build(&params).unwrap(), "/users/john%20doe/posts/12");
+
+        let params = HashMap::from([("id".to_string(), "1".to_string())]);
+        assert!(route.build(&params).is_err());
+
+        assert_eq!(Route::parse("/").build(&HashMap::new()).unwrap(), "/");
+        assert!(Route::parse("/a/{}").build(&HashMap::new()).is_err());
+    }
+
+    #[test]
+    pub fn test_url_for() {
+        let router = BehaviourRouter::new(
+            HashMap::new(),
+            HashMap::from([("user".to_string(), "/users/{id}".to_string())])
+        );
+
+        let params = HashMap::from([("id".to_string(), "a/b".to_string())]);
+        let query = vec![("tab".to_string(), "x y".to_string())];
+        assert_eq!(router.url_for("user", &params, &query).unwrap(), "/users/a%2Fb?tab=x%20y");
+        assert_eq!(router.url_for("user", &params, &[]).unwrap(), "/users/a%2Fb");
+        assert!(router.url_for("user", &HashMap::new(), &[]).is_err());
+        assert!(router.url_for("missing", &params, &[]).is_err());
+    }
+}
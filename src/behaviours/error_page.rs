@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::fs;
+use crate::behaviours::behaviour::BehaviourError;
+use crate::http::http_request::HttpRequest;
+use crate::http::http_response::HttpResponse;
+use crate::utils::escape_html;
+
+/// Lines of source shown around the failing line in development mode
+const SOURCE_CONTEXT: usize = 5;
+/// Bytes of request body shown in development mode
+const MAX_BODY_PREVIEW: usize = 4096;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+	pre{background:#f4f4f4;padding:1em;overflow:auto}\
+	.line{color:#888}.current{background:#fdd;display:block}\
+	table{border-collapse:collapse}td{border:1px solid #ddd;padding:.2em .5em;vertical-align:top}";
+
+fn source_excerpt(script: &str, line: u32) -> Option<String> {
+	let source = fs::read_to_string(script).ok()?;
+	let line = line as usize;
+	let first = line.saturating_sub(SOURCE_CONTEXT).max(1);
+
+	let mut res = String::new();
+	for (idx, text) in source.lines().enumerate().skip(first - 1).take(line + SOURCE_CONTEXT + 1 - first) {
+		let number = idx + 1;
+		let html = format!("<span class=\"line\">{:>4}</span>  {}", number, escape_html(text));
+		if number == line {
+			res.push_str(&format!("<span class=\"current\">{}</span>", html));
+		} else {
+			res.push_str(&html);
+			res.push('\n');
+		}
+	}
+
+	Some(res)
+}
+
+fn table<'a>(rows: impl Iterator<Item = (&'a String, &'a String)>) -> String {
+	let mut rows: Vec<(&String, &String)> = rows.collect();
+	if rows.is_empty() {
+		return "<p>None</p>".to_string();
+	}
+	rows.sort();
+
+	let mut res = "<table>".to_string();
+	for (k, v) in rows {
+		res.push_str(&format!("<tr><td>{}</td><td>{}</td></tr>", escape_html(k), escape_html(v)));
+	}
+	res.push_str("</table>");
+
+	res
+}
+
+/// Development mode page: the error with its location, source excerpt and traceback, and
+/// the request that caused it
+fn render_development(request: &HttpRequest, error: &BehaviourError, request_id: &str) -> String {
+	let mut body = format!("<h1>500: Internal Server Error</h1><h2>{}</h2>", escape_html(&error.message));
+
+	if let Some((script, line)) = &error.location {
+		body.push_str(&format!("<p>In <code>{}</code>, line {}</p>", escape_html(script), line));
+		if let Some(excerpt) = source_excerpt(script, *line) {
+			body.push_str(&format!("<pre>{}</pre>", excerpt));
+		}
+	}
+
+	if let Some(traceback) = &error.traceback {
+		body.push_str(&format!("<h3>Traceback</h3><pre>{}</pre>", escape_html(traceback)));
+	}
+
+	body.push_str(&format!(
+		"<h3>Request</h3><p><code>{} {} {}</code> (request ID {})</p><h3>Headers</h3>{}<h3>Query</h3>{}",
+		request.method.to_str(),
+		escape_html(&request.url.uri),
+		request.version.to_str(),
+		request_id,
+		table(request.headers.iter()),
+		table(request.url.queries.iter())
+	));
+
+	if !request.content.is_empty() {
+		let preview = &request.content[..request.content.len().min(MAX_BODY_PREVIEW)];
+		body.push_str(&format!("<h3>Body ({} bytes)</h3><pre>{}</pre>", request.content.len(), escape_html(&String::from_utf8_lossy(preview))));
+	}
+
+	body
+}
+
+/// Response for a request whose behaviour failed. Development mode shows everything about
+/// the error; otherwise the page only carries the request ID, to be matched with the log.
+pub fn error_response(request: &HttpRequest, error: &BehaviourError, request_id: &str, dev_mode: bool) -> HttpResponse {
+	let body = if dev_mode {
+		render_development(request, error, request_id)
+	} else {
+		format!(
+			"<h1>500: Internal Server Error</h1><p>Something went wrong while handling your request.</p><p>Request ID: <code>{}</code></p>",
+			request_id
+		)
+	};
+
+	let content = format!(
+		"<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>500: Internal Server Error</title><style>{}</style></head><body>{}</body></html>",
+		STYLE,
+		body
+	);
+
+	let mut response = HttpResponse::new(
+		500,
+		HashMap::from([("Content-Type".to_string(), "text/html; charset=utf-8".to_string())]),
+		content.into_bytes(),
+		request.version.clone()
+	);
+	response.set_header("X-Request-ID", request_id);
+
+	response
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::behaviours::behaviour::BehaviourError;
+	use crate::behaviours::error_page::error_response;
+	use crate::http::http_message::HttpMessage;
+	use crate::http::http_request::HttpRequest;
+
+	#[test]
+	pub fn test_error_pages() {
+		let req = HttpRequest::parse(b"GET /fail?x=<b> HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+		let error = BehaviourError {
+			message: "boom <script>".to_string(),
+			traceback: Some("stack traceback:\n\t./a.lua:3: in main chunk".to_string()),
+			location: Some(("./a.lua".to_string(), 3))
+		};
+
+		let dev = String::from_utf8(error_response(&req, &error, "abc123", true).serialize()).unwrap();
+		assert!(dev.contains("boom &lt;script&gt;"));
+		assert!(dev.contains("<code>./a.lua</code>, line 3"));
+		assert!(dev.contains("./a.lua:3: in main chunk"));
+		assert!(dev.contains("&lt;b&gt;"));
+
+		let prod = String::from_utf8(error_response(&req, &error, "abc123", false).serialize()).unwrap();
+		assert!(prod.contains("X-Request-ID: abc123"));
+		assert!(prod.contains("abc123</code>"));
+		assert!(!prod.contains("boom"));
+		assert!(!prod.contains("a.lua"));
+	}
+}
@@ -1,10 +1,23 @@
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use mlua::{Function, Lua, Number, Table, Value};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use mlua::{FromLuaMulti, Function, HookTriggers, IntoLuaMulti, Lua, Table, Value, VmState};
 use mlua::prelude::{LuaResult, LuaString};
-use crate::behaviours::behaviour::Behaviour;
-use crate::config::lua_config::ConfigMgr;
+use crate::behaviours::behaviour::{Behaviour, BehaviourError, ExecutionLimits, IsolationMode, Middleware, RequestContext};
+use crate::behaviours::lua_sandbox::SandboxProfile;
+use crate::behaviours::lua_snapshot::LuaSnapshot;
+use crate::config::lua_config::{ConfigMgr, EndpointConfig};
+use crate::http::http_form::{Form, FormError};
 use crate::http::http_request::HttpRequest;
-use crate::http::http_response::HttpResponse;
+use crate::http::http_response::{code_to_http_status, is_valid_content_type, HttpResponse};
+use crate::lua_api::{cookie, crypto, encoding, http, json, log, session, sse, store, template};
+use crate::session::{new_session_id, now, SessionConfig, SessionRecord};
+use crate::watchdog::process_cpu_time;
+use crate::http::http_cookie::parse_cookies;
+use crate::http::websocket::{is_upgrade_request, read_message, write_message, Message, CLOSE_ABNORMAL, CLOSE_INTERNAL_ERROR, CLOSE_NORMAL};
+use crate::http::http_message::{is_token, is_valid_header_value, normalize_header_name, HttpMessage, HttpVersion};
 
 #[derive(Debug)]
 pub enum LuaBehaviourError {
@@ -16,35 +29,271 @@ impl From<LuaBehaviourError> for std::io::Error {
 	fn from(e: LuaBehaviourError) -> Self {
 		match e {
 			LuaBehaviourError::IoError(e) => e,
-			LuaBehaviourError::LuaError(e) => std::io::Error::new(std::io::ErrorKind::Other, e.to_string())
+			LuaBehaviourError::LuaError(e) => std::io::Error::other(e.to_string())
 		}
 	}
 }
 
-const LUA_BEHAVIOUR_ENTRYPOINT_NAME: &'static str = "run_request";
+const LUA_BEHAVIOUR_ENTRYPOINT_NAME: &str = "run_request";
+
+/// Number of VM instructions between two checks of the execution limits
+const LIMIT_HOOK_INTERVAL: u32 = 1000;
+
+/// The session of the request being run: its ID and creation time once it's stored, and the
+/// `jwx.session` table
+type OpenSession = (Option<(String, u64)>, Table);
 
 pub struct LuaBehaviour {
-	vm: Lua
+	vm: Lua,
+	script_path: String,
+	script_data: Vec<u8>,
+	package_path: String,
+	library_folders: Vec<String>,
+	isolation: IsolationMode,
+	sandbox: Option<SandboxProfile>,
+	limits: ExecutionLimits,
+	max_upload_size: usize,
+	sessions: Option<SessionConfig>,
+	session: RefCell<Option<OpenSession>>,
+	/// Wall-clock deadline of the script being run, lifted once its response is streamed
+	deadline: Rc<Cell<Option<Instant>>>,
+	/// The state the script left when it was loaded, for `snapshot` isolation
+	snapshot: Option<LuaSnapshot>,
+	template_folder: Option<PathBuf>
 }
 
 impl LuaBehaviour {
-	pub fn new(config_mgr: &ConfigMgr, script_path: &str) -> Result<LuaBehaviour, LuaBehaviourError> {
-		let script_data = match std::fs::read(script_path) {
+	pub fn new(config_mgr: &ConfigMgr, endpoint: &EndpointConfig) -> Result<LuaBehaviour, LuaBehaviourError> {
+		let script_data = match std::fs::read(&endpoint.script) {
 			Ok(data) => data,
 			Err(e) => return Err(LuaBehaviourError::IoError(e))
 		};
 
 		let lua = Lua::new();
 		config_mgr.append_library_folders(&lua);
-		if let Err(e) = lua.load(script_data).exec() {
+
+		let package_path = match lua.globals().get::<Table>("package").and_then(|p| p.get::<String>("path")) {
+			Ok(p) => p,
+			Err(e) => return Err(LuaBehaviourError::LuaError(e))
+		};
+
+		let mut behaviour = LuaBehaviour {
+			vm: lua,
+			script_path: endpoint.script.clone(),
+			script_data,
+			package_path,
+			library_folders: config_mgr.get_library_folders().clone(),
+			isolation: endpoint.isolation,
+			sandbox: endpoint.sandbox.clone(),
+			limits: endpoint.limits,
+			max_upload_size: config_mgr.get_max_upload_size(),
+			sessions: config_mgr.get_sessions().cloned(),
+			session: RefCell::new(None),
+			deadline: Rc::new(Cell::new(None)),
+			snapshot: None,
+			template_folder: config_mgr.get_template_folder().map(PathBuf::from)
+		};
+
+		behaviour.load_script(&behaviour.vm)?;
+		if behaviour.isolation == IsolationMode::Snapshot {
+			behaviour.snapshot = Some(LuaSnapshot::take(&behaviour.vm).map_err(LuaBehaviourError::LuaError)?);
+		}
+
+		Ok(behaviour)
+	}
+
+	/// Applies the sandbox, if any, and runs the script in `lua`
+	fn load_script(&self, lua: &Lua) -> Result<(), LuaBehaviourError> {
+		// Hooks don't run in JIT-compiled code, so limits checked by the hook need the interpreter
+		if self.limits.needs_hook() {
+			if let Err(e) = lua.load("if jit then jit.off() jit.flush() end").exec() {
+				return Err(LuaBehaviourError::LuaError(e));
+			}
+		}
+
+		if let Some(sandbox) = &self.sandbox {
+			if let Err(e) = sandbox.apply(lua, &self.library_folders) {
+				return Err(LuaBehaviourError::LuaError(e));
+			}
+		}
+
+		if let Err(e) = LuaBehaviour::register_api(lua, self.template_folder.clone()) {
+			return Err(LuaBehaviourError::LuaError(e));
+		}
+
+		if let Err(e) = lua.load(&self.script_data).set_name(format!("@{}", self.script_path)).exec() {
 			return Err(LuaBehaviourError::LuaError(e));
 		}
 
+		Ok(())
+	}
+
+	/// Loads the script again in a new VM, for endpoints isolated with `fresh`
+	fn fresh_copy(&self) -> Result<LuaBehaviour, LuaBehaviourError> {
+		let lua = Lua::new();
+		if let Err(e) = lua.globals().get::<Table>("package").and_then(|p| p.set("path", self.package_path.clone())) {
+			return Err(LuaBehaviourError::LuaError(e));
+		}
+
+		self.load_script(&lua)?;
+
 		Ok(LuaBehaviour {
-			vm: lua
+			vm: lua,
+			script_path: self.script_path.clone(),
+			script_data: Vec::new(),
+			package_path: self.package_path.clone(),
+			library_folders: Vec::new(),
+			isolation: IsolationMode::Snapshot,
+			sandbox: None,
+			limits: self.limits,
+			max_upload_size: self.max_upload_size,
+			sessions: self.sessions.clone(),
+			session: RefCell::new(None),
+			deadline: Rc::new(Cell::new(None)),
+			snapshot: None,
+			template_folder: self.template_folder.clone()
+		})
+	}
+
+	/// Puts the VM back in the state of the snapshot, if the behaviour has one
+	fn restore_snapshot(&self) -> bool {
+		let snapshot = match &self.snapshot {
+			Some(s) => s,
+			None => return false
+		};
+
+		self.session.replace(None);
+		match snapshot.restore() {
+			Ok(_) => true,
+			Err(e) => {
+				log_error!("LuaBehaviour", "Can't restore the state of {}: {}", self.script_path, e);
+				false
+			}
+		}
+	}
+
+	/// Splits a Lua error into its message and traceback, and finds the line of the script it
+	/// was raised at
+	fn to_behaviour_error(&self, e: &mlua::Error) -> BehaviourError {
+		let (message, traceback) = match e {
+			mlua::Error::CallbackError { traceback, cause } => (cause.to_string(), Some(traceback.clone())),
+			e => {
+				let text = e.to_string();
+				match text.split_once("\nstack traceback:\n") {
+					Some((message, traceback)) => (message.to_string(), Some(format!("stack traceback:\n{}", traceback))),
+					None => (text, None)
+				}
+			}
+		};
+
+		let find_line = |text: &str| -> Option<u32> {
+			let prefix = format!("{}:", self.script_path);
+			let idx = text.find(&prefix)? + prefix.len();
+			let digits: String = text[idx..].chars().take_while(|c| c.is_ascii_digit()).collect();
+			digits.parse::<u32>().ok()
+		};
+
+		let line = find_line(&message).or_else(|| traceback.as_deref().and_then(find_line));
+
+		BehaviourError {
+			message,
+			traceback,
+			location: line.map(|l| (self.script_path.clone(), l))
+		}
+	}
+
+	/// Runs `f` with the execution limits enforced. Returns the status code to answer with
+	/// along with the error if a limit was exceeded.
+	fn with_limits<T>(&self, f: impl FnOnce() -> LuaResult<T>) -> Result<T, (mlua::Error, Option<u16>)> {
+		let exceeded: Rc<Cell<Option<u16>>> = Rc::new(Cell::new(None));
+		self.set_limit_hook(&self.limits, exceeded.clone());
+		let res = f();
+		self.vm.remove_hook();
+
+		res.map_err(|e| {
+			let code = match (exceeded.get(), &e) {
+				(Some(code), _) => Some(code),
+				(None, mlua::Error::MemoryError(_)) => Some(503),
+				_ => None
+			};
+			(e, code)
 		})
 	}
 
+	/// Turns a script error into a `BehaviourError`, except for exceeded limits which are
+	/// answered with a 503 or 504 response (`Ok(Err(response))`)
+	fn check_result<T>(&self, res: Result<T, (mlua::Error, Option<u16>)>, request: &HttpRequest) -> Result<Result<T, HttpResponse>, BehaviourError> {
+		match res {
+			Ok(v) => Ok(Ok(v)),
+			Err((e, Some(code))) => {
+				log_warn!("LuaBehaviour", "{} {}: {}", request.method.to_str(), request.url.uri, e);
+				let content = format!("{}: {}", code, code_to_http_status(code).unwrap_or("Error")).into_bytes();
+				Ok(Err(HttpResponse::new(code, HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]), content, request.version.clone())))
+			},
+			Err((e, None)) => Err(self.to_behaviour_error(&e))
+		}
+	}
+
+	/// Installs a VM hook that stops the script once it goes over one of `limits`, setting
+	/// `exceeded` to the status code to answer with.
+	fn set_limit_hook(&self, limits: &ExecutionLimits, exceeded: Rc<Cell<Option<u16>>>) {
+		if !limits.needs_hook() {
+			return;
+		}
+
+		// LuaJIT uses its own allocator, so the heap size may have to be checked by the hook
+		let max_memory = match limits.max_memory {
+			Some(m) if self.vm.set_memory_limit(m).is_err() => Some(m),
+			_ => None
+		};
+		self.deadline.set(limits.timeout.map(|t| Instant::now() + Duration::from_secs_f64(t)));
+		let deadline = self.deadline.clone();
+		let cpu_deadline = limits.max_cpu_time.map(|t| process_cpu_time() + t);
+		let max_instructions = limits.max_instructions;
+		let instructions = Cell::new(0u64);
+
+		self.vm.set_hook(HookTriggers::new().every_nth_instruction(LIMIT_HOOK_INTERVAL), move |lua, _| {
+			instructions.set(instructions.get() + LIMIT_HOOK_INTERVAL as u64);
+
+			let limit = if deadline.get().is_some_and(|d| Instant::now() >= d) {
+				Some((504, "time"))
+			} else if max_instructions.is_some_and(|m| instructions.get() > m) {
+				Some((503, "instruction"))
+			} else if max_memory.is_some_and(|m| lua.used_memory() > m) {
+				Some((503, "memory"))
+			} else if cpu_deadline.is_some_and(|d| process_cpu_time() >= d) {
+				Some((503, "CPU"))
+			} else {
+				None
+			};
+
+			match limit {
+				Some((code, name)) => {
+					exceeded.set(Some(code));
+					Err(mlua::Error::RuntimeError(format!("Request exceeded its {} limit", name)))
+				},
+				None => Ok(VmState::Continue)
+			}
+		});
+	}
+
+	/// Creates the `jwx` table with the native modules, before the endpoint script and the
+	/// Lua side of the library are loaded
+	fn register_api(lua: &Lua, template_folder: Option<PathBuf>) -> LuaResult<()> {
+		let jwx = lua.create_table()?;
+		json::register(lua, &jwx)?;
+		cookie::register(lua, &jwx)?;
+		sse::register(lua, &jwx)?;
+		store::register(lua, &jwx)?;
+		template::register(lua, &jwx, template_folder)?;
+		crypto::register(lua, &jwx)?;
+		encoding::register(lua, &jwx)?;
+		http::register(lua, &jwx)?;
+		log::register(lua, &jwx)?;
+
+		lua.globals().set("jwx", jwx)
+	}
+
 	fn table_to_hashmap(table: &Table) -> LuaResult<HashMap<String, String>> {
 		let mut map: HashMap<String, String> = HashMap::new();
 		for pair in table.pairs::<Value, Value>(){
@@ -59,7 +308,8 @@ impl LuaBehaviour {
 		Ok(map)
 	}
 
-	fn run_internal(&self, request: &HttpRequest, params: HashMap<String, String>) -> LuaResult<HttpResponse> {
+	/// Builds the `request` table scripts see
+	fn create_request_table(&self, request: &HttpRequest, params: &HashMap<String, String>, form: Option<Form>) -> LuaResult<Table> {
 		let request_table = self.vm.create_table()?;
 
 		let headers_table = self.vm.create_table()?;
@@ -68,8 +318,15 @@ impl LuaBehaviour {
 		}
 		request_table.set("headers", headers_table)?;
 
+		let cookies = match request.find_header("Cookie") {
+			Some(header) => parse_cookies(header),
+			None => HashMap::new()
+		};
+		request_table.set("cookies", cookies)?;
+		request_table.set("lastEventId", request.find_header("Last-Event-ID").cloned())?;
+
 		let params_table = self.vm.create_table()?;
-		for (key, val) in &params {
+		for (key, val) in params {
 			params_table.set(key.clone(), val.clone())?
 		}
 		request_table.set("params", params_table)?;
@@ -82,37 +339,633 @@ impl LuaBehaviour {
 		}
 		request_table.set("query", query_table)?;
 
+		request_table.set("content", self.vm.create_string(&request.content)?)?;
+		request_table.set("json", self.vm.create_function(|lua, this: Table| {
+			let content: LuaString = this.get("content")?;
+			json::decode(lua, &content.as_bytes())
+		})?)?;
+
+		let form_table = self.vm.create_table()?;
+		let files_table = self.vm.create_table()?;
+		if let Some(form) = form {
+			for (k, v) in form.fields {
+				form_table.set(k, v)?;
+			}
+
+			for (k, f) in form.files {
+				let file_table = self.vm.create_table()?;
+				file_table.set("filename", f.filename)?;
+				file_table.set("contentType", f.content_type)?;
+				file_table.set("size", f.content.len())?;
+				file_table.set("content", self.vm.create_string(&f.content)?)?;
+				files_table.set(k, file_table)?;
+			}
+		}
+		request_table.set("form", form_table)?;
+		request_table.set("files", files_table)?;
+
 		request_table.set("method", request.method.to_str().to_string())?;
 		request_table.set("version", request.version.to_str().to_string())?;
 
+		Ok(request_table)
+	}
+
+	/// Parses the request's form, answering a malformed one with a 400 and one over the upload
+	/// size limit with a 413
+	fn parse_form(&self, request: &HttpRequest) -> Result<Option<Form>, Box<HttpResponse>> {
+		Form::parse(request, self.max_upload_size).map_err(|e| {
+			let (code, message) = match e {
+				FormError::Malformed(m) => (400, m),
+				FormError::TooLarge(m) => (413, m)
+			};
+			let content = format!("{}: {}", code, message).into_bytes();
+			Box::new(HttpResponse::new(code, HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]), content, request.version.clone()))
+		})
+	}
+
+	fn run_internal(&self, request: &HttpRequest, params: HashMap<String, String>, context: &RequestContext) -> LuaResult<HttpResponse> {
+		// Persistent and pool workers serve several requests with the same state
+		self.reset_response()?;
+
+		let form = match self.parse_form(request) {
+			Ok(f) => f,
+			Err(response) => return Ok(*response)
+		};
+
+		let submitted_csrf = request.find_header("X-CSRF-Token").cloned()
+			.or(form.as_ref().and_then(|f| f.fields.get("_csrf").cloned()));
+
+		let request_table = self.create_request_table(request, &params, form)?;
 		self.vm.globals().set("request", request_table)?;
 
+		if is_upgrade_request(request) && self.handles_websockets()? {
+			return self.run_websocket(request, context);
+		}
+
+		let jwx: Table = self.vm.globals().get("jwx")?;
+		self.open_session(request, &[], submitted_csrf)?;
+
 		let func: Function = self.vm.globals().get(LUA_BEHAVIOUR_ENTRYPOINT_NAME.to_string())?;
+		self.call_with_api::<()>(&func, (), request.version.clone(), context)?;
+
+		let response: Table = jwx.get("response")?;
+
+		let cookie = self.close_session(context)?.1;
+		LuaBehaviour::add_session_cookie(cookie, Some(&response), context)?;
+
+		if context.stream.borrow().has_started() && context.stream.borrow().is_connected() {
+			LuaBehaviour::flush_response(&response, request.version.clone(), context)?;
+			context.stream.borrow_mut().finish().map_err(mlua::Error::external)?;
+		}
+
+		LuaBehaviour::read_response(&response, request.version.clone())
+	}
+
+	/// Sets `jwx.session` to the session of the request, or to the one `set_cookies` (those of
+	/// the response so far) switched to, if sessions are enabled
+	fn open_session(&self, request: &HttpRequest, set_cookies: &[String], submitted_csrf: Option<String>) -> LuaResult<()> {
+		self.session.replace(None);
+		let config = match &self.sessions {
+			Some(config) => config,
+			None => return Ok(())
+		};
+
+		let id = config.find_id(request.find_header("Cookie"), set_cookies);
+		let (loaded, table) = self.load_session(config, id, submitted_csrf)?;
+		let jwx: Table = self.vm.globals().get("jwx")?;
+		jwx.set("session", &table)?;
+
+		self.session.replace(Some((loaded, table)));
+		Ok(())
+	}
+
+	/// Saves the open session as the script left it so far, returning the cookie to send if
+	/// it got a new ID
+	fn store_session(&self, context: &RequestContext) -> LuaResult<Option<String>> {
+		let renewable = !context.stream.borrow().has_started();
+		match (&self.sessions, self.session.borrow_mut().as_mut()) {
+			(Some(config), Some((loaded, table))) => self.save_session(config, loaded, table, renewable),
+			_ => Ok(None)
+		}
+	}
+
+	/// Saves the open session for the last time, returning the ID it was saved under (if it
+	/// wasn't destroyed) and the cookie to send
+	fn close_session(&self, context: &RequestContext) -> LuaResult<(Option<String>, Option<String>)> {
+		let cookie = self.store_session(context)?;
+		let id = self.session.take().and_then(|(loaded, _)| loaded).map(|(id, _)| id);
+
+		Ok((id, cookie))
+	}
+
+	/// Finds session `id`, if it hasn't expired, and builds `jwx.session` from its data.
+	/// Returns the session ID and creation time along with the table.
+	fn load_session(&self, config: &SessionConfig, id: Option<String>, submitted_csrf: Option<String>) -> LuaResult<(Option<(String, u64)>, Table)> {
+		let record = match &id {
+			Some(id) => config.load(id).map_err(|e| mlua::Error::RuntimeError(format!("Can't load session: {}", e)))?,
+			None => None
+		};
+
+		let table = session::create(&self.vm, record.as_ref().map(|r| r.data.as_str()), submitted_csrf)?;
+		let loaded = match (id, record) {
+			(Some(id), Some(record)) => Some((id, record.created)),
+			_ => None
+		};
+
+		Ok((loaded, table))
+	}
+
+	/// Stores what the script did to `jwx.session`. A session gets a new ID when it's first
+	/// given data, when the script called `regenerate`, or when it's due for rotation; the
+	/// record under the old ID is deleted. `loaded` is updated to the saved session, and the
+	/// cookie to send returned when the ID changed.
+	///
+	/// Once the response has started no cookie can be sent: the session isn't `renewable`,
+	/// rotation waits for a later request, and starting or regenerating a session is an error.
+	fn save_session(&self, config: &SessionConfig, loaded: &mut Option<(String, u64)>, table: &Table, renewable: bool) -> LuaResult<Option<String>> {
+		let state = session::read(&self.vm, table)?;
+		let storage_error = |e: std::io::Error| mlua::Error::RuntimeError(format!("Can't save session: {}", e));
+		let new_session_id = || -> LuaResult<String> {
+			if !renewable {
+				return Err(mlua::Error::RuntimeError("Can't set the session cookie, the response has already been sent".to_string()));
+			}
+
+			new_session_id().map_err(mlua::Error::external)
+		};
+
+		let cookie = if state.destroy {
+			match loaded.take() {
+				Some((id, _)) => {
+					config.delete(&id).map_err(storage_error)?;
+					if renewable { Some(config.cookie(None)) } else { None }
+				},
+				None => None
+			}
+		} else {
+			let (id, created, new_id) = match loaded.take() {
+				None if state.is_empty => return Ok(None),
+				None => (new_session_id()?, now(), true),
+				Some((id, created)) if state.regenerate || (renewable && config.needs_rotation(created)) => {
+					let new_id = new_session_id()?;
+					config.delete(&id).map_err(storage_error)?;
+					(new_id, now(), true)
+				},
+				Some((id, created)) => (id, created, false)
+			};
+
+			// Saving unchanged sessions too pushes their expiry back
+			config.save(&id, &SessionRecord { created, data: state.data }).map_err(storage_error)?;
+			let cookie = if new_id { Some(config.cookie(Some(&config.sign(&id)))) } else { None };
+			*loaded = Some((id, created));
+			cookie
+		};
+
+		// Saved again when the script is done, without getting yet another ID
+		table.set("_regenerate", Value::Nil)?;
+
+		cookie.transpose().map_err(mlua::Error::RuntimeError)
+	}
+
+	/// Adds the session cookie to the `response` table, or to whatever response is sent when
+	/// there is none. The cookie of a session started or renewed once the response has been
+	/// streamed can't be sent anymore: that's an error.
+	fn add_session_cookie(cookie: Option<String>, response: Option<&Table>, context: &RequestContext) -> LuaResult<()> {
+		let cookie = match cookie {
+			Some(c) => c,
+			None => return Ok(())
+		};
+
+		if context.stream.borrow().has_started() {
+			return Err(mlua::Error::RuntimeError("Can't set the session cookie, the response has already been sent".to_string()));
+		}
+
+		match response {
+			Some(response) => response.get::<Table>("cookies")?.push(cookie),
+			None => {
+				context.stream.borrow_mut().add_cookie(cookie);
+				Ok(())
+			}
+		}
+	}
+
+	/// Calls `func` with the parts of the `jwx` API that need the request context
+	fn call_with_api<R: FromLuaMulti>(&self, func: &Function, args: impl IntoLuaMulti, version: HttpVersion, context: &RequestContext) -> LuaResult<R> {
+		let jwx: Table = self.vm.globals().get("jwx")?;
+
+		self.vm.scope(|scope| {
+			let url_for = scope.create_function(|_, (name, params, query): (String, Option<Table>, Option<Table>)| {
+				let params = match params {
+					Some(t) => LuaBehaviour::table_to_hashmap(&t)?,
+					None => HashMap::new()
+				};
+
+				let mut query: Vec<(String, String)> = match query {
+					Some(t) => LuaBehaviour::table_to_hashmap(&t)?.into_iter().collect(),
+					None => Vec::new()
+				};
+				query.sort();
+
+				context.router.url_for(&name, &params, &query).map_err(mlua::Error::RuntimeError)
+			})?;
+			jwx.set("url_for", url_for)?;
 
-		func.call(())?;
+			let flush_response = scope.create_function(|_, response: Table| {
+				// The session cookie has to go with the head
+				if !context.stream.borrow().has_started() {
+					LuaBehaviour::add_session_cookie(self.store_session(context)?, None, context)?;
+				}
 
+				// A streamed response lasts as long as it needs to
+				self.deadline.set(None);
+				LuaBehaviour::flush_response(&response, version.clone(), context)
+			})?;
+			jwx.set("flush_response", flush_response)?;
+
+			let response_connected = scope.create_function(|_, ()| {
+				Ok(context.stream.borrow().is_connected())
+			})?;
+			jwx.set("response_connected", response_connected)?;
+
+			func.call::<R>(args)
+		})
+	}
+
+	fn reset_response(&self) -> LuaResult<Table> {
 		let jwx: Table = self.vm.globals().get("jwx")?;
 		let response: Table = jwx.get("response")?;
+		let reset: Function = response.get("reset")?;
+		reset.call::<()>(&response)?;
+
+		Ok(response)
+	}
+
+	/// Calls the middleware's `before(request)`. Changes to the request's headers, query and
+	/// content are passed on; returning a response table (usually `jwx.response`) answers the
+	/// request right away.
+	fn run_before(&self, request: &mut HttpRequest, params: &HashMap<String, String>, context: &RequestContext) -> LuaResult<Option<HttpResponse>> {
+		let before: Function = match self.vm.globals().get::<Option<Function>>("before")? {
+			Some(f) => f,
+			None => return Ok(None)
+		};
+
+		self.reset_response()?;
+
+		let form = match self.parse_form(request) {
+			Ok(f) => f,
+			Err(response) => return Ok(Some(*response))
+		};
+		let submitted_csrf = request.find_header("X-CSRF-Token").cloned()
+			.or(form.as_ref().and_then(|f| f.fields.get("_csrf").cloned()));
+		let request_table = self.create_request_table(request, params, form)?;
+		self.vm.globals().set("request", &request_table)?;
+		self.open_session(request, &[], submitted_csrf)?;
+
+		let result: Value = self.call_with_api(&before, &request_table, request.version.clone(), context)?;
+
+		let headers: Table = request_table.get("headers")?;
+		request.headers = LuaBehaviour::table_to_hashmap(&headers)?;
+		let query: Table = request_table.get("query")?;
+		request.url.queries = LuaBehaviour::table_to_hashmap(&query)?;
+		let content: LuaString = request_table.get("content")?;
+		request.content = content.as_bytes().to_vec();
+
+		// The session is saved before the rest of the chain loads it. A new ID is passed on in
+		// the request, and its cookie sent with whatever response follows.
+		if let (Some(config), (id, Some(cookie))) = (&self.sessions, self.close_session(context)?) {
+			let header = config.replace_cookie(request.find_header("Cookie"), id.as_deref());
+			request.headers.retain(|k, _| !k.eq_ignore_ascii_case("Cookie"));
+			if let Some(header) = header {
+				request.headers.insert("Cookie".to_string(), header);
+			}
+
+			LuaBehaviour::add_session_cookie(Some(cookie), None, context)?;
+		}
+
+		if context.stream.borrow().has_started() {
+			let jwx: Table = self.vm.globals().get("jwx")?;
+			return LuaBehaviour::read_response(&jwx.get("response")?, request.version.clone()).map(Some);
+		}
+
+		match result {
+			Value::Table(t) => LuaBehaviour::read_response(&t, request.version.clone()).map(Some),
+			_ => Ok(None)
+		}
+	}
+
+	/// Calls the middleware's `after(request, response)` with `jwx.response` filled from
+	/// `response`, and reads it back unless the response has already been sent
+	fn run_after(&self, request: &HttpRequest, params: &HashMap<String, String>, response: &mut HttpResponse, context: &RequestContext) -> LuaResult<()> {
+		let after: Function = match self.vm.globals().get::<Option<Function>>("after")? {
+			Some(f) => f,
+			None => return Ok(())
+		};
+
+		let response_table = self.reset_response()?;
+		response_table.set("statusCode", response.get_code())?;
+		response_table.set("statusText", response.get_status_text().cloned())?;
+		let headers: Table = response_table.get("headers")?;
+		for (name, value) in response.get_headers() {
+			if !name.eq_ignore_ascii_case("Content-Length") {
+				headers.set(name.clone(), value.clone())?;
+			}
+		}
+		response_table.set("content", self.vm.create_string(response.get_content())?)?;
+		response_table.set("cookies", response.get_cookies().clone())?;
+
+		let form = match self.parse_form(request) {
+			Ok(f) => f,
+			Err(error) => {
+				if !context.stream.borrow().has_started() {
+					*response = *error;
+				}
+				return Ok(());
+			}
+		};
+		let submitted_csrf = request.find_header("X-CSRF-Token").cloned()
+			.or(form.as_ref().and_then(|f| f.fields.get("_csrf").cloned()));
+		let request_table = self.create_request_table(request, params, form)?;
+		self.vm.globals().set("request", &request_table)?;
+		self.open_session(request, response.get_cookies(), submitted_csrf)?;
+
+		self.call_with_api::<()>(&after, (&request_table, &response_table), request.version.clone(), context)?;
+
+		let cookie = self.close_session(context)?.1;
+		LuaBehaviour::add_session_cookie(cookie, Some(&response_table), context)?;
+
+		if !context.stream.borrow().has_started() {
+			*response = LuaBehaviour::read_response(&response_table, request.version.clone())?;
+		}
+
+		Ok(())
+	}
+
+	fn handles_websockets(&self) -> LuaResult<bool> {
+		let globals = self.vm.globals();
+		Ok(globals.get::<Option<Function>>("on_open")?.is_some() || globals.get::<Option<Function>>("on_message")?.is_some())
+	}
+
+	/// Accepts a WebSocket upgrade and passes the relayed messages to the script's `on_open`,
+	/// `on_message` and `on_close` functions until the connection is closed by either side.
+	fn run_websocket(&self, request: &HttpRequest, context: &RequestContext) -> LuaResult<HttpResponse> {
+		let globals = self.vm.globals();
+		let on_open: Option<Function> = globals.get("on_open")?;
+		let on_message: Option<Function> = globals.get("on_message")?;
+		let on_close: Option<Function> = globals.get("on_close")?;
+
+		let mut head = HttpResponse::new(101, HashMap::new(), Vec::new(), request.version.clone());
+		head.remove_header("Content-Length");
+		head.set_header("Upgrade", "websocket");
+		head.set_header("Connection", "Upgrade");
+		context.stream.borrow_mut().begin_upgrade(&mut head).map_err(mlua::Error::external)?;
+		self.deadline.set(None);
+
+		let closed = Cell::new(false);
+		let send = |message: &Message| -> LuaResult<()> {
+			if closed.get() {
+				return Err(mlua::Error::RuntimeError("WebSocket is closed".to_string()));
+			}
+
+			let mut data: Vec<u8> = Vec::new();
+			write_message(&mut data, message).map_err(mlua::Error::external)?;
+			context.stream.borrow_mut().write(&data).map_err(mlua::Error::external)
+		};
+
+		let res = self.vm.scope(|scope| {
+			let ws = self.vm.create_table()?;
+			ws.set("send", scope.create_function(|_, (_, text): (Table, LuaString)| {
+				send(&Message::Text(text.to_str()?.to_string()))
+			})?)?;
+			ws.set("sendBinary", scope.create_function(|_, (_, data): (Table, LuaString)| {
+				send(&Message::Binary(data.as_bytes().to_vec()))
+			})?)?;
+			ws.set("close", scope.create_function(|_, (_, code, reason): (Table, Option<u16>, Option<String>)| {
+				send(&Message::Close(code.unwrap_or(CLOSE_NORMAL), reason.unwrap_or_default()))?;
+				closed.set(true);
+				Ok(())
+			})?)?;
+
+			if let Some(f) = &on_open {
+				f.call::<()>(&ws)?;
+			}
+
+			while !closed.get() {
+				let message = read_message(&mut *context.input.borrow_mut())
+					.unwrap_or(Message::Close(CLOSE_ABNORMAL, String::new()));
+
+				match message {
+					Message::Text(text) => {
+						if let Some(f) = &on_message {
+							f.call::<()>((&ws, text, "text"))?;
+						}
+					},
+					Message::Binary(data) => {
+						if let Some(f) = &on_message {
+							f.call::<()>((&ws, self.vm.create_string(&data)?, "binary"))?;
+						}
+					},
+					Message::Close(code, reason) => {
+						closed.set(true);
+						if let Some(f) = &on_close {
+							f.call::<()>((&ws, code, reason))?;
+						}
+					}
+				}
+			}
+
+			Ok(())
+		});
+
+		if res.is_err() {
+			// Sending fails once the client is gone, that's not an error of the script
+			if !context.stream.borrow().is_connected() {
+				return Ok(head);
+			}
+
+			if !closed.get() {
+				_ = send(&Message::Close(CLOSE_INTERNAL_ERROR, String::new()));
+			}
+		}
+		res?;
+
+		Ok(head)
+	}
+
+	/// Sends whatever is in `jwx.response.content` to the client right away, sending the head
+	/// first if this is the first flush. The content is sent chunked, unless the script set
+	/// `Content-Length` itself.
+	fn flush_response(response: &Table, version: HttpVersion, context: &RequestContext) -> LuaResult<()> {
+		let mut stream = context.stream.borrow_mut();
+
+		if !stream.has_started() {
+			let mut head = LuaBehaviour::read_response(response, version)?;
+
+			let headers: Table = response.get("headers")?;
+			let mut content_length: Option<usize> = None;
+			for pair in headers.pairs::<String, Value>() {
+				let (name, value) = pair?;
+				if name.eq_ignore_ascii_case("Content-Length") {
+					content_length = value.to_string()?.trim().parse::<usize>().ok();
+				}
+			}
+
+			stream.begin(&mut head, content_length).map_err(mlua::Error::external)?;
+		}
 
-		let headers: Table = response.get("headers")?;
-		let headers = LuaBehaviour::table_to_hashmap(&headers)?;
-		let status_code: Number = response.get("statusCode")?;
 		let content: LuaString = response.get("content")?;
+		stream.write(&content.as_bytes()).map_err(mlua::Error::external)?;
+		response.set("content", "")?;
+
+		Ok(())
+	}
+
+	/// Converts the `jwx.response` table into an `HttpResponse`, rejecting anything that
+	/// would produce an invalid (or injected) HTTP message.
+	fn read_response(response: &Table, version: HttpVersion) -> LuaResult<HttpResponse> {
+		fn malformed<T>(message: String) -> LuaResult<T> {
+			Err(mlua::Error::RuntimeError(format!("Malformed jwx.response: {}", message)))
+		}
+
+		let status_code = match response.get::<Value>("statusCode")? {
+			Value::Integer(i) => i as f64,
+			Value::Number(n) => n,
+			other => return malformed(format!("statusCode must be a number, got {}", other.type_name()))
+		};
+		if status_code.fract() != 0.0 || !(100.0..=599.0).contains(&status_code) {
+			return malformed(format!("statusCode {} is not in the 100-599 range", status_code));
+		}
+
+		let status_text = match response.get::<Value>("statusText")? {
+			Value::Nil => None,
+			Value::String(s) => {
+				let text = s.to_str()?.to_string();
+				if !is_valid_header_value(&text) {
+					return malformed("statusText contains control characters".to_string());
+				}
+				Some(text)
+			},
+			other => return malformed(format!("statusText must be a string, got {}", other.type_name()))
+		};
+
+		let headers_table = match response.get::<Value>("headers")? {
+			Value::Table(t) => t,
+			other => return malformed(format!("headers must be a table, got {}", other.type_name()))
+		};
+
+		let mut headers: HashMap<String, String> = HashMap::new();
+		for pair in headers_table.pairs::<Value, Value>() {
+			let (key, val) = pair?;
+
+			let name = match key {
+				Value::String(s) => s.to_str()?.to_string(),
+				other => return malformed(format!("header names must be strings, got {}", other.type_name()))
+			};
+			if !is_token(&name) {
+				return malformed(format!("invalid header name '{}'", name));
+			}
+			let name = normalize_header_name(&name);
+			if headers.contains_key(&name) {
+				return malformed(format!("header '{}' is set more than once", name));
+			}
+
+			let value = match val {
+				Value::String(s) => s.to_str()?.to_string(),
+				Value::Integer(i) => i.to_string(),
+				Value::Number(n) => n.to_string(),
+				other => return malformed(format!("value of header '{}' must be a string, got {}", name, other.type_name()))
+			};
+			if !is_valid_header_value(&value) {
+				return malformed(format!("value of header '{}' contains control characters", name));
+			}
+
+			if name.eq_ignore_ascii_case("Content-Type") && !is_valid_content_type(&value) {
+				return malformed(format!("invalid Content-Type '{}'", value));
+			}
+
+			headers.insert(name, value);
+		}
+
+		let content = match response.get::<Value>("content")? {
+			Value::String(s) => s.as_bytes().to_vec(),
+			Value::Nil => Vec::new(),
+			other => return malformed(format!("content must be a string, got {}", other.type_name()))
+		};
+
+		let cookies: Vec<String> = match response.get::<Value>("cookies")? {
+			Value::Nil => Vec::new(),
+			Value::Table(t) => t.sequence_values::<String>().collect::<LuaResult<Vec<String>>>()?,
+			other => return malformed(format!("cookies must be a table, got {}", other.type_name()))
+		};
+
+		let mut response = HttpResponse::new(status_code as u16, headers, content, version);
+		if let Some(text) = status_text {
+			response.set_status_text(&text);
+		}
 
-		let response = HttpResponse::new(status_code as u16, headers,
-										 content.as_bytes().to_vec(), request.version.clone());
+		for c in cookies {
+			if !is_valid_header_value(&c) {
+				return malformed("a cookie contains control characters".to_string());
+			}
+			response.add_cookie(c);
+		}
 
 		Ok(response)
 	}
 }
 
 impl Behaviour for LuaBehaviour {
-	fn run(&self, request: &HttpRequest, params: HashMap<String, String>) -> Result<HttpResponse, std::io::Error> {
-		match self.run_internal(request, params) {
-			Ok(req) => Ok(req),
-			Err(e) => {
-				Err(std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))
-			}
+	fn isolation(&self) -> IsolationMode {
+		self.isolation
+	}
+
+	fn limits(&self) -> ExecutionLimits {
+		self.limits
+	}
+
+	fn run(&self, request: &HttpRequest, params: HashMap<String, String>, context: &RequestContext) -> Result<HttpResponse, BehaviourError> {
+		let fresh;
+		let behaviour = match self.isolation {
+			IsolationMode::Fresh => {
+				fresh = match self.fresh_copy() {
+					Ok(b) => b,
+					Err(LuaBehaviourError::LuaError(e)) => return Err(self.to_behaviour_error(&e)),
+					Err(LuaBehaviourError::IoError(e)) => return Err(e.into())
+				};
+				&fresh
+			},
+			_ => self
+		};
+
+		let res = behaviour.with_limits(|| behaviour.run_internal(request, params, context));
+		match self.check_result(res, request)? {
+			Ok(response) => Ok(response),
+			Err(response) => Ok(response)
 		}
 	}
+
+	fn restore(&self) -> bool {
+		self.restore_snapshot()
+	}
+}
+
+impl Middleware for LuaBehaviour {
+	fn before(&self, request: &mut HttpRequest, params: &HashMap<String, String>, context: &RequestContext) -> Result<Option<HttpResponse>, BehaviourError> {
+		let res = self.with_limits(|| self.run_before(request, params, context));
+		match self.check_result(res, request)? {
+			Ok(response) => Ok(response),
+			Err(response) => Ok(Some(response))
+		}
+	}
+
+	fn after(&self, request: &HttpRequest, params: &HashMap<String, String>, response: &mut HttpResponse, context: &RequestContext) -> Result<(), BehaviourError> {
+		let res = self.with_limits(|| self.run_after(request, params, response, context));
+		if let Err(limit_response) = self.check_result(res, request)? {
+			*response = limit_response;
+		}
+
+		Ok(())
+	}
+
+	fn restore(&self) -> bool {
+		self.restore_snapshot()
+	}
 }
\ No newline at end of file
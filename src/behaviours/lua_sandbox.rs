@@ -0,0 +1,285 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use mlua::prelude::LuaResult;
+use mlua::{ChunkMode, Error, Function, Lua, MultiValue, Table, Value};
+
+/// Standard library modules a sandbox profile can enable. `package` (and `require`) are always
+/// available, restricted to the configured library folders.
+const STDLIB_MODULES: [&str; 8] = ["coroutine", "table", "io", "os", "string", "math", "bit", "jit"];
+
+const DEFAULT_MODULES: [&str; 6] = ["coroutine", "table", "os", "string", "math", "bit"];
+
+/// `os` functions that don't touch the system
+const SAFE_OS_FUNCTIONS: [&str; 4] = ["clock", "date", "difftime", "time"];
+
+/// Restrictions applied to the Lua VM of an endpoint, set with the `sandbox` endpoint option:
+/// `true` for the defaults, or a table `{ modules = {...}, io_root = "..." }`.
+#[derive(Clone, Debug)]
+pub struct SandboxProfile {
+	/// Standard library modules the script can use
+	pub modules: Vec<String>,
+	/// Directory `io.open`, `io.lines`, `os.remove` and `os.rename` are confined to. Without
+	/// it, scripts can't access files at all.
+	pub io_root: Option<PathBuf>
+}
+
+impl Default for SandboxProfile {
+	fn default() -> Self {
+		SandboxProfile {
+			modules: DEFAULT_MODULES.iter().map(|m| m.to_string()).collect(),
+			io_root: None
+		}
+	}
+}
+
+impl SandboxProfile {
+	/// Reads the value of the `sandbox` endpoint option
+	pub fn from_option(value: Value) -> LuaResult<Option<SandboxProfile>> {
+		let table = match value {
+			Value::Nil | Value::Boolean(false) => return Ok(None),
+			Value::Boolean(true) => return Ok(Some(SandboxProfile::default())),
+			Value::Table(t) => t,
+			other => return Err(Error::RuntimeError(format!("sandbox must be a boolean or a table, got {}", other.type_name())))
+		};
+
+		let mut profile = SandboxProfile::default();
+
+		if let Some(modules) = table.get::<Option<Vec<String>>>("modules")? {
+			for m in &modules {
+				if !STDLIB_MODULES.contains(&m.as_str()) {
+					return Err(Error::RuntimeError(format!("Unknown sandbox module '{}'", m)));
+				}
+			}
+			profile.modules = modules;
+		}
+
+		profile.io_root = table.get::<Option<String>>("io_root")?.map(PathBuf::from);
+
+		Ok(Some(profile))
+	}
+
+	fn allows(&self, module: &str) -> bool {
+		self.modules.iter().any(|m| m == module)
+	}
+
+	/// Restricts the globals of `lua` according to this profile. Must run before the endpoint
+	/// script is loaded.
+	pub fn apply(&self, lua: &Lua, library_folders: &[String]) -> LuaResult<()> {
+		let globals = lua.globals();
+		let package: Table = globals.get("package")?;
+		let loaded: Table = package.get("loaded")?;
+
+		globals.set("dofile", Value::Nil)?;
+		globals.set("loadfile", Value::Nil)?;
+
+		// Precompiled chunks can break out of the VM, only accept source code
+		let load: Function = globals.get("load")?;
+		let text_load = lua.create_function(move |_, (chunk, name, _mode, env): (Value, Value, Value, Value)| {
+			match env {
+				Value::Nil => load.call::<MultiValue>((chunk, name, "t")),
+				env => load.call::<MultiValue>((chunk, name, "t", env))
+			}
+		})?;
+		globals.set("load", text_load.clone())?;
+		globals.set("loadstring", text_load)?;
+
+		restrict_require(lua, &package, library_folders)?;
+
+		if self.allows("os") {
+			self.restrict_os(lua, &globals.get("os")?)?;
+		}
+
+		if self.allows("io") {
+			let io = self.restrict_io(lua, &globals.get("io")?)?;
+			globals.set("io", io.clone())?;
+			loaded.set("io", io)?;
+		}
+
+		for module in STDLIB_MODULES {
+			if !self.allows(module) {
+				globals.set(module, Value::Nil)?;
+				loaded.set(module, Value::Nil)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn restrict_os(&self, lua: &Lua, os: &Table) -> LuaResult<()> {
+		let remove: Function = os.get("remove")?;
+		let rename: Function = os.get("rename")?;
+
+		let names: Vec<String> = os.pairs::<String, Value>()
+			.map(|p| p.map(|(k, _)| k))
+			.collect::<LuaResult<Vec<String>>>()?;
+		for name in names {
+			if !SAFE_OS_FUNCTIONS.contains(&name.as_str()) {
+				os.set(name, Value::Nil)?;
+			}
+		}
+
+		if let Some(root) = &self.io_root {
+			let r = root.clone();
+			os.set("remove", lua.create_function(move |_, path: String| {
+				remove.call::<MultiValue>(jail_path(&r, &path)?)
+			})?)?;
+
+			let r = root.clone();
+			os.set("rename", lua.create_function(move |_, (from, to): (String, String)| {
+				rename.call::<MultiValue>((jail_path(&r, &from)?, jail_path(&r, &to)?))
+			})?)?;
+		}
+
+		Ok(())
+	}
+
+	fn restrict_io(&self, lua: &Lua, io: &Table) -> LuaResult<Table> {
+		let res = lua.create_table()?;
+		for name in ["write", "type", "stdout", "stderr"] {
+			res.set(name, io.get::<Value>(name)?)?;
+		}
+
+		if let Some(root) = &self.io_root {
+			let open: Function = io.get("open")?;
+			let r = root.clone();
+			res.set("open", lua.create_function(move |_, (path, mode): (String, Option<String>)| {
+				open.call::<MultiValue>((jail_path(&r, &path)?, mode.unwrap_or("r".to_string())))
+			})?)?;
+
+			let lines: Function = io.get("lines")?;
+			let r = root.clone();
+			res.set("lines", lua.create_function(move |_, path: String| {
+				lines.call::<MultiValue>(jail_path(&r, &path)?)
+			})?)?;
+		}
+
+		Ok(res)
+	}
+}
+
+/// Replaces the searchers used by `require` with one that only loads Lua source files from
+/// `library_folders` (`package.path` style templates), ignoring later changes to
+/// `package.path`. Native modules can't be loaded.
+fn restrict_require(lua: &Lua, package: &Table, library_folders: &[String]) -> LuaResult<()> {
+	package.set("cpath", "")?;
+	package.set("loadlib", Value::Nil)?;
+	package.set("path", library_folders.join(";"))?;
+
+	let loaders: Table = package.get("loaders")?;
+	let preload: Function = loaders.get(1)?;
+
+	let folders = library_folders.to_vec();
+	let searcher = lua.create_function(move |lua, name: String| {
+		let valid = !name.is_empty() && !name.contains("..")
+			&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+		if !valid {
+			return Ok(Value::String(lua.create_string(format!("\n\tinvalid module name '{}'", name))?));
+		}
+
+		let file = name.replace('.', "/");
+		let mut tried = String::new();
+		for folder in &folders {
+			let path = folder.replace('?', &file);
+			match fs::read(&path) {
+				Ok(data) => {
+					return lua.load(data)
+						.set_name(format!("@{}", path))
+						.set_mode(ChunkMode::Text)
+						.into_function()
+						.map(Value::Function);
+				},
+				Err(_) => tried.push_str(&format!("\n\tno file '{}'", path))
+			}
+		}
+
+		Ok(Value::String(lua.create_string(tried)?))
+	})?;
+
+	package.set("loaders", lua.create_sequence_from([Value::Function(preload), Value::Function(searcher)])?)
+}
+
+/// Resolves `path` inside `root`, refusing absolute paths and anything that leaves `root`,
+/// including through symbolic links.
+pub fn jail_path(root: &Path, path: &str) -> LuaResult<String> {
+	fn denied<T>(path: &str) -> LuaResult<T> {
+		Err(Error::RuntimeError(format!("Access to '{}' denied by the sandbox", path)))
+	}
+
+	if path.contains('\0') {
+		return denied(path);
+	}
+
+	let mut relative = PathBuf::new();
+	for component in Path::new(path).components() {
+		match component {
+			Component::Normal(c) => relative.push(c),
+			Component::CurDir => {},
+			Component::ParentDir => {
+				if !relative.pop() {
+					return denied(path);
+				}
+			},
+			Component::RootDir | Component::Prefix(_) => return denied(path)
+		}
+	}
+
+	let root = match root.canonicalize() {
+		Ok(r) => r,
+		Err(e) => return Err(Error::RuntimeError(format!("Sandbox io_root {} is not accessible: {}", root.display(), e)))
+	};
+	let full = root.join(&relative);
+
+	// The file may not exist yet: check the closest existing ancestor instead
+	let mut existing = full.as_path();
+	while !existing.exists() {
+		existing = match existing.parent() {
+			Some(p) => p,
+			None => return denied(path)
+		};
+	}
+
+	match existing.canonicalize() {
+		Ok(p) if p.starts_with(&root) => Ok(full.to_string_lossy().to_string()),
+		_ => denied(path)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use mlua::{Lua, Value};
+	use crate::behaviours::lua_sandbox::{jail_path, SandboxProfile};
+
+	#[test]
+	pub fn test_sandbox() {
+		let root = std::env::temp_dir().join(format!("jwx_sandbox_test_{}", std::process::id()));
+		fs::create_dir_all(root.join("lib")).unwrap();
+		fs::write(root.join("data.txt"), "hello").unwrap();
+		fs::write(root.join("lib/helper.lua"), "return { value = 42 }").unwrap();
+
+		assert!(jail_path(&root, "data.txt").unwrap().ends_with("data.txt"));
+		assert!(jail_path(&root, "a/../new.txt").is_ok());
+		assert!(jail_path(&root, "../data.txt").is_err());
+		assert!(jail_path(&root, "/etc/passwd").is_err());
+
+		let lua = Lua::new();
+		let profile = SandboxProfile {
+			modules: vec!["io".to_string(), "os".to_string(), "string".to_string()],
+			io_root: Some(root.clone())
+		};
+		let folders = vec![format!("{}/lib/?.lua", root.display())];
+		profile.apply(&lua, &folders).unwrap();
+
+		let check = |code: &str| lua.load(code).eval::<Value>().unwrap();
+		assert_eq!(check("return require('helper').value").as_i64(), Some(42));
+		assert!(check("return os.execute").is_nil());
+		assert!(check("return math").is_nil());
+		assert!(check("return package.loadlib").is_nil());
+		assert_eq!(check("return io.open('data.txt'):read('*a')").to_string().unwrap(), "hello");
+		assert!(lua.load("return io.open('../x')").exec().is_err());
+		assert!(lua.load("return load(string.dump(function() end))").eval::<Value>().unwrap().is_nil());
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+}
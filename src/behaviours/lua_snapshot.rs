@@ -0,0 +1,126 @@
+use std::collections::HashSet;
+use std::ffi::c_void;
+use mlua::prelude::LuaResult;
+use mlua::{Lua, Table, Value};
+
+/// A table as it was when the snapshot was taken
+struct SavedTable {
+	table: Table,
+	metatable: Option<Table>,
+	entries: Vec<(Value, Value)>
+}
+
+/// The contents of every table reachable from the globals and the loaded modules of a VM, so
+/// a pool worker can undo what a request changed and serve the next one from the loaded
+/// state. Locals captured by functions (upvalues) and the insides of userdata aren't part of
+/// it.
+pub struct LuaSnapshot {
+	tables: Vec<SavedTable>
+}
+
+impl LuaSnapshot {
+	pub fn take(lua: &Lua) -> LuaResult<LuaSnapshot> {
+		let mut snapshot = LuaSnapshot { tables: Vec::new() };
+		let mut seen: HashSet<*const c_void> = HashSet::new();
+
+		let mut pending = vec![lua.globals()];
+		// Modules loaded by `require` stay there even when `package` isn't a global
+		if let Ok(loaded) = lua.named_registry_value::<Table>("_LOADED") {
+			pending.push(loaded);
+		}
+
+		while let Some(table) = pending.pop() {
+			if !seen.insert(table.to_pointer()) {
+				continue;
+			}
+
+			let mut entries = Vec::new();
+			for pair in table.pairs::<Value, Value>() {
+				let (key, value) = pair?;
+				for v in [&key, &value] {
+					if let Value::Table(t) = v {
+						pending.push(t.clone());
+					}
+				}
+				entries.push((key, value));
+			}
+
+			let metatable = table.metatable();
+			if let Some(mt) = &metatable {
+				pending.push(mt.clone());
+			}
+
+			snapshot.tables.push(SavedTable { table, metatable, entries });
+		}
+
+		Ok(snapshot)
+	}
+
+	/// Puts every saved table back the way it was. Tables created since then are no longer
+	/// reachable and left to the garbage collector.
+	pub fn restore(&self) -> LuaResult<()> {
+		for saved in &self.tables {
+			let keys = saved.table.pairs::<Value, Value>()
+				.map(|pair| pair.map(|(k, _)| k))
+				.collect::<LuaResult<Vec<Value>>>()?;
+			for key in keys {
+				saved.table.raw_set(key, Value::Nil)?;
+			}
+
+			for (key, value) in &saved.entries {
+				saved.table.raw_set(key, value)?;
+			}
+
+			saved.table.set_metatable(saved.metatable.clone());
+		}
+
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::{Lua, Table};
+	use crate::behaviours::lua_snapshot::LuaSnapshot;
+
+	#[test]
+	pub fn test_snapshot_restore() {
+		let lua = Lua::new();
+		lua.load(r#"
+			counter = 0
+			config = { name = "jwx", list = { 1, 2 } }
+			package.loaded.helper = { calls = 0 }
+			setmetatable(config, { __index = function() return "default" end })
+		"#).exec().unwrap();
+
+		let snapshot = LuaSnapshot::take(&lua).unwrap();
+
+		lua.load(r#"
+			counter = counter + 1
+			config.name = nil
+			config.list[3] = 3
+			config.extra = {}
+			setmetatable(config, nil)
+			package.loaded.helper.calls = 5
+			package.loaded.other = true
+			leaked = "global"
+			string.custom = true
+		"#).exec().unwrap();
+
+		snapshot.restore().unwrap();
+
+		let (counter, name, len, missing, calls, other, leaked, custom): (i64, String, i64, String, i64, bool, bool, bool) = lua.load(r#"
+			return counter, config.name, #config.list, config.missing, package.loaded.helper.calls,
+				package.loaded.other ~= nil, leaked ~= nil, string.custom ~= nil
+		"#).eval().unwrap();
+		assert_eq!((counter, name.as_str(), len, missing.as_str(), calls), (0, "jwx", 2, "default", 0));
+		assert!(!other && !leaked && !custom);
+
+		// Restoring again after another request works the same
+		lua.load("counter = 10").exec().unwrap();
+		snapshot.restore().unwrap();
+		assert_eq!(lua.globals().get::<i64>("counter").unwrap(), 0);
+		assert!(lua.globals().get::<Table>("config").unwrap().metatable().is_some());
+	}
+}
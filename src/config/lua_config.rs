@@ -1,34 +1,157 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use mlua::prelude::*;
-use mlua::{Table, Error};
+use mlua::{Table, Error, Value};
+use crate::behaviours::behaviour::{ExecutionLimits, IsolationMode};
+use crate::behaviours::lua_sandbox::SandboxProfile;
+use crate::access_log::AccessLogConfig;
+use crate::log::LogConfig;
+use crate::worker_pool::PoolConfig;
+use crate::session::SessionConfig;
+
+#[derive(Clone, Debug)]
+pub struct EndpointConfig {
+	pub script: String,
+	pub name: Option<String>,
+	pub isolation: IsolationMode,
+	pub sandbox: Option<SandboxProfile>,
+	pub limits: ExecutionLimits
+}
+
+impl EndpointConfig {
+	pub fn new(script: &str) -> Self {
+		EndpointConfig {
+			script: script.to_string(),
+			name: None,
+			isolation: IsolationMode::Snapshot,
+			sandbox: None,
+			limits: ExecutionLimits::default()
+		}
+	}
+
+	fn apply_options(&mut self, options: &Table) -> Result<(), Error> {
+		self.name = options.get("name")?;
+
+		if let Some(isolation) = options.get::<Option<String>>("isolation")? {
+			self.isolation = match IsolationMode::from_str(&isolation) {
+				Some(i) => i,
+				None => return Err(Error::RuntimeError(format!("Unknown isolation mode '{}', expected fresh, snapshot or persistent", isolation)))
+			};
+		}
+
+		self.sandbox = SandboxProfile::from_option(options.get("sandbox")?)?;
+
+		self.limits = ExecutionLimits {
+			timeout: options.get("timeout")?,
+			max_instructions: options.get("max_instructions")?,
+			max_memory: options.get("max_memory")?,
+			max_cpu_time: options.get("max_cpu_time")?,
+			max_address_space: options.get("max_address_space")?
+		};
+
+		Ok(())
+	}
+
+	/// Middlewares run in the worker of the endpoint they wrap, under its isolation mode and
+	/// address space limit, and have no route to name
+	fn apply_middleware_options(&mut self, options: &Table) -> Result<(), Error> {
+		for option in ["isolation", "max_address_space", "name"] {
+			if options.contains_key(option)? {
+				return Err(Error::RuntimeError(format!("{} doesn't apply to middlewares", option)));
+			}
+		}
+
+		self.apply_options(options)
+	}
+}
+
+/// Hashes the content of a script, to tell if it changed
+fn hash_script(path: &str) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	fs::read(path).unwrap_or_default().hash(&mut hasher);
+	hasher.finish()
+}
 
 pub struct ConfigMgr {
 	config_directory: String,
-	endpoints: HashMap<String, String>,
-	library_folders: Vec<String>
+	config_file: String,
+	/// Hash of every endpoint's script when the config was run, to tell what a reload changed
+	script_hashes: HashMap<String, u64>,
+	/// Hash of every middleware's script, in the order of `middlewares`
+	middleware_hashes: Vec<u64>,
+	endpoints: HashMap<String, EndpointConfig>,
+	/// Middleware scripts in registration order, with the route prefix they apply to
+	middlewares: Vec<(String, EndpointConfig)>,
+	library_folders: Vec<String>,
+	max_body_size: usize,
+	max_upload_size: usize,
+	dev_mode: bool,
+	watch: bool,
+	store_file: Option<String>,
+	sessions: Option<SessionConfig>,
+	template_folder: Option<String>,
+	log: LogConfig,
+	access_log: AccessLogConfig,
+	worker_pool: Option<PoolConfig>
 }
 
-const CONFIG_ENV_CFG_PATH_NAME: &'static str = "internal_config_path";
+const CONFIG_ENV_CFG_PATH_NAME: &str = "internal_config_path";
+const CONFIG_ENDPOINT_OPTIONS_NAME: &str = "config_endpoint_options";
+const CONFIG_OPTIONS_NAME: &str = "config_options";
+const CONFIG_MIDDLEWARES_NAME: &str = "config_middlewares";
+
+const DEFAULT_MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Registers a `config_set_*` function that stores its argument in the `config_options` table
+fn register_option_setter(lua: &Lua, function_name: &str, option_name: &'static str) -> Result<(), Error> {
+	let f = lua.create_function(move |lua: &Lua, value: Value| -> Result<i32, Error> {
+		let options: Table = lua.globals().get(CONFIG_OPTIONS_NAME)?;
+		options.set(option_name, value)?;
+		Ok(0)
+	})?;
+
+	lua.globals().set(function_name, f)
+}
 
 impl ConfigMgr {
 	pub fn new(config_dr: &str) -> Self {
 		ConfigMgr {
 			config_directory: config_dr.to_string(),
+			config_file: String::new(),
+			script_hashes: HashMap::new(),
+			middleware_hashes: Vec::new(),
 			endpoints: HashMap::new(),
-			library_folders: Vec::new()
+			middlewares: Vec::new(),
+			library_folders: Vec::new(),
+			max_body_size: DEFAULT_MAX_BODY_SIZE,
+			max_upload_size: DEFAULT_MAX_BODY_SIZE,
+			dev_mode: false,
+			watch: false,
+			store_file: None,
+			sessions: None,
+			template_folder: None,
+			log: LogConfig::default(),
+			access_log: AccessLogConfig::default(),
+			worker_pool: None
 		}
 	}
 
+	// Not used by the Lua config, which fills the fields itself, but kept for building a config
+	// in code
+	#[allow(dead_code)]
 	pub fn add_library_folder(&mut self, folder: &str) {
 		self.library_folders.push(folder.to_string());
 	}
 
-	pub fn set_endpoint(&mut self, endpoint: &str, script: &str) {
-		self.endpoints.insert(endpoint.to_string(), script.to_string());
+	#[allow(dead_code)]
+	pub fn set_endpoint(&mut self, endpoint: &str, config: EndpointConfig) {
+		self.endpoints.insert(endpoint.to_string(), config);
 	}
 
+	#[allow(dead_code)]
 	pub fn remove_endpoint(&mut self, endpoint: &str) {
 		self.endpoints.remove(endpoint);
 	}
@@ -37,10 +160,226 @@ impl ConfigMgr {
 		&self.library_folders
 	}
 
-	pub fn get_endpoints(&self) -> &HashMap<String, String> {
+	pub fn get_endpoints(&self) -> &HashMap<String, EndpointConfig> {
 		&self.endpoints
 	}
 
+	pub fn get_middlewares(&self) -> &Vec<(String, EndpointConfig)> {
+		&self.middlewares
+	}
+
+	/// Largest request body (in bytes) the listener accepts before answering 413
+	pub fn get_max_body_size(&self) -> usize {
+		self.max_body_size
+	}
+
+	/// Largest single file (in bytes) accepted in a multipart upload
+	pub fn get_max_upload_size(&self) -> usize {
+		self.max_upload_size
+	}
+
+	/// Whether failing requests get a detailed error page instead of a generic one
+	pub fn is_dev_mode(&self) -> bool {
+		self.dev_mode
+	}
+
+	/// Whether the config file, endpoint scripts and library folders are watched for changes
+	pub fn is_watching(&self) -> bool {
+		self.watch
+	}
+
+	/// File `jwx.store` is saved to, so its data survives restarts
+	pub fn get_store_file(&self) -> Option<&String> {
+		self.store_file.as_ref()
+	}
+
+	/// Log level, format and destination
+	pub fn get_log(&self) -> &LogConfig {
+		&self.log
+	}
+
+	/// Pre-forked workers serving the requests of non-persistent endpoints; `None` to fork a
+	/// process for every request
+	pub fn get_worker_pool(&self) -> Option<&PoolConfig> {
+		self.worker_pool.as_ref()
+	}
+
+	/// Access log format and destination, written by the listener
+	pub fn get_access_log(&self) -> &AccessLogConfig {
+		&self.access_log
+	}
+
+	/// Folder `jwx.render` loads templates from
+	pub fn get_template_folder(&self) -> Option<&String> {
+		self.template_folder.as_ref()
+	}
+
+	/// Session settings, if `jwx.session` is enabled
+	pub fn get_sessions(&self) -> Option<&SessionConfig> {
+		self.sessions.as_ref()
+	}
+
+	fn load_options(&mut self, options: &Table) -> Result<(), Error> {
+		if let Some(v) = options.get::<Option<usize>>("max_body_size")? {
+			self.max_body_size = v;
+		}
+
+		if let Some(v) = options.get::<Option<usize>>("max_upload_size")? {
+			self.max_upload_size = v;
+		}
+
+		if let Some(v) = options.get::<Option<bool>>("dev_mode")? {
+			self.dev_mode = v;
+		}
+
+		if let Some(v) = options.get::<Option<bool>>("watch")? {
+			self.watch = v;
+		}
+
+		self.store_file = options.get("store_file")?;
+		self.sessions = SessionConfig::from_option(options.get("sessions")?)?;
+		self.template_folder = options.get("template_folder")?;
+		self.log = LogConfig::from_option(options.get("log")?)?;
+		self.access_log = AccessLogConfig::from_option(options.get("access_log")?)?;
+		self.worker_pool = PoolConfig::from_option(options.get("worker_pool")?)?;
+
+		Ok(())
+	}
+
+	/// Maps every endpoint name to the route it was registered on.
+	pub fn get_endpoint_names(&self) -> HashMap<String, String> {
+		let mut names = HashMap::new();
+		for (endpoint, config) in &self.endpoints {
+			if let Some(name) = &config.name {
+				if let Some(previous) = names.insert(name.clone(), endpoint.clone()) {
+					log_warn!("ConfigMgr", "Endpoint name '{}' is used by both {} and {}", name, previous, endpoint);
+				}
+			}
+		}
+
+		names
+	}
+
+	/// Runs the config file again in a new config manager, leaving this one untouched
+	pub fn reload(&self) -> Result<ConfigMgr, String> {
+		let mut res = ConfigMgr::new(&self.config_directory);
+		res.run_config(&self.config_file)?;
+
+		if let (Some(new), Some(old)) = (res.sessions.as_mut(), self.sessions.as_ref()) {
+			new.keep_secret(old);
+		}
+
+		Ok(res)
+	}
+
+	/// Folders holding the config file, the endpoint scripts and the library folders
+	pub fn get_watched_folders(&self) -> Vec<PathBuf> {
+		let mut files: Vec<&str> = vec![&self.config_file];
+		files.extend(self.endpoints.values().map(|e| e.script.as_str()));
+		files.extend(self.middlewares.iter().map(|(_, m)| m.script.as_str()));
+
+		let mut folders: Vec<PathBuf> = files.iter()
+			.map(|f| Path::new(f).parent().map(|p| p.to_path_buf()).unwrap_or_default())
+			.collect();
+
+		// Library folders are package.path templates such as ./lua/lib/?.lua
+		for folder in &self.library_folders {
+			let prefix = &folder[..folder.find('?').unwrap_or(folder.len())];
+			folders.push(Path::new(prefix).parent().map(|p| p.to_path_buf()).unwrap_or_default());
+		}
+
+		let mut res: Vec<PathBuf> = Vec::new();
+		for folder in folders {
+			let folder = if folder.as_os_str().is_empty() { PathBuf::from(".") } else { folder };
+			if !res.contains(&folder) {
+				res.push(folder);
+			}
+		}
+
+		res
+	}
+
+	/// Describes what differs between this configuration and `new`, for the reload log
+	pub fn describe_changes(&self, new: &ConfigMgr) -> Vec<String> {
+		let mut res = Vec::new();
+
+		for (endpoint, config) in &new.endpoints {
+			match self.endpoints.get(endpoint) {
+				None => res.push(format!("Added endpoint {} ({})", endpoint, config.script)),
+				Some(old) => {
+					if format!("{:?}", old) != format!("{:?}", config) {
+						res.push(format!("Changed options of endpoint {}", endpoint));
+					} else if self.script_hashes.get(endpoint) != new.script_hashes.get(endpoint) {
+						res.push(format!("Changed script of endpoint {} ({})", endpoint, config.script));
+					}
+				}
+			}
+		}
+
+		for endpoint in self.endpoints.keys() {
+			if !new.endpoints.contains_key(endpoint) {
+				res.push(format!("Removed endpoint {}", endpoint));
+			}
+		}
+
+		if format!("{:?}", self.middlewares) != format!("{:?}", new.middlewares) {
+			let scripts: Vec<String> = new.middlewares.iter().map(|(prefix, m)| format!("{} ({})", m.script, prefix)).collect();
+			res.push(format!("Middlewares: {}", scripts.join(", ")));
+		} else {
+			for ((prefix, config), (old, new)) in new.middlewares.iter().zip(self.middleware_hashes.iter().zip(&new.middleware_hashes)) {
+				if old != new {
+					res.push(format!("Changed script of middleware {} ({})", config.script, prefix));
+				}
+			}
+		}
+
+		if self.library_folders != new.library_folders {
+			res.push(format!("Library folders: {:?}", new.library_folders));
+		}
+
+		if self.max_upload_size != new.max_upload_size {
+			res.push(format!("max_upload_size: {} -> {}", self.max_upload_size, new.max_upload_size));
+		}
+
+		if self.dev_mode != new.dev_mode {
+			res.push(format!("dev_mode: {} -> {}", self.dev_mode, new.dev_mode));
+		}
+
+		if self.watch != new.watch {
+			res.push(format!("watch: {} -> {}", self.watch, new.watch));
+		}
+
+		if self.max_body_size != new.max_body_size {
+			res.push(format!("max_body_size: {} -> {} (applied on restart)", self.max_body_size, new.max_body_size));
+		}
+
+		if self.store_file != new.store_file {
+			res.push(format!("store_file: {:?} -> {:?} (applied on restart)", self.store_file, new.store_file));
+		}
+
+		if self.log != new.log {
+			res.push(format!("log: {:?} -> {:?} (applied on restart)", self.log, new.log));
+		}
+
+		if self.worker_pool != new.worker_pool {
+			res.push(format!("worker_pool: {:?} -> {:?}", self.worker_pool, new.worker_pool));
+		}
+
+		if self.access_log != new.access_log {
+			res.push(format!("access_log: {:?} -> {:?} (applied on restart)", self.access_log, new.access_log));
+		}
+
+		if self.template_folder != new.template_folder {
+			res.push(format!("template_folder: {:?} -> {:?}", self.template_folder, new.template_folder));
+		}
+
+		if format!("{:?}", self.sessions) != format!("{:?}", new.sessions) {
+			res.push(format!("sessions: {:?}", new.sessions));
+		}
+
+		res
+	}
+
 	pub fn append_library_folders(&self, lua: &Lua) {
 		let package_table: Table = match lua.globals().get("package") {
 			Ok(v) => v,
@@ -55,34 +394,40 @@ impl ConfigMgr {
 		let mut full_path = String::new();
 		for folder in self.library_folders.iter() {
 			full_path.push_str(folder);
-			full_path.push_str(";");
+			full_path.push(';');
 		}
 
 		full_path.push_str(&package_path);
 
-		match package_table.set("path", full_path) {
-			Ok(_) => {},
-			Err(_) => return //TODO: Log error
+		if let Err(e) = package_table.set("path", full_path) {
+			log_error!("ConfigMgr", "Can't set package.path: {}", e);
 		}
 	}
 
-	pub fn run_config(&mut self, config_path: &str) {
+	/// Runs the config file. On error the configuration may be partially applied.
+	pub fn run_config(&mut self, config_path: &str) -> Result<(), String> {
 		let lua = Lua::new();
 		self.append_library_folders(&lua);
 
-		let endpoints = self.endpoints.clone();
+		let endpoints: HashMap<String, String> = self.endpoints.iter()
+			.map(|(k, v)| (k.clone(), v.script.clone()))
+			.collect();
 		let library_folders = self.library_folders.clone();
 
 		lua.globals().set("config_endpoints", endpoints).unwrap();
+		lua.globals().set(CONFIG_ENDPOINT_OPTIONS_NAME, lua.create_table().unwrap()).unwrap();
+		lua.globals().set(CONFIG_OPTIONS_NAME, lua.create_table().unwrap()).unwrap();
+		lua.globals().set(CONFIG_MIDDLEWARES_NAME, lua.create_table().unwrap()).unwrap();
 		lua.globals().set("config_library_folders", library_folders).unwrap();
 
 		if let Err(e) = lua.globals().set(CONFIG_ENV_CFG_PATH_NAME, self.config_directory.to_string()) {
-			println!("[ConfigMgr] Error setting config_directory: {}", e);
-			return
+			return Err(format!("Error setting config_directory: {}", e));
 		}
 
-		let set_config_endpoint = match lua.create_function(|lua: &Lua, args: (String, String)| -> Result<i32, Error> {
+		let set_config_endpoint = match lua.create_function(|lua: &Lua, args: (String, String, Option<Table>)| -> Result<i32, Error> {
 			let mut endpoints: HashMap<String, String> = lua.globals().get("config_endpoints").unwrap();
+			let options: Table = lua.globals().get(CONFIG_ENDPOINT_OPTIONS_NAME).unwrap();
+			options.set(args.0.clone(), args.2)?;
 			endpoints.insert(args.0, args.1);
 			lua.globals().set("config_endpoints", endpoints).unwrap();
 
@@ -90,32 +435,48 @@ impl ConfigMgr {
 		}) {
 			Ok(f) => f,
 			Err(e) => {
-				println!("[ConfigMgr] Error creating config_set_endpoint: {}", e);
-				return
+				return Err(format!("Error creating config_set_endpoint: {}", e));
 			}
 		};
 
 		if let Err(e) = lua.globals().set("config_set_endpoint", set_config_endpoint) {
-			println!("[ConfigMgr] Error setting config_set_endpoint: {}", e);
-			return
+			return Err(format!("Error setting config_set_endpoint: {}", e));
 		}
 
 		let remove_config_endpoint = match lua.create_function(|lua: &Lua, endpoint: String| -> Result<i32, Error> {
 			let mut endpoints: HashMap<String, String> = lua.globals().get("config_endpoints").unwrap();
+			let options: Table = lua.globals().get(CONFIG_ENDPOINT_OPTIONS_NAME).unwrap();
+			options.set(endpoint.clone(), mlua::Nil)?;
 			endpoints.remove(&endpoint);
 			lua.globals().set("config_endpoints", endpoints).unwrap();
 			Ok(0)
 		}) {
 			Ok(f) => f,
 			Err(e) => {
-				println!("[ConfigMgr] Error creating config_remove_endpoint: {}", e);
-				return
+				return Err(format!("Error creating config_remove_endpoint: {}", e));
 			}
 		};
 
 		if let Err(e) = lua.globals().set("config_remove_endpoint", remove_config_endpoint) {
-			println!("[ConfigMgr] Error setting config_remove_endpoint: {}", e);
-			return
+			return Err(format!("Error setting config_remove_endpoint: {}", e));
+		}
+
+		let add_config_middleware = match lua.create_function(|lua: &Lua, args: (String, Option<Table>)| -> Result<i32, Error> {
+			let middlewares: Table = lua.globals().get(CONFIG_MIDDLEWARES_NAME)?;
+			let entry = lua.create_table()?;
+			entry.set("script", args.0)?;
+			entry.set("options", args.1)?;
+			middlewares.push(entry)?;
+			Ok(0)
+		}) {
+			Ok(f) => f,
+			Err(e) => {
+				return Err(format!("Error creating config_add_middleware: {}", e));
+			}
+		};
+
+		if let Err(e) = lua.globals().set("config_add_middleware", add_config_middleware) {
+			return Err(format!("Error setting config_add_middleware: {}", e));
 		}
 
 		let add_config_library_folder = match lua.create_function(|lua: &Lua, folder: String| -> Result<i32, Error> {
@@ -126,14 +487,29 @@ impl ConfigMgr {
 		}) {
 			Ok(f) => f,
 			Err(e) => {
-				println!("[ConfigMgr] Error creating config_add_library_folder: {}", e);
-				return
+				return Err(format!("Error creating config_add_library_folder: {}", e));
 			}
 		};
 
 		if let Err(e) = lua.globals().set("config_add_library_folder", add_config_library_folder) {
-			println!("[ConfigMgr] Error setting config_add_library_folder: {}", e);
-			return
+			return Err(format!("Error setting config_add_library_folder: {}", e));
+		}
+
+		for (function_name, option_name) in [
+			("config_set_max_body_size", "max_body_size"),
+			("config_set_max_upload_size", "max_upload_size"),
+			("config_set_dev_mode", "dev_mode"),
+			("config_set_watch", "watch"),
+			("config_set_store_file", "store_file"),
+			("config_set_sessions", "sessions"),
+			("config_set_template_folder", "template_folder"),
+			("config_set_log", "log"),
+			("config_set_access_log", "access_log"),
+			("config_set_worker_pool", "worker_pool")
+		] {
+			if let Err(e) = register_option_setter(&lua, function_name, option_name) {
+				return Err(format!("Error setting {}: {}", function_name, e));
+			}
 		}
 
 		let p = Path::new(config_path);
@@ -142,22 +518,81 @@ impl ConfigMgr {
 			target = Path::new(&self.config_directory).join(p).to_str().unwrap().to_string();
 		}
 
-		println!("[ConfigMgr] Running config: {}", target);
+		log_info!("ConfigMgr", "Running config: {}", target);
+		self.config_file = target.clone();
 
-		let stream = match fs::read_to_string(target) {
+		let stream = match fs::read_to_string(&target) {
 			Ok(s) => s,
 			Err(e) => {
-				println!("[ConfigMgr] Error reading config file: {}", e);
-				return
+				return Err(format!("Error reading config file: {}", e));
 			}
 		};
 
-		if let Err(e) = lua.load(stream).exec() {
-			println!("[ConfigMgr] Error executing config file: {}", e);
-			return
+		if let Err(e) = lua.load(stream).set_name(format!("@{}", target)).exec() {
+			return Err(format!("Error executing config file: {}", e));
 		}
 
 		self.library_folders = lua.globals().get("config_library_folders").unwrap();
-		self.endpoints = lua.globals().get("config_endpoints").unwrap();
+
+		// Invalid options are skipped so the rest of the config still applies, but reported
+		let mut errors: Vec<String> = Vec::new();
+
+		let options: Table = lua.globals().get(CONFIG_OPTIONS_NAME).unwrap();
+		if let Err(e) = self.load_options(&options) {
+			errors.push(format!("Invalid option value: {}", e));
+		}
+
+		let endpoints: HashMap<String, String> = lua.globals().get("config_endpoints").unwrap();
+		let options: Table = lua.globals().get(CONFIG_ENDPOINT_OPTIONS_NAME).unwrap();
+
+		self.endpoints.clear();
+		for (endpoint, script) in endpoints {
+			let mut config = EndpointConfig::new(&script);
+			if let Ok(Some(o)) = options.get::<Option<Table>>(endpoint.clone()) {
+				if let Err(e) = config.apply_options(&o) {
+					errors.push(format!("Invalid options for endpoint {}: {}", endpoint, e));
+					continue;
+				}
+			}
+
+			self.endpoints.insert(endpoint, config);
+		}
+
+		self.middlewares.clear();
+		let middlewares: Table = lua.globals().get(CONFIG_MIDDLEWARES_NAME).unwrap();
+		for entry in middlewares.sequence_values::<Table>() {
+			let (script, options) = match entry.and_then(|e| Ok((e.get::<String>("script")?, e.get::<Option<Table>>("options")?))) {
+				Ok(v) => v,
+				Err(e) => {
+					errors.push(format!("Invalid middleware: {}", e));
+					continue;
+				}
+			};
+
+			let mut config = EndpointConfig::new(&script);
+			let mut prefix = "/".to_string();
+			if let Some(o) = options {
+				let res = config.apply_middleware_options(&o).and_then(|_| o.get::<Option<String>>("prefix"));
+				match res {
+					Ok(p) => prefix = p.unwrap_or(prefix),
+					Err(e) => {
+						errors.push(format!("Invalid options for middleware {}: {}", script, e));
+						continue;
+					}
+				}
+			}
+
+			self.middlewares.push((prefix, config));
+		}
+
+		self.script_hashes = self.endpoints.iter()
+			.map(|(endpoint, config)| (endpoint.clone(), hash_script(&config.script)))
+			.collect();
+		self.middleware_hashes = self.middlewares.iter().map(|(_, config)| hash_script(&config.script)).collect();
+
+		match errors.is_empty() {
+			true => Ok(()),
+			false => Err(errors.join("; "))
+		}
 	}
 }
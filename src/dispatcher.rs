@@ -1,110 +1,663 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
-use crate::behaviours::behaviour::Behaviour;
-use crate::behaviours::behaviour_router::BehaviourRouter;
+use std::io::Read;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use libc::{c_int, pid_t};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Duration;
+use crate::behaviours::behaviour::{Behaviour, ExecutionLimits, IsolationMode};
+use crate::behaviours::behaviour_router::{BehaviourRouter, Route};
 use crate::behaviours::lua_behaviour::LuaBehaviour;
 use crate::config::lua_config::ConfigMgr;
-use crate::http::http_message::HttpMessage;
 use crate::http::http_request::HttpRequest;
-use crate::ipc::{IpcMessage, IpcMessageReceiver, IpcMessageSender};
+use crate::http::response_stream::ResponseStream;
+use crate::ipc::{read_message_with_fds, send_message_with_fds, IpcMessage, IpcMessageSender};
+use crate::reload::{drain, sighup_notifier, FileWatcher};
+use crate::supervisor::{reap_children, sigchld_notifier};
 use crate::utils::{safe_fork, ForkResult};
+use crate::watchdog::{apply_rlimits, TrackedWriter, Watchdog};
+use crate::worker_pool::{PendingRequest, WorkerPool};
 
-pub fn run_lua_dispatcher(config_mgr: ConfigMgr, mut lua_recv: File, mut control_send: File) -> std::io::Result<()> {
+/// How long to wait after a watched file changes before reloading
+const WATCH_SETTLE_TIME: Duration = Duration::from_millis(200);
+/// How often the worker pool looks for idle workers to stop
+const POOL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handles a request read from `stream`, writing the response to `output`
+pub fn serve_request(router: &BehaviourRouter, mut stream: File, output: File) -> std::io::Result<()> {
+	let mut len_buff = [0u8; 8];
+	stream.read_exact(&mut len_buff)?;
+	let len = u64::from_ne_bytes(len_buff);
+
+	let mut data = vec![0u8; len as usize];
+	stream.read_exact(&mut data)?;
+
+	let request = match HttpRequest::parse(&data) {
+		Some(r) => r,
+		None => {
+			log_error!("Dispatcher", "Error while parsing request.");
+			return Ok(());
+		}
+	};
+
+	let input: RefCell<Box<dyn Read>> = RefCell::new(Box::new(stream));
+	let stream = output;
+
+	let started = Arc::new(AtomicBool::new(false));
+	let watchdog = match router.get(&request.url.uri) {
+		Some((_, b)) => Watchdog::start(
+			&b.limits(),
+			format!("{} {}", request.method.to_str(), request.url.uri),
+			stream.try_clone()?,
+			started.clone(),
+			request.version.clone()
+		),
+		None => None
+	};
+
+	let stream = TrackedWriter::new(stream, started);
+	let stream = RefCell::new(ResponseStream::new(Box::new(stream), request.version.clone()));
+	let mut response = router.run(request, &stream, &input);
+	if !stream.borrow().has_started() {
+		stream.borrow_mut().send(&mut response)?;
+	}
+
+	if let Some(w) = watchdog {
+		w.stop();
+	}
+
+	Ok(())
+}
+
+/// Takes the input and output streams passed with a request
+pub fn request_streams(fds: Vec<File>) -> Option<(File, File)> {
+	let mut fds = fds.into_iter();
+	match (fds.next(), fds.next()) {
+		(Some(input), Some(output)) => Some((input, output)),
+		_ => None
+	}
+}
+
+/// Serves the requests of a persistent endpoint one after the other, so the Lua state of
+/// its behaviour is kept from one request to the next. Exits when the dispatcher goes away.
+fn run_persistent_worker(router: &BehaviourRouter, mut requests: UnixStream) -> std::io::Result<()> {
+	loop {
+		match read_message_with_fds(&mut requests) {
+			Ok((IpcMessage::Request { .. }, fds)) => {
+				let res = match request_streams(fds) {
+					Some((input, output)) => serve_request(router, input, output),
+					None => Err(std::io::Error::other("Request streams missing"))
+				};
+
+				if let Err(e) = res {
+					log_error!("Dispatcher", "Persistent worker failed to serve request: {:?}", e);
+				}
+			},
+			Ok((IpcMessage::Close, _)) | Err(_) => break,
+			Ok(_) => {}
+		}
+	}
+
+	Ok(())
+}
+
+/// Creates the behaviour of every endpoint of `config_mgr`
+fn build_router(config_mgr: &ConfigMgr) -> std::io::Result<BehaviourRouter> {
 	let mut behaviours: HashMap<String, Box<dyn Behaviour>> = HashMap::new();
 	for e in config_mgr.get_endpoints() {
-		if e.1.to_lowercase().ends_with(".lua") {
-			let b = LuaBehaviour::new(&config_mgr, &e.1)?;
+		if e.1.script.to_lowercase().ends_with(".lua") {
+			let b = match LuaBehaviour::new(config_mgr, e.1) {
+				Ok(b) => b,
+				Err(err) => return Err(std::io::Error::other(format!("{}: {}", e.0, std::io::Error::from(err))))
+			};
 
 			behaviours.insert(e.0.clone(), Box::new(b));
 		} else {
-			println!("Unknown behaviour type for endpoint {}. Suppoerted types are: .lua", e.0);
+			log_error!("Dispatcher", "Unknown behaviour type for endpoint {}. Suppoerted types are: .lua", e.0);
+		}
+
+	}
+
+	let mut router = BehaviourRouter::new(behaviours, config_mgr.get_endpoint_names());
+	router.set_dev_mode(config_mgr.is_dev_mode());
+
+	for (prefix, config) in config_mgr.get_middlewares() {
+		if !config.script.to_lowercase().ends_with(".lua") {
+			log_error!("Dispatcher", "Unknown middleware type for {}. Supported types are: .lua", config.script);
+			continue;
+		}
+
+		match LuaBehaviour::new(config_mgr, config) {
+			Ok(m) => router.add_middleware(prefix, Box::new(m)),
+			Err(err) => return Err(std::io::Error::other(format!("Middleware {}: {}", config.script, std::io::Error::from(err))))
+		}
+	}
+
+	Ok(router)
+}
+
+/// The long-lived worker of a persistent endpoint
+struct PersistentWorker {
+	pid: pid_t,
+	socket: UnixStream,
+	limits: ExecutionLimits
+}
+
+/// Forks the worker of the persistent endpoint at `route`. It closes `inherited`, which must
+/// hold the sockets of the other workers.
+fn spawn_persistent_worker(router: &BehaviourRouter, route: &str, limits: ExecutionLimits, inherited: &[RawFd]) -> std::io::Result<PersistentWorker> {
+	let (recv, send) = UnixStream::pair()?;
+	match safe_fork()? {
+		ForkResult::Child => {
+			drop(send);
+			for fd in inherited {
+				unsafe { libc::close(*fd) };
+			}
+
+			apply_rlimits(&limits, false);
+
+			let res = run_persistent_worker(router, recv);
+			std::process::exit(if res.is_ok() { 0 } else { 1 });
+		},
+		ForkResult::Parent(pid) => {
+			log_info!("Dispatcher", "Started persistent worker {} for {}", pid, route);
+			Ok(PersistentWorker {
+				pid,
+				socket: send,
+				limits
+			})
+		}
+	}
+}
+
+/// Forks a long-lived worker for every persistent endpoint, keyed by route. The workers close
+/// the dispatcher's own descriptors (`inherited`), those of the previous workers included.
+fn start_persistent_workers(config_mgr: &ConfigMgr, router: &BehaviourRouter, inherited: &[RawFd]) -> std::io::Result<HashMap<String, PersistentWorker>> {
+	let endpoints: Vec<String> = config_mgr.get_endpoints().iter()
+		.filter(|(_, config)| config.isolation == IsolationMode::Persistent)
+		.map(|(endpoint, _)| endpoint.clone())
+		.collect();
+
+	start_persistent_workers_for(router, &endpoints, inherited)
+}
+
+fn start_persistent_workers_for(router: &BehaviourRouter, endpoints: &[String], inherited: &[RawFd]) -> std::io::Result<HashMap<String, PersistentWorker>> {
+	let mut workers: HashMap<String, PersistentWorker> = HashMap::new();
+	for endpoint in endpoints {
+		let mut fds = inherited.to_vec();
+		fds.extend(worker_fds(&workers));
+
+		let limits = router.get(endpoint).map(|(_, b)| b.limits()).unwrap_or_default();
+		let route = Route::parse(endpoint).to_string();
+		let worker = spawn_persistent_worker(router, &route, limits, &fds)?;
+		workers.insert(route, worker);
+	}
+
+	Ok(workers)
+}
+
+/// Replaces the worker of `route`, which exited or can't be reached. `inherited` are the
+/// descriptors of the dispatcher besides those of the workers.
+fn restart_persistent_worker(router: &BehaviourRouter, workers: &mut HashMap<String, PersistentWorker>, route: &str, limits: ExecutionLimits, inherited: &[RawFd]) -> std::io::Result<()> {
+	// Dropped first, so the new worker doesn't close a descriptor reusing its number
+	workers.remove(route);
+
+	let mut fds = inherited.to_vec();
+	fds.extend(worker_fds(workers));
+
+	let worker = spawn_persistent_worker(router, route, limits, &fds)?;
+	workers.insert(route.to_string(), worker);
+
+	Ok(())
+}
+
+/// Restarts the persistent workers among the processes that exited, `pids`
+fn restart_exited_workers(router: &BehaviourRouter, workers: &mut HashMap<String, PersistentWorker>, pids: &[pid_t], inherited: &[RawFd]) {
+	let exited: Vec<(String, ExecutionLimits)> = workers.iter()
+		.filter(|(_, w)| pids.contains(&w.pid))
+		.map(|(route, w)| (route.clone(), w.limits))
+		.collect();
+
+	for (route, limits) in exited {
+		log_warn!("Dispatcher", "Persistent worker for {} exited, restarting it", route);
+		if let Err(e) = restart_persistent_worker(router, workers, &route, limits, inherited) {
+			log_error!("Dispatcher", "Can't restart the persistent worker for {}: {:?}", route, e);
 		}
+	}
+}
+
+fn worker_fds(workers: &HashMap<String, PersistentWorker>) -> Vec<RawFd> {
+	workers.values().map(|w| w.socket.as_raw_fd()).collect()
+}
+
+fn start_watcher(config_mgr: &ConfigMgr) -> Option<FileWatcher> {
+	if !config_mgr.is_watching() {
+		return None;
+	}
 
+	match FileWatcher::new(&config_mgr.get_watched_folders()) {
+		Ok(w) => Some(w),
+		Err(e) => {
+			log_warn!("Dispatcher", "Can't watch files for changes: {:?}", e);
+			None
+		}
 	}
+}
+
+enum Wakeup {
+	Message,
+	Reload,
+	/// A pool worker is done or exited, or it's time to check for idle workers
+	Pool,
+	/// Child processes exited
+	Children
+}
 
-	let router = BehaviourRouter::new(behaviours);
+/// Blocks until the listener sends a message, SIGHUP is received, a watched file changes,
+/// children exit or something happens in the worker pool
+fn wait_for_wakeup(lua_recv: &UnixStream, sighup: &mut File, sigchld: &mut File, mut watcher: Option<&mut FileWatcher>, pool: Option<&WorkerPool>) -> std::io::Result<Wakeup> {
+	let mut fds = vec![
+		libc::pollfd { fd: lua_recv.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+		libc::pollfd { fd: sighup.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+		libc::pollfd { fd: sigchld.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+	];
+	let watcher_idx = fds.len();
+	if let Some(w) = &watcher {
+		fds.push(libc::pollfd { fd: w.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+	}
+	let pool_idx = fds.len();
+	for fd in pool.map(|p| p.worker_fds()).unwrap_or_default() {
+		fds.push(libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+	}
+	let timeout = if pool.is_some() { POOL_CHECK_INTERVAL.as_millis() as c_int } else { -1 };
 
 	loop {
-		let msg = lua_recv.read_message()?;
+		for fd in fds.iter_mut() {
+			fd.revents = 0;
+		}
+
+		let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+		if ready < 0 {
+			let e = std::io::Error::last_os_error();
+			if e.kind() == std::io::ErrorKind::Interrupted {
+				continue;
+			}
+
+			return Err(e);
+		}
+
+		if ready == 0 {
+			return Ok(Wakeup::Pool);
+		}
+
+		if fds[1].revents != 0 {
+			drain(sighup);
+			log_info!("Dispatcher", "SIGHUP received");
+			return Ok(Wakeup::Reload);
+		}
+
+		if fds[2].revents != 0 {
+			drain(sigchld);
+			return Ok(Wakeup::Children);
+		}
+
+		if let (Some(w), true) = (watcher.as_deref_mut(), pool_idx > watcher_idx && fds[watcher_idx].revents != 0) {
+			// Editors often save in several steps, let them finish
+			thread::sleep(WATCH_SETTLE_TIME);
+
+			let changed = w.changed_files();
+			if !changed.is_empty() {
+				let names: Vec<String> = changed.iter().map(|p| p.display().to_string()).collect();
+				log_info!("Dispatcher", "Changed files: {}", names.join(", "));
+				return Ok(Wakeup::Reload);
+			}
+		}
+
+		if fds[0].revents != 0 {
+			return Ok(Wakeup::Message);
+		}
+
+		if fds[pool_idx..].iter().any(|fd| fd.revents != 0) {
+			return Ok(Wakeup::Pool);
+		}
+	}
+}
+
+/// Runs the config again and builds a new router and persistent workers from it. Returns
+/// `None`, leaving the current ones in place, if anything fails to load.
+fn reload(config_mgr: &ConfigMgr, inherited: &[RawFd]) -> Option<(ConfigMgr, BehaviourRouter, HashMap<String, PersistentWorker>)> {
+	log_info!("Dispatcher", "Reloading configuration");
+
+	let new_config = match config_mgr.reload() {
+		Ok(c) => c,
+		Err(e) => {
+			log_error!("Dispatcher", "Reload failed, keeping the current configuration: {}", e);
+			return None;
+		}
+	};
+
+	let router = match build_router(&new_config) {
+		Ok(r) => r,
+		Err(e) => {
+			log_error!("Dispatcher", "Reload failed, keeping the current configuration: {}", e);
+			return None;
+		}
+	};
+
+	let new_workers = match start_persistent_workers(&new_config, &router, inherited) {
+		Ok(w) => w,
+		Err(e) => {
+			log_error!("Dispatcher", "Reload failed, keeping the current configuration: {:?}", e);
+			return None;
+		}
+	};
+
+	let changes = config_mgr.describe_changes(&new_config);
+	if changes.is_empty() {
+		log_info!("Dispatcher", "Reloaded, no endpoint or option changed");
+	}
+	for change in changes {
+		log_info!("Dispatcher", "Reloaded: {}", change);
+	}
+
+	Some((new_config, router, new_workers))
+}
+
+/// Descriptors of the dispatcher itself, closed by the processes it forks. Those of the
+/// workers and the pool come and go, they are added by whoever forks.
+fn dispatcher_fds(channel: &UnixStream, signals: [&File; 2], watcher: Option<&FileWatcher>) -> Vec<RawFd> {
+	let mut fds = vec![channel.as_raw_fd(), signals[0].as_raw_fd(), signals[1].as_raw_fd()];
+	fds.extend(watcher.map(|w| w.as_raw_fd()));
+
+	fds
+}
+
+/// Serves `request` in a process of its own, which closes `inherited` first
+fn fork_request(router: &BehaviourRouter, request: PendingRequest, inherited: &[RawFd]) -> std::io::Result<()> {
+	match safe_fork()? {
+		ForkResult::Parent(pid) => {
+			log_debug!("Dispatcher", "Forked worker {} for {}", pid, request.uri);
+			Ok(())
+		},
+		ForkResult::Child => {
+			for fd in inherited {
+				unsafe { libc::close(*fd) };
+			}
+
+			if let Some((_, b)) = router.get(&request.uri) {
+				apply_rlimits(&b.limits(), true);
+			}
+
+			let res = serve_request(router, request.input, request.output);
+			std::process::exit(if res.is_ok() { 0 } else { 1 });
+		}
+	}
+}
+
+/// Sends `request` to the worker of the persistent endpoint at `route`. A worker that can't
+/// be reached (it exited and hasn't been collected yet) is restarted first.
+fn send_to_persistent_worker(router: &BehaviourRouter, workers: &mut HashMap<String, PersistentWorker>, route: &str, request: &PendingRequest, inherited: &[RawFd]) -> std::io::Result<()> {
+	let fds = [request.input.as_raw_fd(), request.output.as_raw_fd()];
+	let message = || IpcMessage::Request { id: request.id, uri: request.uri.clone(), streaming: request.streaming };
+
+	let sent = match workers.get_mut(route) {
+		Some(w) => send_message_with_fds(&mut w.socket, message(), &fds),
+		None => Err(std::io::Error::other("No worker"))
+	};
+
+	let e = match sent {
+		Ok(_) => return Ok(()),
+		Err(e) => e
+	};
+
+	log_warn!("Dispatcher", "Persistent worker for {} is unavailable, restarting it: {}", route, e);
+
+	// The request's own streams go to the new worker over its socket, not through the fork
+	let mut inherited = inherited.to_vec();
+	inherited.extend(fds);
+
+	let limits = router.get(&request.uri).map(|(_, b)| b.limits()).unwrap_or_default();
+	restart_persistent_worker(router, workers, route, limits, &inherited)?;
+	match workers.get_mut(route) {
+		Some(w) => send_message_with_fds(&mut w.socket, message(), &fds),
+		None => Err(e)
+	}
+}
+
+/// Hands `request` to the worker of its endpoint if it is persistent, to the worker pool if
+/// there is one, or to a new process. Streaming requests (WebSockets, event streams) always
+/// get a process of their own, forked from the loaded state even for persistent endpoints, so
+/// long-lived connections can't take up the whole pool or the only worker of an endpoint.
+/// `inherited` are the descriptors of the dispatcher itself, see `dispatcher_fds`.
+fn dispatch(router: &BehaviourRouter, workers: &mut HashMap<String, PersistentWorker>, pool: Option<&mut WorkerPool>, request: PendingRequest, inherited: &[RawFd]) -> std::io::Result<()> {
+	let persistent = match router.get(&request.uri) {
+		Some((route, b)) if !request.streaming && b.isolation() == IsolationMode::Persistent => Some(route.to_string()),
+		_ => None
+	};
+
+	if let Some(route) = persistent {
+		let mut fds = inherited.to_vec();
+		fds.extend(pool.map(|p| p.fds()).unwrap_or_default());
+
+		return send_to_persistent_worker(router, workers, &route, &request, &fds)
+			.map_err(|e| std::io::Error::other(format!("Persistent worker for {} is unavailable: {}", route, e)));
+	}
+
+	let mut fds = inherited.to_vec();
+	fds.extend(worker_fds(workers));
+
+	match pool {
+		// The pool adds its own descriptors
+		Some(pool) if !request.streaming => {
+			pool.dispatch(request, router, &fds);
+			Ok(())
+		},
+		pool => {
+			fds.extend(pool.map(|p| p.fds()).unwrap_or_default());
+			fork_request(router, request, &fds)
+		}
+	}
+}
+
+/// Serves the requests the listener sends over `channel`, a socket that also carries the
+/// streams of each request and the answers to the listener
+pub fn run_lua_dispatcher(mut config_mgr: ConfigMgr, mut channel: UnixStream) -> std::io::Result<()> {
+	let mut router = build_router(&config_mgr)?;
+	let mut sighup = sighup_notifier()?;
+	// Every worker is a child of the dispatcher, collected as soon as it exits
+	let mut sigchld = sigchld_notifier()?;
+	let mut watcher = start_watcher(&config_mgr);
+
+	// Persistent endpoints get a long-lived worker each, keyed by route
+	let mut workers = start_persistent_workers(&config_mgr, &router, &dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref()))?;
+
+	let mut pool = config_mgr.get_worker_pool().cloned().map(WorkerPool::new);
+	if let Some(p) = pool.as_mut() {
+		let mut fds = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+		fds.extend(worker_fds(&workers));
+		p.maintain(&router, &fds);
+	}
+
+	loop {
+		match wait_for_wakeup(&channel, &mut sighup, &mut sigchld, watcher.as_mut(), pool.as_ref())? {
+			Wakeup::Reload => {
+				let mut fds = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+				fds.extend(worker_fds(&workers));
+				fds.extend(pool.as_ref().map(|p| p.fds()).unwrap_or_default());
+
+				if let Some((c, r, w)) = reload(&config_mgr, &fds) {
+					// Dropping the previous workers' sockets makes them exit once idle
+					config_mgr = c;
+					router = r;
+					workers = w;
+					watcher = start_watcher(&config_mgr);
+
+					// Requests waiting for the previous pool go to the new one
+					let mut queue = pool.take().map(|p| p.into_queue()).unwrap_or_default();
+					pool = config_mgr.get_worker_pool().cloned().map(WorkerPool::new);
+
+					let inherited = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+					match pool.as_mut() {
+						Some(p) => {
+							let mut fds = inherited;
+							fds.extend(worker_fds(&workers));
+							p.requeue(queue);
+							p.maintain(&router, &fds);
+						},
+						None => while let Some(request) = queue.pop_front() {
+							// The requests still waiting are closed by the processes forked meanwhile
+							let mut fds = inherited.clone();
+							fds.extend(queue.iter().flat_map(|r| [r.input.as_raw_fd(), r.output.as_raw_fd()]));
+							_ = dispatch(&router, &mut workers, None, request, &fds);
+						}
+					}
+				}
+				continue;
+			},
+			Wakeup::Pool => {
+				if let Some(p) = pool.as_mut() {
+					p.collect();
+
+					let mut fds = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+					fds.extend(worker_fds(&workers));
+					p.maintain(&router, &fds);
+				}
+				continue;
+			},
+			Wakeup::Children => {
+				let exited = reap_children();
+
+				let mut fds = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+				fds.extend(pool.as_ref().map(|p| p.fds()).unwrap_or_default());
+				restart_exited_workers(&router, &mut workers, &exited, &fds);
+				continue;
+			},
+			Wakeup::Message => {}
+		}
+
+		let (msg, fds) = read_message_with_fds(&mut channel)?;
 		match msg {
 			IpcMessage::Poll => {
-				control_send.send_message(IpcMessage::Ok)?;
+				channel.send_message(IpcMessage::Ok)?;
 			}
 			IpcMessage::Ok => {
-				control_send.send_message(IpcMessage::Ok)?;
+				channel.send_message(IpcMessage::Ok)?;
 			}
-			IpcMessage::Request {request_path } => {
-				match safe_fork() {
-					Ok(ForkResult::Parent(_)) => {
-						control_send.send_message(IpcMessage::Ok)?;
+			IpcMessage::Request { id, uri, streaming } => {
+				let (input, output) = match request_streams(fds) {
+					Some(streams) => streams,
+					None => {
+						log_error!("Dispatcher", "Request for {} came without its streams", uri);
+						channel.send_message(IpcMessage::Reply { id, accepted: false })?;
+						continue;
 					}
-					Ok(ForkResult::Child) => {
-						drop(control_send);
-						drop(lua_recv);
-
-						let out_name = format!("/tmp/jwx_client_{request_path}.out");
-						let in_name = format!("/tmp/jwx_client_{request_path}.in");
-
-						if Path::new(&out_name).exists() && Path::new(&in_name).exists() {
-
-							let mut stream = match File::options().read(true).write(false).open(&out_name) {
-								Ok(stream) => stream,
-								Err(e) => {
-									println!("Failed to open FIFO: {:?}", e);
-									return Err(e)
-								}
-							};
-
-							let mut len_buff = [0u8; 8];
-							stream.read_exact(&mut len_buff)?;
-							let len = u64::from_ne_bytes(len_buff);
-
-							let mut data = vec![0u8; len as usize];
-							stream.read_exact(&mut data)?;
-
-							let request = match HttpRequest::parse(&data) {
-								Some(r) => r,
-								None => {
-									println!("Error while parsing request.");
-									return Ok(());
-								}
-							};
-
-							drop(stream);
-
-							let mut stream = match File::options().read(false).write(true).open(&in_name) {
-								Ok(stream) => stream,
-								Err(e) => {
-									println!("Failed to open FIFO: {:?}", e);
-									return Err(e)
-								}
-							};
-
-							let response = router.run(&request).serialize();
-							stream.write_all(&response)?;
-
-							drop(stream);
-						}
+				};
 
-						return Ok(());
-					},
+				let inherited = dispatcher_fds(&channel, [&sighup, &sigchld], watcher.as_ref());
+				let accepted = match dispatch(&router, &mut workers, pool.as_mut(), PendingRequest { id, uri, streaming, input, output }, &inherited) {
+					Ok(_) => true,
 					Err(e) => {
-						println!("Error while forking: {:?}", e);
-						control_send.send_message(IpcMessage::Close)?;
-						break
+						log_error!("Dispatcher", "Can't dispatch request: {:?}", e);
+						false
 					}
-				}
+				};
+				channel.send_message(IpcMessage::Reply { id, accepted })?;
 			},
+			IpcMessage::Reply { .. } => {},
 			IpcMessage::Close => {
-				print!("Dispatcher: CLOSE");
+				log_debug!("Dispatcher", "Close requested by the listener");
 				break
 			}
 		}
 	}
 
-	println!("[Dispatcher] Exiting");
+	log_info!("Dispatcher", "Exiting");
 
 	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::io::{Read, Write};
+	use crate::behaviours::behaviour::{Behaviour, BehaviourError, IsolationMode, RequestContext};
+	use crate::behaviours::behaviour_router::BehaviourRouter;
+	use crate::dispatcher::{dispatch, start_persistent_workers_for, PersistentWorker};
+	use crate::http::http_message::HttpMessage;
+	use crate::http::http_request::HttpRequest;
+	use crate::http::http_response::HttpResponse;
+	use crate::utils::new_pipe;
+	use crate::worker_pool::PendingRequest;
+
+	struct Hello;
+
+	impl Behaviour for Hello {
+		fn isolation(&self) -> IsolationMode {
+			IsolationMode::Persistent
+		}
+
+		fn run(&self, request: &HttpRequest, _: HashMap<String, String>, _: &RequestContext) -> Result<HttpResponse, BehaviourError> {
+			Ok(HttpResponse::new(200, HashMap::new(), b"hello".to_vec(), request.version.clone()))
+		}
+	}
+
+	fn request(router: &BehaviourRouter, workers: &mut HashMap<String, PersistentWorker>, streaming: bool) -> String {
+		let (input, mut request) = new_pipe().unwrap();
+		let (mut response, output) = new_pipe().unwrap();
+
+		let data = HttpRequest::parse(b"GET /hello HTTP/1.1\r\n\r\n").unwrap().serialize();
+		request.write_all(&(data.len() as u64).to_ne_bytes()).unwrap();
+		request.write_all(&data).unwrap();
+
+		dispatch(router, workers, None, PendingRequest { id: 1, uri: "/hello".to_string(), streaming, input, output }, &[]).unwrap();
+
+		let mut res = String::new();
+		response.read_to_string(&mut res).unwrap();
+		res
+	}
+
+	#[test]
+	pub fn test_persistent_worker_restart() {
+		let behaviours: HashMap<String, Box<dyn Behaviour>> = HashMap::from([("/hello".to_string(), Box::new(Hello) as Box<dyn Behaviour>)]);
+		let router = BehaviourRouter::new(behaviours, HashMap::new());
+		let mut workers = start_persistent_workers_for(&router, &["/hello".to_string()], &[]).unwrap();
+
+		assert!(request(&router, &mut workers, false).ends_with("hello"));
+
+		let pid = workers["/hello"].pid;
+		unsafe {
+			libc::kill(pid, libc::SIGKILL);
+			libc::waitpid(pid, std::ptr::null_mut(), 0);
+		}
+
+		assert!(request(&router, &mut workers, false).ends_with("hello"));
+		assert_ne!(workers["/hello"].pid, pid);
+
+		let pid = workers["/hello"].pid;
+		drop(workers);
+		unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+	}
+
+	#[test]
+	pub fn test_persistent_streaming_request() {
+		let behaviours: HashMap<String, Box<dyn Behaviour>> = HashMap::from([("/hello".to_string(), Box::new(Hello) as Box<dyn Behaviour>)]);
+		let router = BehaviourRouter::new(behaviours, HashMap::new());
+		let mut workers = start_persistent_workers_for(&router, &["/hello".to_string()], &[]).unwrap();
+
+		// A streaming request doesn't wait for the (here stopped) worker of the endpoint
+		let pid = workers["/hello"].pid;
+		unsafe { libc::kill(pid, libc::SIGSTOP) };
+		assert!(request(&router, &mut workers, true).ends_with("hello"));
+		unsafe { libc::kill(pid, libc::SIGCONT) };
+
+		assert!(request(&router, &mut workers, false).ends_with("hello"));
+		assert_eq!(workers["/hello"].pid, pid);
+
+		drop(workers);
+		unsafe { libc::waitpid(pid, std::ptr::null_mut(), 0) };
+	}
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use crate::http::http_message::is_token;
+
+#[derive(Default)]
+pub struct CookieOptions {
+	pub path: Option<String>,
+	pub domain: Option<String>,
+	pub max_age: Option<i64>,
+	/// Already formatted as an HTTP date
+	pub expires: Option<String>,
+	pub secure: bool,
+	pub http_only: bool,
+	pub same_site: Option<String>
+}
+
+/// RFC 6265 `cookie-octet`
+fn is_cookie_octet(c: char) -> bool {
+	c.is_ascii_graphic() && c != '"' && c != ',' && c != ';' && c != '\\'
+}
+
+fn is_valid_attribute(value: &str) -> bool {
+	value.chars().all(|c| !c.is_ascii_control() && c != ';')
+}
+
+/// Parses the value of a request `Cookie` header. When a cookie appears twice the first value
+/// (the most specific one, per RFC 6265) is kept.
+pub fn parse_cookies(header: &str) -> HashMap<String, String> {
+	let mut cookies = HashMap::new();
+
+	for pair in header.split(';') {
+		let pair = pair.trim();
+		let eq_idx = match pair.find('=') {
+			Some(idx) => idx,
+			None => continue
+		};
+
+		let name = pair[..eq_idx].trim();
+		let mut value = pair[eq_idx + 1..].trim();
+		if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+			value = &value[1..value.len() - 1];
+		}
+
+		if !name.is_empty() && !cookies.contains_key(name) {
+			cookies.insert(name.to_string(), value.to_string());
+		}
+	}
+
+	cookies
+}
+
+/// Builds the value of a `Set-Cookie` header
+pub fn serialize_cookie(name: &str, value: &str, options: &CookieOptions) -> Result<String, String> {
+	if !is_token(name) {
+		return Err(format!("Invalid cookie name '{}'", name));
+	}
+
+	if !value.chars().all(is_cookie_octet) {
+		return Err(format!("Invalid characters in the value of cookie '{}' (encode it first)", name));
+	}
+
+	let mut res = format!("{}={}", name, value);
+
+	if let Some(path) = &options.path {
+		if !is_valid_attribute(path) {
+			return Err(format!("Invalid Path for cookie '{}'", name));
+		}
+		res.push_str("; Path=");
+		res.push_str(path);
+	}
+
+	if let Some(domain) = &options.domain {
+		if !is_valid_attribute(domain) {
+			return Err(format!("Invalid Domain for cookie '{}'", name));
+		}
+		res.push_str("; Domain=");
+		res.push_str(domain);
+	}
+
+	if let Some(max_age) = options.max_age {
+		res.push_str(&format!("; Max-Age={}", max_age));
+	}
+
+	if let Some(expires) = &options.expires {
+		if !is_valid_attribute(expires) {
+			return Err(format!("Invalid Expires for cookie '{}'", name));
+		}
+		res.push_str("; Expires=");
+		res.push_str(expires);
+	}
+
+	if let Some(same_site) = &options.same_site {
+		let same_site = match same_site.to_lowercase().as_str() {
+			"strict" => "Strict",
+			"lax" => "Lax",
+			"none" => {
+				if !options.secure {
+					return Err(format!("Cookie '{}' uses SameSite=None and must be secure", name));
+				}
+				"None"
+			},
+			_ => return Err(format!("Invalid SameSite value '{}' for cookie '{}'", same_site, name))
+		};
+		res.push_str("; SameSite=");
+		res.push_str(same_site);
+	}
+
+	if options.secure {
+		res.push_str("; Secure");
+	}
+
+	if options.http_only {
+		res.push_str("; HttpOnly");
+	}
+
+	Ok(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::http::http_cookie::{parse_cookies, serialize_cookie, CookieOptions};
+	use crate::utils::format_http_date;
+
+	#[test]
+	pub fn test_parse_cookies() {
+		let cookies = parse_cookies("session=abc123; theme=\"dark\";empty=; session=other");
+		assert_eq!(cookies.len(), 3);
+		assert_eq!(cookies["session"], "abc123");
+		assert_eq!(cookies["theme"], "dark");
+		assert_eq!(cookies["empty"], "");
+	}
+
+	#[test]
+	pub fn test_serialize_cookie() {
+		let options = CookieOptions {
+			path: Some("/".to_string()),
+			max_age: Some(3600),
+			expires: Some(format_http_date(784111777)),
+			http_only: true,
+			secure: true,
+			same_site: Some("lax".to_string()),
+			..Default::default()
+		};
+
+		assert_eq!(
+			serialize_cookie("id", "42", &options).unwrap(),
+			"id=42; Path=/; Max-Age=3600; Expires=Sun, 06 Nov 1994 08:49:37 GMT; SameSite=Lax; Secure; HttpOnly"
+		);
+
+		assert!(serialize_cookie("bad name", "1", &CookieOptions::default()).is_err());
+		assert!(serialize_cookie("id", "a;b", &CookieOptions::default()).is_err());
+
+		let none = CookieOptions { same_site: Some("None".to_string()), ..Default::default() };
+		assert!(serialize_cookie("id", "1", &none).is_err());
+	}
+}
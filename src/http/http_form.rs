@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use crate::http::http_message::{find_header_end, HttpMessage};
+use crate::http::http_request::HttpRequest;
+use crate::url::percent_decode;
+
+pub struct FormFile {
+	pub filename: String,
+	pub content_type: String,
+	pub content: Vec<u8>
+}
+
+pub struct Form {
+	pub fields: HashMap<String, String>,
+	pub files: HashMap<String, FormFile>
+}
+
+#[derive(Debug)]
+pub enum FormError {
+	Malformed(String),
+	TooLarge(String)
+}
+
+/// Extracts a `key=value` parameter (e.g. `boundary` or `name`) from a header value such as
+/// `multipart/form-data; boundary=xyz`. Quoted values are unquoted.
+fn header_parameter(header: &str, name: &str) -> Option<String> {
+	for section in header.split(';').skip(1) {
+		let section = section.trim();
+		let eq_idx = match section.find('=') {
+			Some(idx) => idx,
+			None => continue
+		};
+
+		if !section[..eq_idx].trim().eq_ignore_ascii_case(name) {
+			continue;
+		}
+
+		let val = section[eq_idx + 1..].trim();
+		if val.len() >= 2 && val.starts_with('"') && val.ends_with('"') {
+			return Some(val[1..val.len() - 1].to_string());
+		}
+		return Some(val.to_string());
+	}
+
+	None
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	if needle.len() > haystack.len() {
+		return None;
+	}
+
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Parses an `application/x-www-form-urlencoded` body (or query string).
+pub fn parse_urlencoded(data: &[u8]) -> HashMap<String, String> {
+	let mut fields = HashMap::new();
+	let data = String::from_utf8_lossy(data).replace('+', " ");
+
+	for pair in data.split('&') {
+		if pair.is_empty() {
+			continue;
+		}
+
+		match pair.find('=') {
+			Some(idx) => fields.insert(percent_decode(&pair[..idx]), percent_decode(&pair[idx + 1..])),
+			None => fields.insert(percent_decode(pair), String::new())
+		};
+	}
+
+	fields
+}
+
+fn parse_multipart(data: &[u8], boundary: &str, max_upload_size: usize) -> Result<Form, FormError> {
+	let mut form = Form {
+		fields: HashMap::new(),
+		files: HashMap::new()
+	};
+
+	let delimiter = format!("--{}", boundary);
+	let delimiter = delimiter.as_bytes();
+
+	let mut idx = match find_bytes(data, delimiter) {
+		Some(i) => i + delimiter.len(),
+		None => return Err(FormError::Malformed("Multipart boundary not found".to_string()))
+	};
+
+	loop {
+		let rest = &data[idx..];
+		if rest.starts_with(b"--") {
+			break;
+		}
+
+		if !rest.starts_with(b"\r\n") {
+			return Err(FormError::Malformed("Invalid multipart delimiter".to_string()));
+		}
+		let rest = &rest[2..];
+
+		let head_end = match find_header_end(rest) {
+			Some(i) => i,
+			None => return Err(FormError::Malformed("Unterminated multipart headers".to_string()))
+		};
+
+		let mut part_delimiter = b"\r\n".to_vec();
+		part_delimiter.extend_from_slice(delimiter);
+		let content_len = match find_bytes(&rest[head_end..], &part_delimiter) {
+			Some(i) => i,
+			None => return Err(FormError::Malformed("Unterminated multipart part".to_string()))
+		};
+
+		let head = String::from_utf8_lossy(&rest[..head_end]);
+		let content = &rest[head_end..head_end + content_len];
+
+		let mut disposition: Option<String> = None;
+		let mut content_type = "text/plain".to_string();
+		for line in head.split("\r\n") {
+			let colon = match line.find(':') {
+				Some(i) => i,
+				None => continue
+			};
+
+			let name = line[..colon].trim();
+			let val = line[colon + 1..].trim();
+			if name.eq_ignore_ascii_case("Content-Disposition") {
+				disposition = Some(val.to_string());
+			} else if name.eq_ignore_ascii_case("Content-Type") {
+				content_type = val.to_string();
+			}
+		}
+
+		let disposition = match disposition {
+			Some(d) => d,
+			None => return Err(FormError::Malformed("Multipart part without Content-Disposition".to_string()))
+		};
+
+		let name = match header_parameter(&disposition, "name") {
+			Some(n) => n,
+			None => return Err(FormError::Malformed("Multipart part without a name".to_string()))
+		};
+
+		match header_parameter(&disposition, "filename") {
+			Some(filename) => {
+				if content.len() > max_upload_size {
+					return Err(FormError::TooLarge(format!("Uploaded file '{}' exceeds {} bytes", filename, max_upload_size)));
+				}
+
+				form.files.insert(name, FormFile {
+					filename,
+					content_type,
+					content: content.to_vec()
+				});
+			},
+			None => {
+				form.fields.insert(name, String::from_utf8_lossy(content).to_string());
+			}
+		}
+
+		idx += 2 + head_end + content_len + part_delimiter.len();
+	}
+
+	Ok(form)
+}
+
+impl Form {
+	/// Parses the body of `request` according to its `Content-Type`. Returns `None` for
+	/// content types that are not forms.
+	pub fn parse(request: &HttpRequest, max_upload_size: usize) -> Result<Option<Form>, FormError> {
+		let content_type = match request.find_header("Content-Type") {
+			Some(c) => c.to_string(),
+			None => return Ok(None)
+		};
+
+		let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+		match mime.as_str() {
+			"application/x-www-form-urlencoded" => Ok(Some(Form {
+				fields: parse_urlencoded(&request.content),
+				files: HashMap::new()
+			})),
+			"multipart/form-data" => {
+				match header_parameter(&content_type, "boundary") {
+					Some(boundary) => parse_multipart(&request.content, &boundary, max_upload_size).map(Some),
+					None => Err(FormError::Malformed("multipart/form-data without boundary".to_string()))
+				}
+			},
+			_ => Ok(None)
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::http::http_form::{Form, FormError};
+	use crate::http::http_request::HttpRequest;
+
+	#[test]
+	pub fn test_urlencoded_form() {
+		let data = b"POST /form HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 26\r\n\r\nname=John+Doe&city=Rome%21";
+		let req = HttpRequest::parse(data).unwrap();
+		assert_eq!(req.content, b"name=John+Doe&city=Rome%21");
+
+		let form = Form::parse(&req, 1024).unwrap().unwrap();
+		assert_eq!(form.fields["name"], "John Doe");
+		assert_eq!(form.fields["city"], "Rome!");
+		assert!(form.files.is_empty());
+	}
+
+	#[test]
+	pub fn test_multipart_form() {
+		let body = "--XyZ\r\n\
+			Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+			Hello\r\n\
+			--XyZ\r\n\
+			Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+			Content-Type: text/plain\r\n\r\n\
+			line 1\r\nline 2\r\n\
+			--XyZ--\r\n";
+		let data = format!("POST /form HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=\"XyZ\"\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+		let req = HttpRequest::parse(data.as_bytes()).unwrap();
+
+		let form = Form::parse(&req, 1024).unwrap().unwrap();
+		assert_eq!(form.fields["title"], "Hello");
+
+		let file = &form.files["upload"];
+		assert_eq!(file.filename, "a.txt");
+		assert_eq!(file.content_type, "text/plain");
+		assert_eq!(file.content, b"line 1\r\nline 2");
+
+		assert!(matches!(Form::parse(&req, 4), Err(FormError::TooLarge(_))));
+	}
+}
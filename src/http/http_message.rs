@@ -61,13 +61,66 @@ impl HttpMethod {
             HttpMethod::Delete => "DELETE",
         }
     }
+
+    /// True for methods that can be repeated without further effect (RFC 9110 9.2.2)
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, HttpMethod::Post | HttpMethod::Patch)
+    }
+}
+
+/// Returns the index right after the `\r\n\r\n` separating the head of a message from its
+/// content, if the whole head has been received.
+pub fn find_header_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n").map(|idx| idx + 4)
+}
+
+/// Checks `string` is a non-empty RFC 9110 `token` (used for header and cookie names)
+pub fn is_token(string: &str) -> bool {
+    !string.is_empty() && string.chars().all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
 }
 
-#[derive(PartialEq)]
-enum MessageParseState {
-    FirstLine,
-    Headers,
-    Content
+/// Header names are case-insensitive; this puts them in the usual `Content-Length` form so two
+/// spellings of one header end up under the same key.
+pub fn normalize_header_name(name: &str) -> String {
+    name.split('-').map(|part| {
+        let mut chars = part.chars();
+        match chars.next() {
+            Some(first) => first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase(),
+            None => String::new()
+        }
+    }).collect::<Vec<String>>().join("-")
+}
+
+/// Header values (and reason phrases) may not contain control characters, so CR/LF can't be
+/// used to inject extra headers.
+pub fn is_valid_header_value(string: &str) -> bool {
+    string.chars().all(|c| c == '\t' || !c.is_control())
+}
+
+/// Works out how long the body of a request is from its raw head. On failure returns the status
+/// to answer with: 400 for a malformed or repeated `Content-Length`, 411 for a chunked body and
+/// 501 for any other transfer coding, since only `Content-Length` framing is supported.
+/// Refusing these outright keeps a proxy in front of us from reading a different message
+/// boundary than we do.
+pub fn request_body_length(head: &[u8]) -> Result<usize, u16> {
+    let head = String::from_utf8_lossy(head);
+    let mut length = None;
+    for line in head.split("\r\n").skip(1) {
+        let Some(idx) = line.find(':') else { continue };
+        let name = line[..idx].trim();
+        let value = line[idx + 1..].trim();
+        if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            return Err(if value.eq_ignore_ascii_case("chunked") { 411 } else { 501 });
+        }
+        if name.eq_ignore_ascii_case("Content-Length") {
+            if length.is_some() || value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(400);
+            }
+            length = Some(value.parse::<usize>().map_err(|_| 400u16)?);
+        }
+    }
+
+    Ok(length.unwrap_or(0))
 }
 
 pub trait HttpMessage {
@@ -75,71 +128,60 @@ pub trait HttpMessage {
     fn get_first_line(&self) -> String;
     fn get_headers(&self) -> &HashMap<String, String>;
     fn get_content(&self) -> &[u8];
+
+    /// Headers that may appear more than once (e.g. `Set-Cookie`) and therefore can't live in
+    /// the header map.
+    fn get_repeated_headers(&self) -> Vec<(&str, &str)> {
+        Vec::new()
+    }
     fn register_header(&mut self, name: &str, value: &str);
 
     fn register_content(&mut self, data: &[u8]);
 
     fn load(&mut self, data: &[u8]) -> bool {
-        if data.len() == 0 {
+        if data.is_empty() {
             return false;
         }
 
-        let mut current_line: &str = "";
-        let mut state = MessageParseState::FirstLine;
+        let (head, content) = match find_header_end(data) {
+            Some(idx) => (&data[..idx], &data[idx..]),
+            None => (data, &data[data.len()..])
+        };
 
-        let mut idx = 0;
-        while idx < data.len() {
-            if state == MessageParseState::Content {
-                self.register_content(&data[idx..]);
-                return true
-            }
+        let head = match std::str::from_utf8(head) {
+            Ok(string) => string,
+            Err(_) => return false
+        };
 
-            let mut i: usize = 0;
-            while i < data.len() - idx {
-                if data[idx + i] == '\n' as u8 && i > 1 && data[idx + i - 1] == '\r' as u8 {
-                    current_line = match std::str::from_utf8(&data[idx..(idx + i - 1)]) {
-                        Ok(string) => string,
-                        Err(_) => return false
-                    };
-
-                    if current_line != "" {
-                        break
-                    }
-                }
-                i += 1;
-            }
+        let mut lines = head.split("\r\n").filter(|l| !l.is_empty());
 
-            if current_line.len() == 0 {
-                current_line = match std::str::from_utf8(&data[idx..]) {
-                    Ok(string) => string,
-                    Err(_) => return false
+        match lines.next() {
+            Some(line) => {
+                if !self.parse_first_line(line) {
+                    return false;
                 }
-            }
+            },
+            None => return false
+        }
 
-            idx += i;
-
-            match state {
-                MessageParseState::FirstLine => {
-                    if !self.parse_first_line(current_line) {
-                        return false;
-                    }
-                    state = MessageParseState::Headers;
-                },
-                MessageParseState::Headers => {
-                    if current_line.len() == 0 {
-                        state = MessageParseState::Content;
-                    }
-                    self.parse_header(current_line);
-                },
-                _ => {}
-            }
+        for line in lines {
+            self.parse_header(line);
+        }
 
-            current_line = "";
+        if !content.is_empty() {
+            self.register_content(content);
         }
 
         true
     }
 
+    /// Case-insensitive header lookup
+    fn find_header(&self, name: &str) -> Option<&String> {
+        self.get_headers().iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
     fn parse_header(&mut self, line: &str) {
         let idx = match line.find(": ") {
             Some(idx) => idx,
@@ -156,7 +198,8 @@ pub trait HttpMessage {
         self.register_header(name, val);
     }
 
-    fn serialize(&self) -> Vec<u8> {
+    /// Serializes the first line and the headers, including the empty line ending them
+    fn serialize_head(&self) -> Vec<u8> {
         let mut res: Vec<u8> = Vec::new();
 
         let first_line = self.get_first_line();
@@ -168,10 +211,54 @@ pub trait HttpMessage {
             res.extend_from_slice(h.1.as_bytes());
             res.extend_from_slice(b"\r\n");
         }
+
+        for (name, value) in self.get_repeated_headers() {
+            res.extend_from_slice(name.as_bytes());
+            res.extend_from_slice(b": ");
+            res.extend_from_slice(value.as_bytes());
+            res.extend_from_slice(b"\r\n");
+        }
         res.extend_from_slice(b"\r\n");
 
+        res
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut res = self.serialize_head();
+
         res.extend_from_slice(self.get_content());
 
         res
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_header_name() {
+        assert_eq!(normalize_header_name("content-length"), "Content-Length");
+        assert_eq!(normalize_header_name("CONTENT-LENGTH"), "Content-Length");
+        assert_eq!(normalize_header_name("x-ID--2"), "X-Id--2");
+        assert_eq!(normalize_header_name("etag"), "Etag");
+    }
+
+    #[test]
+    fn test_request_body_length() {
+        let head = |headers: &str| format!("POST / HTTP/1.1\r\nHost: a\r\n{}\r\n\r\n", headers);
+
+        assert_eq!(request_body_length(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"), Ok(0));
+        assert_eq!(request_body_length(head("content-length: 12").as_bytes()), Ok(12));
+        assert_eq!(request_body_length(head("Content-Length: +5").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Content-Length: 5, 5").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Content-Length:").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Content-Length: 99999999999999999999999").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Content-Length: 5\r\nContent-Length: 5").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Content-Length: 5\r\nContent-Length: 7").as_bytes()), Err(400));
+        assert_eq!(request_body_length(head("Transfer-Encoding: chunked").as_bytes()), Err(411));
+        assert_eq!(request_body_length(head("Content-Length: 5\r\nTransfer-Encoding: chunked").as_bytes()), Err(411));
+        assert_eq!(request_body_length(head("Transfer-Encoding: gzip, chunked").as_bytes()), Err(501));
+    }
+}
@@ -8,6 +8,9 @@ pub struct HttpRequest {
     pub content: Vec<u8>,
     pub version: HttpVersion,
     pub url: URL,
+    /// First line of the request as received, for logs: `url` doesn't keep the order or the
+    /// encoding of the query
+    pub request_line: String,
 }
 
 impl HttpRequest {
@@ -18,6 +21,7 @@ impl HttpRequest {
             content: vec![],
             version: HttpVersion::Http1_0,
             url: URL { uri: "".to_string(), queries: HashMap::new() },
+            request_line: String::new(),
         };
 
         if this.load(data) {
@@ -26,10 +30,17 @@ impl HttpRequest {
 
         None
     }
+
+    /// Query string of the request line, with its `?`, or an empty string
+    pub fn raw_query(&self) -> &str {
+        let target = self.request_line.split_whitespace().nth(1).unwrap_or("");
+        target.find('?').map(|idx| &target[idx..]).unwrap_or("")
+    }
 }
 
 impl HttpMessage for HttpRequest {
     fn parse_first_line(&mut self, line: &str) -> bool {
+        self.request_line = line.trim().to_string();
 
         let next_section: &str = match line.find(' ') {
             Some(idx) => {
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 use std::str::FromStr;
-use crate::http::http_message::{HttpMessage, HttpVersion};
+use crate::http::http_message::{is_token, HttpMessage, HttpVersion};
 
 pub struct HttpResponse {
 	code: u16,
 	headers: HashMap<String, String>,
 	content: Vec<u8>,
-	version: HttpVersion
+	version: HttpVersion,
+	cookies: Vec<String>,
+	status_text: Option<String>
 }
 
 pub fn code_to_http_status(code: u16) -> Option<&'static str> {
@@ -77,13 +79,39 @@ pub fn code_to_http_status(code: u16) -> Option<&'static str> {
 	})
 }
 
+/// Checks a `Content-Type` value: a `type/subtype` media type, optionally followed by
+/// `; name=value` parameters
+pub fn is_valid_content_type(value: &str) -> bool {
+	let mut sections = value.split(';');
+
+	let media_type = sections.next().unwrap_or("").trim();
+	match media_type.find('/') {
+		Some(idx) => {
+			if !is_token(&media_type[..idx]) || !is_token(&media_type[idx + 1..]) {
+				return false;
+			}
+		},
+		None => return false
+	}
+
+	sections.all(|p| {
+		let p = p.trim();
+		match p.find('=') {
+			Some(idx) => is_token(&p[..idx]) && !p[idx + 1..].is_empty(),
+			None => false
+		}
+	})
+}
+
 impl HttpResponse {
 	pub fn parse(from: &[u8]) -> Option<HttpResponse> {
 		let mut this = HttpResponse {
 			code: 500,
 			headers: HashMap::new(),
 			content: Vec::new(),
-			version: HttpVersion::Http1_1
+			version: HttpVersion::Http1_1,
+			cookies: Vec::new(),
+			status_text: None
 		};
 
 		if this.load(from) {
@@ -98,15 +126,58 @@ impl HttpResponse {
 			code,
 			headers,
 			version,
-			content
+			content,
+			cookies: Vec::new(),
+			status_text: None
 		};
 
 		//if !resp.headers.contains_key("Content-Length") { // Maybe leave this out?
-			resp.headers.insert("Content-Length".to_string(), resp.content.len().to_string());
+			resp.set_header("Content-Length", &resp.content.len().to_string());
 		//}
 
 		resp
 	}
+
+	pub fn get_code(&self) -> u16 {
+		self.code
+	}
+
+	pub fn get_version(&self) -> &HttpVersion {
+		&self.version
+	}
+
+	pub fn get_status_text(&self) -> Option<&String> {
+		self.status_text.as_ref()
+	}
+
+	pub fn get_cookies(&self) -> &Vec<String> {
+		&self.cookies
+	}
+
+	/// Replaces every header called `name`, whatever its case
+	pub fn set_header(&mut self, name: &str, value: &str) {
+		self.remove_header(name);
+		self.headers.insert(name.to_string(), value.to_string());
+	}
+
+	pub fn remove_header(&mut self, name: &str) {
+		self.headers.retain(|k, _| !k.eq_ignore_ascii_case(name));
+	}
+
+	/// Overrides the reason phrase sent after the status code
+	pub fn set_status_text(&mut self, text: &str) {
+		self.status_text = Some(text.to_string());
+	}
+
+	/// Adds a serialized cookie, sent as its own `Set-Cookie` header
+	pub fn add_cookie(&mut self, cookie: String) {
+		self.cookies.push(cookie);
+	}
+
+	/// Adds cookies sent before those of the response, which win if they set the same name
+	pub fn prepend_cookies(&mut self, cookies: &[String]) {
+		self.cookies.splice(0..0, cookies.iter().cloned());
+	}
 }
 
 impl HttpMessage for HttpResponse {
@@ -123,23 +194,28 @@ impl HttpMessage for HttpResponse {
 			None => return false
 		};
 
-		match next_section.find(" ") {
-			Some(idx) => {
-				self.code = match u16::from_str(&next_section[0..idx]) {
-					Ok(c) => c,
-					Err(_) => return false
-				};
-			},
-			None => return false
+		let (code, text) = match next_section.find(" ") {
+			Some(idx) => (&next_section[0..idx], next_section[idx..].trim()),
+			None => (next_section, "")
 		};
 
-		//Ignoring the "OK", "Not Found" parts at the end. Using only the code
+		self.code = match u16::from_str(code) {
+			Ok(c) => c,
+			Err(_) => return false
+		};
+
+		if !text.is_empty() {
+			self.status_text = Some(text.to_string());
+		}
 
 		true
 	}
 
 	fn get_first_line(&self) -> String {
-		let code_str = code_to_http_status(self.code).unwrap_or("Unknown");
+		let code_str = match &self.status_text {
+			Some(text) => text.as_str(),
+			None => code_to_http_status(self.code).unwrap_or("Unknown")
+		};
 		format!("{} {} {}\r\n", self.version.to_str(), self.code, code_str)
 	}
 
@@ -151,12 +227,65 @@ impl HttpMessage for HttpResponse {
 		&self.content
 	}
 
+	fn get_repeated_headers(&self) -> Vec<(&str, &str)> {
+		self.cookies.iter().map(|c| ("Set-Cookie", c.as_str())).collect()
+	}
+
 	fn register_header(&mut self, name: &str, value: &str) {
-		self.headers.insert(name.to_string(), value.to_string());
+		if name.eq_ignore_ascii_case("Set-Cookie") {
+			self.cookies.push(value.to_string());
+			return;
+		}
+
+		self.set_header(name, value);
 	}
 
 	fn register_content(&mut self, data: &[u8]) {
 		self.content = data.to_vec();
-		self.headers.insert("Content-Length".to_string(), self.content.len().to_string());
+		self.set_header("Content-Length", &self.content.len().to_string());
 	}
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use crate::http::http_message::{HttpMessage, HttpVersion};
+	use crate::http::http_response::{is_valid_content_type, HttpResponse};
+
+	#[test]
+	pub fn test_status_text() {
+		let mut resp = HttpResponse::new(299, HashMap::new(), vec![], HttpVersion::Http1_1);
+		assert_eq!(resp.get_first_line(), "HTTP/1.1 299 Unknown\r\n");
+
+		resp.set_status_text("Custom Reason");
+		assert_eq!(resp.get_first_line(), "HTTP/1.1 299 Custom Reason\r\n");
+
+		let parsed = HttpResponse::parse(b"HTTP/1.1 404 Nope\r\nContent-Length: 0\r\n\r\n").unwrap();
+		assert_eq!(parsed.get_first_line(), "HTTP/1.1 404 Nope\r\n");
+	}
+
+	#[test]
+	pub fn test_header_case() {
+		let headers = HashMap::from([
+			("content-length".to_string(), "99".to_string()),
+			("X-Test".to_string(), "1".to_string())
+		]);
+		let mut resp = HttpResponse::new(200, headers, b"abc".to_vec(), HttpVersion::Http1_1);
+		resp.set_header("x-test", "2");
+
+		let data = String::from_utf8(resp.serialize()).unwrap();
+		assert_eq!(data.to_ascii_lowercase().matches("content-length").count(), 1);
+		assert!(data.contains("Content-Length: 3\r\n"));
+		assert!(data.contains("x-test: 2\r\n") && !data.contains("X-Test"));
+	}
+
+	#[test]
+	pub fn test_content_type_validation() {
+		assert!(is_valid_content_type("text/html"));
+		assert!(is_valid_content_type("text/html; charset=utf-8"));
+		assert!(!is_valid_content_type("text"));
+		assert!(!is_valid_content_type("text/html\r\nX-Injected: 1"));
+		assert!(!is_valid_content_type("text/html; charset"));
+	}
+}
@@ -0,0 +1,501 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use crate::http::http_message::{is_token, is_valid_header_value, HttpMessage, HttpMethod, HttpVersion};
+use crate::http::http_request::HttpRequest;
+use crate::http::http_response::HttpResponse;
+use crate::url::URL;
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Largest response body read into memory
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+/// Idle connections kept per host
+const MAX_IDLE_CONNECTIONS: usize = 4;
+/// Headers carrying credentials, not sent on when a redirect leads to another host or port
+const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+type Connection = BufReader<TcpStream>;
+
+thread_local! {
+	/// Idle keep-alive connections of this worker, by host and port
+	static POOL: RefCell<HashMap<(String, u16), Vec<Connection>>> = RefCell::new(HashMap::new());
+}
+
+/// A request made by a script with `jwx.http.request`
+pub struct OutboundRequest {
+	pub method: HttpMethod,
+	pub url: String,
+	pub headers: Vec<(String, String)>,
+	pub body: Vec<u8>,
+	/// Time allowed for the whole exchange, redirects included
+	pub timeout: Duration,
+	pub max_redirects: usize
+}
+
+/// The final response, and the URL it came from after redirects
+pub struct OutboundResponse {
+	pub url: String,
+	pub response: HttpResponse
+}
+
+struct Target {
+	host: String,
+	port: u16,
+	/// Path and query, sent as they are
+	path: String
+}
+
+impl Target {
+	fn parse(url: &str) -> Result<Target, String> {
+		let rest = match url.get(..7) {
+			Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+			_ if url.get(..8).is_some_and(|s| s.eq_ignore_ascii_case("https://")) => return Err("https URLs are not supported".to_string()),
+			_ => return Err(format!("Invalid URL '{}', expected http://host/path", url))
+		};
+
+		let (authority, path) = match rest.find(['/', '?']) {
+			Some(idx) if rest[idx..].starts_with('?') => (&rest[..idx], format!("/{}", &rest[idx..])),
+			Some(idx) => (&rest[..idx], rest[idx..].to_string()),
+			None => (rest, "/".to_string())
+		};
+
+		if authority.contains('@') {
+			return Err("Credentials in URLs are not supported, use an Authorization header".to_string());
+		}
+
+		// IPv6 addresses are in brackets: [::1]:8080
+		let port_idx = match authority.rfind(':') {
+			Some(idx) if !authority[idx..].contains(']') => Some(idx),
+			_ => None
+		};
+		let (host, port) = match port_idx {
+			Some(idx) => (&authority[..idx], authority[idx + 1..].parse::<u16>().map_err(|_| format!("Invalid port in '{}'", url))?),
+			None => (authority, 80)
+		};
+
+		if host.is_empty() || !is_valid_header_value(host) || host.contains(char::is_whitespace) {
+			return Err(format!("Invalid host in '{}'", url));
+		}
+
+		if !is_valid_header_value(&path) || path.contains(' ') {
+			return Err(format!("Invalid path in '{}'", url));
+		}
+
+		Ok(Target {
+			host: host.to_string(),
+			port,
+			path
+		})
+	}
+
+	fn host_header(&self) -> String {
+		if self.port == 80 {
+			self.host.clone()
+		} else {
+			format!("{}:{}", self.host, self.port)
+		}
+	}
+
+	/// Resolves the `Location` of a redirect against this URL
+	fn resolve(&self, location: &str) -> String {
+		if location.contains("://") {
+			return location.to_string();
+		}
+
+		if let Some(rest) = location.strip_prefix("//") {
+			return format!("http://{}", rest);
+		}
+
+		let path = if location.starts_with('/') {
+			location.to_string()
+		} else {
+			let current = &self.path[..self.path.find('?').unwrap_or(self.path.len())];
+			format!("{}{}", &current[..current.rfind('/').map(|i| i + 1).unwrap_or(0)], location)
+		};
+
+		format!("http://{}{}", self.host_header(), path)
+	}
+}
+
+fn remaining(deadline: Instant) -> Result<Duration, Error> {
+	match deadline.checked_duration_since(Instant::now()) {
+		Some(d) if !d.is_zero() => Ok(d),
+		_ => Err(Error::new(ErrorKind::TimedOut, "Request timed out"))
+	}
+}
+
+fn connect(target: &Target, deadline: Instant) -> Result<Connection, Error> {
+	let mut last_error = Error::new(ErrorKind::NotFound, format!("Can't resolve {}", target.host));
+	for address in (target.host.trim_start_matches('[').trim_end_matches(']'), target.port).to_socket_addrs()? {
+		match TcpStream::connect_timeout(&address, remaining(deadline)?) {
+			Ok(stream) => {
+				stream.set_nodelay(true)?;
+				return Ok(BufReader::new(stream));
+			},
+			Err(e) => last_error = e
+		}
+	}
+
+	Err(last_error)
+}
+
+/// Reads a line, including its line break, failing past `MAX_HEAD_SIZE`
+fn read_line(conn: &mut Connection, deadline: Instant) -> Result<Vec<u8>, Error> {
+	conn.get_ref().set_read_timeout(Some(remaining(deadline)?))?;
+
+	let mut line = Vec::new();
+	conn.by_ref().take(MAX_HEAD_SIZE as u64).read_until(b'\n', &mut line)?;
+	if !line.ends_with(b"\n") {
+		return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed in the middle of a line"));
+	}
+
+	Ok(line)
+}
+
+fn read_head(conn: &mut Connection, deadline: Instant) -> Result<Vec<u8>, Error> {
+	let mut head = Vec::new();
+	loop {
+		let line = read_line(conn, deadline)?;
+		let done = line == b"\r\n" || line == b"\n";
+		head.extend_from_slice(&line);
+
+		if done {
+			return Ok(head);
+		}
+
+		if head.len() > MAX_HEAD_SIZE {
+			return Err(Error::new(ErrorKind::InvalidData, "Response head too large"));
+		}
+	}
+}
+
+fn read_exact(conn: &mut Connection, len: usize, out: &mut Vec<u8>, deadline: Instant) -> Result<(), Error> {
+	if out.len() + len > MAX_RESPONSE_SIZE {
+		return Err(Error::new(ErrorKind::InvalidData, "Response too large"));
+	}
+
+	let start = out.len();
+	out.resize(start + len, 0);
+	let mut filled = start;
+	while filled < out.len() {
+		conn.get_ref().set_read_timeout(Some(remaining(deadline)?))?;
+		match conn.read(&mut out[filled..]) {
+			Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed before the end of the response")),
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e)
+		}
+	}
+
+	Ok(())
+}
+
+fn read_chunked(conn: &mut Connection, deadline: Instant) -> Result<Vec<u8>, Error> {
+	let mut body = Vec::new();
+	loop {
+		let line = read_line(conn, deadline)?;
+		let line = String::from_utf8_lossy(&line);
+		let size = line.split(';').next().unwrap_or("").trim();
+		let size = usize::from_str_radix(size, 16).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid chunk size"))?;
+
+		if size == 0 {
+			// Trailers are ignored
+			while read_line(conn, deadline)?.trim_ascii() != b"" {}
+			return Ok(body);
+		}
+
+		read_exact(conn, size, &mut body, deadline)?;
+		read_line(conn, deadline)?;
+	}
+}
+
+/// Sends a serialized request on `conn` and reads the response. Also returns whether the
+/// connection can be used again.
+fn exchange(conn: &mut Connection, data: &[u8], is_head: bool, deadline: Instant) -> Result<(HttpResponse, bool), Error> {
+	conn.get_ref().set_write_timeout(Some(remaining(deadline)?))?;
+	conn.get_mut().write_all(data)?;
+
+	let mut response = loop {
+		let head = read_head(conn, deadline)?;
+		let response = HttpResponse::parse(&head).ok_or(Error::new(ErrorKind::InvalidData, "Malformed response head"))?;
+		// Interim responses (100 Continue, 103 Early Hints) are followed by the real one
+		if !(100..200).contains(&response.get_code()) {
+			break response;
+		}
+	};
+
+	let code = response.get_code();
+	let close = response.find_header("Connection").is_some_and(|c| c.to_ascii_lowercase().contains("close"));
+	let mut reusable = *response.get_version() == HttpVersion::Http1_1 && !close;
+
+	let body = if is_head || code == 204 || code == 304 {
+		Vec::new()
+	} else if response.find_header("Transfer-Encoding").is_some_and(|t| t.to_ascii_lowercase().contains("chunked")) {
+		read_chunked(conn, deadline)?
+	} else if let Some(len) = response.find_header("Content-Length") {
+		let len = len.trim().parse::<usize>().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid Content-Length"))?;
+		let mut body = Vec::new();
+		read_exact(conn, len, &mut body, deadline)?;
+		body
+	} else {
+		// The body ends with the connection
+		reusable = false;
+		let mut body = Vec::new();
+		conn.get_ref().set_read_timeout(Some(remaining(deadline)?))?;
+		conn.by_ref().take(MAX_RESPONSE_SIZE as u64 + 1).read_to_end(&mut body)?;
+		if body.len() > MAX_RESPONSE_SIZE {
+			return Err(Error::new(ErrorKind::InvalidData, "Response too large"));
+		}
+		body
+	};
+
+	response.remove_header("Content-Length");
+	response.remove_header("Transfer-Encoding");
+	response.register_content(&body);
+
+	Ok((response, reusable))
+}
+
+/// Sends `data` to `target`, on an idle pooled connection if there is one. A pooled
+/// connection the server closed in the meantime is replaced by a new one, and the request
+/// sent again if it is `idempotent`: otherwise there is no telling whether the server
+/// received it before closing.
+fn send(target: &Target, data: &[u8], is_head: bool, idempotent: bool, deadline: Instant) -> Result<HttpResponse, Error> {
+	let key = (target.host.clone(), target.port);
+	let pooled = POOL.with_borrow_mut(|pool| pool.get_mut(&key).and_then(|idle| idle.pop()));
+
+	let (response, reusable, conn) = match pooled {
+		Some(mut conn) => match exchange(&mut conn, data, is_head, deadline) {
+			Ok((response, reusable)) => (response, reusable, conn),
+			Err(e) if idempotent && matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe) => {
+				let mut conn = connect(target, deadline)?;
+				let (response, reusable) = exchange(&mut conn, data, is_head, deadline)?;
+				(response, reusable, conn)
+			},
+			Err(e) => return Err(e)
+		},
+		None => {
+			let mut conn = connect(target, deadline)?;
+			let (response, reusable) = exchange(&mut conn, data, is_head, deadline)?;
+			(response, reusable, conn)
+		}
+	};
+
+	if reusable {
+		POOL.with_borrow_mut(|pool| {
+			let idle = pool.entry(key).or_default();
+			if idle.len() < MAX_IDLE_CONNECTIONS {
+				idle.push(conn);
+			}
+		});
+	}
+
+	Ok(response)
+}
+
+/// Sends `request`, following redirects
+pub fn fetch(request: OutboundRequest) -> Result<OutboundResponse, String> {
+	for (name, value) in &request.headers {
+		if !is_token(name) || !is_valid_header_value(value) {
+			return Err(format!("Invalid header '{}'", name));
+		}
+	}
+
+	let deadline = Instant::now() + request.timeout;
+	let mut url = request.url;
+	let mut method = request.method;
+	let mut body = request.body;
+	let mut redirects = 0;
+	let mut origin: Option<(String, u16)> = None;
+
+	loop {
+		let target = Target::parse(&url)?;
+
+		// Credentials are only meant for the host the request was made to
+		let target_origin = (target.host.to_ascii_lowercase(), target.port);
+		let same_origin = *origin.get_or_insert_with(|| target_origin.clone()) == target_origin;
+
+		let mut headers = HashMap::from([
+			("Host".to_string(), target.host_header()),
+			("User-Agent".to_string(), "jwx-rs".to_string()),
+			("Accept".to_string(), "*/*".to_string())
+		]);
+		for (name, value) in &request.headers {
+			if !same_origin && CREDENTIAL_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+				continue;
+			}
+
+			headers.retain(|k, _| !k.eq_ignore_ascii_case(name));
+			headers.insert(name.clone(), value.clone());
+		}
+		headers.retain(|k, _| !k.eq_ignore_ascii_case("Content-Length") && !k.eq_ignore_ascii_case("Transfer-Encoding"));
+		if !body.is_empty() || matches!(method, HttpMethod::Post | HttpMethod::Put | HttpMethod::Patch) {
+			headers.insert("Content-Length".to_string(), body.len().to_string());
+		}
+
+		let is_head = method == HttpMethod::Head;
+		let outgoing = HttpRequest {
+			method,
+			headers,
+			content: body,
+			version: HttpVersion::Http1_1,
+			url: URL { uri: target.path.clone(), queries: HashMap::new() },
+			request_line: String::new()
+		};
+
+		let response = send(&target, &outgoing.serialize(), is_head, outgoing.method.is_idempotent(), deadline).map_err(|e| format!("{}: {}", url, e))?;
+		(method, body) = (outgoing.method, outgoing.content);
+
+		let location = match response.get_code() {
+			301 | 302 | 303 | 307 | 308 => response.find_header("Location").cloned(),
+			_ => None
+		};
+
+		match location {
+			Some(location) if redirects < request.max_redirects => {
+				redirects += 1;
+				url = target.resolve(&location);
+
+				// 303, and 301/302 after a POST as browsers do, continue with a GET
+				let code = response.get_code();
+				if code == 303 || (method == HttpMethod::Post && (code == 301 || code == 302)) {
+					method = if method == HttpMethod::Head { HttpMethod::Head } else { HttpMethod::Get };
+					body = Vec::new();
+				}
+			},
+			_ => return Ok(OutboundResponse { url, response })
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	use std::time::Duration;
+	use crate::http::http_message::{HttpMessage, HttpMethod};
+	use crate::http::outbound::{fetch, OutboundRequest, Target};
+
+	fn request(url: String, method: HttpMethod, body: &[u8]) -> OutboundRequest {
+		OutboundRequest {
+			method,
+			url,
+			headers: vec![("X-Test".to_string(), "1".to_string())],
+			body: body.to_vec(),
+			timeout: Duration::from_secs(5),
+			max_redirects: 5
+		}
+	}
+
+	#[test]
+	pub fn test_outbound_requests() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+
+		// Stand-in server: answers every request on a connection until it's closed, and
+		// reports how many connections it accepted
+		let server = std::thread::spawn(move || {
+			let mut connections = 0;
+			for stream in listener.incoming().take(2) {
+				connections += 1;
+				let mut reader = BufReader::new(stream.unwrap());
+				loop {
+					let mut head = String::new();
+					let mut line = String::new();
+					while reader.read_line(&mut line).unwrap_or(0) > 2 {
+						head.push_str(&line);
+						line.clear();
+					}
+					if head.is_empty() {
+						break;
+					}
+
+					let length = head.lines().find_map(|l| l.strip_prefix("Content-Length: ")).map(|l| l.parse::<usize>().unwrap()).unwrap_or(0);
+					let mut body = vec![0u8; length];
+					std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+
+					let out = reader.get_mut();
+					if head.starts_with("POST /old") {
+						out.write_all(b"HTTP/1.1 303 See Other\r\nLocation: chunked?x=1\r\nContent-Length: 0\r\n\r\n").unwrap();
+					} else if head.starts_with("GET /chunked?x=1") {
+						assert!(head.contains("X-Test: 1\r\n"));
+						out.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").unwrap();
+					} else if head.starts_with("PUT /echo") {
+						out.write_all(format!("HTTP/1.1 201 Created\r\nContent-Length: {}\r\nSet-Cookie: a=1\r\n\r\n", body.len()).as_bytes()).unwrap();
+						out.write_all(&body).unwrap();
+					} else {
+						out.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\ngone").unwrap();
+						break;
+					}
+				}
+			}
+			connections
+		});
+
+		let res = fetch(request(format!("http://127.0.0.1:{}/old", port), HttpMethod::Post, b"data")).unwrap();
+		assert_eq!(res.url, format!("http://127.0.0.1:{}/chunked?x=1", port));
+		assert_eq!(res.response.get_code(), 200);
+		assert_eq!(res.response.get_content(), b"hello world");
+
+		let res = fetch(request(format!("http://127.0.0.1:{}/echo", port), HttpMethod::Put, b"payload")).unwrap();
+		assert_eq!(res.response.get_code(), 201);
+		assert_eq!(res.response.get_content(), b"payload");
+		assert_eq!(res.response.get_cookies(), &vec!["a=1".to_string()]);
+
+		let res = fetch(request(format!("http://127.0.0.1:{}/missing", port), HttpMethod::Get, b"")).unwrap();
+		assert_eq!(res.response.get_code(), 404);
+		assert_eq!(res.response.get_content(), b"gone");
+
+		// The first connection was kept alive for the three requests, then closed by the server
+		let res = fetch(request(format!("http://127.0.0.1:{}/missing", port), HttpMethod::Get, b"")).unwrap();
+		assert_eq!(res.response.get_content(), b"gone");
+		assert_eq!(server.join().unwrap(), 2);
+
+		assert!(Target::parse("https://example.com").is_err());
+		assert!(Target::parse("http://user:pw@example.com/").is_err());
+		let target = Target::parse("http://[::1]:8080?q=1").unwrap();
+		assert_eq!((target.host.as_str(), target.port, target.path.as_str()), ("[::1]", 8080, "/?q=1"));
+		assert_eq!(target.resolve("a/b"), "http://[::1]:8080/a/b");
+	}
+
+	/// Answers one request with `response`, returning the head it received
+	fn serve_once(listener: TcpListener, response: String) -> std::thread::JoinHandle<String> {
+		std::thread::spawn(move || {
+			let mut reader = BufReader::new(listener.accept().unwrap().0);
+			let mut head = String::new();
+			let mut line = String::new();
+			while reader.read_line(&mut line).unwrap_or(0) > 2 {
+				head.push_str(&line);
+				line.clear();
+			}
+
+			reader.get_mut().write_all(response.as_bytes()).unwrap();
+			head
+		})
+	}
+
+	#[test]
+	pub fn test_cross_origin_redirect() {
+		let first = TcpListener::bind("127.0.0.1:0").unwrap();
+		let second = TcpListener::bind("127.0.0.1:0").unwrap();
+		let (first_port, second_port) = (first.local_addr().unwrap().port(), second.local_addr().unwrap().port());
+
+		let first = serve_once(first, format!("HTTP/1.1 302 Found\r\nLocation: http://127.0.0.1:{}/next\r\nContent-Length: 0\r\n\r\n", second_port));
+		let second = serve_once(second, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string());
+
+		let mut req = request(format!("http://127.0.0.1:{}/start", first_port), HttpMethod::Get, b"");
+		req.headers.push(("Authorization".to_string(), "Bearer secret".to_string()));
+		req.headers.push(("Cookie".to_string(), "sid=1".to_string()));
+
+		let res = fetch(req).unwrap();
+		assert_eq!(res.response.get_content(), b"ok");
+
+		let (first, second) = (first.join().unwrap(), second.join().unwrap());
+		assert!(first.contains("Authorization: Bearer secret\r\n") && first.contains("Cookie: sid=1\r\n"));
+		assert!(!second.contains("Authorization") && !second.contains("Cookie"));
+		assert!(second.contains("X-Test: 1\r\n"));
+	}
+}
@@ -0,0 +1,206 @@
+use std::io::Write;
+use crate::http::http_message::{HttpMessage, HttpVersion};
+use crate::http::http_response::HttpResponse;
+
+#[derive(PartialEq, Debug)]
+enum StreamState {
+	/// Nothing has been sent yet, the response can still be sent in one go
+	Idle,
+	/// The head has been sent, content follows as chunks
+	Chunked,
+	/// The head has been sent, content follows as-is (fixed length or HTTP/1.0)
+	Raw,
+	Finished
+}
+
+/// Output side of a request handled by a behaviour. A response is either sent whole with
+/// `send`, or streamed with `begin`, `write` and `finish`.
+pub struct ResponseStream {
+	out: Box<dyn Write>,
+	version: HttpVersion,
+	state: StreamState,
+	connected: bool,
+	/// Set before the behaviour runs (e.g. a session started by a middleware), sent with
+	/// whatever response follows
+	cookies: Vec<String>
+}
+
+impl ResponseStream {
+	pub fn new(out: Box<dyn Write>, version: HttpVersion) -> Self {
+		ResponseStream {
+			out,
+			version,
+			state: StreamState::Idle,
+			connected: true,
+			cookies: Vec::new()
+		}
+	}
+
+	/// Adds a `Set-Cookie` value to the response, however it's sent
+	pub fn add_cookie(&mut self, cookie: String) {
+		self.cookies.push(cookie);
+	}
+
+	/// False once a write failed because the client went away
+	pub fn is_connected(&self) -> bool {
+		self.connected
+	}
+
+	fn check_write(&mut self, res: std::io::Result<()>) -> std::io::Result<()> {
+		if let Err(e) = &res {
+			if e.kind() == std::io::ErrorKind::BrokenPipe {
+				self.connected = false;
+				self.state = StreamState::Finished;
+			}
+		}
+
+		res
+	}
+
+	/// True once anything has been written to the client
+	pub fn has_started(&self) -> bool {
+		self.state != StreamState::Idle
+	}
+
+	pub fn send(&mut self, response: &mut HttpResponse) -> std::io::Result<()> {
+		response.prepend_cookies(&self.cookies);
+		self.out.write_all(&response.serialize())?;
+		self.out.flush()?;
+		self.state = StreamState::Finished;
+
+		Ok(())
+	}
+
+	/// Sends the status line and headers of `head`, ignoring its content. Without a known
+	/// `content_length` the content is sent with chunked transfer encoding (or until the
+	/// connection closes, on HTTP/1.0).
+	pub fn begin(&mut self, head: &mut HttpResponse, content_length: Option<usize>) -> std::io::Result<()> {
+		if self.state != StreamState::Idle {
+			return Err(std::io::Error::other("Response already started"));
+		}
+
+		head.remove_header("Content-Length");
+		head.remove_header("Transfer-Encoding");
+		head.prepend_cookies(&self.cookies);
+
+		self.state = match content_length {
+			Some(len) => {
+				head.set_header("Content-Length", &len.to_string());
+				StreamState::Raw
+			},
+			None => {
+				if self.version == HttpVersion::Http1_1 {
+					head.set_header("Transfer-Encoding", "chunked");
+					StreamState::Chunked
+				} else {
+					head.remove_header("Connection");
+					head.set_header("Connection", "close");
+					StreamState::Raw
+				}
+			}
+		};
+
+		self.out.write_all(&head.serialize_head())?;
+		self.out.flush()
+	}
+
+	/// Sends a `101 Switching Protocols` head; whatever is written afterwards is passed
+	/// through as-is.
+	pub fn begin_upgrade(&mut self, head: &mut HttpResponse) -> std::io::Result<()> {
+		if self.state != StreamState::Idle {
+			return Err(std::io::Error::other("Response already started"));
+		}
+
+		self.state = StreamState::Raw;
+
+		head.prepend_cookies(&self.cookies);
+		let res = self.out.write_all(&head.serialize_head()).and_then(|_| self.out.flush());
+		self.check_write(res)
+	}
+
+	pub fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+		let res = self.write_internal(data);
+		self.check_write(res)
+	}
+
+	fn write_internal(&mut self, data: &[u8]) -> std::io::Result<()> {
+		if !self.connected {
+			return Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected"));
+		}
+
+		match self.state {
+			StreamState::Chunked => {
+				if data.is_empty() {
+					// An empty chunk would end the response
+					return Ok(());
+				}
+
+				self.out.write_all(format!("{:X}\r\n", data.len()).as_bytes())?;
+				self.out.write_all(data)?;
+				self.out.write_all(b"\r\n")?;
+			},
+			StreamState::Raw => {
+				self.out.write_all(data)?;
+			},
+			_ => return Err(std::io::Error::other("Response is not being streamed"))
+		}
+
+		self.out.flush()
+	}
+
+	pub fn finish(&mut self) -> std::io::Result<()> {
+		if self.state == StreamState::Chunked {
+			let res = self.out.write_all(b"0\r\n\r\n").and_then(|_| self.out.flush());
+			self.check_write(res)?;
+		}
+
+		self.state = StreamState::Finished;
+
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::cell::RefCell;
+	use std::collections::HashMap;
+	use std::io::Write;
+	use std::rc::Rc;
+	use crate::http::http_message::HttpVersion;
+	use crate::http::http_response::HttpResponse;
+	use crate::http::response_stream::ResponseStream;
+
+	#[derive(Clone)]
+	struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.borrow_mut().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	pub fn test_chunked_stream() {
+		let buffer = SharedBuffer(Rc::new(RefCell::new(Vec::new())));
+		let mut stream = ResponseStream::new(Box::new(buffer.clone()), HttpVersion::Http1_1);
+
+		let mut head = HttpResponse::new(200, HashMap::new(), b"ignored".to_vec(), HttpVersion::Http1_1);
+		stream.begin(&mut head, None).unwrap();
+		stream.write(b"Hello, ").unwrap();
+		stream.write(b"").unwrap();
+		stream.write(b"world!").unwrap();
+		stream.finish().unwrap();
+
+		let data = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+		assert!(data.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(data.contains("Transfer-Encoding: chunked\r\n"));
+		assert!(!data.contains("Content-Length"));
+		assert!(data.ends_with("\r\n\r\n7\r\nHello, \r\n6\r\nworld!\r\n0\r\n\r\n"));
+	}
+}
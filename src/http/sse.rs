@@ -0,0 +1,81 @@
+use crate::http::http_message::HttpMessage;
+use crate::http::http_request::HttpRequest;
+use crate::http::websocket::is_upgrade_request;
+
+/// True if `request` comes from an `EventSource`, which asks for `text/event-stream`
+pub fn accepts_event_stream(request: &HttpRequest) -> bool {
+	request.find_header("Accept").is_some_and(|v| v.to_ascii_lowercase().contains("text/event-stream"))
+}
+
+/// True for requests whose response lasts as long as the connection: WebSocket upgrades and
+/// event streams
+pub fn is_streaming_request(request: &HttpRequest) -> bool {
+	is_upgrade_request(request) || accepts_event_stream(request)
+}
+
+/// Formats a Server-Sent Events frame. Multi-line data is split over several `data:` fields.
+pub fn format_event(event: Option<&str>, data: &str, id: Option<&str>, retry: Option<u64>) -> Result<String, String> {
+	let mut res = String::new();
+
+	if let Some(event) = event {
+		if event.contains(['\r', '\n']) {
+			return Err("SSE event names can't contain line breaks".to_string());
+		}
+		res.push_str("event: ");
+		res.push_str(event);
+		res.push('\n');
+	}
+
+	if let Some(id) = id {
+		if id.contains(['\r', '\n', '\0']) {
+			return Err("SSE ids can't contain line breaks or NUL".to_string());
+		}
+		res.push_str("id: ");
+		res.push_str(id);
+		res.push('\n');
+	}
+
+	if let Some(retry) = retry {
+		res.push_str(&format!("retry: {}\n", retry));
+	}
+
+	for line in data.replace("\r\n", "\n").split(['\n', '\r']) {
+		res.push_str("data: ");
+		res.push_str(line);
+		res.push('\n');
+	}
+
+	res.push('\n');
+
+	Ok(res)
+}
+
+/// Formats an SSE comment line, ignored by clients (used as a heartbeat)
+pub fn format_comment(comment: &str) -> String {
+	let mut res = String::new();
+	for line in comment.replace("\r\n", "\n").split(['\n', '\r']) {
+		res.push_str(": ");
+		res.push_str(line);
+		res.push('\n');
+	}
+	res.push('\n');
+
+	res
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::http::sse::{format_comment, format_event};
+
+	#[test]
+	pub fn test_format_event() {
+		assert_eq!(format_event(None, "hello", None, None).unwrap(), "data: hello\n\n");
+		assert_eq!(
+			format_event(Some("update"), "a\nb", Some("42"), Some(3000)).unwrap(),
+			"event: update\nid: 42\nretry: 3000\ndata: a\ndata: b\n\n"
+		);
+		assert!(format_event(Some("a\nb"), "x", None, None).is_err());
+		assert_eq!(format_comment("ping"), ": ping\n\n");
+	}
+}
@@ -0,0 +1,268 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1::{Digest, Sha1};
+use crate::http::http_message::HttpMessage;
+use crate::http::http_request::HttpRequest;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_NO_STATUS: u16 = 1005;
+pub const CLOSE_ABNORMAL: u16 = 1006;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+pub struct Frame {
+	pub fin: bool,
+	pub opcode: u8,
+	pub masked: bool,
+	pub payload: Vec<u8>
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+	Io(std::io::Error),
+	Protocol(String),
+	TooLarge
+}
+
+impl FrameError {
+	/// Close code to report the error to the peer with
+	pub fn close_code(&self) -> u16 {
+		match self {
+			FrameError::Io(_) => CLOSE_ABNORMAL,
+			FrameError::Protocol(_) => CLOSE_PROTOCOL_ERROR,
+			FrameError::TooLarge => CLOSE_TOO_BIG
+		}
+	}
+}
+
+impl Display for FrameError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			FrameError::Io(e) => write!(f, "{}", e),
+			FrameError::Protocol(message) => write!(f, "{}", message),
+			FrameError::TooLarge => write!(f, "Message too large")
+		}
+	}
+}
+
+impl From<std::io::Error> for FrameError {
+	fn from(e: std::io::Error) -> Self {
+		FrameError::Io(e)
+	}
+}
+
+/// A complete message, as relayed between the listener and the worker handling the socket
+#[derive(PartialEq, Debug)]
+pub enum Message {
+	Text(String),
+	Binary(Vec<u8>),
+	Close(u16, String)
+}
+
+fn header_has_token(request: &HttpRequest, name: &str, token: &str) -> bool {
+	request.find_header(name)
+		.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+/// True if `request` asks to switch the connection to the WebSocket protocol
+pub fn is_upgrade_request(request: &HttpRequest) -> bool {
+	header_has_token(request, "Upgrade", "websocket") && header_has_token(request, "Connection", "upgrade")
+}
+
+/// Value of `Sec-WebSocket-Accept` answering the client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+	let mut hasher = Sha1::new();
+	hasher.update(key.trim().as_bytes());
+	hasher.update(HANDSHAKE_GUID.as_bytes());
+
+	STANDARD.encode(hasher.finalize())
+}
+
+/// Reads one frame. Payloads larger than `max_size` are rejected before being read.
+pub fn read_frame(reader: &mut impl Read, max_size: usize) -> Result<Frame, FrameError> {
+	let mut head = [0u8; 2];
+	reader.read_exact(&mut head)?;
+
+	if head[0] & 0x70 != 0 {
+		return Err(FrameError::Protocol("Reserved bits set without a negotiated extension".to_string()));
+	}
+
+	let fin = head[0] & 0x80 != 0;
+	let opcode = head[0] & 0x0F;
+	let masked = head[1] & 0x80 != 0;
+
+	let len = match head[1] & 0x7F {
+		126 => {
+			let mut buff = [0u8; 2];
+			reader.read_exact(&mut buff)?;
+			u16::from_be_bytes(buff) as u64
+		},
+		127 => {
+			let mut buff = [0u8; 8];
+			reader.read_exact(&mut buff)?;
+			u64::from_be_bytes(buff)
+		},
+		len => len as u64
+	};
+
+	if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+		return Err(FrameError::Protocol("Control frames can't be fragmented or longer than 125 bytes".to_string()));
+	}
+
+	if len > max_size as u64 {
+		return Err(FrameError::TooLarge);
+	}
+
+	let mut mask = [0u8; 4];
+	if masked {
+		reader.read_exact(&mut mask)?;
+	}
+
+	let mut payload = vec![0u8; len as usize];
+	reader.read_exact(&mut payload)?;
+
+	if masked {
+		for (i, b) in payload.iter_mut().enumerate() {
+			*b ^= mask[i % 4];
+		}
+	}
+
+	Ok(Frame {
+		fin,
+		opcode,
+		masked,
+		payload
+	})
+}
+
+/// Encodes a final frame. Servers send unmasked frames; clients must pass a `mask`.
+pub fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+	let mut res = vec![0x80 | opcode];
+	let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+
+	match payload.len() {
+		len if len < 126 => res.push(mask_bit | len as u8),
+		len if len <= u16::MAX as usize => {
+			res.push(mask_bit | 126);
+			res.extend_from_slice(&(len as u16).to_be_bytes());
+		},
+		len => {
+			res.push(mask_bit | 127);
+			res.extend_from_slice(&(len as u64).to_be_bytes());
+		}
+	}
+
+	match mask {
+		Some(mask) => {
+			res.extend_from_slice(&mask);
+			res.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+		},
+		None => res.extend_from_slice(payload)
+	}
+
+	res
+}
+
+pub fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+	let mut res = code.to_be_bytes().to_vec();
+
+	// Control frames are limited to 125 bytes
+	let mut end = reason.len().min(123);
+	while !reason.is_char_boundary(end) {
+		end -= 1;
+	}
+	res.extend_from_slice(&reason.as_bytes()[..end]);
+
+	res
+}
+
+pub fn parse_close_payload(payload: &[u8]) -> (u16, String) {
+	if payload.len() < 2 {
+		return (CLOSE_NO_STATUS, String::new());
+	}
+
+	(u16::from_be_bytes([payload[0], payload[1]]), String::from_utf8_lossy(&payload[2..]).to_string())
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Message) -> std::io::Result<()> {
+	let (kind, data) = match message {
+		Message::Text(s) => (OPCODE_TEXT, s.as_bytes().to_vec()),
+		Message::Binary(b) => (OPCODE_BINARY, b.clone()),
+		Message::Close(code, reason) => (OPCODE_CLOSE, close_payload(*code, reason))
+	};
+
+	let mut buff = vec![kind];
+	buff.extend_from_slice(&(data.len() as u64).to_ne_bytes());
+	buff.extend_from_slice(&data);
+
+	writer.write_all(&buff)?;
+	writer.flush()
+}
+
+pub fn read_message(reader: &mut impl Read) -> std::io::Result<Message> {
+	let mut kind = [0u8; 1];
+	reader.read_exact(&mut kind)?;
+
+	let mut len = [0u8; 8];
+	reader.read_exact(&mut len)?;
+
+	let mut data = vec![0u8; u64::from_ne_bytes(len) as usize];
+	reader.read_exact(&mut data)?;
+
+	match kind[0] {
+		OPCODE_TEXT => Ok(Message::Text(String::from_utf8_lossy(&data).to_string())),
+		OPCODE_BINARY => Ok(Message::Binary(data)),
+		OPCODE_CLOSE => {
+			let (code, reason) = parse_close_payload(&data);
+			Ok(Message::Close(code, reason))
+		},
+		_ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid WebSocket relay message"))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::http::websocket::{accept_key, encode_frame, read_frame, read_message, write_message, FrameError, Message, OPCODE_PING, OPCODE_TEXT};
+
+	#[test]
+	pub fn test_accept_key() {
+		// Example from RFC 6455, section 1.3
+		assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+	}
+
+	#[test]
+	pub fn test_frames() {
+		let data = encode_frame(OPCODE_TEXT, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d]));
+		assert_eq!(data, [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+
+		let frame = read_frame(&mut data.as_slice(), 1024).unwrap();
+		assert!(frame.fin && frame.masked);
+		assert_eq!(frame.opcode, OPCODE_TEXT);
+		assert_eq!(frame.payload, b"Hello");
+
+		let long = vec![7u8; 70000];
+		let frame = read_frame(&mut encode_frame(OPCODE_TEXT, &long, None).as_slice(), 100000).unwrap();
+		assert!(!frame.masked);
+		assert_eq!(frame.payload, long);
+		assert!(matches!(read_frame(&mut encode_frame(OPCODE_TEXT, &long, None).as_slice(), 1024), Err(FrameError::TooLarge)));
+		assert!(matches!(read_frame(&mut encode_frame(OPCODE_PING, &long[..200], None).as_slice(), 1024), Err(FrameError::Protocol(_))));
+
+		let mut relay: Vec<u8> = Vec::new();
+		write_message(&mut relay, &Message::Close(1001, "bye".to_string())).unwrap();
+		assert_eq!(read_message(&mut relay.as_slice()).unwrap(), Message::Close(1001, "bye".to_string()));
+	}
+}
@@ -1,33 +1,58 @@
-use crate::http::http_message::{HttpMessage, HttpVersion};
+use crate::access_log;
+use crate::access_log::AccessEntry;
+use crate::http::http_message::{find_header_end, request_body_length, HttpMessage, HttpMethod, HttpVersion};
 use crate::http::http_request::HttpRequest;
-use crate::http::http_response::HttpResponse;
+use crate::http::http_response::{code_to_http_status, HttpResponse};
+use crate::http::sse::is_streaming_request;
+use crate::http::websocket::{accept_key, close_payload, encode_frame, is_upgrade_request, parse_close_payload, read_frame, read_message, write_message, FrameError, Message, CLOSE_ABNORMAL, CLOSE_INVALID_DATA, CLOSE_NORMAL, CLOSE_PROTOCOL_ERROR, CLOSE_TOO_BIG, OPCODE_BINARY, OPCODE_CLOSE, OPCODE_CONTINUATION, OPCODE_PING, OPCODE_PONG, OPCODE_TEXT};
 use crate::ipc::request_pipe::RequestPipe;
-use crate::ipc::IpcMessage;
-use crate::utils::new_named_pipe;
+use crate::utils::new_pipe;
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{Chain, Cursor, Read, Write};
 use std::net::{SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
 use std::path::Path;
-use std::sync::{atomic, Arc, Mutex};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 pub struct HttpClient {
     stream: TcpStream,
     address: SocketAddr,
-    sender: Arc<Mutex<RequestPipe>>,
-	default_headers: HashMap<String, String>
+    sender: Arc<RequestPipe>,
+	default_headers: HashMap<String, String>,
+	max_body_size: usize,
+	keep_alive: bool,
+	/// Status and body size of the last response, for the access log
+	status: u16,
+	body_sent: usize
 }
 
-static CLIENT_COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+/// The pair of pipes connecting a client to the worker handling its request. The request
+/// side stays open to relay WebSocket messages.
+struct WorkerChannel {
+	request: File,
+	response: File
+}
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// What the client sends over a WebSocket: whatever arrived along with the handshake, then the
+/// socket itself.
+type WebSocketInput = Chain<Cursor<Vec<u8>>, TcpStream>;
 
 impl HttpClient {
-    pub fn new(stream: TcpStream, address: SocketAddr, lua_send: Arc<Mutex<RequestPipe>>, default_headers: HashMap<String, String>) -> Self {
+    pub fn new(stream: TcpStream, address: SocketAddr, lua_send: Arc<RequestPipe>, default_headers: HashMap<String, String>, max_body_size: usize) -> Self {
         Self {
             stream,
             address,
             sender: lua_send,
-			default_headers
+			default_headers,
+			max_body_size,
+			keep_alive: true,
+			status: 0,
+			body_sent: 0
         }
     }
 
@@ -39,6 +64,41 @@ impl HttpClient {
 		HttpResponse::new(code, headers, content, version)
 	}
 
+	fn write_response(&mut self, resp: &HttpResponse) -> std::io::Result<()> {
+		self.status = resp.get_code();
+		self.body_sent = resp.get_content().len();
+		self.stream.write_all(resp.serialize().as_ref())
+	}
+
+	fn send_error(&mut self, code: u16, version: HttpVersion) -> bool {
+		let content = format!("{}: {}", code, code_to_http_status(code).unwrap_or("Error"));
+		let resp = self.mk_response(
+			code,
+			HashMap::from([
+				("Content-Type".to_string(), "text/plain".to_string())
+			]),
+			content.into_bytes(),
+			version
+		);
+
+		if let Err(e) = self.write_response(&resp) {
+			log_error!("HttpClient", "Failed to write response to stream: {:?}", e);
+			return false;
+		}
+
+		true
+	}
+
+	fn expects_continue(head: &[u8]) -> bool {
+		String::from_utf8_lossy(head).split("\r\n").any(|line| {
+			match line.find(':') {
+				Some(idx) => line[..idx].trim().eq_ignore_ascii_case("Expect")
+					&& line[idx + 1..].trim().eq_ignore_ascii_case("100-continue"),
+				None => false
+			}
+		})
+	}
+
 	fn serve_static_file(&mut self, req: &HttpRequest, path: &Path) -> bool {
 		let data = match fs::read(path) {
 			Ok(data) => data,
@@ -69,7 +129,7 @@ impl HttpClient {
 			req.version.clone()
 		);
 
-		_ = self.stream.write(request.serialize().as_ref());
+		_ = self.write_response(&request);
 		true
 	}
 
@@ -105,8 +165,7 @@ impl HttpClient {
 					req.version.clone()
 				);
 
-				let serialized = resp.serialize();
-				_ = self.stream.write_all(serialized.as_slice());
+				_ = self.write_response(&resp);
 				return true;
 			}
 
@@ -121,139 +180,411 @@ impl HttpClient {
 		false
 	}
 
-	fn handle_dynamic_request(&mut self, req: &HttpRequest) -> bool {
-		let id =
-			CLIENT_COUNTER.fetch_add(1, atomic::Ordering::AcqRel);
+	/// Copies a response produced by the dispatcher to the client as it arrives, so streamed
+	/// responses reach the client without being buffered. Returns false only if nothing
+	/// could be sent.
+	fn forward_response(&mut self, source: &mut impl Read) -> bool {
+		_ = self.stream.set_nonblocking(false);
+
+		let mut head: Vec<u8> = Vec::new();
+		let mut head_complete = false;
+		let mut chunked = false;
+		let mut tail: Vec<u8> = Vec::new();
+		let mut sent: usize = 0;
+		let mut head_size: usize = 0;
+
+		let mut buff = [0u8; 8192];
+		loop {
+			let size = match source.read(&mut buff) {
+				Ok(0) => break,
+				Ok(size) => size,
+				Err(e) => {
+					log_error!("HttpClient", "Failed to read response from dispatcher: {:?}", e);
+					self.keep_alive = false;
+					break;
+				}
+			};
+			let data = &buff[0..size];
+
+			if !head_complete {
+				head.extend_from_slice(data);
+				if let Some(idx) = find_header_end(&head) {
+					head_complete = true;
+					head_size = idx;
+					if let Some(resp) = HttpResponse::parse(&head[..idx]) {
+						self.status = resp.get_code();
+						if resp.find_header("Connection").is_some_and(|v| v.eq_ignore_ascii_case("close")) {
+							self.keep_alive = false;
+						}
+						chunked = resp.find_header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked"));
+					}
+				}
+			}
+
+			tail.extend_from_slice(data);
+			if tail.len() > 5 {
+				tail.drain(..tail.len() - 5);
+			}
+
+			if let Err(e) = self.stream.write_all(data) {
+				log_error!("HttpClient", "Failed to write to stream: {:?}", e);
+				self.keep_alive = false;
+				break;
+			}
+			sent += size;
+		}
+
+		// A chunked response that didn't end with the last chunk was cut short by an error:
+		// the connection can't be reused
+		if chunked && tail != b"0\r\n\r\n" {
+			self.keep_alive = false;
+		}
 
-		let name = format!("{}_{:?}", std::process::id(), id);
-		let out_name = format!("{name}.out");
-		let in_name = format!("{name}.in");
+		_ = self.stream.set_nonblocking(true);
+		self.body_sent = sent.saturating_sub(head_size);
+
+		sent > 0
+	}
 
-		let out_fifo_path = match new_named_pipe(&out_name) {
+	/// Asks the dispatcher for a worker and sends it `req`. If the request couldn't be handed
+	/// over, returns the status to answer with: 503 if the dispatcher is down, 500 otherwise.
+	fn dispatch_request(&mut self, req: &HttpRequest) -> Result<WorkerChannel, u16> {
+		// The worker gets the read end of the request pipe and the write end of the response
+		// pipe, passed over the dispatcher socket
+		let (worker_input, mut request) = match new_pipe() {
 			Ok(p) => p,
 			Err(e) => {
-				println!("Failed to create named pipe: {:?}", e);
-				return false;
+				log_error!("HttpClient", "Failed to create pipe: {:?}", e);
+				return Err(500);
 			}
 		};
 
-		let in_fifo_path = match new_named_pipe(&in_name) {
+		let (response, worker_output) = match new_pipe() {
 			Ok(p) => p,
 			Err(e) => {
-				println!("Failed to create named pipe: {:?}", e);
-				return false;
+				log_error!("HttpClient", "Failed to create pipe: {:?}", e);
+				return Err(500);
+			}
+		};
+
+		let accepted =
+			match self.sender.send_request(req.url.uri.clone(), is_streaming_request(req), &[worker_input.as_raw_fd(), worker_output.as_raw_fd()]) {
+				Ok(accepted) => accepted,
+				Err(e) => {
+					log_error!("HttpClient", "Failed to send IPC request: {:?}", e);
+					return Err(503);
+				}
+			};
+
+		// Only the worker keeps these, so the response ends when the worker is done
+		drop(worker_input);
+		drop(worker_output);
+
+		if !accepted {
+			log_warn!("HttpClient", "Request denied");
+			return Err(503);
+		}
+
+		let data = req.serialize();
+
+		let size: u64 = data.len() as u64;
+		let size_buff = size.to_ne_bytes();
+		if let Err(e) = request.write_all(&size_buff) {
+			log_error!("HttpClient", "Failed to write request size to pipe: {:?}", e);
+			return Err(500);
+		}
+
+		if let Err(e) = request.write_all(&data) {
+			log_error!("HttpClient", "Failed to write to pipe: {:?}", e);
+			return Err(500);
+		}
+
+		Ok(WorkerChannel {
+			request,
+			response
+		})
+	}
+
+	fn handle_dynamic_request(&mut self, req: &HttpRequest) -> bool {
+		match self.dispatch_request(req) {
+			Ok(mut channel) => self.forward_response(&mut channel.response),
+			Err(code) => self.send_error(code, req.version.clone())
+		}
+	}
+
+	/// Reads a response head one byte at a time, so nothing that follows it is consumed
+	fn read_head(source: &mut impl Read) -> std::io::Result<Vec<u8>> {
+		let mut head: Vec<u8> = Vec::new();
+		let mut byte = [0u8; 1];
+
+		while find_header_end(&head).is_none() {
+			if head.len() > MAX_HEAD_SIZE {
+				return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Response head too large"));
 			}
+
+			source.read_exact(&mut byte)?;
+			head.push(byte[0]);
+		}
+
+		Ok(head)
+	}
+
+	/// Hands a WebSocket upgrade request to a worker. If the matched behaviour accepts it,
+	/// completes the handshake and relays messages until either side closes the connection;
+	/// otherwise forwards the worker's regular response.
+	/// `buffered` holds bytes the client sent right after the handshake, which already belong to
+	/// the WebSocket.
+	fn handle_websocket(&mut self, req: &HttpRequest, buffered: Vec<u8>) -> bool {
+		self.keep_alive = false;
+
+		let key = match req.find_header("Sec-WebSocket-Key") {
+			Some(key) if req.method == HttpMethod::Get && req.version == HttpVersion::Http1_1 => key.clone(),
+			_ => return self.send_error(400, req.version.clone())
+		};
+
+		if req.find_header("Sec-WebSocket-Version").map(|v| v.trim()) != Some("13") {
+			let resp = self.mk_response(
+				426,
+				HashMap::from([
+					("Sec-WebSocket-Version".to_string(), "13".to_string()),
+					("Content-Type".to_string(), "text/plain".to_string())
+				]),
+				b"426: Upgrade Required".to_vec(),
+				req.version.clone()
+			);
+
+			_ = self.write_response(&resp);
+			return true;
+		}
+
+		let mut channel = match self.dispatch_request(req) {
+			Ok(channel) => channel,
+			Err(code) => return self.send_error(code, req.version.clone())
 		};
 
-		let mut l = match self.sender.lock() {
-			Ok(s) => s,
-			Err(_) => {
-				println!("Error while locking send mutex");
+		let head = match HttpClient::read_head(&mut channel.response) {
+			Ok(head) => head,
+			Err(e) => {
+				log_error!("HttpClient", "Failed to read response from dispatcher: {:?}", e);
 				return false;
 			}
 		};
 
-		let msg =
-			match l.send_message_and_wait(IpcMessage::Request {
-				request_path: name.clone(),
-			}) {
-				Ok(msg) => msg,
-				Err(e) => {
-					println!("Failed to send IPC request: {:?}", e);
-					return false;
-				}
-			};
+		let worker_head = match HttpResponse::parse(&head) {
+			Some(r) if r.get_code() == 101 => r,
+			_ => return self.forward_response(&mut Cursor::new(head).chain(&mut channel.response))
+		};
 
-		drop(l);
-
-		match msg {
-			IpcMessage::Ok => {
-				let mut fifo = match File::options()
-					.read(false)
-					.write(true)
-					.open(&out_fifo_path)
-				{
-					Ok(f) => f,
-					Err(e) => {
-						println!("Failed to open fifo: {:?}", e);
-						return false;
-					}
-				};
+		let mut resp = self.mk_response(101, HashMap::new(), Vec::new(), req.version.clone());
+		for (name, value) in worker_head.get_headers() {
+			resp.set_header(name, value);
+		}
+		resp.remove_header("Content-Length");
+		resp.remove_header("Connection");
+		resp.set_header("Upgrade", "websocket");
+		resp.set_header("Connection", "Upgrade");
+		resp.set_header("Sec-WebSocket-Accept", &accept_key(&key));
+
+		_ = self.stream.set_nonblocking(false);
+		self.status = 101;
+		if let Err(e) = self.stream.write_all(&resp.serialize_head()) {
+			log_error!("HttpClient", "Failed to write to stream: {:?}", e);
+			return true;
+		}
+
+		let input = match self.stream.try_clone() {
+			Ok(stream) => Cursor::new(buffered).chain(stream),
+			Err(e) => {
+				log_error!("HttpClient", "Failed to clone stream: {:?}", e);
+				return true;
+			}
+		};
+		self.relay_websocket(&mut channel, input);
+
+		true
+	}
+
+	fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> bool {
+		self.stream.write_all(&encode_frame(opcode, payload, None)).is_ok()
+	}
 
-				let data = req.serialize();
+	/// Reads the next frame from the client. Returns a message once it is complete, or the
+	/// close code to end the connection with.
+	fn read_websocket_message(&mut self, input: &mut WebSocketInput, fragments: &mut Option<(u8, Vec<u8>)>) -> Result<Option<Message>, u16> {
+		let frame = match read_frame(input, self.max_body_size) {
+			Ok(frame) => frame,
+			Err(e) => {
+				if !matches!(e, FrameError::Io(_)) {
+					log_info!("HttpClient", "Closing WebSocket: {}", e);
+				}
+				return Err(e.close_code());
+			}
+		};
+
+		// Clients must mask every frame
+		if !frame.masked {
+			return Err(CLOSE_PROTOCOL_ERROR);
+		}
 
-				let size: u64 = data.len() as u64;
-				let size_buff = size.to_ne_bytes();
-				if let Err(e) = fifo.write_all(&size_buff) {
-					println!(
-						"Failed to write request size to fifo: {:?}",
-						e
-					);
-					return false;
+		let (opcode, payload) = match frame.opcode {
+			OPCODE_PING => {
+				self.send_frame(OPCODE_PONG, &frame.payload);
+				return Ok(None);
+			},
+			OPCODE_PONG => return Ok(None),
+			OPCODE_CLOSE => {
+				let (code, reason) = parse_close_payload(&frame.payload);
+				self.send_frame(OPCODE_CLOSE, &frame.payload);
+				return Ok(Some(Message::Close(code, reason)));
+			},
+			OPCODE_TEXT | OPCODE_BINARY => {
+				if fragments.is_some() {
+					return Err(CLOSE_PROTOCOL_ERROR);
 				}
 
-				if let Err(e) = fifo.write_all(&data) {
-					println!("Failed to write to fifo: {:?}", e);
-					return false;
+				if !frame.fin {
+					*fragments = Some((frame.opcode, frame.payload));
+					return Ok(None);
 				}
 
-				let mut fifo = match File::options()
-					.read(true)
-					.write(false)
-					.open(&in_fifo_path)
-				{
-					Ok(f) => f,
-					Err(e) => {
-						println!("Failed to open fifo: {:?}", e);
-						return false;
-					}
+				(frame.opcode, frame.payload)
+			},
+			OPCODE_CONTINUATION => {
+				let (_, data) = match fragments.as_mut() {
+					Some(f) => f,
+					None => return Err(CLOSE_PROTOCOL_ERROR)
 				};
 
-				let mut data: Vec<u8> = Vec::new();
-				let mut buff = [0u8; 1024];
-				loop {
-					match fifo.read(&mut buff) {
-						Ok(a) => {
-							if a == 0 {
-								break;
-							}
-							data.extend_from_slice(&buff[0..a]);
-						}
-						Err(_) => {
-							break;
-						}
-					};
+				data.extend_from_slice(&frame.payload);
+				if data.len() > self.max_body_size {
+					return Err(CLOSE_TOO_BIG);
 				}
 
-				drop(fifo);
+				if !frame.fin {
+					return Ok(None);
+				}
 
-				if let Err(e) = self.stream.write_all(&data) {
-					println!("Failed to write to stream: {:?}", e);
-					return false;
+				match fragments.take() {
+					Some(f) => f,
+					None => return Err(CLOSE_PROTOCOL_ERROR)
 				}
+			},
+			_ => return Err(CLOSE_PROTOCOL_ERROR)
+		};
+
+		if opcode == OPCODE_TEXT {
+			match String::from_utf8(payload) {
+				Ok(text) => Ok(Some(Message::Text(text))),
+				Err(_) => Err(CLOSE_INVALID_DATA)
+			}
+		} else {
+			Ok(Some(Message::Binary(payload)))
+		}
+	}
+
+	/// Sends a close frame and waits (briefly) for the client to acknowledge it
+	fn close_websocket(&mut self, code: u16, reason: &str) {
+		if !self.send_frame(OPCODE_CLOSE, &close_payload(code, reason)) {
+			return;
+		}
+
+		_ = self.stream.set_read_timeout(Some(Duration::from_secs(5)));
+		while let Ok(frame) = read_frame(&mut self.stream, self.max_body_size) {
+			if frame.opcode == OPCODE_CLOSE {
+				break;
+			}
+		}
+	}
+
+	/// Copies messages between the client and the worker until one of them closes the
+	/// connection. The worker receives whole messages; control frames are answered here.
+	fn relay_websocket(&mut self, channel: &mut WorkerChannel, mut input: WebSocketInput) {
+		let mut fds = [
+			libc::pollfd { fd: self.stream.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+			libc::pollfd { fd: channel.response.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+		];
+
+		let mut fragments: Option<(u8, Vec<u8>)> = None;
 
-				_ = fs::remove_file(&out_fifo_path);
-				_ = fs::remove_file(&in_fifo_path);
+		loop {
+			for fd in fds.iter_mut() {
+				fd.revents = 0;
+			}
+
+			// Frames that came in with the handshake won't wake up poll
+			let buffered = input.get_ref().0.position() < input.get_ref().0.get_ref().len() as u64;
+			let timeout = if buffered { 0 } else { -1 };
+
+			if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+				let e = std::io::Error::last_os_error();
+				if e.kind() == std::io::ErrorKind::Interrupted {
+					continue;
+				}
 
-				true
+				log_error!("HttpClient", "poll failed: {:?}", e);
+				break;
 			}
-			IpcMessage::Close => {
-				println!("Request denied");
-				false
+
+			if buffered || fds[0].revents != 0 {
+				match self.read_websocket_message(&mut input, &mut fragments) {
+					Ok(Some(message)) => {
+						let closing = matches!(message, Message::Close(_, _));
+						if write_message(&mut channel.request, &message).is_err() || closing {
+							break;
+						}
+					},
+					Ok(None) => {},
+					Err(code) => {
+						if code != CLOSE_ABNORMAL {
+							self.close_websocket(code, "");
+						}
+						_ = write_message(&mut channel.request, &Message::Close(code, String::new()));
+						break;
+					}
+				}
 			}
-			any => {
-				println!("Invalid IPC message: {:?}", any);
-				false
+
+			if fds[1].revents != 0 {
+				let sent = match read_message(&mut channel.response) {
+					Ok(Message::Text(text)) => self.send_frame(OPCODE_TEXT, text.as_bytes()),
+					Ok(Message::Binary(data)) => self.send_frame(OPCODE_BINARY, &data),
+					Ok(Message::Close(code, reason)) => {
+						self.close_websocket(code, &reason);
+						break;
+					},
+					Err(_) => {
+						// The worker is done without closing explicitly
+						self.close_websocket(CLOSE_NORMAL, "");
+						break;
+					}
+				};
+
+				if !sent {
+					_ = write_message(&mut channel.request, &Message::Close(CLOSE_ABNORMAL, String::new()));
+					break;
+				}
 			}
 		}
 	}
 
+	fn log_access(&self, req: &HttpRequest, time: SystemTime, started: Instant) {
+		access_log::write(&AccessEntry {
+			address: self.address,
+			request: req,
+			status: self.status,
+			bytes: self.body_sent,
+			time,
+			duration: started.elapsed()
+		});
+	}
+
 
     pub fn run(&mut self, content_root: &Path) {
         _ = self.stream.set_nonblocking(true);
         let mut data: Vec<u8> = Vec::new();
 
         let mut alive = true;
+        let mut continue_sent = false;
 
         while alive {
             let mut buffer: [u8; 1024] = [0; 1024];
@@ -267,40 +598,76 @@ impl HttpClient {
                 }
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
-                        if data.len() > 0 {
-                            match HttpRequest::parse(&data) {
+                        if !data.is_empty() {
+                            let header_end = match find_header_end(&data) {
+                                Some(idx) => idx,
+                                None => {
+                                    if data.len() > MAX_HEAD_SIZE {
+                                        self.send_error(431, HttpVersion::Http1_1);
+                                        alive = false;
+                                    }
+                                    continue;
+                                }
+                            };
+
+                            let content_length = match request_body_length(&data[..header_end]) {
+                                Ok(length) => length,
+                                Err(code) => {
+                                    self.send_error(code, HttpVersion::Http1_1);
+                                    alive = false;
+                                    continue;
+                                }
+                            };
+                            if content_length > self.max_body_size {
+                                self.send_error(413, HttpVersion::Http1_1);
+                                alive = false;
+                                continue;
+                            }
+
+                            if data.len() < header_end + content_length {
+                                if !continue_sent && HttpClient::expects_continue(&data[..header_end]) {
+                                    _ = self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+                                    continue_sent = true;
+                                }
+                                continue;
+                            }
+
+                            let request_data: Vec<u8> = data.drain(..(header_end + content_length)).collect();
+                            continue_sent = false;
+
+                            match HttpRequest::parse(&request_data) {
                                 Some(req) => {
-                                    println!(
-                                        "[HttpClient] {} {}",
-                                        req.method.to_str(),
-                                        req.url.to_string()
-                                    );
-
-                                    if !self.handle_static_file_request(&req, content_root) && !self.handle_dynamic_request(&req) {
-										let content = "500: Internal server error".as_bytes();
-										let resp = self.mk_response(
-											500,
-											HashMap::from([
-												("Content-Type".to_string(), "text/plain".to_string())
-											]),
-											content.to_vec(),
-											req.version.clone()
-										);
-
-										if let Err(e) = self.stream.write_all(resp.serialize().as_ref()) {
-											println!("Failed to write response to stream: {:?}", e);
-											break;
-										}
+                                    let time = SystemTime::now();
+                                    let started = Instant::now();
+                                    self.status = 0;
+                                    self.body_sent = 0;
+
+                                    if is_upgrade_request(&req) {
+                                        if !self.handle_websocket(&req, std::mem::take(&mut data)) {
+                                            self.send_error(500, req.version.clone());
+                                        }
+                                        self.log_access(&req, time, started);
+                                        break;
+                                    }
+
+                                    let sent = self.handle_static_file_request(&req, content_root) || self.handle_dynamic_request(&req)
+                                        || self.send_error(500, req.version.clone());
+                                    self.log_access(&req, time, started);
+                                    if !sent {
+                                        break;
+                                    }
+
+                                    if !self.keep_alive {
+                                        alive = false;
                                     }
                                 }
                                 None => {
-                                    println!("Failed to parse http request");
+                                    log_error!("HttpClient", "Failed to parse http request");
                                 }
                             };
-                            data.clear();
                         }
                     } else {
-                        println!("{}", e);
+                        log_warn!("HttpClient", "{}", e);
                         alive = false;
                         continue;
                     }
@@ -1,11 +1,23 @@
 pub mod request_pipe;
 
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// Most file descriptors accepted with one message
+const MAX_FDS: usize = 4;
 
 #[derive(Debug)]
 pub enum IpcMessage {
 	Poll,
-	Request{ request_path: String },
+	/// A request for `uri`. Its input and output streams are passed along with the message,
+	/// see `send_message_with_fds`. `id` tells the answers to concurrent requests apart,
+	/// `streaming` is set for WebSocket upgrades and event streams, which hold their worker
+	/// for as long as the connection lasts.
+	Request{ id: u64, uri: String, streaming: bool },
+	/// The dispatcher's answer to request `id`, `accepted` once it was handed to a worker
+	Reply{ id: u64, accepted: bool },
 	Ok,
 	Close
 }
@@ -22,18 +34,15 @@ impl <T: Write> IpcMessageSender for T {
 	fn send_message(&mut self, msg: IpcMessage) -> Result<(), std::io::Error> {
 		match msg {
 			IpcMessage::Poll => {
-				let preamble = ['p' as u8];
+				let preamble = [b'p'];
 				self.write_all(&preamble)
 			},
 			IpcMessage::Ok => {
-				let preamble = ['o' as u8];
+				let preamble = [b'o'];
 				self.write_all(&preamble)
 			}
-			IpcMessage::Request { request_path } => {
-				let data = request_path.as_bytes();
-				let len = (data.len() as u64).to_ne_bytes();
-
-				let preamble = ['r' as u8];
+			IpcMessage::Request { id, uri, streaming } => {
+				let preamble = [b'r'];
 				match self.write_all(&preamble) {
 					Ok(_) => {},
 					Err(e) => {
@@ -41,17 +50,20 @@ impl <T: Write> IpcMessageSender for T {
 					}
 				}
 
-				match self.write(&len) {
-					Ok(_) => {},
-					Err(e) => {
-						return Err(e);
-					}
-				}
-
-				self.write_all(data)
+				self.write_all(&id.to_ne_bytes())?;
+				self.write_all(&[streaming as u8])?;
+				let len = (uri.len() as u64).to_ne_bytes();
+				self.write_all(&len)?;
+				self.write_all(uri.as_bytes())
+			},
+			IpcMessage::Reply { id, accepted } => {
+				let preamble = [b'a'];
+				self.write_all(&preamble)?;
+				self.write_all(&id.to_ne_bytes())?;
+				self.write_all(&[accepted as u8])
 			},
 			IpcMessage::Close => {
-				let preamble = ['c' as u8];
+				let preamble = [b'c'];
 				self.write_all(&preamble)
 			}
 		}
@@ -62,29 +74,166 @@ impl <T: Read> IpcMessageReceiver for T {
 	fn read_message(&mut self) -> Result<IpcMessage, std::io::Error> {
 		let mut preamble: [u8; 1] = [0];
 
-		if let Err(e) = self.read_exact(&mut preamble) {
-			return Err(e);
-		}
+		self.read_exact(&mut preamble)?;
 
 		match preamble[0] as char {
 			'p' => Ok(IpcMessage::Poll),
 			'c' => Ok(IpcMessage::Close),
 			'o' => Ok(IpcMessage::Ok),
+			'a' => {
+				let mut data = [0u8; 9];
+				self.read_exact(&mut data)?;
+
+				let mut id = [0u8; 8];
+				id.copy_from_slice(&data[..8]);
+				Ok(IpcMessage::Reply { id: u64::from_ne_bytes(id), accepted: data[8] != 0 })
+			},
 			'r' => {
+				let mut id: [u8; 8] = [0; 8];
+				self.read_exact(&mut id)?;
+				let mut streaming = [0u8; 1];
+				self.read_exact(&mut streaming)?;
+
 				let mut len: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
-				if let Err(e) = self.read_exact(&mut len) {
-					return Err(e);
-				}
+				self.read_exact(&mut len)?;
 
 				let len = u64::from_ne_bytes(len);
 				let mut data = vec![0; len as usize];
-				if let Err(e) = self.read(&mut data) {
-					return Err(e);
-				}
+				self.read_exact(&mut data)?;
 
-				Ok(IpcMessage::Request{ request_path: String::from_utf8(data).unwrap() })
+				match String::from_utf8(data) {
+					Ok(uri) => Ok(IpcMessage::Request{ id: u64::from_ne_bytes(id), uri, streaming: streaming[0] != 0 }),
+					Err(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid IPC request"))
+				}
 			}
 			_ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid IPC message"))
 		}
 	}
+}
+
+/// Sends `msg` over a Unix socket with `fds` attached (`SCM_RIGHTS`): the receiving process
+/// gets its own copies of the descriptors, the caller's stay open.
+pub fn send_message_with_fds(socket: &mut UnixStream, msg: IpcMessage, fds: &[RawFd]) -> Result<(), Error> {
+	if fds.len() > MAX_FDS {
+		return Err(Error::new(ErrorKind::InvalidInput, "Too many file descriptors"));
+	}
+
+	let mut data: Vec<u8> = Vec::new();
+	data.send_message(msg)?;
+
+	let fds_size = size_of_val(fds) as u32;
+	let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+	let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+
+	let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+	header.msg_iov = &mut iov;
+	header.msg_iovlen = 1;
+	if !fds.is_empty() {
+		header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+		header.msg_controllen = control.len() as _;
+
+		unsafe {
+			let cmsg = libc::CMSG_FIRSTHDR(&header);
+			(*cmsg).cmsg_level = libc::SOL_SOCKET;
+			(*cmsg).cmsg_type = libc::SCM_RIGHTS;
+			(*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+			std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+		}
+	}
+
+	let sent = loop {
+		let res = unsafe { libc::sendmsg(socket.as_raw_fd(), &header, libc::MSG_NOSIGNAL) };
+		if res >= 0 {
+			break res as usize;
+		}
+
+		let e = Error::last_os_error();
+		if e.kind() != ErrorKind::Interrupted {
+			return Err(e);
+		}
+	};
+
+	// The descriptors went with the first bytes, whatever is left is plain data
+	socket.write_all(&data[sent..])
+}
+
+/// Reads a message sent by `send_message_with_fds`, along with the descriptors attached to it
+pub fn read_message_with_fds(socket: &mut UnixStream) -> Result<(IpcMessage, Vec<File>), Error> {
+	let mut preamble = [0u8; 1];
+	let mut control = vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * size_of::<RawFd>()) as u32) } as usize];
+	let mut iov = libc::iovec { iov_base: preamble.as_mut_ptr() as *mut libc::c_void, iov_len: 1 };
+
+	let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+	header.msg_iov = &mut iov;
+	header.msg_iovlen = 1;
+	header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+	header.msg_controllen = control.len() as _;
+
+	let received = loop {
+		let res = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+		if res >= 0 {
+			break res;
+		}
+
+		let e = Error::last_os_error();
+		if e.kind() != ErrorKind::Interrupted {
+			return Err(e);
+		}
+	};
+
+	if received == 0 {
+		return Err(Error::from(ErrorKind::UnexpectedEof));
+	}
+
+	let mut files: Vec<File> = Vec::new();
+	unsafe {
+		let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+		while !cmsg.is_null() {
+			if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+				let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+				let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+				for i in 0..count {
+					files.push(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+				}
+			}
+
+			cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+		}
+	}
+
+	if header.msg_flags & libc::MSG_CTRUNC != 0 {
+		return Err(Error::new(ErrorKind::InvalidData, "File descriptors were dropped"));
+	}
+
+	let msg = Cursor::new(preamble).chain(socket).read_message()?;
+	Ok((msg, files))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::io::{Read, Write};
+	use std::os::fd::AsRawFd;
+	use std::os::unix::net::UnixStream;
+	use crate::ipc::{read_message_with_fds, send_message_with_fds, IpcMessage};
+	use crate::utils::new_pipe;
+
+	#[test]
+	pub fn test_fd_passing() {
+		let (mut a, mut b) = UnixStream::pair().unwrap();
+		let (recv, mut send) = new_pipe().unwrap();
+
+		send_message_with_fds(&mut a, IpcMessage::Request { id: 7, uri: "/test".to_string(), streaming: true }, &[recv.as_raw_fd()]).unwrap();
+		drop(recv);
+
+		let (msg, mut files) = read_message_with_fds(&mut b).unwrap();
+		assert!(matches!(msg, IpcMessage::Request { id: 7, uri, streaming: true } if uri == "/test"));
+		assert_eq!(files.len(), 1);
+
+		send.write_all(b"hello").unwrap();
+		drop(send);
+		let mut data = String::new();
+		files[0].read_to_string(&mut data).unwrap();
+		assert_eq!(data, "hello");
+	}
 }
\ No newline at end of file
@@ -1,24 +1,197 @@
-use std::fs::File;
-use crate::ipc::{IpcMessage, IpcMessageReceiver, IpcMessageSender};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::os::fd::RawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::ipc::{send_message_with_fds, IpcMessage, IpcMessageReceiver, IpcMessageSender};
 
+/// How long a request waits for the dispatcher to accept it. The dispatcher answers as soon as
+/// a worker has the request, so this only runs out when it's stuck.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests waiting for the dispatcher's answer by ID, with the connection they were sent on
+type Waiters = Arc<Mutex<HashMap<u64, (usize, Sender<bool>)>>>;
+
+/// The listener's side of the connection to the dispatcher. Any number of requests can be in
+/// flight at once: the socket is only locked while a request is written, and a reader thread
+/// hands each answer to the request with the same ID.
 pub struct RequestPipe {
-	send: File,
-	recv: File
+	/// `None` while the dispatcher is down
+	socket: Mutex<Option<UnixStream>>,
+	/// Counts connections, so a reader only fails the requests sent on its own connection
+	connection: AtomicUsize,
+	waiters: Waiters,
+	next_id: AtomicU64
+}
+
+/// Reads the dispatcher's answers until it goes away, then fails the requests still waiting
+/// for one
+fn read_replies(mut socket: UnixStream, connection: usize, waiters: Waiters) {
+	loop {
+		match socket.read_message() {
+			Ok(IpcMessage::Reply { id, accepted }) => {
+				let waiter = waiters.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+				match waiter {
+					Some((_, sender)) => _ = sender.send(accepted),
+					None => log_warn!("RequestPipe", "Answer to unknown request {}", id)
+				}
+			},
+			Ok(msg) => log_warn!("RequestPipe", "Unexpected IPC message: {:?}", msg),
+			Err(_) => break
+		}
+	}
+
+	// Dropping the senders wakes the requests up with an error
+	waiters.lock().unwrap_or_else(|e| e.into_inner()).retain(|_, (c, _)| *c != connection);
 }
 
 impl RequestPipe {
-	pub fn new(send: File, recv: File) -> Self {
-		Self {
-			send,
-			recv
+	pub fn new(socket: UnixStream) -> Self {
+		let pipe = Self {
+			socket: Mutex::new(None),
+			connection: AtomicUsize::new(0),
+			waiters: Arc::new(Mutex::new(HashMap::new())),
+			next_id: AtomicU64::new(1)
+		};
+
+		pipe.connect(socket);
+		pipe
+	}
+
+	/// Connects to a (restarted) dispatcher and starts reading its answers
+	pub fn connect(&self, socket: UnixStream) {
+		let mut guard = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+		let connection = self.connection.fetch_add(1, Ordering::SeqCst) + 1;
+
+		match socket.try_clone() {
+			Ok(reader) => {
+				let waiters = self.waiters.clone();
+				thread::spawn(move || read_replies(reader, connection, waiters));
+				*guard = Some(socket);
+			},
+			Err(e) => {
+				log_error!("RequestPipe", "Can't read from the dispatcher: {:?}", e);
+				*guard = None;
+			}
+		}
+	}
+
+	pub fn disconnect(&self) {
+		*self.socket.lock().unwrap_or_else(|e| e.into_inner()) = None;
+	}
+
+	/// Sends a request for `uri` with its streams `fds` attached and waits for the dispatcher
+	/// to accept or deny it. `streaming` requests keep their worker for the whole connection.
+	pub fn send_request(&self, uri: String, streaming: bool, fds: &[RawFd]) -> Result<bool, Error> {
+		self.send_request_within(uri, streaming, fds, REPLY_TIMEOUT)
+	}
+
+	fn send_request_within(&self, uri: String, streaming: bool, fds: &[RawFd], timeout: Duration) -> Result<bool, Error> {
+		let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+		let (sender, receiver) = channel();
+
+		{
+			let mut guard = self.socket.lock().unwrap_or_else(|e| e.into_inner());
+			let socket = match guard.as_mut() {
+				Some(s) => s,
+				None => return Err(Error::new(ErrorKind::NotConnected, "Dispatcher is down"))
+			};
+
+			let connection = self.connection.load(Ordering::SeqCst);
+			self.waiters.lock().unwrap_or_else(|e| e.into_inner()).insert(id, (connection, sender));
+
+			if let Err(e) = send_message_with_fds(socket, IpcMessage::Request { id, uri, streaming }, fds) {
+				self.waiters.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+				return Err(e);
+			}
+		}
+
+		match receiver.recv_timeout(timeout) {
+			Ok(accepted) => Ok(accepted),
+			Err(RecvTimeoutError::Timeout) => {
+				self.waiters.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+				Err(Error::new(ErrorKind::TimedOut, "Dispatcher didn't answer"))
+			},
+			Err(RecvTimeoutError::Disconnected) => Err(Error::new(ErrorKind::ConnectionAborted, "Dispatcher went away"))
 		}
 	}
 
-	pub fn send_message_and_wait(&mut self, message: IpcMessage) -> Result<IpcMessage, std::io::Error> {
-		if let Err(e) = self.send.send_message(message) {
-			return Err(e);
+	/// Tells the dispatcher to exit
+	pub fn close(&self) -> Result<(), Error> {
+		match self.socket.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+			Some(socket) => socket.send_message(IpcMessage::Close),
+			None => Ok(())
 		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::os::unix::net::UnixStream;
+	use std::io::ErrorKind;
+	use std::sync::Arc;
+	use std::thread;
+	use std::time::Duration;
+	use crate::ipc::request_pipe::RequestPipe;
+	use crate::ipc::{read_message_with_fds, IpcMessage, IpcMessageSender};
+
+	#[test]
+	pub fn test_concurrent_requests() {
+		let (listener, mut dispatcher) = UnixStream::pair().unwrap();
+		let pipe = Arc::new(RequestPipe::new(listener));
+
+		let requests: Vec<_> = (0..4).map(|i| {
+			let pipe = pipe.clone();
+			thread::spawn(move || pipe.send_request(format!("/{}", i), false, &[]).unwrap())
+		}).collect();
+
+		// Every request is in flight before the first answer, which are sent in reverse order
+		let mut received = Vec::new();
+		for _ in 0..4 {
+			match read_message_with_fds(&mut dispatcher).unwrap().0 {
+				IpcMessage::Request { id, uri, .. } => received.push((id, uri)),
+				other => panic!("Unexpected message {:?}", other)
+			}
+		}
+
+		for (id, uri) in received.iter().rev() {
+			dispatcher.send_message(IpcMessage::Reply { id: *id, accepted: uri != "/2" }).unwrap();
+		}
+
+		let answers: Vec<bool> = requests.into_iter().map(|t| t.join().unwrap()).collect();
+		assert_eq!(answers, vec![true, true, false, true]);
+
+		// Requests waiting when the dispatcher goes away fail
+		let waiting = {
+			let pipe = pipe.clone();
+			thread::spawn(move || pipe.send_request("/late".to_string(), false, &[]))
+		};
+		read_message_with_fds(&mut dispatcher).unwrap();
+		drop(dispatcher);
+		assert!(waiting.join().unwrap().is_err());
+	}
+
+	#[test]
+	pub fn test_reply_timeout() {
+		let (listener, mut dispatcher) = UnixStream::pair().unwrap();
+		let pipe = RequestPipe::new(listener);
+
+		let res = pipe.send_request_within("/stuck".to_string(), false, &[], Duration::from_millis(50));
+		assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+		assert!(pipe.waiters.lock().unwrap().is_empty());
 
-		self.recv.read_message()
+		// A late answer is dropped
+		let id = match read_message_with_fds(&mut dispatcher).unwrap().0 {
+			IpcMessage::Request { id, .. } => id,
+			other => panic!("Unexpected message {:?}", other)
+		};
+		dispatcher.send_message(IpcMessage::Reply { id, accepted: true }).unwrap();
+		thread::sleep(Duration::from_millis(50));
+		assert!(pipe.waiters.lock().unwrap().is_empty());
 	}
 }
\ No newline at end of file
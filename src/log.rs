@@ -0,0 +1,291 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use libc::c_int;
+use mlua::prelude::LuaResult;
+use mlua::{Table, Value};
+use crate::lua_api::json::escape_json_string;
+use crate::utils::civil_from_days;
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+	Debug,
+	Info,
+	Warn,
+	Error
+}
+
+impl Level {
+	pub fn from_str(string: &str) -> Option<Level> {
+		match string.to_lowercase().as_str() {
+			"debug" => Some(Level::Debug),
+			"info" => Some(Level::Info),
+			"warn" => Some(Level::Warn),
+			"error" => Some(Level::Error),
+			_ => None
+		}
+	}
+
+	pub fn to_str(self) -> &'static str {
+		match self {
+			Level::Debug => "debug",
+			Level::Info => "info",
+			Level::Warn => "warn",
+			Level::Error => "error"
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+	/// `2024-05-01T12:00:00.000Z INFO  [1234] [Component] message key=value`
+	Text,
+	/// One JSON object per line
+	Json
+}
+
+/// Logging settings, from `config_set_log`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+	pub level: Level,
+	pub format: LogFormat,
+	/// Log file, opened for appending; stderr if not set
+	pub file: Option<String>
+}
+
+impl Default for LogConfig {
+	fn default() -> Self {
+		LogConfig {
+			level: Level::Info,
+			format: LogFormat::Text,
+			file: None
+		}
+	}
+}
+
+impl LogConfig {
+	/// Reads the value of the `log` option, a table `{ level, format = "text" | "json", file }`
+	pub fn from_option(value: Value) -> LuaResult<LogConfig> {
+		let table: Table = match value {
+			Value::Nil => return Ok(LogConfig::default()),
+			Value::Table(t) => t,
+			other => return Err(mlua::Error::RuntimeError(format!("log must be a table, got {}", other.type_name())))
+		};
+
+		let level = match table.get::<Option<String>>("level")? {
+			Some(l) => Level::from_str(&l).ok_or(mlua::Error::RuntimeError(format!("Unknown log level '{}', expected debug, info, warn or error", l)))?,
+			None => Level::Info
+		};
+
+		let format = match table.get::<Option<String>>("format")?.as_deref() {
+			None | Some("text") => LogFormat::Text,
+			Some("json") => LogFormat::Json,
+			Some(other) => return Err(mlua::Error::RuntimeError(format!("Unknown log format '{}', expected text or json", other)))
+		};
+
+		Ok(LogConfig {
+			level,
+			format,
+			file: table.get("file")?
+		})
+	}
+}
+
+struct Logger {
+	level: Level,
+	format: LogFormat,
+	path: Option<String>,
+	file: Option<File>,
+	/// Value of `REOPEN_GENERATION` when the file was opened
+	generation: usize
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+	level: Level::Info,
+	format: LogFormat::Text,
+	path: None,
+	file: None,
+	generation: 0
+});
+
+/// Incremented on SIGUSR1; log files opened before the last increment are reopened
+static REOPEN_GENERATION: AtomicUsize = AtomicUsize::new(0);
+/// Process SIGUSR1 is passed on to (set in the listener)
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+
+thread_local! {
+	/// ID of the request being handled, added to every entry
+	static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Applies `config`. Called before forking, so every process logs the same way; lines are
+/// written with a single append, so entries of different processes don't mix.
+pub fn init(config: &LogConfig) -> Result<(), Error> {
+	let file = match &config.file {
+		Some(path) => Some(open_log_file(path)?),
+		None => None
+	};
+
+	let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+	logger.level = config.level;
+	logger.format = config.format;
+	logger.path = config.file.clone();
+	logger.file = file;
+	logger.generation = reopen_generation();
+
+	Ok(())
+}
+
+pub fn open_log_file(path: &str) -> Result<File, Error> {
+	OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub fn reopen_generation() -> usize {
+	REOPEN_GENERATION.load(Ordering::SeqCst)
+}
+
+extern "C" fn on_sigusr1(_: c_int) {
+	REOPEN_GENERATION.fetch_add(1, Ordering::SeqCst);
+
+	let pid = FORWARD_PID.load(Ordering::SeqCst);
+	if pid > 0 {
+		unsafe { libc::kill(pid, libc::SIGUSR1) };
+	}
+}
+
+/// Makes SIGUSR1 reopen the log files (after logrotate moved them), passing the signal on to
+/// `forward` if set. Files are reopened on their next write.
+pub fn reopen_on_sigusr1(forward: Option<libc::pid_t>) -> Result<(), Error> {
+	FORWARD_PID.store(forward.unwrap_or(0), Ordering::SeqCst);
+
+	unsafe {
+		let mut action: libc::sigaction = std::mem::zeroed();
+		action.sa_sigaction = on_sigusr1 as extern "C" fn(c_int) as usize;
+		action.sa_flags = libc::SA_RESTART;
+		libc::sigemptyset(&mut action.sa_mask);
+
+		if libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()) != 0 {
+			return Err(Error::last_os_error());
+		}
+	}
+
+	Ok(())
+}
+
+pub fn set_request_id(id: Option<String>) {
+	REQUEST_ID.with_borrow_mut(|r| *r = id);
+}
+
+pub fn enabled(level: Level) -> bool {
+	level >= LOGGER.lock().unwrap_or_else(|e| e.into_inner()).level
+}
+
+fn timestamp() -> String {
+	format_timestamp(SystemTime::now())
+}
+
+/// UTC time with milliseconds, e.g. `2024-05-01T12:00:00.000Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+	let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = now.as_secs() as i64;
+	let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+	let time = secs.rem_euclid(86400);
+
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, time / 3600, (time % 3600) / 60, time % 60, now.subsec_millis())
+}
+
+fn format_entry(format: LogFormat, level: Level, component: &str, message: &str, fields: &[(String, String)]) -> String {
+	let request_id = REQUEST_ID.with_borrow(|r| r.clone());
+	let fields = request_id.map(|id| ("request_id".to_string(), id)).into_iter().chain(fields.iter().cloned());
+
+	match format {
+		LogFormat::Text => {
+			let mut res = format!("{} {:<5} [{}] [{}] {}", timestamp(), level.to_str().to_uppercase(), std::process::id(), component, message);
+			for (key, value) in fields {
+				if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+					res.push_str(&format!(" {}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"")));
+				} else {
+					res.push_str(&format!(" {}={}", key, value));
+				}
+			}
+			res.replace('\n', "\\n").replace('\r', "\\r") + "\n"
+		},
+		LogFormat::Json => {
+			let mut res = format!(
+				"{{\"time\":\"{}\",\"level\":\"{}\",\"pid\":{},\"component\":\"{}\",\"message\":\"{}\"",
+				timestamp(), level.to_str(), std::process::id(), escape_json_string(component), escape_json_string(message)
+			);
+			for (key, value) in fields {
+				res.push_str(&format!(",\"{}\":\"{}\"", escape_json_string(&key), escape_json_string(&value)));
+			}
+			res + "}\n"
+		}
+	}
+}
+
+/// Writes an entry if `level` is enabled. Use the `log_*` macros rather than calling this.
+pub fn write(level: Level, component: &str, message: &str, fields: &[(String, String)]) {
+	let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+	if level < logger.level {
+		return;
+	}
+
+	let generation = reopen_generation();
+	if logger.generation != generation {
+		logger.generation = generation;
+		if let Some(path) = logger.path.clone() {
+			match open_log_file(&path) {
+				Ok(file) => logger.file = Some(file),
+				Err(e) => _ = std::io::stderr().write_all(format!("[Log] Can't reopen {}: {:?}\n", path, e).as_bytes())
+			}
+		}
+	}
+
+	let entry = format_entry(logger.format, level, component, message, fields);
+	_ = match &mut logger.file {
+		Some(file) => file.write_all(entry.as_bytes()),
+		None => std::io::stderr().write_all(entry.as_bytes())
+	};
+}
+
+macro_rules! log_debug {
+	($component:expr, $($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, $component, &format!($($arg)*), &[]) };
+}
+
+macro_rules! log_info {
+	($component:expr, $($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, $component, &format!($($arg)*), &[]) };
+}
+
+macro_rules! log_warn {
+	($component:expr, $($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, $component, &format!($($arg)*), &[]) };
+}
+
+macro_rules! log_error {
+	($component:expr, $($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, $component, &format!($($arg)*), &[]) };
+}
+
+
+#[cfg(test)]
+mod tests {
+	use crate::log::{format_entry, set_request_id, Level, LogFormat};
+
+	#[test]
+	pub fn test_log_format() {
+		set_request_id(Some("abc".to_string()));
+		let fields = vec![("user".to_string(), "bob smith".to_string())];
+
+		let text = format_entry(LogFormat::Text, Level::Warn, "Lua", "line\nbreak", &fields);
+		assert!(text.contains(" WARN  ["));
+		assert!(text.ends_with("[Lua] line\\nbreak request_id=abc user=\"bob smith\"\n"));
+
+		let json = format_entry(LogFormat::Json, Level::Info, "Lua", "say \"hi\"", &fields);
+		assert!(json.contains("\"level\":\"info\""));
+		assert!(json.ends_with("\"component\":\"Lua\",\"message\":\"say \\\"hi\\\"\",\"request_id\":\"abc\",\"user\":\"bob smith\"}\n"));
+
+		set_request_id(None);
+		assert!(!format_entry(LogFormat::Text, Level::Info, "Lua", "x", &[]).contains("request_id"));
+	}
+}
@@ -0,0 +1,46 @@
+use mlua::prelude::LuaResult;
+use mlua::{Error, Lua, Table, Value};
+use crate::http::http_cookie::{parse_cookies, serialize_cookie, CookieOptions};
+use crate::utils::format_http_date;
+
+fn options_from_table(options: Option<Table>) -> LuaResult<CookieOptions> {
+	let options = match options {
+		Some(o) => o,
+		None => return Ok(CookieOptions::default())
+	};
+
+	// `expires` is either a unix timestamp or an already formatted date
+	let expires = match options.get::<Value>("expires")? {
+		Value::Nil => None,
+		Value::Integer(i) => Some(format_http_date(i)),
+		Value::Number(n) => Some(format_http_date(n as i64)),
+		Value::String(s) => Some(s.to_str()?.to_string()),
+		other => return Err(Error::RuntimeError(format!("Invalid cookie expires value: {}", other.type_name())))
+	};
+
+	Ok(CookieOptions {
+		path: options.get("path")?,
+		domain: options.get("domain")?,
+		max_age: options.get("maxAge")?,
+		expires,
+		secure: options.get::<Option<bool>>("secure")?.unwrap_or(false),
+		http_only: options.get::<Option<bool>>("httpOnly")?.unwrap_or(false),
+		same_site: options.get("sameSite")?
+	})
+}
+
+/// Registers the `jwx.cookie` module
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let cookie = lua.create_table()?;
+
+	cookie.set("serialize", lua.create_function(|_, (name, value, options): (String, String, Option<Table>)| {
+		let options = options_from_table(options)?;
+		serialize_cookie(&name, &value, &options).map_err(Error::RuntimeError)
+	})?)?;
+
+	cookie.set("parse", lua.create_function(|_, header: String| {
+		Ok(parse_cookies(&header))
+	})?)?;
+
+	jwx.set("cookie", cookie)
+}
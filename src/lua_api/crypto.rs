@@ -0,0 +1,188 @@
+use argon2::{Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use hmac::{Hmac, Mac};
+use mlua::prelude::{LuaResult, LuaString};
+use mlua::{Error, Lua, Table, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use crate::utils::{constant_time_eq, random_bytes, to_hex};
+
+const DEFAULT_TOKEN_BYTES: usize = 32;
+const DEFAULT_BCRYPT_COST: u32 = 12;
+const SALT_BYTES: usize = 16;
+/// Upper bound for `randomBytes` and `randomToken`, so a script can't allocate without limit
+const MAX_RANDOM_BYTES: usize = 1024 * 1024;
+
+fn random_buffer(count: usize) -> LuaResult<Vec<u8>> {
+	if count > MAX_RANDOM_BYTES {
+		return Err(Error::RuntimeError(format!("Can't generate more than {} random bytes", MAX_RANDOM_BYTES)));
+	}
+
+	let mut buff = vec![0u8; count];
+	random_bytes(&mut buff).map_err(Error::external)?;
+	Ok(buff)
+}
+
+/// Digests are returned as hex, or as raw bytes when `raw` is true
+fn digest_result(lua: &Lua, digest: &[u8], raw: Option<bool>) -> LuaResult<Value> {
+	if raw.unwrap_or(false) {
+		Ok(Value::String(lua.create_string(digest)?))
+	} else {
+		Ok(Value::String(lua.create_string(to_hex(digest))?))
+	}
+}
+
+fn hash(algorithm: &str, data: &[u8]) -> LuaResult<Vec<u8>> {
+	match algorithm {
+		"sha1" => Ok(Sha1::digest(data).to_vec()),
+		"sha256" => Ok(Sha256::digest(data).to_vec()),
+		"sha512" => Ok(Sha512::digest(data).to_vec()),
+		other => Err(Error::RuntimeError(format!("Unknown hash algorithm '{}', expected sha1, sha256 or sha512", other)))
+	}
+}
+
+fn hmac(algorithm: &str, key: &[u8], data: &[u8]) -> LuaResult<Vec<u8>> {
+	fn compute<M: Mac + hmac::digest::KeyInit>(key: &[u8], data: &[u8]) -> Vec<u8> {
+		let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+		mac.update(data);
+		mac.finalize().into_bytes().to_vec()
+	}
+
+	match algorithm {
+		"sha1" => Ok(compute::<Hmac<Sha1>>(key, data)),
+		"sha256" => Ok(compute::<Hmac<Sha256>>(key, data)),
+		"sha512" => Ok(compute::<Hmac<Sha512>>(key, data)),
+		other => Err(Error::RuntimeError(format!("Unknown HMAC algorithm '{}', expected sha1, sha256 or sha512", other)))
+	}
+}
+
+fn random_salt() -> LuaResult<[u8; SALT_BYTES]> {
+	let mut salt = [0u8; SALT_BYTES];
+	random_bytes(&mut salt).map_err(Error::external)?;
+	Ok(salt)
+}
+
+/// Hashes a password with argon2id (the default) or bcrypt. Options: `algorithm`, `cost` for
+/// bcrypt, and `memory` (KiB), `iterations` and `parallelism` for argon2.
+fn hash_password(password: &[u8], options: Option<Table>) -> LuaResult<String> {
+	let get = |name: &str| -> LuaResult<Option<u32>> {
+		match &options {
+			Some(o) => o.get(name),
+			None => Ok(None)
+		}
+	};
+	let algorithm: Option<String> = match &options {
+		Some(o) => o.get("algorithm")?,
+		None => None
+	};
+
+	match algorithm.as_deref().unwrap_or("argon2") {
+		"argon2" => {
+			let params = Params::new(
+				get("memory")?.unwrap_or(Params::DEFAULT_M_COST),
+				get("iterations")?.unwrap_or(Params::DEFAULT_T_COST),
+				get("parallelism")?.unwrap_or(Params::DEFAULT_P_COST),
+				None
+			).map_err(|e| Error::RuntimeError(format!("Invalid argon2 options: {}", e)))?;
+
+			let salt = SaltString::encode_b64(&random_salt()?).map_err(|e| Error::RuntimeError(e.to_string()))?;
+			let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+			Ok(argon2.hash_password(password, &salt).map_err(|e| Error::RuntimeError(e.to_string()))?.to_string())
+		},
+		"bcrypt" => {
+			let hash = bcrypt::hash_with_salt(password, get("cost")?.unwrap_or(DEFAULT_BCRYPT_COST), random_salt()?)
+				.map_err(|e| Error::RuntimeError(format!("Can't hash password: {}", e)))?;
+			Ok(hash.format_for_version(bcrypt::Version::TwoB))
+		},
+		other => Err(Error::RuntimeError(format!("Unknown password hash algorithm '{}', expected argon2 or bcrypt", other)))
+	}
+}
+
+/// Checks a password against a hash made by `hash_password`, telling the algorithm by its prefix
+fn verify_password(password: &[u8], hash: &str) -> LuaResult<bool> {
+	if hash.starts_with("$argon2") {
+		let parsed = PasswordHash::new(hash).map_err(|e| Error::RuntimeError(format!("Invalid password hash: {}", e)))?;
+		return Ok(Argon2::default().verify_password(password, &parsed).is_ok());
+	}
+
+	if hash.starts_with("$2") {
+		return bcrypt::verify(password, hash).map_err(|e| Error::RuntimeError(format!("Invalid password hash: {}", e)));
+	}
+
+	Err(Error::RuntimeError("Unknown password hash format".to_string()))
+}
+
+/// Registers the `jwx.crypto` module
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let crypto = lua.create_table()?;
+
+	for algorithm in ["sha1", "sha256", "sha512"] {
+		crypto.set(algorithm, lua.create_function(move |lua, (data, raw): (LuaString, Option<bool>)| {
+			digest_result(lua, &hash(algorithm, &data.as_bytes())?, raw)
+		})?)?;
+	}
+
+	crypto.set("hmac", lua.create_function(|lua, (algorithm, key, data, raw): (String, LuaString, LuaString, Option<bool>)| {
+		digest_result(lua, &hmac(&algorithm, &key.as_bytes(), &data.as_bytes())?, raw)
+	})?)?;
+
+	crypto.set("randomBytes", lua.create_function(|lua, count: usize| {
+		lua.create_string(random_buffer(count)?)
+	})?)?;
+
+	crypto.set("randomToken", lua.create_function(|_, count: Option<usize>| {
+		Ok(to_hex(&random_buffer(count.unwrap_or(DEFAULT_TOKEN_BYTES))?))
+	})?)?;
+
+	crypto.set("constantTimeEquals", lua.create_function(|_, (a, b): (LuaString, LuaString)| {
+		Ok(constant_time_eq(&a.as_bytes(), &b.as_bytes()))
+	})?)?;
+
+	crypto.set("hashPassword", lua.create_function(|_, (password, options): (LuaString, Option<Table>)| {
+		hash_password(&password.as_bytes(), options)
+	})?)?;
+
+	crypto.set("verifyPassword", lua.create_function(|_, (password, hash): (LuaString, String)| {
+		verify_password(&password.as_bytes(), &hash)
+	})?)?;
+
+	jwx.set("crypto", crypto)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::{Lua, Table};
+	use crate::lua_api::{crypto, encoding};
+
+	#[test]
+	pub fn test_crypto_lua_api() {
+		let lua = Lua::new();
+		let jwx: Table = lua.create_table().unwrap();
+		crypto::register(&lua, &jwx).unwrap();
+		encoding::register(&lua, &jwx).unwrap();
+		lua.globals().set("jwx", jwx).unwrap();
+
+		lua.load(r#"
+			local c, e = jwx.crypto, jwx.encoding
+			assert(c.sha256("abc") == "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+			assert(#c.sha512("abc", true) == 64)
+			assert(c.hmac("sha256", "key", "The quick brown fox jumps over the lazy dog") == "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8")
+			assert(#c.randomBytes(10) == 10 and #c.randomToken() == 64 and c.randomToken() ~= c.randomToken())
+			assert(not pcall(c.randomBytes, 1e12) and not pcall(c.randomToken, 1e12))
+			assert(c.constantTimeEquals("abc", "abc") and not c.constantTimeEquals("abc", "abd"))
+
+			local hash = c.hashPassword("secret", { memory = 1024, iterations = 1 })
+			assert(hash:sub(1, 9) == "$argon2id" and c.verifyPassword("secret", hash) and not c.verifyPassword("wrong", hash))
+			hash = c.hashPassword("secret", { algorithm = "bcrypt", cost = 4 })
+			assert(hash:sub(1, 4) == "$2b$" and c.verifyPassword("secret", hash) and not c.verifyPassword("wrong", hash))
+
+			assert(e.base64Encode("hi?>") == "aGk/Pg==" and e.base64Decode("aGk/Pg==") == "hi?>")
+			assert(e.base64UrlEncode("hi?>") == "aGk_Pg" and e.base64UrlDecode("aGk_Pg") == "hi?>")
+			assert(e.hexEncode("\0\255") == "00ff" and e.hexDecode("00FF") == "\0\255")
+			assert(e.urlEncode("a b&c") == "a%20b%26c" and e.urlDecode("a%20b%26c") == "a b&c")
+			assert(not pcall(e.base64Decode, "%%%"))
+			assert(not pcall(e.hexDecode, "+1+2") and not pcall(e.hexDecode, "0g"))
+		"#).exec().unwrap();
+	}
+}
@@ -0,0 +1,49 @@
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use mlua::prelude::{LuaResult, LuaString};
+use mlua::{Error, Lua, Table};
+use crate::url::{percent_decode, percent_encode};
+use crate::utils::{from_hex, to_hex};
+
+/// Registers the `jwx.encoding` module
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let encoding = lua.create_table()?;
+
+	encoding.set("base64Encode", lua.create_function(|_, data: LuaString| {
+		Ok(STANDARD.encode(data.as_bytes()))
+	})?)?;
+
+	encoding.set("base64Decode", lua.create_function(|lua, data: String| {
+		let decoded = STANDARD.decode(data.trim()).map_err(|e| Error::RuntimeError(format!("Invalid base64: {}", e)))?;
+		lua.create_string(decoded)
+	})?)?;
+
+	// URL-safe alphabet without padding, as used in JWTs
+	encoding.set("base64UrlEncode", lua.create_function(|_, data: LuaString| {
+		Ok(URL_SAFE_NO_PAD.encode(data.as_bytes()))
+	})?)?;
+
+	encoding.set("base64UrlDecode", lua.create_function(|lua, data: String| {
+		let decoded = URL_SAFE_NO_PAD.decode(data.trim().trim_end_matches('=')).map_err(|e| Error::RuntimeError(format!("Invalid base64: {}", e)))?;
+		lua.create_string(decoded)
+	})?)?;
+
+	encoding.set("hexEncode", lua.create_function(|_, data: LuaString| {
+		Ok(to_hex(&data.as_bytes()))
+	})?)?;
+
+	encoding.set("hexDecode", lua.create_function(|lua, data: String| {
+		let decoded = from_hex(data.trim()).ok_or_else(|| Error::RuntimeError("Invalid hex string".to_string()))?;
+		lua.create_string(decoded)
+	})?)?;
+
+	encoding.set("urlEncode", lua.create_function(|_, data: String| {
+		Ok(percent_encode(&data))
+	})?)?;
+
+	encoding.set("urlDecode", lua.create_function(|_, data: String| {
+		Ok(percent_decode(&data))
+	})?)?;
+
+	jwx.set("encoding", encoding)
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+use mlua::prelude::{LuaResult, LuaString};
+use mlua::{Error, Lua, MultiValue, Table, Value, IntoLuaMulti};
+use crate::http::http_message::{HttpMessage, HttpMethod};
+use crate::http::outbound::{fetch, OutboundRequest};
+
+const DEFAULT_TIMEOUT: f64 = 30.0;
+const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+fn read_request(options: &Table) -> LuaResult<OutboundRequest> {
+	let method: Option<String> = options.get("method")?;
+	let method = match &method {
+		Some(m) => HttpMethod::from_str(m).ok_or_else(|| Error::RuntimeError(format!("Unsupported method '{}'", m)))?,
+		None => HttpMethod::Get
+	};
+
+	let url: String = options.get::<Option<String>>("url")?.ok_or_else(|| Error::RuntimeError("Missing url".to_string()))?;
+
+	let mut headers = Vec::new();
+	if let Some(table) = options.get::<Option<Table>>("headers")? {
+		for pair in table.pairs::<String, String>() {
+			headers.push(pair?);
+		}
+	}
+
+	let body = match options.get::<Option<LuaString>>("body")? {
+		Some(b) => b.as_bytes().to_vec(),
+		None => Vec::new()
+	};
+
+	let timeout = options.get::<Option<f64>>("timeout")?.unwrap_or(DEFAULT_TIMEOUT);
+	if !timeout.is_finite() || timeout <= 0.0 {
+		return Err(Error::RuntimeError("timeout must be a positive number of seconds".to_string()));
+	}
+
+	Ok(OutboundRequest {
+		method,
+		url,
+		headers,
+		body,
+		timeout: Duration::from_secs_f64(timeout),
+		max_redirects: options.get::<Option<usize>>("maxRedirects")?.unwrap_or(DEFAULT_MAX_REDIRECTS)
+	})
+}
+
+/// Registers the `jwx.http` module. `jwx.http.request{ method, url, headers, body, timeout,
+/// maxRedirects }` returns `{ status, statusText, headers, cookies, body, url }`, or `nil` and
+/// a message if the server couldn't be reached or didn't answer in time.
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let http = lua.create_table()?;
+
+	http.set("request", lua.create_function(|lua, options: Table| -> LuaResult<MultiValue> {
+		let request = read_request(&options)?;
+		let res = match fetch(request) {
+			Ok(res) => res,
+			Err(e) => return (Value::Nil, e).into_lua_multi(lua)
+		};
+
+		let response = lua.create_table()?;
+		response.set("status", res.response.get_code())?;
+		response.set("statusText", res.response.get_status_text().cloned())?;
+		response.set("headers", res.response.get_headers().clone())?;
+		response.set("cookies", res.response.get_cookies().clone())?;
+		response.set("body", lua.create_string(res.response.get_content())?)?;
+		response.set("url", res.url)?;
+
+		response.into_lua_multi(lua)
+	})?)?;
+
+	jwx.set("http", http)
+}
@@ -0,0 +1,551 @@
+use mlua::prelude::{LuaResult, LuaString};
+use mlua::{Error, Lua, Table, Value};
+
+const JSON_ARRAY_METATABLE_NAME: &str = "jwx.json.array";
+const JSON_OBJECT_METATABLE_NAME: &str = "jwx.json.object";
+const JSON_MAX_DEPTH: usize = 128;
+
+fn json_error(message: String) -> Error {
+	Error::RuntimeError(format!("JSON: {}", message))
+}
+
+/// Escapes `string` as the body of a JSON string literal (without the surrounding quotes)
+pub fn escape_json_string(string: &str) -> String {
+	let mut res = String::with_capacity(string.len());
+	for c in string.chars() {
+		match c {
+			'"' => res.push_str("\\\""),
+			'\\' => res.push_str("\\\\"),
+			'\n' => res.push_str("\\n"),
+			'\r' => res.push_str("\\r"),
+			'\t' => res.push_str("\\t"),
+			c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+			c => res.push(c)
+		}
+	}
+
+	res
+}
+
+fn has_metatable(lua: &Lua, table: &Table, name: &str) -> bool {
+	match (table.metatable(), lua.named_registry_value::<Table>(name)) {
+		(Some(mt), Ok(marker)) => mt.to_pointer() == marker.to_pointer(),
+		_ => false
+	}
+}
+
+struct Encoder<'a> {
+	lua: &'a Lua,
+	indent: Option<String>,
+	out: String
+}
+
+impl Encoder<'_> {
+	fn newline(&mut self, depth: usize) {
+		if let Some(indent) = &self.indent {
+			self.out.push('\n');
+			for _ in 0..depth {
+				self.out.push_str(indent);
+			}
+		}
+	}
+
+	fn encode_number(&mut self, n: f64) -> LuaResult<()> {
+		if !n.is_finite() {
+			return Err(json_error(format!("cannot encode {}", n)));
+		}
+
+		if n.fract() == 0.0 && n.abs() < 1e15 {
+			self.out.push_str(&(n as i64).to_string());
+		} else {
+			self.out.push_str(&n.to_string());
+		}
+
+		Ok(())
+	}
+
+	fn encode_table(&mut self, table: &Table, depth: usize) -> LuaResult<()> {
+		let mut keys: Vec<Value> = Vec::new();
+		for pair in table.pairs::<Value, Value>() {
+			keys.push(pair?.0);
+		}
+
+		let len = table.raw_len();
+		let is_array = if has_metatable(self.lua, table, JSON_ARRAY_METATABLE_NAME) {
+			true
+		} else if has_metatable(self.lua, table, JSON_OBJECT_METATABLE_NAME) || keys.is_empty() {
+			false
+		} else {
+			len == keys.len()
+		};
+
+		if is_array {
+			self.out.push('[');
+			for i in 1..=len {
+				if i > 1 {
+					self.out.push(',');
+				}
+				self.newline(depth + 1);
+				self.encode_value(&table.raw_get::<Value>(i)?, depth + 1)?;
+			}
+			if len > 0 {
+				self.newline(depth);
+			}
+			self.out.push(']');
+			return Ok(());
+		}
+
+		let mut entries: Vec<(String, Value)> = Vec::new();
+		for key in keys {
+			let name = match &key {
+				Value::String(s) => s.to_str()?.to_string(),
+				Value::Integer(i) => i.to_string(),
+				Value::Number(n) => n.to_string(),
+				other => return Err(json_error(format!("cannot use a {} as an object key", other.type_name())))
+			};
+			entries.push((name, table.raw_get::<Value>(key)?));
+		}
+		entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+		self.out.push('{');
+		for (i, (name, value)) in entries.iter().enumerate() {
+			if i > 0 {
+				self.out.push(',');
+			}
+			self.newline(depth + 1);
+			self.out.push('"');
+			self.out.push_str(&escape_json_string(name));
+			self.out.push_str(if self.indent.is_some() { "\": " } else { "\":" });
+			self.encode_value(value, depth + 1)?;
+		}
+		if !entries.is_empty() {
+			self.newline(depth);
+		}
+		self.out.push('}');
+
+		Ok(())
+	}
+
+	fn encode_value(&mut self, value: &Value, depth: usize) -> LuaResult<()> {
+		if depth > JSON_MAX_DEPTH {
+			return Err(json_error("value is nested too deeply (cyclic table?)".to_string()));
+		}
+
+		match value {
+			Value::Nil => self.out.push_str("null"),
+			Value::LightUserData(ud) if ud.0.is_null() => self.out.push_str("null"),
+			Value::Boolean(b) => self.out.push_str(if *b { "true" } else { "false" }),
+			Value::Integer(i) => self.out.push_str(&i.to_string()),
+			Value::Number(n) => self.encode_number(*n)?,
+			Value::String(s) => {
+				self.out.push('"');
+				self.out.push_str(&escape_json_string(&s.to_string_lossy()));
+				self.out.push('"');
+			},
+			Value::Table(t) => self.encode_table(t, depth)?,
+			other => return Err(json_error(format!("cannot encode a {}", other.type_name())))
+		}
+
+		Ok(())
+	}
+}
+
+/// Serializes a Lua value as JSON. `indent` enables pretty-printing.
+pub fn encode(lua: &Lua, value: &Value, indent: Option<String>) -> LuaResult<String> {
+	let mut encoder = Encoder {
+		lua,
+		indent,
+		out: String::new()
+	};
+
+	encoder.encode_value(value, 0)?;
+
+	Ok(encoder.out)
+}
+
+/// Checks `text` against the JSON number grammar, which is stricter than Rust's float parsing
+/// (no leading zeros, `+` signs or bare dots)
+fn is_json_number(text: &[u8]) -> bool {
+	let digits = |i: usize| text[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+
+	let mut i = usize::from(text.first() == Some(&b'-'));
+	let int = digits(i);
+	if int == 0 || (int > 1 && text[i] == b'0') {
+		return false;
+	}
+	i += int;
+
+	if text.get(i) == Some(&b'.') {
+		let frac = digits(i + 1);
+		if frac == 0 {
+			return false;
+		}
+		i += 1 + frac;
+	}
+
+	if matches!(text.get(i), Some(b'e' | b'E')) {
+		i += 1;
+		if matches!(text.get(i), Some(b'+' | b'-')) {
+			i += 1;
+		}
+		let exp = digits(i);
+		if exp == 0 {
+			return false;
+		}
+		i += exp;
+	}
+
+	i == text.len()
+}
+
+struct Decoder<'a> {
+	lua: &'a Lua,
+	data: &'a [u8],
+	idx: usize
+}
+
+impl Decoder<'_> {
+	fn error<T>(&self, message: &str) -> LuaResult<T> {
+		Err(json_error(format!("{} at position {}", message, self.idx)))
+	}
+
+	fn skip_whitespace(&mut self) {
+		while self.idx < self.data.len() && matches!(self.data[self.idx], b' ' | b'\t' | b'\r' | b'\n') {
+			self.idx += 1;
+		}
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.data.get(self.idx).copied()
+	}
+
+	fn expect_literal(&mut self, literal: &str, value: Value) -> LuaResult<Value> {
+		if self.data[self.idx..].starts_with(literal.as_bytes()) {
+			self.idx += literal.len();
+			Ok(value)
+		} else {
+			self.error("invalid literal")
+		}
+	}
+
+	fn parse_hex4(&mut self) -> LuaResult<u32> {
+		if self.idx + 4 > self.data.len() {
+			return self.error("truncated unicode escape");
+		}
+
+		// from_str_radix would also take a sign
+		let hex = &self.data[self.idx..self.idx + 4];
+		if !hex.iter().all(|c| c.is_ascii_hexdigit()) {
+			return self.error("invalid unicode escape");
+		}
+
+		self.idx += 4;
+		Ok(hex.iter().fold(0, |v, c| (v << 4) | (*c as char).to_digit(16).unwrap_or(0)))
+	}
+
+	fn parse_string(&mut self) -> LuaResult<String> {
+		// Skip the opening quote
+		self.idx += 1;
+
+		let mut res: Vec<u8> = Vec::new();
+		loop {
+			let c = match self.peek() {
+				Some(c) => c,
+				None => return self.error("unterminated string")
+			};
+			self.idx += 1;
+
+			match c {
+				b'"' => break,
+				b'\\' => {
+					let e = match self.peek() {
+						Some(e) => e,
+						None => return self.error("unterminated string")
+					};
+					self.idx += 1;
+
+					match e {
+						b'"' => res.push(b'"'),
+						b'\\' => res.push(b'\\'),
+						b'/' => res.push(b'/'),
+						b'b' => res.push(0x08),
+						b'f' => res.push(0x0c),
+						b'n' => res.push(b'\n'),
+						b'r' => res.push(b'\r'),
+						b't' => res.push(b'\t'),
+						b'u' => {
+							let mut code = self.parse_hex4()?;
+							if (0xD800..0xDC00).contains(&code) {
+								if !self.data[self.idx..].starts_with(b"\\u") {
+									return self.error("unpaired surrogate");
+								}
+								self.idx += 2;
+								let low = self.parse_hex4()?;
+								if !(0xDC00..0xE000).contains(&low) {
+									return self.error("invalid surrogate pair");
+								}
+								code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+							} else if (0xDC00..0xE000).contains(&code) {
+								return self.error("unpaired surrogate");
+							}
+							// Every other code below 0x110000 is a scalar value now
+							let ch = char::from_u32(code).unwrap();
+							let mut buf = [0u8; 4];
+							res.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+						},
+						_ => return self.error("invalid escape sequence")
+					}
+				},
+				c if c < 0x20 => return self.error("control character in string"),
+				c => res.push(c)
+			}
+		}
+
+		Ok(String::from_utf8_lossy(&res).to_string())
+	}
+
+	fn parse_number(&mut self) -> LuaResult<Value> {
+		let start = self.idx;
+		while let Some(c) = self.peek() {
+			if c.is_ascii_digit() || matches!(c, b'-' | b'+' | b'.' | b'e' | b'E') {
+				self.idx += 1;
+			} else {
+				break;
+			}
+		}
+
+		let text = String::from_utf8_lossy(&self.data[start..self.idx]).to_string();
+		match text.parse::<f64>() {
+			Ok(n) if is_json_number(text.as_bytes()) => Ok(Value::Number(n)),
+			_ => {
+				self.idx = start;
+				self.error("invalid number")
+			}
+		}
+	}
+
+	fn parse_value(&mut self, depth: usize) -> LuaResult<Value> {
+		if depth > JSON_MAX_DEPTH {
+			return self.error("document is nested too deeply");
+		}
+
+		self.skip_whitespace();
+		match self.peek() {
+			None => self.error("unexpected end of input"),
+			Some(b'n') => self.expect_literal("null", Value::NULL),
+			Some(b't') => self.expect_literal("true", Value::Boolean(true)),
+			Some(b'f') => self.expect_literal("false", Value::Boolean(false)),
+			Some(b'"') => {
+				let s = self.parse_string()?;
+				Ok(Value::String(self.lua.create_string(&s)?))
+			},
+			Some(b'[') => {
+				self.idx += 1;
+				let table = self.lua.create_table()?;
+				table.set_metatable(Some(self.lua.named_registry_value(JSON_ARRAY_METATABLE_NAME)?));
+
+				self.skip_whitespace();
+				if self.peek() == Some(b']') {
+					self.idx += 1;
+					return Ok(Value::Table(table));
+				}
+
+				let mut i = 1;
+				loop {
+					let value = self.parse_value(depth + 1)?;
+					table.raw_set(i, value)?;
+					i += 1;
+
+					self.skip_whitespace();
+					match self.peek() {
+						Some(b',') => self.idx += 1,
+						Some(b']') => {
+							self.idx += 1;
+							break;
+						},
+						_ => return self.error("expected ',' or ']'")
+					}
+				}
+
+				Ok(Value::Table(table))
+			},
+			Some(b'{') => {
+				self.idx += 1;
+				let table = self.lua.create_table()?;
+				table.set_metatable(Some(self.lua.named_registry_value(JSON_OBJECT_METATABLE_NAME)?));
+
+				self.skip_whitespace();
+				if self.peek() == Some(b'}') {
+					self.idx += 1;
+					return Ok(Value::Table(table));
+				}
+
+				loop {
+					self.skip_whitespace();
+					if self.peek() != Some(b'"') {
+						return self.error("expected object key");
+					}
+					let key = self.parse_string()?;
+
+					self.skip_whitespace();
+					if self.peek() != Some(b':') {
+						return self.error("expected ':'");
+					}
+					self.idx += 1;
+
+					let value = self.parse_value(depth + 1)?;
+					table.raw_set(key, value)?;
+
+					self.skip_whitespace();
+					match self.peek() {
+						Some(b',') => self.idx += 1,
+						Some(b'}') => {
+							self.idx += 1;
+							break;
+						},
+						_ => return self.error("expected ',' or '}'")
+					}
+				}
+
+				Ok(Value::Table(table))
+			},
+			Some(c) if c == b'-' || c.is_ascii_digit() => self.parse_number(),
+			Some(_) => self.error("unexpected character")
+		}
+	}
+}
+
+/// Parses a JSON document into a Lua value. `null` becomes `jwx.json.null`, arrays and
+/// objects keep a marker metatable so they encode back to the same JSON type.
+pub fn decode(lua: &Lua, data: &[u8]) -> LuaResult<Value> {
+	let mut decoder = Decoder {
+		lua,
+		data,
+		idx: 0
+	};
+
+	let value = decoder.parse_value(0)?;
+	decoder.skip_whitespace();
+	if decoder.idx != data.len() {
+		return decoder.error("trailing characters");
+	}
+
+	Ok(value)
+}
+
+fn indent_from_options(options: Option<Table>) -> LuaResult<Option<String>> {
+	let options = match options {
+		Some(o) => o,
+		None => return Ok(None)
+	};
+
+	let pretty: Option<bool> = options.get("pretty")?;
+	if pretty != Some(true) {
+		return Ok(None);
+	}
+
+	let indent: Option<usize> = options.get("indent")?;
+	Ok(Some(" ".repeat(indent.unwrap_or(2))))
+}
+
+/// Registers the `jwx.json` module
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	lua.set_named_registry_value(JSON_ARRAY_METATABLE_NAME, lua.create_table()?)?;
+	lua.set_named_registry_value(JSON_OBJECT_METATABLE_NAME, lua.create_table()?)?;
+
+	let json = lua.create_table()?;
+
+	json.set("null", Value::NULL)?;
+
+	json.set("encode", lua.create_function(|lua, (value, options): (Value, Option<Table>)| {
+		encode(lua, &value, indent_from_options(options)?)
+	})?)?;
+
+	json.set("decode", lua.create_function(|lua, data: LuaString| {
+		decode(lua, &data.as_bytes())
+	})?)?;
+
+	json.set("array", lua.create_function(|lua, table: Option<Table>| {
+		let table = match table {
+			Some(t) => t,
+			None => lua.create_table()?
+		};
+		table.set_metatable(Some(lua.named_registry_value(JSON_ARRAY_METATABLE_NAME)?));
+		Ok(table)
+	})?)?;
+
+	json.set("object", lua.create_function(|lua, table: Option<Table>| {
+		let table = match table {
+			Some(t) => t,
+			None => lua.create_table()?
+		};
+		table.set_metatable(Some(lua.named_registry_value(JSON_OBJECT_METATABLE_NAME)?));
+		Ok(table)
+	})?)?;
+
+	jwx.set("json", json)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::{Lua, Table, Value};
+	use crate::lua_api::json::{decode, encode, register};
+
+	fn setup() -> (Lua, Table) {
+		let lua = Lua::new();
+		let jwx = lua.create_table().unwrap();
+		register(&lua, &jwx).unwrap();
+		lua.globals().set("jwx", jwx.clone()).unwrap();
+		(lua, jwx)
+	}
+
+	#[test]
+	pub fn test_json_roundtrip() {
+		let (lua, _) = setup();
+
+		let data = r#"{"a":[1,2.5,"x\nè"],"b":null,"c":{},"d":[],"e":true}"#;
+		let value = decode(&lua, data.as_bytes()).unwrap();
+		assert_eq!(encode(&lua, &value, None).unwrap(), data);
+
+		let value: Value = lua.load(r#"{ name = "jwx", list = { 3, 2, 1 }, empty = {} }"#).eval().unwrap();
+		assert_eq!(encode(&lua, &value, None).unwrap(), r#"{"empty":{},"list":[3,2,1],"name":"jwx"}"#);
+
+		let pretty = encode(&lua, &value, Some("  ".to_string())).unwrap();
+		assert_eq!(pretty, "{\n  \"empty\": {},\n  \"list\": [\n    3,\n    2,\n    1\n  ],\n  \"name\": \"jwx\"\n}");
+	}
+
+	#[test]
+	pub fn test_json_lua_api() {
+		let (lua, _) = setup();
+
+		let res: String = lua.load(r#"
+			local v = jwx.json.decode('{"n": null, "list": []}')
+			assert(v.n == jwx.json.null)
+			return jwx.json.encode({ items = jwx.json.array(), missing = jwx.json.null })
+		"#).eval().unwrap();
+		assert_eq!(res, r#"{"items":[],"missing":null}"#);
+
+		assert!(decode(&lua, b"{\"a\": 1,}").is_err());
+		assert!(decode(&lua, b"[1, 2] x").is_err());
+		for bad in ["01", "-01", "+1", "1.", ".5", "1e", "1e+", "--1", "0x10"] {
+			assert!(decode(&lua, bad.as_bytes()).is_err(), "{} was accepted", bad);
+		}
+		for good in ["0", "-0", "10", "0.5", "-1.25e+3", "1E-2"] {
+			assert!(decode(&lua, good.as_bytes()).is_ok(), "{} was rejected", good);
+		}
+
+		let pair = decode(&lua, br#""\ud83d\ude00""#).unwrap();
+		assert_eq!(pair.as_string().unwrap().to_str().unwrap(), "\u{1F600}");
+		assert!(decode(&lua, br#""\ud83d\u0041""#).is_err());
+		assert!(decode(&lua, br#""\ud83d""#).is_err());
+		assert!(decode(&lua, br#""\ud83dx""#).is_err());
+		assert!(decode(&lua, br#""\ude00""#).is_err());
+		assert!(decode(&lua, br#""\ude00\ud83d""#).is_err());
+		assert!(decode(&lua, br#""\u+041""#).is_err());
+		assert!(lua.load("local t = {}; t.t = t; return jwx.json.encode(t)").exec().is_err());
+
+		let mixed: String = lua.load("return jwx.json.encode({ 1, 2, x = 3 })").eval().unwrap();
+		assert_eq!(mixed, r#"{"1":1,"2":2,"x":3}"#);
+	}
+}
@@ -0,0 +1,31 @@
+use mlua::prelude::LuaResult;
+use mlua::{Lua, Table, Value};
+use crate::log::{enabled, write, Level};
+
+/// Registers the `jwx.log` module: `jwx.log.info(message, fields)` and the like write to the
+/// server's log, tagged with the request ID. `fields` is an optional table of extra values.
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let log = lua.create_table()?;
+
+	for level in [Level::Debug, Level::Info, Level::Warn, Level::Error] {
+		log.set(level.to_str(), lua.create_function(move |_, (message, fields): (Value, Option<Table>)| {
+			if !enabled(level) {
+				return Ok(());
+			}
+
+			let mut extra: Vec<(String, String)> = Vec::new();
+			if let Some(fields) = fields {
+				for pair in fields.pairs::<Value, Value>() {
+					let (key, value) = pair?;
+					extra.push((key.to_string()?, value.to_string()?));
+				}
+				extra.sort();
+			}
+
+			write(level, "Lua", &message.to_string()?, &extra);
+			Ok(())
+		})?)?;
+	}
+
+	jwx.set("log", log)
+}
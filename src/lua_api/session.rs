@@ -0,0 +1,114 @@
+use mlua::prelude::LuaResult;
+use mlua::{Error, Lua, Table, Value};
+use crate::lua_api::json;
+use crate::utils::{constant_time_eq, random_hex};
+
+/// Key of the CSRF token in the session data
+const CSRF_KEY: &str = "_csrf";
+const CSRF_TOKEN_BYTES: usize = 32;
+
+/// What a request did to its session, read back from `jwx.session` once the script is done
+pub struct SessionState {
+	/// The session data as JSON
+	pub data: String,
+	pub is_empty: bool,
+	pub regenerate: bool,
+	pub destroy: bool
+}
+
+/// Builds the `jwx.session` table for one request. `data` is the JSON loaded from storage,
+/// `None` for a new session; `submitted_csrf` is the token sent in the `X-CSRF-Token` header
+/// or the `_csrf` form field, checked by `verifyCsrf` when it isn't given a token.
+pub fn create(lua: &Lua, data: Option<&str>, submitted_csrf: Option<String>) -> LuaResult<Table> {
+	let session = lua.create_table()?;
+
+	session.set("isNew", data.is_none())?;
+	session.set("data", json::decode(lua, data.unwrap_or("{}").as_bytes())?)?;
+
+	session.set("get", lua.create_function(|_, (this, key): (Table, String)| {
+		this.get::<Table>("data")?.get::<Value>(key)
+	})?)?;
+
+	session.set("set", lua.create_function(|_, (this, key, value): (Table, String, Value)| {
+		this.get::<Table>("data")?.set(key, value)
+	})?)?;
+
+	session.set("regenerate", lua.create_function(|_, this: Table| {
+		this.set("_regenerate", true)
+	})?)?;
+
+	session.set("destroy", lua.create_function(|lua, this: Table| {
+		this.set("data", json::decode(lua, b"{}")?)?;
+		this.set("_destroy", true)
+	})?)?;
+
+	session.set("csrfToken", lua.create_function(|_, this: Table| {
+		let data: Table = this.get("data")?;
+		if let Some(token) = data.get::<Option<String>>(CSRF_KEY)? {
+			return Ok(token);
+		}
+
+		let token = random_hex(CSRF_TOKEN_BYTES).map_err(Error::external)?;
+		data.set(CSRF_KEY, token.clone())?;
+		Ok(token)
+	})?)?;
+
+	session.set("verifyCsrf", lua.create_function(move |_, (this, token): (Table, Option<String>)| {
+		let expected: Option<String> = this.get::<Table>("data")?.get(CSRF_KEY)?;
+		match (expected, token.or(submitted_csrf.clone())) {
+			(Some(expected), Some(token)) => Ok(constant_time_eq(expected.as_bytes(), token.as_bytes())),
+			_ => Ok(false)
+		}
+	})?)?;
+
+	Ok(session)
+}
+
+/// Reads back the data and flags of a table made by `create`
+pub fn read(lua: &Lua, session: &Table) -> LuaResult<SessionState> {
+	let data: Value = session.get("data")?;
+	let is_empty = match &data {
+		Value::Table(t) => t.pairs::<Value, Value>().next().is_none(),
+		other => return Err(Error::RuntimeError(format!("jwx.session.data must be a table, got {}", other.type_name())))
+	};
+
+	Ok(SessionState {
+		data: json::encode(lua, &data, None)?,
+		is_empty,
+		regenerate: session.get::<Option<bool>>("_regenerate")?.unwrap_or(false),
+		destroy: session.get::<Option<bool>>("_destroy")?.unwrap_or(false)
+	})
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::Lua;
+	use crate::lua_api::json;
+	use crate::lua_api::session::{create, read};
+
+	#[test]
+	pub fn test_session_table() {
+		let lua = Lua::new();
+		let jwx = lua.create_table().unwrap();
+		json::register(&lua, &jwx).unwrap();
+
+		let session = create(&lua, Some("{\"user\":\"alice\"}"), Some("wrong".to_string())).unwrap();
+		lua.globals().set("session", &session).unwrap();
+		let (user, is_new, valid, token_valid, forged): (String, bool, bool, bool, bool) = lua.load(r#"
+			local token = session:csrfToken()
+			assert(token == session:csrfToken())
+			session:set("count", 1)
+			return session:get("user"), session.isNew, session:verifyCsrf(), session:verifyCsrf(token), session:verifyCsrf(token .. "0")
+		"#).eval().unwrap();
+		assert_eq!(user, "alice");
+		assert!(!is_new && !valid && token_valid && !forged);
+
+		let state = read(&lua, &session).unwrap();
+		assert!(state.data.contains("\"count\":1") && !state.is_empty && !state.regenerate);
+
+		lua.load("session:destroy()").exec().unwrap();
+		let state = read(&lua, &session).unwrap();
+		assert!(state.destroy && state.is_empty);
+	}
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+use mlua::prelude::LuaResult;
+use mlua::{Error, Lua, Table, Value};
+use crate::http::sse::{format_comment, format_event};
+
+/// Registers the `jwx.sse` module, plus `jwx.sleep` for event loops
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let sse = lua.create_table()?;
+
+	sse.set("format", lua.create_function(|_, event: Table| {
+		let data = match event.get::<Value>("data")? {
+			Value::Nil => String::new(),
+			v => v.to_string()?
+		};
+		let name: Option<String> = event.get("event")?;
+		let id = match event.get::<Value>("id")? {
+			Value::Nil => None,
+			v => Some(v.to_string()?)
+		};
+		let retry: Option<u64> = event.get("retry")?;
+
+		format_event(name.as_deref(), &data, id.as_deref(), retry).map_err(Error::RuntimeError)
+	})?)?;
+
+	sse.set("comment", lua.create_function(|_, comment: String| {
+		Ok(format_comment(&comment))
+	})?)?;
+
+	jwx.set("sse", sse)?;
+
+	jwx.set("sleep", lua.create_function(|_, seconds: f64| {
+		if seconds > 0.0 {
+			std::thread::sleep(Duration::from_secs_f64(seconds));
+		}
+		Ok(())
+	})?)
+}
@@ -0,0 +1,120 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use mlua::prelude::{LuaResult, LuaString};
+use mlua::{Error, Lua, Table, Value};
+use crate::store::{StoreClient, OP_CAS, OP_DELETE, OP_GET, OP_INCR, OP_SET, RESERVED_PREFIX};
+
+/// Runs one store operation, connecting on first use, in a forked child and after a failure.
+/// The first field is always the key.
+fn call(client: &RefCell<Option<StoreClient>>, op: u8, fields: &[Option<&[u8]>]) -> LuaResult<Vec<Option<Vec<u8>>>> {
+	if fields.first().copied().flatten().is_some_and(|key| key.starts_with(RESERVED_PREFIX.as_bytes())) {
+		return Err(Error::RuntimeError(format!("Store keys starting with {:?} are reserved", RESERVED_PREFIX)));
+	}
+
+	let mut client = client.borrow_mut();
+	if !client.as_ref().is_some_and(|c| c.is_owned()) {
+		*client = Some(StoreClient::connect().map_err(|e| Error::RuntimeError(format!("Can't reach the store: {}", e)))?);
+	}
+
+	match client.as_mut().unwrap().call(op, fields) {
+		Ok(res) => Ok(res),
+		Err(Ok(message)) => Err(Error::RuntimeError(message)),
+		Err(Err(e)) => {
+			*client = None;
+			Err(Error::RuntimeError(format!("Store connection lost: {}", e)))
+		}
+	}
+}
+
+/// Stored values are strings; numbers are converted
+fn value_bytes(value: &Value) -> LuaResult<Option<Vec<u8>>> {
+	match value {
+		Value::Nil => Ok(None),
+		Value::String(s) => Ok(Some(s.as_bytes().to_vec())),
+		Value::Integer(_) | Value::Number(_) => Ok(Some(value.to_string()?.into_bytes())),
+		other => Err(Error::RuntimeError(format!("Store values must be strings or numbers, got {}", other.type_name())))
+	}
+}
+
+fn first(lua: &Lua, res: Vec<Option<Vec<u8>>>) -> LuaResult<Option<LuaString>> {
+	match res.into_iter().next().flatten() {
+		Some(data) => Ok(Some(lua.create_string(data)?)),
+		None => Ok(None)
+	}
+}
+
+fn first_bool(res: Vec<Option<Vec<u8>>>) -> bool {
+	res.into_iter().next().flatten().is_some_and(|v| v == b"1")
+}
+
+/// Registers the `jwx.store` module: a key-value store shared by every worker
+pub fn register(lua: &Lua, jwx: &Table) -> LuaResult<()> {
+	let store = lua.create_table()?;
+	let client: Rc<RefCell<Option<StoreClient>>> = Rc::new(RefCell::new(None));
+
+	let c = client.clone();
+	store.set("get", lua.create_function(move |lua, key: String| {
+		first(lua, call(&c, OP_GET, &[Some(key.as_bytes())])?)
+	})?)?;
+
+	let c = client.clone();
+	store.set("set", lua.create_function(move |_, (key, value, ttl): (String, Value, Option<f64>)| {
+		let value = value_bytes(&value)?.ok_or_else(|| Error::RuntimeError("Missing value, use jwx.store.delete to remove a key".to_string()))?;
+		let ttl = ttl.map(|t| t.to_string());
+		call(&c, OP_SET, &[Some(key.as_bytes()), Some(&value), ttl.as_ref().map(|t| t.as_bytes())])?;
+		Ok(())
+	})?)?;
+
+	let c = client.clone();
+	store.set("delete", lua.create_function(move |_, key: String| {
+		Ok(first_bool(call(&c, OP_DELETE, &[Some(key.as_bytes())])?))
+	})?)?;
+
+	let c = client.clone();
+	store.set("incr", lua.create_function(move |_, (key, by, ttl): (String, Option<i64>, Option<f64>)| {
+		let by = by.unwrap_or(1).to_string();
+		let ttl = ttl.map(|t| t.to_string());
+		let res = call(&c, OP_INCR, &[Some(key.as_bytes()), Some(by.as_bytes()), ttl.as_ref().map(|t| t.as_bytes())])?;
+
+		let value = res.into_iter().next().flatten().unwrap_or_default();
+		String::from_utf8_lossy(&value).parse::<i64>().map_err(Error::external)
+	})?)?;
+
+	let c = client;
+	store.set("cas", lua.create_function(move |_, (key, expected, new, ttl): (String, Value, Value, Option<f64>)| {
+		let expected = value_bytes(&expected)?;
+		let new = value_bytes(&new)?;
+		let ttl = ttl.map(|t| t.to_string());
+		let res = call(&c, OP_CAS, &[Some(key.as_bytes()), expected.as_deref(), new.as_deref(), ttl.as_ref().map(|t| t.as_bytes())])?;
+
+		Ok(first_bool(res))
+	})?)?;
+
+	jwx.set("store", store)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use mlua::{Lua, Table};
+	use crate::lua_api::store;
+
+	#[test]
+	pub fn test_reserved_keys() {
+		let lua = Lua::new();
+		let jwx: Table = lua.create_table().unwrap();
+		store::register(&lua, &jwx).unwrap();
+		lua.globals().set("jwx", jwx).unwrap();
+
+		// Refused before the store is even reached
+		let message: String = lua.load(r#"
+			for _, f in ipairs({ "get", "delete", "incr" }) do
+				assert(not pcall(jwx.store[f], "\0jwx:session:abc"))
+			end
+			assert(not pcall(jwx.store.set, "\0jwx:session:abc", "{}"))
+			assert(not pcall(jwx.store.cas, "\0jwx:session:abc", nil, "{}"))
+			return tostring(select(2, pcall(jwx.store.get, "\0jwx:x")))
+		"#).eval().unwrap();
+		assert!(message.contains("reserved"), "{}", message);
+	}
+}
@@ -0,0 +1,17 @@
+use std::path::PathBuf;
+use mlua::prelude::LuaResult;
+use mlua::{Error, Lua, Table};
+use crate::template::render;
+
+/// Registers `jwx.render(name, context)`, rendering templates from `folder`
+pub fn register(lua: &Lua, jwx: &Table, folder: Option<PathBuf>) -> LuaResult<()> {
+	jwx.set("render", lua.create_function(move |lua, (name, context): (String, Option<Table>)| {
+		let folder = folder.as_ref().ok_or(Error::RuntimeError("No template folder, see config_set_template_folder".to_string()))?;
+		let context = match context {
+			Some(c) => c,
+			None => lua.create_table()?
+		};
+
+		render(lua, folder, &name, context).map_err(|e| Error::RuntimeError(e.to_string()))
+	})?)
+}
@@ -1,38 +1,70 @@
+#[macro_use]
+mod log;
+mod access_log;
 mod url;
 mod utils;
 mod http_client;
 mod ipc;
 mod dispatcher;
+mod watchdog;
+mod worker_pool;
+mod reload;
+mod store;
+mod supervisor;
+mod session;
+mod template;
 
 mod http {
 	pub mod http_message;
+	pub mod http_form;
+	pub mod http_cookie;
+	pub mod response_stream;
+	pub mod sse;
+	pub mod websocket;
+	pub mod outbound;
 	pub mod http_request;
 	pub mod http_response;
 }
 
 mod behaviours {
 	pub mod lua_behaviour;
+	pub mod lua_sandbox;
+	pub mod lua_snapshot;
 	pub mod behaviour_router;
 	pub mod behaviour;
+	pub mod error_page;
 }
 
 mod config {
 	pub mod lua_config;
 }
 
+mod lua_api {
+	pub mod json;
+	pub mod cookie;
+	pub mod sse;
+	pub mod store;
+	pub mod session;
+	pub mod template;
+	pub mod crypto;
+	pub mod encoding;
+	pub mod http;
+	pub mod log;
+}
+
 use std::collections::HashMap;
 use std::{env, thread};
-use std::fs::File;
+use std::io::Read;
 use std::net::TcpListener;
-use std::path::{Path};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread::{JoinHandle};
 use http_client::HttpClient;
 use crate::config::lua_config::ConfigMgr;
-use crate::dispatcher::run_lua_dispatcher;
-use crate::ipc::{IpcMessage};
 use crate::ipc::request_pipe::RequestPipe;
-use crate::utils::{new_pipe, safe_fork, ForkResult};
+use crate::store::Store;
+use crate::utils::{safe_fork, ForkResult};
 
 struct ArgDefinition {
 	name: String,
@@ -42,7 +74,7 @@ struct ArgDefinition {
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn parse_args(args: &Vec<String>, definitions: &Vec<ArgDefinition>) -> Result<HashMap<String, String>, String> {
+fn parse_args(args: &[String], definitions: &Vec<ArgDefinition>) -> Result<HashMap<String, String>, String> {
 	let mut current_arg: Option<&ArgDefinition> = None;
 	let mut values: HashMap<String, String> = HashMap::new();
 
@@ -94,15 +126,13 @@ fn parse_args(args: &Vec<String>, definitions: &Vec<ArgDefinition>) -> Result<Ha
 	Ok(values)
 }
 
-fn run_listener(target_port: u16, lua_send: File, control_recv: File, content_root: &Path) -> Result<(), std::io::Error> {
+fn run_listener(target_port: u16, communicator: Arc<RequestPipe>, mut supervisor_control: UnixStream, content_root: &Path, max_body_size: usize) -> Result<(), std::io::Error> {
 
 	let addr = format!("0.0.0.0:{}", target_port);
 
 	let listener = TcpListener::bind(addr)?;
 	let mut client_threads: Vec<JoinHandle<()>> = Vec::new();
 
-	let communicator = Arc::new(Mutex::new(RequestPipe::new(lua_send, control_recv)));
-
 	loop {
 		match listener.accept() {
 			Ok((stream, addr)) => {
@@ -116,41 +146,78 @@ fn run_listener(target_port: u16, lua_send: File, control_recv: File, content_ro
 						HashMap::from([
 							("Server".to_string(), "jwx-rs/0.1.0".to_string()),
 							("Connection".to_string(), "Keep-Alive".to_string()),
-						])
+						]),
+						max_body_size
 					).run(&root);
 				});
 
 				client_threads.push(t);
 			},
 			Err(e) => {
-				println!("accept error = {:?}", e);
+				log_error!("Listener", "accept error = {:?}", e);
 				break;
 			}
 		}
 	}
 
 
-	let mut send = match communicator.lock() {
-		Ok(s) => s,
-		Err(_) => {
-			println!("Error while locking send mutex");
-			return Ok(());
-		}
-	};
-
-	println!("[Listener] Sending close message");
-	_ = send.send_message_and_wait(IpcMessage::Close);
+	log_info!("Listener", "Sending close message");
+	supervisor::shut_down(&mut supervisor_control);
+	_ = communicator.close();
 
 	for j in client_threads.drain(..) {
 		match j.join() {
 			Ok(_) => (),
-			Err(e) => println!("Error while joining client thread: {:?}", e)
+			Err(e) => log_error!("Listener", "Error while joining client thread: {:?}", e)
 		}
 	}
 
 	Ok(())
 }
 
+/// Passes the SIGHUP and SIGUSR1 received by the listener on to the supervisor, which passes
+/// them on to the dispatcher
+fn forward_signals(pid: libc::pid_t) {
+	if let Err(e) = reload::forward_sighup(pid) {
+		log_warn!("Listener", "Can't forward SIGHUP to the supervisor: {:?}", e);
+	}
+
+	if let Err(e) = log::reopen_on_sigusr1(Some(pid)) {
+		log_warn!("Listener", "Can't reopen log files on SIGUSR1: {:?}", e);
+	}
+}
+
+/// Writes the store to its persistence file, if it has one
+fn save_store(store: &Mutex<Store>, path: Option<&Path>) {
+	if let (Some(path), Ok(mut store)) = (path, store.lock()) {
+		if let Err(e) = store.save(path) {
+			log_error!("Store", "Error saving {}: {:?}", path.display(), e);
+		}
+	}
+}
+
+/// Stops the server on SIGTERM or SIGINT: the listener never returns from `accept` on its
+/// own, so the store is saved and the supervisor stopped from a thread of its own
+fn stop_on_termination(store: Arc<Mutex<Store>>, store_file: Option<PathBuf>, communicator: Arc<RequestPipe>, mut supervisor_control: UnixStream) -> Result<(), std::io::Error> {
+	let mut notifier = supervisor::termination_notifier()?;
+
+	thread::spawn(move || {
+		let mut byte = [0u8; 1];
+		if notifier.read_exact(&mut byte).is_err() {
+			return;
+		}
+
+		log_info!("Listener", "Shutting down");
+		save_store(&store, store_file.as_deref());
+		store::remove_socket();
+		supervisor::shut_down(&mut supervisor_control);
+		_ = communicator.close();
+		std::process::exit(0);
+	});
+
+	Ok(())
+}
+
 fn main() -> Result<(), std::io::Error> {
 	let mut target_content_path = "./content";
 	let mut target_config_path = "./config";
@@ -209,7 +276,7 @@ fn main() -> Result<(), std::io::Error> {
 		target_port = match port.parse::<u16>() {
 			Ok(p) => p,
 			Err(_) => {
-				println!("Invalid port: {}", port);
+				log_error!("Listener", "Invalid port: {}", port);
 				return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid port"));
 			}
 		};
@@ -225,30 +292,58 @@ fn main() -> Result<(), std::io::Error> {
 
 
 	let mut mgr = ConfigMgr::new(target_config_path);
-	mgr.run_config(&target_config_file);
+	if let Err(e) = mgr.run_config(target_config_file) {
+		log_error!("ConfigMgr", "{}", e);
+	}
 
-	let (lua_recv, lua_send) = match new_pipe() {
-		Ok(res) => res,
-		Err(e) => {
-			return Err(e);
-		}
-	};
+	if let Err(e) = log::init(mgr.get_log()) {
+		log_error!("Listener", "Can't open log file {:?}: {:?}", mgr.get_log().file, e);
+	}
 
-	let (control_recv, control_send) = match new_pipe() {
-		Ok(res) => res,
-		Err(e) => {
-			return Err(e);
-		}
-	};
+	// Requests, and the streams they are read from and answered to, go to the dispatcher over
+	// this socket
+	let (listener_socket, dispatcher_socket) = UnixStream::pair()?;
+
+	// Bound before forking, so workers can reach the store as soon as they start
+	let store_listener = store::bind()?;
+
+	// The dispatcher is started, and restarted if it crashes, by a supervisor forked before
+	// the listener starts any thread
+	let (control, supervisor_control) = UnixStream::pair()?;
 
 	match safe_fork() {
 		Ok(ForkResult::Child) => {
-			run_lua_dispatcher(mgr, lua_recv, control_send)
+			drop(store_listener);
+			drop(listener_socket);
+			drop(control);
+			supervisor::run_supervisor(mgr, dispatcher_socket, supervisor_control)
 		},
-		Ok(ForkResult::Parent(_)) => run_listener(target_port, lua_send, control_recv, Path::new(target_content_path)),
-		Err(e) => {
-			return Err(e)
-		}
+		Ok(ForkResult::Parent(pid)) => {
+			forward_signals(pid);
+
+			if let Err(e) = access_log::init(mgr.get_access_log()) {
+				log_error!("Listener", "Can't open access log {:?}: {:?}", mgr.get_access_log().file, e);
+			}
+
+			let store_file = mgr.get_store_file().map(PathBuf::from);
+			let store = store::start(store_listener, store_file.clone());
+
+			drop(dispatcher_socket);
+			drop(supervisor_control);
+			let max_body_size = mgr.get_max_body_size();
+
+			let communicator = Arc::new(RequestPipe::new(listener_socket));
+			supervisor::watch(control.try_clone()?, communicator.clone());
+			stop_on_termination(store.clone(), store_file.clone(), communicator.clone(), control.try_clone()?)?;
+
+			let res = run_listener(target_port, communicator, control, Path::new(target_content_path), max_body_size);
+
+			save_store(&store, store_file.as_deref());
+			store::remove_socket();
+
+			res
+		},
+		Err(e) => Err(e)
 	}
 
 }
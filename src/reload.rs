@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, Ordering};
+use libc::c_int;
+use crate::utils::new_pipe;
+
+/// Process SIGHUP is passed on to (set in the listener)
+static FORWARD_PID: AtomicI32 = AtomicI32::new(0);
+/// Write end of the pipe signalling a reload (set in the dispatcher)
+static NOTIFY_FD: AtomicI32 = AtomicI32::new(-1);
+
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM | libc::IN_CREATE | libc::IN_DELETE;
+
+extern "C" fn on_sighup(_: c_int) {
+	let pid = FORWARD_PID.load(Ordering::SeqCst);
+	if pid > 0 {
+		unsafe { libc::kill(pid, libc::SIGHUP) };
+		return;
+	}
+
+	let fd = NOTIFY_FD.load(Ordering::SeqCst);
+	if fd >= 0 {
+		let byte = 1u8;
+		unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+	}
+}
+
+fn install_sighup_handler() -> Result<(), Error> {
+	unsafe {
+		let mut action: libc::sigaction = std::mem::zeroed();
+		action.sa_sigaction = on_sighup as extern "C" fn(c_int) as usize;
+		action.sa_flags = libc::SA_RESTART;
+		libc::sigemptyset(&mut action.sa_mask);
+
+		if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+			return Err(Error::last_os_error());
+		}
+	}
+
+	Ok(())
+}
+
+pub fn set_nonblocking(fd: RawFd) -> Result<(), Error> {
+	let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+	if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+		return Err(Error::last_os_error());
+	}
+
+	Ok(())
+}
+
+/// Passes the SIGHUP received by the listener on to the dispatcher, which owns the configuration
+pub fn forward_sighup(pid: libc::pid_t) -> Result<(), Error> {
+	FORWARD_PID.store(pid, Ordering::SeqCst);
+	install_sighup_handler()
+}
+
+/// Returns a pipe that becomes readable every time the process receives SIGHUP
+pub fn sighup_notifier() -> Result<File, Error> {
+	let (recv, send) = new_pipe()?;
+	set_nonblocking(recv.as_raw_fd())?;
+	set_nonblocking(send.as_raw_fd())?;
+
+	// The write end lives as long as the process, the signal handler may use it at any time
+	NOTIFY_FD.store(send.into_raw_fd(), Ordering::SeqCst);
+	install_sighup_handler()?;
+
+	Ok(recv)
+}
+
+/// Empties a non-blocking pipe or inotify descriptor, returning what was read
+pub fn drain(file: &mut File) -> Vec<u8> {
+	let mut res = Vec::new();
+	let mut buff = [0u8; 4096];
+	loop {
+		match file.read(&mut buff) {
+			Ok(0) => break,
+			Ok(len) => res.extend_from_slice(&buff[..len]),
+			Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+			Err(_) => break
+		}
+	}
+
+	res
+}
+
+/// Watches folders for changes to Lua files, with inotify
+pub struct FileWatcher {
+	inotify: File,
+	folders: HashMap<c_int, PathBuf>
+}
+
+impl FileWatcher {
+	pub fn new(folders: &[PathBuf]) -> Result<FileWatcher, Error> {
+		let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+		if fd < 0 {
+			return Err(Error::last_os_error());
+		}
+		let inotify = unsafe { File::from_raw_fd(fd) };
+
+		let mut watched = HashMap::new();
+		for folder in folders {
+			let path = CString::new(folder.as_os_str().as_bytes())?;
+			let wd = unsafe { libc::inotify_add_watch(fd, path.as_ptr(), WATCH_MASK) };
+			if wd < 0 {
+				log_warn!("FileWatcher", "Can't watch {}: {:?}", folder.display(), Error::last_os_error());
+				continue;
+			}
+
+			watched.insert(wd, folder.clone());
+		}
+
+		Ok(FileWatcher {
+			inotify,
+			folders: watched
+		})
+	}
+
+	/// Lua files created, modified, moved or deleted since the last call
+	pub fn changed_files(&mut self) -> Vec<PathBuf> {
+		let data = drain(&mut self.inotify);
+		let header_len = std::mem::size_of::<libc::inotify_event>();
+
+		let mut res: Vec<PathBuf> = Vec::new();
+		let mut offset = 0;
+		while offset + header_len <= data.len() {
+			let wd = c_int::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+			let name_len = u32::from_ne_bytes(data[offset + 12..offset + 16].try_into().unwrap()) as usize;
+			let name_start = offset + header_len;
+			offset = name_start + name_len;
+
+			if offset > data.len() {
+				break;
+			}
+
+			let name = &data[name_start..offset];
+			let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+			if !name.ends_with(b".lua") {
+				continue;
+			}
+
+			if let Some(folder) = self.folders.get(&wd) {
+				let path = folder.join(String::from_utf8_lossy(name).as_ref());
+				if !res.contains(&path) {
+					res.push(path);
+				}
+			}
+		}
+
+		res
+	}
+}
+
+impl AsRawFd for FileWatcher {
+	fn as_raw_fd(&self) -> RawFd {
+		self.inotify.as_raw_fd()
+	}
+}
@@ -1,26 +1,59 @@
-jwx = {}
+jwx = jwx or {}
+
+-- Captured when the library loads, so runEvents keeps working if the script later removes or
+-- replaces the global. nil when a sandbox profile leaves the coroutine module out.
+local coroutine = coroutine
 
 local resp = {
     ---@type table
     headers = {},
     ---@type number
     statusCode = 200,
-    ---@type string
-    statusText = "OK",
+    ---@type string|nil
+    statusText = nil,
     ---@type string
     content = "",
+    ---@type string[]
+    cookies = {},
     ---@param self table
     ---@param data string
     writeContent = function(self, data)
         self.content = self.content .. data
-        local len =  string.len(self.content)
-        self:writeHeader("Content-Length", "" .. len)
     end,
     ---@param self table
     ---@param data string
     replaceContent = function(self, data)
         self.content = data
-        self:writeHeader("Content-Length", "" .. string.len(self.content))
+    end,
+    --- Clears the status, headers and content. Called before every request handled by a
+    --- persistent worker, so each one starts with an empty response.
+    ---@param self table
+    reset = function(self)
+        self.headers = {}
+        self.statusCode = 200
+        self.statusText = nil
+        self.content = ""
+        self.cookies = {}
+    end,
+    --- Sends the buffered content to the client immediately. The first call sends the status
+    --- and headers, which can't be changed afterwards. Unless a Content-Length header was
+    --- set, the response uses chunked transfer encoding.
+    ---@param self table
+    flush = function(self)
+        jwx.flush_response(self)
+    end,
+    ---@param self table
+    ---@param data string
+    write = function(self, data)
+        self:writeContent(data)
+        self:flush()
+    end,
+    ---@param self table
+    ---@param value any
+    ---@param options table|nil
+    json = function(self, value, options)
+        self:writeHeader("Content-Type", "application/json")
+        self:replaceContent(jwx.json.encode(value, options))
     end,
     ---@param self table
     ---@param headerName string
@@ -35,6 +68,97 @@ local resp = {
         return self.headers[headerName]
     end,
     ---@param self table
+    ---@return boolean
+    isConnected = function(self)
+        return jwx.response_connected()
+    end,
+    --- Turns the response into a Server-Sent Events stream and sends its headers
+    ---@param self table
+    startEvents = function(self)
+        self:writeHeader("Content-Type", "text/event-stream")
+        self:writeHeader("Cache-Control", "no-cache")
+        self:flush()
+    end,
+    ---@param self table
+    ---@param event table|string {event, data, id, retry} or just the data
+    sendEvent = function(self, event)
+        if type(event) ~= "table" then
+            event = { data = event }
+        end
+        self:write(jwx.sse.format(event))
+    end,
+    ---@param self table
+    ---@param comment string|nil
+    heartbeat = function(self, comment)
+        self:write(jwx.sse.comment(comment or "heartbeat"))
+    end,
+    --- Runs `producer` as a coroutine and streams what it yields: a table (or string) is sent
+    --- as an event, a number waits that many seconds, sending heartbeat comments every
+    --- `options.heartbeat` seconds (default 15). Returns when the producer ends or the client
+    --- disconnects; `options.onClose` is called in both cases. Needs the coroutine module,
+    --- which sandboxed endpoints must keep in their `modules`.
+    ---@param self table
+    ---@param producer function
+    ---@param options table|nil {heartbeat, onClose}
+    runEvents = function(self, producer, options)
+        if not coroutine then
+            error("runEvents needs the coroutine module, which the sandbox of this endpoint doesn't enable", 2)
+        end
+        options = options or {}
+        local heartbeat = options.heartbeat or 15
+        local co = coroutine.create(producer)
+
+        local ok, err = pcall(function()
+            self:startEvents()
+            while coroutine.status(co) ~= "dead" do
+                local success, value = coroutine.resume(co)
+                if not success then
+                    error(value, 0)
+                end
+
+                if type(value) == "number" then
+                    local remaining = value
+                    while remaining > 0 do
+                        local step = remaining < heartbeat and remaining or heartbeat
+                        jwx.sleep(step)
+                        remaining = remaining - step
+                        if step == heartbeat then
+                            self:heartbeat()
+                        end
+                    end
+                elseif value ~= nil then
+                    self:sendEvent(value)
+                end
+            end
+        end)
+
+        if options.onClose then
+            options.onClose()
+        end
+
+        if not ok and self:isConnected() then
+            error(err, 0)
+        end
+    end,
+    ---@param self table
+    ---@param name string
+    ---@param value string
+    ---@param options table|nil {path, domain, maxAge, expires, secure, httpOnly, sameSite}
+    setCookie = function(self, name, value, options)
+        table.insert(self.cookies, jwx.cookie.serialize(name, value, options))
+    end,
+    ---@param self table
+    ---@param name string
+    ---@param options table|nil {path, domain}
+    deleteCookie = function(self, name, options)
+        local o = { maxAge = 0, expires = 0 }
+        if options then
+            o.path = options.path
+            o.domain = options.domain
+        end
+        self:setCookie(name, "", o)
+    end,
+    ---@param self table
     ---@param code number
     setStatusCode = function(self, code)
         self.statusCode = code
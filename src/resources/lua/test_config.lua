@@ -1,4 +1,67 @@
 config_add_library_folder("./lua/lib/?.lua")
 
-config_set_endpoint("/lua", "./lua/test_endpoint.lua")
+-- Show the Lua error, source excerpt, traceback and request on failing requests. Keep it off in
+-- production: clients then only get a request ID matching the server log.
+-- config_set_dev_mode(true)
+
+-- Reload the config and endpoint scripts when they (or files in library folders) change. SIGHUP
+-- always triggers a reload; if anything fails to load, the current configuration is kept.
+-- config_set_watch(true)
+
+-- Middleware scripts define before(request) and/or after(request, response). before can change the
+-- request's headers, query and content, or return jwx.response to answer without running the
+-- endpoint; after can change the response. They run in registration order for every endpoint under
+-- `prefix` (default "/") and take the sandbox and limit options of endpoints, except
+-- max_address_space: they run in the worker of the endpoint, which sets isolation and that limit.
+-- config_add_middleware("./lua/auth.lua", { prefix = "/admin" })
+
+-- jwx.store (get, set, delete, incr, cas, with optional TTLs in seconds) is shared by every worker.
+-- With a store file, its data is saved every few seconds and on shutdown, and loaded on startup.
+-- config_set_store_file("./data/store.bin")
+
+-- Sessions: jwx.session keeps data per visitor in a signed HttpOnly cookie, stored in jwx.store
+-- (storage = "memory") or one file per session (storage = "file", path = "./data/sessions", where
+-- the files of expired sessions are deleted every few minutes).
+-- Without a secret a random one is used and sessions end with the server. expiry is in seconds
+-- of inactivity; with rotation, sessions older than that many seconds get a new ID. Middlewares
+-- see jwx.session too: it's saved after each before and after, so the whole chain shares it.
+-- config_set_sessions({ secret = "change me", expiry = 3600, rotation = 900, secure = true })
+
+-- Templates for jwx.render(name, context): {{ value }} is HTML-escaped unless piped through raw
+-- (other filters: upper, lower, trim, length), {% if %}/{% elif %}/{% else %}/{% endif %},
+-- {% for item in list %}...{% else %}...{% endfor %} (with loop.index, loop.first, loop.last),
+-- {% include "name" %}, {% extends "layout" %} with {% block name %}...{% endblock %}, and
+-- {# comments #}. Templates are compiled once per worker and again when their file changes.
+-- config_set_template_folder("./templates")
+
+-- Log level (debug, info, warn, error), format ("text" or "json") and file (stderr if unset).
+-- Scripts log with jwx.log.info(message, { key = value }); entries carry the request ID.
+-- config_set_log({ level = "info", format = "json", file = "./logs/jwx.log" })
+
+-- Access log, written after every response: format is "common" (default), "combined", "json" or
+-- a template of Apache directives (%h %t %r %>s %b %D %{User-Agent}i ...); file is stdout if unset.
+-- config_set_access_log(false) turns it off. SIGUSR1 reopens log files, e.g. after logrotate
+-- (persistent workers reopen theirs when they are restarted by a reload).
+-- config_set_access_log({ format = "combined", file = "./logs/access.log" })
+
+-- Worker pool: instead of forking a process when a request arrives, keep between min and max
+-- workers forked ahead of time. A worker serves requests one after the other and puts the tables
+-- reachable from the globals and loaded modules of snapshot endpoints and middlewares back as they
+-- were loaded after each one; locals captured by functions are not restored. A request to an
+-- endpoint with max_cpu_time or max_address_space is the last of its worker. A worker is replaced
+-- after max_requests requests or if it crashes; workers above min idle for idle_timeout seconds are
+-- stopped. Persistent endpoints, WebSockets and event streams are not pooled.
+-- config_set_worker_pool({ min = 2, max = 16, max_requests = 1000, idle_timeout = 60 })
+
+-- Endpoint options: name (used by jwx.url_for) and isolation, one of "snapshot" (default: every
+-- request starts from the state after the script was loaded), "fresh" (the script is loaded again
+-- for every request) or "persistent" (a long-lived worker keeps its globals between requests;
+-- WebSockets and event streams still get a process of their own, forked from the loaded state),
+-- and sandbox: true, or { modules = { "string", "table", "io", ... }, io_root = "./data" } to
+-- restrict the standard library, confine io to a folder and limit require to library folders
+-- (jwx.response:runEvents needs "coroutine" in modules).
+-- Execution limits: timeout and max_cpu_time (seconds), max_instructions, max_memory (Lua heap,
+-- bytes) and max_address_space (bytes, whole worker process). timeout stops counting once the
+-- response is streamed, so event streams and WebSockets stay open.
+config_set_endpoint("/lua", "./lua/test_endpoint.lua", { name = "test" })
 print("[Config-Lua] Done");
\ No newline at end of file
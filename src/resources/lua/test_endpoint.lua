@@ -1,7 +1,7 @@
 require("jwx_library_main")
 
 function run_request()
-    jwx.response:writeContent("Test ok.")
+    jwx.response:writeContent("Test ok. This endpoint lives at " .. jwx.url_for("test"))
     jwx.response:setStatusCode(200)
     jwx.response:setStatusText("Ok")
 end
\ No newline at end of file
@@ -0,0 +1,406 @@
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use mlua::prelude::LuaResult;
+use mlua::{Table, Value};
+use sha2::Sha256;
+use crate::http::http_cookie::{parse_cookies, serialize_cookie, CookieOptions};
+use crate::store::{StoreClient, OP_DELETE, OP_GET, OP_SET, RESERVED_PREFIX};
+use crate::utils::{from_hex, random_hex, to_hex};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DEFAULT_COOKIE_NAME: &str = "jwx_session";
+const DEFAULT_EXPIRY: u64 = 3600;
+/// Random bytes in a session ID
+const SESSION_ID_BYTES: usize = 24;
+/// Seconds between two sweeps of the expired session files of a folder
+const SWEEP_INTERVAL: u64 = 300;
+/// File in a session folder holding the time of its last sweep
+const SWEEP_FILE: &str = ".last_sweep";
+
+/// Where session data is kept between requests
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionStorage {
+	/// In `jwx.store`, shared by all workers
+	Memory,
+	/// One file per session in a folder
+	File(PathBuf)
+}
+
+/// Session settings, from `config_set_sessions`
+#[derive(Clone)]
+pub struct SessionConfig {
+	pub cookie_name: String,
+	secret: Vec<u8>,
+	/// No secret was configured: a random one is used, so sessions end with the server
+	pub generated_secret: bool,
+	pub storage: SessionStorage,
+	/// Seconds without a request after which a session expires
+	pub expiry: u64,
+	/// Seconds after which a session gets a new ID, keeping its data
+	pub rotation: Option<u64>,
+	pub secure: bool,
+	pub same_site: String
+}
+
+impl Debug for SessionConfig {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SessionConfig")
+			.field("cookie_name", &self.cookie_name)
+			.field("generated_secret", &self.generated_secret)
+			.field("storage", &self.storage)
+			.field("expiry", &self.expiry)
+			.field("rotation", &self.rotation)
+			.field("secure", &self.secure)
+			.field("same_site", &self.same_site)
+			.finish()
+	}
+}
+
+/// What is stored for a session: when it was created (for rotation) and its data as JSON
+pub struct SessionRecord {
+	pub created: u64,
+	pub data: String
+}
+
+/// Unix time in seconds
+pub fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn store_error(e: Result<String, Error>) -> Error {
+	match e {
+		Ok(message) => Error::other(message),
+		Err(e) => e
+	}
+}
+
+pub fn new_session_id() -> Result<String, Error> {
+	random_hex(SESSION_ID_BYTES)
+}
+
+fn is_session_id(id: &str) -> bool {
+	id.len() == SESSION_ID_BYTES * 2 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Key of a memory session in the store, out of reach of `jwx.store`
+fn store_key(id: &str) -> String {
+	format!("{}session:{}", RESERVED_PREFIX, id)
+}
+
+/// Reads a stored session, `{created} {expires}\n{data}`, returning it with its expiry time
+fn parse_record(data: &[u8]) -> Result<(SessionRecord, u64), Error> {
+	let data = String::from_utf8_lossy(data);
+	let (head, data) = data.split_once('\n').ok_or(Error::new(ErrorKind::InvalidData, "Invalid session record"))?;
+	let mut times = head.split(' ').map(|t| t.parse::<u64>().ok());
+	match (times.next().flatten(), times.next().flatten()) {
+		(Some(created), Some(expires)) => Ok((SessionRecord { created, data: data.to_string() }, expires)),
+		_ => Err(Error::new(ErrorKind::InvalidData, "Invalid session record"))
+	}
+}
+
+/// Deletes the files of expired sessions in `folder`, unless that was done less than
+/// `SWEEP_INTERVAL` ago (by any worker)
+fn sweep(folder: &Path) -> Result<(), Error> {
+	let marker = folder.join(SWEEP_FILE);
+	let last = fs::read_to_string(&marker).ok().and_then(|t| t.trim().parse::<u64>().ok());
+	let now = now();
+	if last.is_some_and(|t| t + SWEEP_INTERVAL > now) {
+		return Ok(());
+	}
+
+	let entries = match fs::read_dir(folder) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e)
+	};
+	fs::write(&marker, now.to_string())?;
+
+	for entry in entries {
+		let path = entry?.path();
+		if path.extension().is_none_or(|e| e != "session") {
+			continue;
+		}
+
+		let expired = fs::read(&path).ok()
+			.and_then(|data| parse_record(&data).ok())
+			.is_some_and(|(_, expires)| expires <= now);
+		if expired {
+			_ = fs::remove_file(&path);
+		}
+	}
+
+	Ok(())
+}
+
+impl SessionConfig {
+	/// Reads the value of the `sessions` option, a table
+	/// `{ secret, storage = "memory" | "file", path, cookie, expiry, rotation, secure, same_site }`
+	pub fn from_option(value: Value) -> LuaResult<Option<SessionConfig>> {
+		let table: Table = match value {
+			Value::Nil | Value::Boolean(false) => return Ok(None),
+			Value::Boolean(true) => return SessionConfig::from_table(None).map(Some),
+			Value::Table(t) => t,
+			other => return Err(mlua::Error::RuntimeError(format!("sessions must be a boolean or a table, got {}", other.type_name())))
+		};
+
+		SessionConfig::from_table(Some(table)).map(Some)
+	}
+
+	fn from_table(table: Option<Table>) -> LuaResult<SessionConfig> {
+		let get_string = |name: &str| -> LuaResult<Option<String>> {
+			match &table {
+				Some(t) => t.get(name),
+				None => Ok(None)
+			}
+		};
+
+		let storage = match get_string("storage")?.as_deref() {
+			None | Some("memory") => SessionStorage::Memory,
+			Some("file") => match get_string("path")? {
+				Some(path) => SessionStorage::File(PathBuf::from(path)),
+				None => return Err(mlua::Error::RuntimeError("File session storage needs a path".to_string()))
+			},
+			Some(other) => return Err(mlua::Error::RuntimeError(format!("Unknown session storage '{}', expected memory or file", other)))
+		};
+
+		let (secret, generated_secret) = match get_string("secret")? {
+			Some(s) if !s.is_empty() => (s.into_bytes(), false),
+			_ => (random_hex(32).map_err(mlua::Error::external)?.into_bytes(), true)
+		};
+
+		let (expiry, rotation, secure) = match &table {
+			Some(t) => (t.get::<Option<u64>>("expiry")?, t.get::<Option<u64>>("rotation")?, t.get::<Option<bool>>("secure")?),
+			None => (None, None, None)
+		};
+
+		Ok(SessionConfig {
+			cookie_name: get_string("cookie")?.unwrap_or(DEFAULT_COOKIE_NAME.to_string()),
+			secret,
+			generated_secret,
+			storage,
+			expiry: expiry.unwrap_or(DEFAULT_EXPIRY),
+			rotation,
+			secure: secure.unwrap_or(false),
+			same_site: get_string("same_site")?.unwrap_or("Lax".to_string())
+		})
+	}
+
+	/// Keeps the random secret of `previous` when reloading a config without a secret, so
+	/// existing sessions stay valid
+	pub fn keep_secret(&mut self, previous: &SessionConfig) {
+		if self.generated_secret && previous.generated_secret {
+			self.secret = previous.secret.clone();
+		}
+	}
+
+	fn mac(&self, id: &str) -> HmacSha256 {
+		let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+		mac.update(id.as_bytes());
+		mac
+	}
+
+	/// Cookie value for session `id`: the ID followed by its signature
+	pub fn sign(&self, id: &str) -> String {
+		format!("{}.{}", id, to_hex(&self.mac(id).finalize().into_bytes()))
+	}
+
+	/// Returns the session ID of a cookie value, if its signature is valid
+	pub fn verify(&self, value: &str) -> Option<String> {
+		let (id, signature) = value.split_once('.')?;
+		if !is_session_id(id) {
+			return None;
+		}
+
+		let signature = from_hex(signature)?;
+
+		self.mac(id).verify_slice(&signature).ok().map(|_| id.to_string())
+	}
+
+	/// Returns the session ID of the request's `Cookie` header, or of the last session cookie
+	/// in `set_cookies` (the `Set-Cookie` values of the response so far) when there is one
+	pub fn find_id(&self, cookie_header: Option<&String>, set_cookies: &[String]) -> Option<String> {
+		let set = set_cookies.iter().rev()
+			.filter_map(|c| c.split(';').next().and_then(|pair| pair.split_once('=')))
+			.find(|(name, _)| name.trim() == self.cookie_name)
+			.map(|(_, value)| value.trim().to_string());
+
+		set.or_else(|| cookie_header.and_then(|header| parse_cookies(header).remove(&self.cookie_name)))
+			.and_then(|value| self.verify(&value))
+	}
+
+	/// Returns `cookie_header` with the session cookie replaced by the one of session `id`,
+	/// or removed if `id` is `None`
+	pub fn replace_cookie(&self, cookie_header: Option<&String>, id: Option<&str>) -> Option<String> {
+		let mut cookies: Vec<String> = cookie_header.map(|header| header.split(';')
+			.map(|c| c.trim())
+			.filter(|c| !c.is_empty() && c.split('=').next().map(|n| n.trim()) != Some(self.cookie_name.as_str()))
+			.map(|c| c.to_string())
+			.collect()
+		).unwrap_or_default();
+
+		if let Some(id) = id {
+			cookies.push(format!("{}={}", self.cookie_name, self.sign(id)));
+		}
+
+		if cookies.is_empty() { None } else { Some(cookies.join("; ")) }
+	}
+
+	/// `Set-Cookie` value for the session cookie, or one deleting it if `value` is `None`
+	pub fn cookie(&self, value: Option<&str>) -> Result<String, String> {
+		let options = CookieOptions {
+			path: Some("/".to_string()),
+			domain: None,
+			max_age: if value.is_none() { Some(0) } else { None },
+			expires: None,
+			secure: self.secure,
+			http_only: true,
+			same_site: Some(self.same_site.clone())
+		};
+
+		serialize_cookie(&self.cookie_name, value.unwrap_or(""), &options)
+	}
+
+	fn file_path(&self, folder: &Path, id: &str) -> PathBuf {
+		folder.join(format!("{}.session", id))
+	}
+
+	pub fn load(&self, id: &str) -> Result<Option<SessionRecord>, Error> {
+		let data = match &self.storage {
+			SessionStorage::Memory => {
+				let res = StoreClient::connect()?.call(OP_GET, &[Some(store_key(id).as_bytes())]).map_err(store_error)?;
+				match res.into_iter().next().flatten() {
+					Some(data) => data,
+					None => return Ok(None)
+				}
+			},
+			SessionStorage::File(folder) => {
+				// Files of sessions that were never loaded again would stay forever
+				if let Err(e) = sweep(folder) {
+					log_warn!("Session", "Can't delete expired sessions in {}: {:?}", folder.display(), e);
+				}
+
+				match fs::read(self.file_path(folder, id)) {
+					Ok(data) => data,
+					Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+					Err(e) => return Err(e)
+				}
+			}
+		};
+
+		let (record, expires) = parse_record(&data)?;
+		if expires <= now() {
+			self.delete(id)?;
+			return Ok(None);
+		}
+
+		Ok(Some(record))
+	}
+
+	/// Stores `record`, pushing the session's expiry back
+	pub fn save(&self, id: &str, record: &SessionRecord) -> Result<(), Error> {
+		let data = format!("{} {}\n{}", record.created, now() + self.expiry, record.data);
+
+		match &self.storage {
+			SessionStorage::Memory => {
+				let ttl = self.expiry.to_string();
+				let key = store_key(id);
+				StoreClient::connect()?.call(OP_SET, &[Some(key.as_bytes()), Some(data.as_bytes()), Some(ttl.as_bytes())]).map_err(store_error)?;
+			},
+			SessionStorage::File(folder) => {
+				fs::create_dir_all(folder)?;
+				let path = self.file_path(folder, id);
+				let tmp = path.with_extension("tmp");
+				fs::write(&tmp, data)?;
+				fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+				fs::rename(&tmp, &path)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn delete(&self, id: &str) -> Result<(), Error> {
+		match &self.storage {
+			SessionStorage::Memory => {
+				StoreClient::connect()?.call(OP_DELETE, &[Some(store_key(id).as_bytes())]).map_err(store_error)?;
+			},
+			SessionStorage::File(folder) => match fs::remove_file(self.file_path(folder, id)) {
+				Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+				_ => {}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// True if a session created at `created` is due for a new ID
+	pub fn needs_rotation(&self, created: u64) -> bool {
+		self.rotation.is_some_and(|r| now() >= created + r)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use mlua::{Lua, Value};
+	use crate::session::{new_session_id, SessionConfig, SessionRecord, SWEEP_FILE};
+
+	#[test]
+	pub fn test_sessions() {
+		let folder = std::env::temp_dir().join(format!("jwx_session_test_{}", std::process::id()));
+		let lua = Lua::new();
+		let options: Value = lua.load(format!("{{ secret = 'abc', storage = 'file', path = '{}' }}", folder.display())).eval().unwrap();
+		let config = SessionConfig::from_option(options).unwrap().unwrap();
+
+		let id = new_session_id().unwrap();
+		let cookie = config.sign(&id);
+		assert_eq!(config.verify(&cookie).unwrap(), id);
+		assert!(config.verify(&format!("{}0", cookie)).is_none());
+		assert!(config.verify(&cookie.replace('.', "")).is_none());
+
+		let other = SessionConfig::from_option(Value::Boolean(true)).unwrap().unwrap();
+		assert!(other.generated_secret);
+		assert!(other.verify(&cookie).is_none());
+
+		assert!(config.load(&id).unwrap().is_none());
+		config.save(&id, &SessionRecord { created: 5, data: "{\"user\":\"alice\"}".to_string() }).unwrap();
+		let record = config.load(&id).unwrap().unwrap();
+		assert_eq!(record.created, 5);
+		assert_eq!(record.data, "{\"user\":\"alice\"}");
+
+		config.delete(&id).unwrap();
+		assert!(config.load(&id).unwrap().is_none());
+
+		assert!(config.cookie(Some(&cookie)).unwrap().contains("HttpOnly"));
+		assert!(config.cookie(None).unwrap().contains("Max-Age=0"));
+
+		let (expired, kept) = (new_session_id().unwrap(), new_session_id().unwrap());
+		fs::write(folder.join(format!("{}.session", expired)), "1 2\n{}").unwrap();
+		config.save(&kept, &SessionRecord { created: 5, data: "{}".to_string() }).unwrap();
+		fs::remove_file(folder.join(SWEEP_FILE)).unwrap();
+		assert!(config.load(&id).unwrap().is_none());
+		assert!(!folder.join(format!("{}.session", expired)).exists());
+		assert!(config.load(&kept).unwrap().is_some());
+
+		let header = format!("a=1; jwx_session={}; b=2", cookie);
+		assert_eq!(config.find_id(Some(&header), &[]).unwrap(), id);
+		let rotated = new_session_id().unwrap();
+		let set_cookies = vec!["a=3; Path=/".to_string(), config.cookie(Some(&config.sign(&rotated))).unwrap()];
+		assert_eq!(config.find_id(Some(&header), &set_cookies).unwrap(), rotated);
+		assert!(config.find_id(Some(&header), &[config.cookie(None).unwrap()]).is_none());
+
+		let replaced = config.replace_cookie(Some(&header), Some(&rotated)).unwrap();
+		assert_eq!(replaced, format!("a=1; b=2; jwx_session={}", config.sign(&rotated)));
+		assert_eq!(config.replace_cookie(Some(&header), None).unwrap(), "a=1; b=2");
+		assert!(config.replace_cookie(None, None).is_none());
+
+		fs::remove_dir_all(&folder).unwrap();
+	}
+}
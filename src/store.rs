@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::utils::{random_bytes, to_hex};
+
+/// How often a changed store is written to its persistence file
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest TTL accepted, in seconds (100 years)
+const MAX_TTL: f64 = 100.0 * 365.0 * 86400.0;
+/// Largest key or value, so a corrupt persistence file can't make us allocate without limit
+const MAX_FIELD_SIZE: usize = 64 * 1024 * 1024;
+
+pub const OP_GET: u8 = b'g';
+pub const OP_SET: u8 = b's';
+pub const OP_DELETE: u8 = b'd';
+pub const OP_INCR: u8 = b'i';
+pub const OP_CAS: u8 = b'c';
+
+/// Keys starting with this hold the server's own data (memory sessions). `jwx.store` refuses
+/// them, so scripts can't read or forge that data.
+pub const RESERVED_PREFIX: &str = "\0jwx:";
+
+const STATUS_OK: u8 = b'o';
+const STATUS_ERROR: u8 = b'e';
+
+/// Socket the store is served on, set before the dispatcher is forked so workers inherit it
+static SOCKET_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+struct Entry {
+	value: Vec<u8>,
+	/// Unix time in milliseconds
+	expires: Option<u64>
+}
+
+impl Entry {
+	fn is_expired(&self, now: u64) -> bool {
+		self.expires.is_some_and(|e| e <= now)
+	}
+}
+
+fn now_ms() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn expiry(ttl: Option<f64>) -> Option<u64> {
+	ttl.map(|t| now_ms().saturating_add((t.clamp(0.0, MAX_TTL) * 1000.0) as u64))
+}
+
+/// Key-value data shared by all workers, owned by the listener process
+#[derive(Default)]
+pub struct Store {
+	entries: HashMap<String, Entry>,
+	dirty: bool
+}
+
+impl Store {
+	fn live(&self, key: &str) -> Option<&Entry> {
+		self.entries.get(key).filter(|e| !e.is_expired(now_ms()))
+	}
+
+	pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+		self.live(key).map(|e| e.value.clone())
+	}
+
+	/// Sets `key`, expiring after `ttl` seconds if given
+	pub fn set(&mut self, key: &str, value: Vec<u8>, ttl: Option<f64>) {
+		self.entries.insert(key.to_string(), Entry {
+			value,
+			expires: expiry(ttl)
+		});
+		self.dirty = true;
+	}
+
+	/// Returns whether the key existed
+	pub fn delete(&mut self, key: &str) -> bool {
+		let existed = self.live(key).is_some();
+		self.entries.remove(key);
+		self.dirty = true;
+
+		existed
+	}
+
+	/// Adds `by` to the integer stored at `key`, starting from 0. `ttl` only applies when the
+	/// key is created, so a counter expires a fixed time after its first increment.
+	pub fn incr(&mut self, key: &str, by: i64, ttl: Option<f64>) -> Result<i64, String> {
+		let (current, expires) = match self.live(key) {
+			Some(e) => {
+				let value = std::str::from_utf8(&e.value).ok()
+					.and_then(|v| v.parse::<i64>().ok())
+					.ok_or_else(|| format!("Value of '{}' is not an integer", key))?;
+				(value, e.expires)
+			},
+			None => (0, expiry(ttl))
+		};
+
+		let value = current.checked_add(by).ok_or_else(|| format!("Value of '{}' would overflow", key))?;
+		self.entries.insert(key.to_string(), Entry {
+			value: value.to_string().into_bytes(),
+			expires
+		});
+		self.dirty = true;
+
+		Ok(value)
+	}
+
+	/// Compare-and-set: replaces the value of `key` with `new` (deleting it if `None`) only if
+	/// its current value is `expected` (`None` meaning the key must not exist)
+	pub fn cas(&mut self, key: &str, expected: Option<&[u8]>, new: Option<Vec<u8>>, ttl: Option<f64>) -> bool {
+		if self.live(key).map(|e| e.value.as_slice()) != expected {
+			return false;
+		}
+
+		match new {
+			Some(value) => self.set(key, value, ttl),
+			None => {
+				self.delete(key);
+			}
+		}
+
+		true
+	}
+
+	pub fn purge_expired(&mut self) {
+		let now = now_ms();
+		let before = self.entries.len();
+		self.entries.retain(|_, e| !e.is_expired(now));
+		if self.entries.len() != before {
+			self.dirty = true;
+		}
+	}
+
+	/// Reads a store saved with `save`, skipping entries that expired in the meantime
+	pub fn load(path: &Path) -> Result<Store, Error> {
+		let mut reader = BufReader::new(File::open(path)?);
+		let mut store = Store::default();
+		let now = now_ms();
+
+		loop {
+			let fields = match read_fields(&mut reader) {
+				Ok(f) => f,
+				Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+				Err(e) => return Err(e)
+			};
+
+			let (key, value, expires) = match fields.as_slice() {
+				[Some(key), Some(value), expires] => (key, value, expires),
+				_ => return Err(Error::new(ErrorKind::InvalidData, "Invalid store entry"))
+			};
+
+			let expires = match expires {
+				Some(e) => Some(parse_field::<u64>(e).map_err(|e| Error::new(ErrorKind::InvalidData, e))?),
+				None => None
+			};
+
+			let entry = Entry {
+				value: value.clone(),
+				expires
+			};
+			if !entry.is_expired(now) {
+				store.entries.insert(String::from_utf8_lossy(key).to_string(), entry);
+			}
+		}
+
+		Ok(store)
+	}
+
+	/// Writes the store to `path`, through a temporary file so a crash can't leave it truncated
+	pub fn save(&mut self, path: &Path) -> Result<(), Error> {
+		self.purge_expired();
+
+		let tmp = path.with_extension("tmp");
+		let mut writer = BufWriter::new(File::create(&tmp)?);
+		for (key, entry) in &self.entries {
+			let expires = entry.expires.map(|e| e.to_string());
+			write_fields(&mut writer, &[Some(key.as_bytes()), Some(&entry.value), expires.as_ref().map(|e| e.as_bytes())])?;
+		}
+		writer.flush()?;
+		drop(writer);
+
+		fs::rename(&tmp, path)?;
+		self.dirty = false;
+
+		Ok(())
+	}
+}
+
+/// Writes a list of optional byte strings: a presence byte, then the length and data
+pub fn write_fields(writer: &mut impl Write, fields: &[Option<&[u8]>]) -> Result<(), Error> {
+	let mut buff = vec![fields.len() as u8];
+	for field in fields {
+		match field {
+			Some(data) if data.len() > MAX_FIELD_SIZE => {
+				return Err(Error::new(ErrorKind::InvalidInput, format!("Store values are limited to {} bytes", MAX_FIELD_SIZE)));
+			},
+			Some(data) => {
+				buff.push(1);
+				buff.extend_from_slice(&(data.len() as u64).to_ne_bytes());
+				buff.extend_from_slice(data);
+			},
+			None => buff.push(0)
+		}
+	}
+
+	writer.write_all(&buff)
+}
+
+pub fn read_fields(reader: &mut impl Read) -> Result<Vec<Option<Vec<u8>>>, Error> {
+	let mut count = [0u8; 1];
+	reader.read_exact(&mut count)?;
+
+	let mut res = Vec::with_capacity(count[0] as usize);
+	for _ in 0..count[0] {
+		let mut present = [0u8; 1];
+		reader.read_exact(&mut present)?;
+		if present[0] == 0 {
+			res.push(None);
+			continue;
+		}
+
+		let mut len = [0u8; 8];
+		reader.read_exact(&mut len)?;
+		let len = u64::from_ne_bytes(len);
+		if len > MAX_FIELD_SIZE as u64 {
+			return Err(Error::new(ErrorKind::InvalidData, format!("Field of {} bytes is over the {} bytes limit", len, MAX_FIELD_SIZE)));
+		}
+
+		let mut data = vec![0u8; len as usize];
+		reader.read_exact(&mut data)?;
+		res.push(Some(data));
+	}
+
+	Ok(res)
+}
+
+fn parse_field<T: std::str::FromStr>(data: &[u8]) -> Result<T, String> {
+	std::str::from_utf8(data).ok()
+		.and_then(|s| s.parse::<T>().ok())
+		.ok_or_else(|| format!("Invalid number '{}'", String::from_utf8_lossy(data)))
+}
+
+fn bool_field(value: bool) -> Option<Vec<u8>> {
+	Some(if value { b"1".to_vec() } else { b"0".to_vec() })
+}
+
+/// Runs one operation, as sent by `StoreClient::call`
+fn apply(store: &mut Store, op: u8, mut fields: Vec<Option<Vec<u8>>>) -> Result<Vec<Option<Vec<u8>>>, String> {
+	fields.resize(4, None);
+
+	let key = match &fields[0] {
+		Some(k) => String::from_utf8_lossy(k).to_string(),
+		None => return Err("Missing key".to_string())
+	};
+
+	let ttl = |field: &Option<Vec<u8>>| -> Result<Option<f64>, String> {
+		match field.as_ref().map(|f| parse_field::<f64>(f)).transpose()? {
+			Some(t) if !t.is_finite() || t > MAX_TTL => Err(format!("TTL out of range: {}", t)),
+			ttl => Ok(ttl)
+		}
+	};
+
+	match op {
+		OP_GET => Ok(vec![store.get(&key)]),
+		OP_SET => {
+			let value = fields[1].take().ok_or("Missing value")?;
+			store.set(&key, value, ttl(&fields[2])?);
+			Ok(Vec::new())
+		},
+		OP_DELETE => Ok(vec![bool_field(store.delete(&key))]),
+		OP_INCR => {
+			let by = match &fields[1] {
+				Some(f) => parse_field::<i64>(f)?,
+				None => 1
+			};
+			let value = store.incr(&key, by, ttl(&fields[2])?)?;
+			Ok(vec![Some(value.to_string().into_bytes())])
+		},
+		OP_CAS => {
+			let ttl = ttl(&fields[3])?;
+			let new = fields[2].take();
+			Ok(vec![bool_field(store.cas(&key, fields[1].as_deref(), new, ttl))])
+		},
+		_ => Err(format!("Unknown store operation '{}'", op as char))
+	}
+}
+
+fn handle_connection(mut stream: UnixStream, store: Arc<Mutex<Store>>) {
+	loop {
+		let mut op = [0u8; 1];
+		if stream.read_exact(&mut op).is_err() {
+			return;
+		}
+
+		let fields = match read_fields(&mut stream) {
+			Ok(f) => f,
+			Err(_) => return
+		};
+
+		let res = match store.lock() {
+			Ok(mut s) => apply(&mut s, op[0], fields),
+			Err(_) => Err("Store unavailable".to_string())
+		};
+
+		let sent = match res {
+			Ok(fields) => {
+				let fields: Vec<Option<&[u8]>> = fields.iter().map(|f| f.as_deref()).collect();
+				stream.write_all(&[STATUS_OK]).and_then(|_| write_fields(&mut stream, &fields))
+			},
+			Err(e) => stream.write_all(&[STATUS_ERROR]).and_then(|_| write_fields(&mut stream, &[Some(e.as_bytes())]))
+		};
+
+		if sent.is_err() {
+			return;
+		}
+	}
+}
+
+/// Creates the socket workers reach the store through. It lives in a new directory with an
+/// unguessable name that only this user can enter, so no one else can connect, not even
+/// before the socket's own permissions could be set. Must be called before forking the
+/// dispatcher.
+pub fn bind() -> Result<UnixListener, Error> {
+	let mut suffix = [0u8; 8];
+	random_bytes(&mut suffix)?;
+	let dir = std::env::temp_dir().join(format!("jwx_{}_{}", std::process::id(), to_hex(&suffix)));
+	fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+	let path = dir.join("store.sock");
+	let listener = UnixListener::bind(&path)?;
+	_ = SOCKET_PATH.set(path);
+
+	Ok(listener)
+}
+
+/// Removes the socket and its directory, on exit
+pub fn remove_socket() {
+	if let Some(path) = SOCKET_PATH.get() {
+		_ = fs::remove_file(path);
+		if let Some(dir) = path.parent() {
+			_ = fs::remove_dir(dir);
+		}
+	}
+}
+
+/// Serves the store in background threads, loading it from `persist_path` first and saving
+/// it there whenever it changed, every `SAVE_INTERVAL`. Returns the store so it can be saved
+/// one last time on exit.
+pub fn start(listener: UnixListener, persist_path: Option<PathBuf>) -> Arc<Mutex<Store>> {
+	let store = match &persist_path {
+		Some(path) if path.exists() => match Store::load(path) {
+			Ok(s) => {
+				log_info!("Store", "Loaded {} entries from {}", s.entries.len(), path.display());
+				s
+			},
+			Err(e) => {
+				log_error!("Store", "Error loading {}: {:?}", path.display(), e);
+				Store::default()
+			}
+		},
+		_ => Store::default()
+	};
+	let store = Arc::new(Mutex::new(store));
+
+	let s = store.clone();
+	thread::spawn(move || {
+		for stream in listener.incoming() {
+			match stream {
+				Ok(stream) => {
+					let s = s.clone();
+					thread::spawn(move || handle_connection(stream, s));
+				},
+				Err(e) => log_error!("Store", "accept error = {:?}", e)
+			}
+		}
+	});
+
+	let s = store.clone();
+	thread::spawn(move || {
+		loop {
+			thread::sleep(SAVE_INTERVAL);
+
+			let mut store = match s.lock() {
+				Ok(store) => store,
+				Err(_) => return
+			};
+
+			store.purge_expired();
+			if let (Some(path), true) = (&persist_path, store.dirty) {
+				if let Err(e) = store.save(path) {
+					log_error!("Store", "Error saving {}: {:?}", path.display(), e);
+				}
+			}
+		}
+	});
+
+	store
+}
+
+/// Connection from a worker to the store
+pub struct StoreClient {
+	stream: UnixStream,
+	/// Process that connected: a child forked from it shares the stream, and must not use it
+	pid: libc::pid_t
+}
+
+impl StoreClient {
+	pub fn connect() -> Result<StoreClient, Error> {
+		let path = match SOCKET_PATH.get() {
+			Some(p) => p,
+			None => return Err(Error::new(ErrorKind::NotFound, "The store is not running"))
+		};
+
+		Ok(StoreClient {
+			stream: UnixStream::connect(path)?,
+			pid: unsafe { libc::getpid() }
+		})
+	}
+
+	/// False in a process forked after the connection was made
+	pub fn is_owned(&self) -> bool {
+		self.pid == unsafe { libc::getpid() }
+	}
+
+	/// Sends one operation and waits for its result. `Err(Ok(message))` is an error reported
+	/// by the store (e.g. incrementing a non-integer), `Err(Err(e))` a broken connection.
+	pub fn call(&mut self, op: u8, fields: &[Option<&[u8]>]) -> Result<Vec<Option<Vec<u8>>>, Result<String, Error>> {
+		let mut request = vec![op];
+		write_fields(&mut request, fields).map_err(Err)?;
+		self.stream.write_all(&request).map_err(Err)?;
+
+		let mut status = [0u8; 1];
+		self.stream.read_exact(&mut status).map_err(Err)?;
+		let res = read_fields(&mut self.stream).map_err(Err)?;
+
+		match status[0] {
+			STATUS_OK => Ok(res),
+			_ => {
+				let message = res.into_iter().next().flatten().unwrap_or_default();
+				Err(Ok(String::from_utf8_lossy(&message).to_string()))
+			}
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use crate::store::{apply, read_fields, write_fields, Store, MAX_FIELD_SIZE, OP_CAS, OP_INCR, OP_SET};
+
+	#[test]
+	pub fn test_store() {
+		let mut store = Store::default();
+
+		store.set("a", b"1".to_vec(), None);
+		assert_eq!(store.get("a").unwrap(), b"1");
+		assert_eq!(store.incr("a", 4, None).unwrap(), 5);
+		assert_eq!(store.incr("new", -2, None).unwrap(), -2);
+		store.set("text", b"x".to_vec(), None);
+		assert!(store.incr("text", 1, None).is_err());
+
+		assert!(!store.cas("a", Some(b"1"), Some(b"2".to_vec()), None));
+		assert!(store.cas("a", Some(b"5"), Some(b"6".to_vec()), None));
+		assert!(store.cas("b", None, Some(b"x".to_vec()), None));
+		assert!(!store.cas("b", None, Some(b"y".to_vec()), None));
+		assert!(store.cas("b", Some(b"x"), None, None));
+		assert!(store.get("b").is_none());
+
+		store.set("gone", b"x".to_vec(), Some(0.0));
+		assert!(store.get("gone").is_none());
+		assert!(!store.delete("gone"));
+		assert!(store.delete("a"));
+
+		let res = apply(&mut store, OP_INCR, vec![Some(b"n".to_vec()), None, Some(b"60".to_vec())]).unwrap();
+		assert_eq!(res, vec![Some(b"1".to_vec())]);
+		let res = apply(&mut store, OP_CAS, vec![Some(b"n".to_vec()), Some(b"1".to_vec()), Some(b"9".to_vec())]).unwrap();
+		assert_eq!(res, vec![Some(b"1".to_vec())]);
+		assert!(apply(&mut store, OP_INCR, vec![Some(b"n".to_vec()), Some(b"x".to_vec())]).is_err());
+
+		for ttl in ["inf", "NaN", "1e300"] {
+			assert!(apply(&mut store, OP_SET, vec![Some(b"t".to_vec()), Some(b"x".to_vec()), Some(ttl.as_bytes().to_vec())]).is_err());
+		}
+		store.set("long", b"x".to_vec(), Some(f64::MAX));
+		assert_eq!(store.get("long").unwrap(), b"x");
+
+		let path = std::env::temp_dir().join(format!("jwx_store_test_{}.bin", std::process::id()));
+		store.save(&path).unwrap();
+		let loaded = Store::load(&path).unwrap();
+		assert_eq!(loaded.get("n").unwrap(), b"9");
+		assert_eq!(loaded.get("new").unwrap(), b"-2");
+		assert!(loaded.get("gone").is_none());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	pub fn test_field_size_limit() {
+		let mut data = vec![1u8, 1];
+		data.extend_from_slice(&u64::MAX.to_ne_bytes());
+		assert!(read_fields(&mut data.as_slice()).is_err());
+
+		let big = vec![0u8; MAX_FIELD_SIZE + 1];
+		assert!(write_fields(&mut Vec::new(), &[Some(&big)]).is_err());
+
+		let mut buff = Vec::new();
+		write_fields(&mut buff, &[Some(b"key"), None]).unwrap();
+		assert_eq!(read_fields(&mut buff.as_slice()).unwrap(), vec![Some(b"key".to_vec()), None]);
+	}
+}
@@ -0,0 +1,327 @@
+use std::fs::File;
+use std::io::Error;
+use std::os::fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use libc::{c_int, pid_t};
+use crate::config::lua_config::ConfigMgr;
+use crate::dispatcher::run_lua_dispatcher;
+use crate::ipc::request_pipe::RequestPipe;
+use crate::ipc::{read_message_with_fds, send_message_with_fds, IpcMessage, IpcMessageSender};
+use crate::log;
+use crate::reload::{drain, forward_sighup, set_nonblocking};
+use crate::utils::{new_pipe, safe_fork, ForkResult};
+
+/// Wait before the first restart of a crashed dispatcher, doubled on every crash in a row
+const MIN_RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+/// A dispatcher that ran that long is considered healthy, the restart delay starts over
+const STABLE_TIME: Duration = Duration::from_secs(60);
+
+/// Write end of the pipe signalling SIGCHLD (set in the dispatcher)
+static SIGCHLD_FD: AtomicI32 = AtomicI32::new(-1);
+/// Set in the listener when it shuts down, so the supervisor exiting is expected
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+/// Write end of the pipe signalling SIGTERM and SIGINT (set in the listener)
+static TERMINATE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigchld(_: c_int) {
+	let fd = SIGCHLD_FD.load(Ordering::SeqCst);
+	if fd >= 0 {
+		let byte = 1u8;
+		unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+	}
+}
+
+/// Returns a pipe that becomes readable every time a child of the process exits
+pub fn sigchld_notifier() -> Result<File, Error> {
+	let (recv, send) = new_pipe()?;
+	set_nonblocking(recv.as_raw_fd())?;
+	set_nonblocking(send.as_raw_fd())?;
+
+	// The write end lives as long as the process, the signal handler may use it at any time
+	SIGCHLD_FD.store(send.into_raw_fd(), Ordering::SeqCst);
+
+	unsafe {
+		let mut action: libc::sigaction = std::mem::zeroed();
+		action.sa_sigaction = on_sigchld as extern "C" fn(c_int) as usize;
+		action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+		libc::sigemptyset(&mut action.sa_mask);
+
+		if libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) != 0 {
+			return Err(Error::last_os_error());
+		}
+	}
+
+	Ok(recv)
+}
+
+extern "C" fn on_terminate(_: c_int) {
+	let fd = TERMINATE_FD.load(Ordering::SeqCst);
+	if fd >= 0 {
+		let byte = 1u8;
+		unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+	}
+}
+
+/// Returns a pipe that becomes readable when the process is asked to stop with SIGTERM or
+/// SIGINT, instead of being killed on the spot
+pub fn termination_notifier() -> Result<File, Error> {
+	let (recv, send) = new_pipe()?;
+	set_nonblocking(send.as_raw_fd())?;
+
+	// The write end lives as long as the process, the signal handler may use it at any time
+	TERMINATE_FD.store(send.into_raw_fd(), Ordering::SeqCst);
+
+	for signal in [libc::SIGTERM, libc::SIGINT] {
+		unsafe {
+			let mut action: libc::sigaction = std::mem::zeroed();
+			action.sa_sigaction = on_terminate as extern "C" fn(c_int) as usize;
+			action.sa_flags = libc::SA_RESTART;
+			libc::sigemptyset(&mut action.sa_mask);
+
+			if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+				return Err(Error::last_os_error());
+			}
+		}
+	}
+
+	Ok(recv)
+}
+
+/// Describes a status returned by `waitpid`, `None` for a normal exit
+fn abnormal_exit(status: c_int) -> Option<String> {
+	if libc::WIFSIGNALED(status) {
+		Some(format!("was killed by signal {}", libc::WTERMSIG(status)))
+	} else if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) != 0 {
+		Some(format!("exited with status {}", libc::WEXITSTATUS(status)))
+	} else {
+		None
+	}
+}
+
+/// Collects every child that has exited, logging those that didn't exit normally. Returns
+/// their pids.
+pub fn reap_children() -> Vec<pid_t> {
+	let mut exited = Vec::new();
+	loop {
+		let mut status: c_int = 0;
+		let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+		if pid <= 0 {
+			break;
+		}
+
+		if let Some(reason) = abnormal_exit(status) {
+			log_warn!("Dispatcher", "Worker {} {}", pid, reason);
+		}
+		exited.push(pid);
+	}
+
+	exited
+}
+
+/// Blocks until process `pid` exits, returning its status
+fn wait_for(pid: pid_t) -> Result<c_int, Error> {
+	loop {
+		let mut status: c_int = 0;
+		if unsafe { libc::waitpid(pid, &mut status, 0) } >= 0 {
+			return Ok(status);
+		}
+
+		let e = Error::last_os_error();
+		if e.kind() != std::io::ErrorKind::Interrupted {
+			return Err(e);
+		}
+	}
+}
+
+/// What the supervisor wakes up for
+enum Event {
+	/// The dispatcher exited, with this status
+	Exited(c_int),
+	/// The listener is shutting down (or gone)
+	ShutDown,
+	Timeout
+}
+
+/// Waits for the dispatcher `pid` to exit or the listener to shut down, for at most `timeout`
+fn wait_for_event(pid: pid_t, sigchld: &mut File, control: &mut UnixStream, timeout: Option<Duration>) -> Result<Event, Error> {
+	let deadline = timeout.map(|t| Instant::now() + t);
+	loop {
+		// SIGCHLD may have come before the notifier was read, look first
+		let mut status: c_int = 0;
+		if pid > 0 && unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } == pid {
+			return Ok(Event::Exited(status));
+		}
+
+		let remaining = match deadline {
+			Some(d) if Instant::now() >= d => return Ok(Event::Timeout),
+			Some(d) => (d - Instant::now()).as_millis() as c_int + 1,
+			None => -1
+		};
+
+		let mut fds = [
+			libc::pollfd { fd: sigchld.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+			libc::pollfd { fd: control.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+		];
+		if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, remaining) } < 0 {
+			let e = Error::last_os_error();
+			if e.kind() != std::io::ErrorKind::Interrupted {
+				return Err(e);
+			}
+			continue;
+		}
+
+		if fds[0].revents != 0 {
+			drain(sigchld);
+		}
+
+		// The listener only ever sends `Close`, or goes away
+		if fds[1].revents != 0 {
+			return Ok(Event::ShutDown);
+		}
+	}
+}
+
+/// Runs a dispatcher in a process forked by the supervisor, serving the requests sent over
+/// `socket`. It closes `inherited`, the supervisor's own descriptors.
+fn run_dispatcher(config_mgr: ConfigMgr, socket: UnixStream, inherited: &[RawFd]) -> ! {
+	for fd in inherited {
+		unsafe { libc::close(*fd) };
+	}
+
+	// Signals go to the dispatcher's own handlers, not on to itself
+	_ = forward_sighup(0);
+	_ = log::reopen_on_sigusr1(None);
+
+	let res = run_lua_dispatcher(config_mgr, socket);
+	if let Err(e) = &res {
+		log_error!("Dispatcher", "{}", e);
+	}
+	std::process::exit(if res.is_ok() { 0 } else { 1 });
+}
+
+/// Forks a dispatcher once the previous one exited. It runs the config again, which may have
+/// been fixed meanwhile.
+fn restart_dispatcher(config_mgr: &ConfigMgr, socket: UnixStream, inherited: &[RawFd]) -> Result<pid_t, Error> {
+	match safe_fork()? {
+		ForkResult::Child => match config_mgr.reload() {
+			Ok(mgr) => {
+				if let Err(e) = log::init(mgr.get_log()) {
+					log_error!("Dispatcher", "Can't open log file {:?}: {:?}", mgr.get_log().file, e);
+				}
+				run_dispatcher(mgr, socket, inherited)
+			},
+			Err(e) => {
+				log_error!("Dispatcher", "{}", e);
+				std::process::exit(1);
+			}
+		},
+		ForkResult::Parent(pid) => Ok(dispatcher_started(pid))
+	}
+}
+
+/// SIGHUP and SIGUSR1 come from the listener, they are meant for the dispatcher `pid`
+fn dispatcher_started(pid: pid_t) -> pid_t {
+	if let Err(e) = forward_sighup(pid) {
+		log_warn!("Supervisor", "Can't forward SIGHUP to the dispatcher: {:?}", e);
+	}
+	if let Err(e) = log::reopen_on_sigusr1(Some(pid)) {
+		log_warn!("Supervisor", "Can't reopen log files on SIGUSR1: {:?}", e);
+	}
+
+	pid
+}
+
+/// Runs the supervisor, a process forked from the listener before it starts any thread, so
+/// the dispatchers forked from it don't inherit locks held by threads that don't exist there.
+/// It starts the dispatcher with `socket` and restarts it whenever it exits, waiting longer
+/// after every crash in a row. Over `control`, the listener is told when the dispatcher is
+/// down (`Close`, dynamic requests are answered with a 503 meanwhile) and gets its end of the
+/// socket of every new dispatcher (`Ok`); it sends `Close` when shutting down.
+pub fn run_supervisor(config_mgr: ConfigMgr, socket: UnixStream, mut control: UnixStream) -> Result<(), Error> {
+	let mut sigchld = sigchld_notifier()?;
+	let inherited = [sigchld.as_raw_fd(), control.as_raw_fd(), SIGCHLD_FD.load(Ordering::SeqCst)];
+
+	let mut pid = match safe_fork()? {
+		ForkResult::Child => run_dispatcher(config_mgr, socket, &inherited),
+		ForkResult::Parent(pid) => {
+			drop(socket);
+			dispatcher_started(pid)
+		}
+	};
+	let mut delay = MIN_RESTART_DELAY;
+	loop {
+		let started = Instant::now();
+		let status = match wait_for_event(pid, &mut sigchld, &mut control, None)? {
+			Event::Exited(status) => status,
+			_ => break
+		};
+
+		_ = control.send_message(IpcMessage::Close);
+
+		if started.elapsed() >= STABLE_TIME {
+			delay = MIN_RESTART_DELAY;
+		}
+
+		let reason = abnormal_exit(status).unwrap_or("exited".to_string());
+		log_error!("Supervisor", "Dispatcher {} {}, restarting it in {:?}", pid, reason, delay);
+		_ = forward_sighup(0);
+
+		pid = loop {
+			if let Event::ShutDown = wait_for_event(0, &mut sigchld, &mut control, Some(delay))? {
+				return Ok(());
+			}
+			delay = (delay * 2).min(MAX_RESTART_DELAY);
+
+			let (listener_socket, dispatcher_socket) = UnixStream::pair()?;
+			match restart_dispatcher(&config_mgr, dispatcher_socket, &inherited) {
+				Ok(new_pid) => {
+					send_message_with_fds(&mut control, IpcMessage::Ok, &[listener_socket.as_raw_fd()])?;
+					break new_pid;
+				},
+				Err(e) => log_error!("Supervisor", "Can't restart the dispatcher, trying again in {:?}: {:?}", delay, e)
+			}
+		};
+
+		log_info!("Supervisor", "Dispatcher restarted as process {}", pid);
+	}
+
+	// The listener is shutting down and tells the dispatcher to exit as well
+	_ = wait_for(pid);
+
+	Ok(())
+}
+
+/// Follows the supervisor from the listener: disconnects `pipe` while the dispatcher is down
+/// and connects it to every new dispatcher
+pub fn watch(mut control: UnixStream, pipe: Arc<RequestPipe>) -> JoinHandle<()> {
+	thread::spawn(move || {
+		loop {
+			match read_message_with_fds(&mut control) {
+				Ok((IpcMessage::Close, _)) => pipe.disconnect(),
+				Ok((IpcMessage::Ok, fds)) => match fds.into_iter().next() {
+					Some(socket) => pipe.connect(UnixStream::from(OwnedFd::from(socket))),
+					None => log_error!("Supervisor", "New dispatcher came without its socket")
+				},
+				Ok(_) => {},
+				Err(_) => {
+					if !SHUTTING_DOWN.load(Ordering::SeqCst) {
+						log_error!("Supervisor", "Supervisor exited, the dispatcher won't be restarted");
+					}
+					return;
+				}
+			}
+		}
+	})
+}
+
+/// Tells the supervisor the listener is shutting down, so the dispatcher exiting is expected
+pub fn shut_down(control: &mut UnixStream) {
+	SHUTTING_DOWN.store(true, Ordering::SeqCst);
+	_ = control.send_message(IpcMessage::Close);
+}
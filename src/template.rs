@@ -0,0 +1,820 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::time::SystemTime;
+use mlua::{Lua, Table, Value};
+use crate::utils::escape_html;
+
+/// Depth of nested includes and layouts, past which a template is assumed to include itself
+const MAX_DEPTH: usize = 32;
+
+thread_local! {
+	/// Compiled templates of this worker, with the modification time of their file
+	static CACHE: RefCell<HashMap<PathBuf, (SystemTime, Rc<Template>)>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub struct TemplateError {
+	pub template: String,
+	pub line: usize,
+	pub message: String
+}
+
+impl Display for TemplateError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		if self.line > 0 {
+			write!(f, "{}:{}: {}", self.template, self.line, self.message)
+		} else {
+			write!(f, "{}: {}", self.template, self.message)
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+	Str(String),
+	Number(f64),
+	Bool(bool),
+	Nil,
+	/// A variable followed by fields (`user.name`) or indexes (`items.1`)
+	Path(Vec<String>),
+	Not(Box<Expr>),
+	And(Box<Expr>, Box<Expr>),
+	Or(Box<Expr>, Box<Expr>),
+	Compare(String, Box<Expr>, Box<Expr>)
+}
+
+#[derive(Debug)]
+enum Node {
+	Text(String),
+	Output { expr: Expr, filters: Vec<String>, line: usize },
+	If { branches: Vec<(Expr, Vec<Node>)>, otherwise: Vec<Node> },
+	For { key: Option<String>, value: String, iterable: Expr, body: Vec<Node>, otherwise: Vec<Node>, line: usize },
+	Include { name: String, line: usize },
+	Block { name: String }
+}
+
+/// A compiled template
+#[derive(Debug)]
+pub struct Template {
+	name: String,
+	extends: Option<String>,
+	nodes: Vec<Node>,
+	blocks: HashMap<String, Vec<Node>>
+}
+
+enum Token {
+	Text(String),
+	Output(String, usize),
+	Tag(String, usize)
+}
+
+fn tokenize(name: &str, source: &str) -> Result<Vec<Token>, TemplateError> {
+	let mut res = Vec::new();
+	let mut rest = source;
+	let mut line = 1;
+
+	while !rest.is_empty() {
+		let start = match rest.find('{') {
+			Some(idx) => idx,
+			None => {
+				res.push(Token::Text(rest.to_string()));
+				break;
+			}
+		};
+
+		let close = match rest[start..].chars().nth(1) {
+			Some('{') => "}}",
+			Some('%') => "%}",
+			Some('#') => "#}",
+			_ => {
+				res.push(Token::Text(rest[..start + 1].to_string()));
+				line += rest[..start + 1].matches('\n').count();
+				rest = &rest[start + 1..];
+				continue;
+			}
+		};
+
+		if start > 0 {
+			res.push(Token::Text(rest[..start].to_string()));
+			line += rest[..start].matches('\n').count();
+		}
+
+		let end = rest[start + 2..].find(close).ok_or(TemplateError {
+			template: name.to_string(),
+			line,
+			message: format!("Unclosed tag, expected '{}'", close)
+		})? + start + 2;
+		let inner = rest[start + 2..end].trim().to_string();
+		let tag_line = line;
+		line += rest[start..end].matches('\n').count();
+		rest = &rest[end + 2..];
+
+		match close {
+			"}}" => res.push(Token::Output(inner, tag_line)),
+			"%}" => {
+				res.push(Token::Tag(inner, tag_line));
+				// A newline right after a statement tag is dropped, so tags on their own line
+				// don't leave blank lines behind
+				if let Some(stripped) = rest.strip_prefix('\n') {
+					rest = stripped;
+					line += 1;
+				}
+			},
+			_ => {}
+		}
+	}
+
+	Ok(res)
+}
+
+struct ExprParser<'a> {
+	tokens: Vec<&'a str>,
+	idx: usize
+}
+
+fn split_expr(source: &str) -> Result<Vec<&str>, String> {
+	let mut res = Vec::new();
+	let bytes = source.as_bytes();
+	let mut idx = 0;
+
+	while idx < bytes.len() {
+		let c = bytes[idx];
+		let start = idx;
+		if c.is_ascii_whitespace() {
+			idx += 1;
+			continue;
+		}
+
+		if c == b'"' || c == b'\'' {
+			idx += 1;
+			while idx < bytes.len() && bytes[idx] != c {
+				idx += 1;
+			}
+			if idx == bytes.len() {
+				return Err("Unclosed string".to_string());
+			}
+			idx += 1;
+		} else if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' {
+			while idx < bytes.len() && (bytes[idx].is_ascii_alphanumeric() || bytes[idx] == b'_' || bytes[idx] == b'.') {
+				idx += 1;
+			}
+		} else if source[idx..].starts_with("==") || source[idx..].starts_with("!=") || source[idx..].starts_with("<=") || source[idx..].starts_with(">=") {
+			idx += 2;
+		} else if b"<>()|,".contains(&c) {
+			idx += 1;
+		} else {
+			return Err(format!("Unexpected character '{}'", c as char));
+		}
+
+		res.push(&source[start..idx]);
+	}
+
+	Ok(res)
+}
+
+impl<'a> ExprParser<'a> {
+	fn new(source: &'a str) -> Result<ExprParser<'a>, String> {
+		Ok(ExprParser {
+			tokens: split_expr(source)?,
+			idx: 0
+		})
+	}
+
+	fn peek(&self) -> Option<&'a str> {
+		self.tokens.get(self.idx).copied()
+	}
+
+	fn next(&mut self) -> Option<&'a str> {
+		let res = self.peek();
+		self.idx += 1;
+		res
+	}
+
+	fn expect(&mut self, token: &str) -> Result<(), String> {
+		match self.next() {
+			Some(t) if t == token => Ok(()),
+			Some(t) => Err(format!("Expected '{}', got '{}'", token, t)),
+			None => Err(format!("Expected '{}'", token))
+		}
+	}
+
+	fn finish(&self) -> Result<(), String> {
+		match self.peek() {
+			Some(t) => Err(format!("Unexpected '{}'", t)),
+			None => Ok(())
+		}
+	}
+
+	fn parse_or(&mut self) -> Result<Expr, String> {
+		let mut res = self.parse_and()?;
+		while self.peek() == Some("or") {
+			self.next();
+			res = Expr::Or(Box::new(res), Box::new(self.parse_and()?));
+		}
+
+		Ok(res)
+	}
+
+	fn parse_and(&mut self) -> Result<Expr, String> {
+		let mut res = self.parse_not()?;
+		while self.peek() == Some("and") {
+			self.next();
+			res = Expr::And(Box::new(res), Box::new(self.parse_not()?));
+		}
+
+		Ok(res)
+	}
+
+	fn parse_not(&mut self) -> Result<Expr, String> {
+		if self.peek() == Some("not") {
+			self.next();
+			return Ok(Expr::Not(Box::new(self.parse_not()?)));
+		}
+
+		let left = self.parse_primary()?;
+		match self.peek() {
+			Some(op) if ["==", "!=", "<", ">", "<=", ">="].contains(&op) => {
+				self.next();
+				Ok(Expr::Compare(op.to_string(), Box::new(left), Box::new(self.parse_primary()?)))
+			},
+			_ => Ok(left)
+		}
+	}
+
+	fn parse_primary(&mut self) -> Result<Expr, String> {
+		let token = self.next().ok_or("Expected an expression".to_string())?;
+
+		if token == "(" {
+			let res = self.parse_or()?;
+			self.expect(")")?;
+			return Ok(res);
+		}
+
+		if token.starts_with('"') || token.starts_with('\'') {
+			return Ok(Expr::Str(token[1..token.len() - 1].to_string()));
+		}
+
+		if token.starts_with(|c: char| c.is_ascii_digit()) {
+			return token.parse::<f64>().map(Expr::Number).map_err(|_| format!("Invalid number '{}'", token));
+		}
+
+		match token {
+			"true" => return Ok(Expr::Bool(true)),
+			"false" => return Ok(Expr::Bool(false)),
+			"nil" => return Ok(Expr::Nil),
+			_ => {}
+		}
+
+		let path: Vec<String> = token.split('.').map(|s| s.to_string()).collect();
+		if path.iter().any(|s| s.is_empty()) || !token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+			return Err(format!("Invalid name '{}'", token));
+		}
+
+		Ok(Expr::Path(path))
+	}
+}
+
+fn parse_expr(source: &str) -> Result<Expr, String> {
+	let mut parser = ExprParser::new(source)?;
+	let res = parser.parse_or()?;
+	parser.finish()?;
+
+	Ok(res)
+}
+
+/// `expr | filter | filter`
+fn parse_output(source: &str) -> Result<(Expr, Vec<String>), String> {
+	let mut parser = ExprParser::new(source)?;
+	let expr = parser.parse_or()?;
+
+	let mut filters = Vec::new();
+	while parser.peek() == Some("|") {
+		parser.next();
+		let filter = parser.next().ok_or("Expected a filter name".to_string())?;
+		if !["raw", "upper", "lower", "trim", "length"].contains(&filter) {
+			return Err(format!("Unknown filter '{}'", filter));
+		}
+		filters.push(filter.to_string());
+	}
+	parser.finish()?;
+
+	Ok((expr, filters))
+}
+
+/// Reads a quoted template name
+fn parse_name(source: &str) -> Result<String, String> {
+	match parse_expr(source)? {
+		Expr::Str(s) => Ok(s),
+		_ => Err("Expected a quoted template name".to_string())
+	}
+}
+
+/// Tag ending a body: its keyword, arguments and line
+type EndTag = (String, String, usize);
+
+struct Parser {
+	name: String,
+	tokens: std::vec::IntoIter<Token>,
+	extends: Option<String>,
+	blocks: HashMap<String, Vec<Node>>
+}
+
+impl Parser {
+	fn error<T>(&self, line: usize, message: String) -> Result<T, TemplateError> {
+		Err(TemplateError {
+			template: self.name.clone(),
+			line,
+			message
+		})
+	}
+
+	/// Parses nodes until one of the `end` tags, which is returned with its arguments
+	fn parse_nodes(&mut self, end: &[&str]) -> Result<(Vec<Node>, Option<EndTag>), TemplateError> {
+		let mut nodes = Vec::new();
+
+		while let Some(token) = self.tokens.next() {
+			let (tag, line) = match token {
+				Token::Text(text) => {
+					nodes.push(Node::Text(text));
+					continue;
+				},
+				Token::Output(source, line) => {
+					let (expr, filters) = match parse_output(&source) {
+						Ok(res) => res,
+						Err(e) => return self.error(line, e)
+					};
+					nodes.push(Node::Output { expr, filters, line });
+					continue;
+				},
+				Token::Tag(tag, line) => (tag, line)
+			};
+
+			let (keyword, args) = match tag.split_once(char::is_whitespace) {
+				Some((k, a)) => (k.to_string(), a.trim().to_string()),
+				None => (tag.clone(), String::new())
+			};
+
+			if end.contains(&keyword.as_str()) {
+				return Ok((nodes, Some((keyword, args, line))));
+			}
+
+			let node = match keyword.as_str() {
+				"if" => self.parse_if(&args, line)?,
+				"for" => self.parse_for(&args, line)?,
+				"include" => match parse_name(&args) {
+					Ok(name) => Node::Include { name, line },
+					Err(e) => return self.error(line, e)
+				},
+				"block" => {
+					if args.is_empty() || !args.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+						return self.error(line, format!("Invalid block name '{}'", args));
+					}
+					let (body, _) = self.parse_until(&["endblock"], "block", line)?;
+					if self.blocks.insert(args.clone(), body).is_some() {
+						return self.error(line, format!("Block '{}' is defined twice", args));
+					}
+					Node::Block { name: args }
+				},
+				"extends" => {
+					if self.extends.is_some() {
+						return self.error(line, "A template can only extend one layout".to_string());
+					}
+					match parse_name(&args) {
+						Ok(name) => self.extends = Some(name),
+						Err(e) => return self.error(line, e)
+					}
+					continue;
+				},
+				other => return self.error(line, format!("Unexpected tag '{}'", other))
+			};
+			nodes.push(node);
+		}
+
+		Ok((nodes, None))
+	}
+
+	fn parse_until(&mut self, end: &[&str], opening: &str, line: usize) -> Result<(Vec<Node>, EndTag), TemplateError> {
+		match self.parse_nodes(end)? {
+			(nodes, Some(tag)) => Ok((nodes, tag)),
+			(_, None) => self.error(line, format!("Unclosed '{}', expected {}", opening, end.join(" or ")))
+		}
+	}
+
+	fn parse_if(&mut self, condition: &str, line: usize) -> Result<Node, TemplateError> {
+		let mut branches = Vec::new();
+		let mut condition = condition.to_string();
+		let mut line = line;
+
+		loop {
+			let expr = match parse_expr(&condition) {
+				Ok(e) => e,
+				Err(e) => return self.error(line, e)
+			};
+			let (body, (tag, args, tag_line)) = self.parse_until(&["elif", "else", "endif"], "if", line)?;
+			branches.push((expr, body));
+
+			match tag.as_str() {
+				"elif" => {
+					condition = args;
+					line = tag_line;
+				},
+				"else" => {
+					let (otherwise, _) = self.parse_until(&["endif"], "else", tag_line)?;
+					return Ok(Node::If { branches, otherwise });
+				},
+				_ => return Ok(Node::If { branches, otherwise: Vec::new() })
+			}
+		}
+	}
+
+	/// `for value in expr` or `for key, value in expr`
+	fn parse_for(&mut self, args: &str, line: usize) -> Result<Node, TemplateError> {
+		let (vars, iterable) = match args.split_once(" in ") {
+			Some(parts) => parts,
+			None => return self.error(line, "Expected 'for <name> in <expression>'".to_string())
+		};
+
+		let vars: Vec<String> = vars.split(',').map(|v| v.trim().to_string()).collect();
+		if vars.len() > 2 || vars.iter().any(|v| v.is_empty() || !v.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+			return self.error(line, format!("Invalid loop variables '{}'", vars.join(",")));
+		}
+
+		let iterable = match parse_expr(iterable) {
+			Ok(e) => e,
+			Err(e) => return self.error(line, e)
+		};
+
+		let (body, (tag, _, tag_line)) = self.parse_until(&["else", "endfor"], "for", line)?;
+		let otherwise = if tag == "else" {
+			self.parse_until(&["endfor"], "else", tag_line)?.0
+		} else {
+			Vec::new()
+		};
+
+		let (key, value) = match vars.as_slice() {
+			[value] => (None, value.clone()),
+			[key, value] => (Some(key.clone()), value.clone()),
+			_ => unreachable!()
+		};
+
+		Ok(Node::For { key, value, iterable, body, otherwise, line })
+	}
+}
+
+impl Template {
+	pub fn compile(name: &str, source: &str) -> Result<Template, TemplateError> {
+		let mut parser = Parser {
+			name: name.to_string(),
+			tokens: tokenize(name, source)?.into_iter(),
+			extends: None,
+			blocks: HashMap::new()
+		};
+
+		let (nodes, end) = parser.parse_nodes(&[])?;
+		if let Some((tag, _, line)) = end {
+			return parser.error(line, format!("Unexpected tag '{}'", tag));
+		}
+
+		Ok(Template {
+			name: name.to_string(),
+			extends: parser.extends,
+			nodes,
+			blocks: parser.blocks
+		})
+	}
+}
+
+/// Path of template `name` in `folder`, refusing names that leave the folder
+fn resolve(folder: &Path, name: &str) -> Option<PathBuf> {
+	let path = Path::new(name);
+	if path.components().all(|c| matches!(c, Component::Normal(_))) {
+		Some(folder.join(path))
+	} else {
+		None
+	}
+}
+
+/// Returns the compiled template `name`, compiling it on first use or when its file changed
+pub fn load(folder: &Path, name: &str) -> Result<Rc<Template>, TemplateError> {
+	let error = |message: String| TemplateError {
+		template: name.to_string(),
+		line: 0,
+		message
+	};
+
+	let path = resolve(folder, name).ok_or(error("Invalid template name".to_string()))?;
+	let modified = fs::metadata(&path).and_then(|m| m.modified()).map_err(|e| error(e.to_string()))?;
+
+	if let Some(template) = CACHE.with_borrow(|cache| cache.get(&path).filter(|(time, _)| *time == modified).map(|(_, t)| t.clone())) {
+		return Ok(template);
+	}
+
+	let source = fs::read_to_string(&path).map_err(|e| error(e.to_string()))?;
+	let template = Rc::new(Template::compile(name, &source)?);
+	CACHE.with_borrow_mut(|cache| cache.insert(path, (modified, template.clone())));
+
+	Ok(template)
+}
+
+fn is_truthy(value: &Value) -> bool {
+	match value {
+		Value::Nil | Value::Boolean(false) => false,
+		Value::String(s) => !s.as_bytes().is_empty(),
+		Value::Table(t) => t.clone().pairs::<Value, Value>().next().is_some(),
+		_ => true
+	}
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+	match value {
+		Value::Integer(i) => Some(*i as f64),
+		Value::Number(n) => Some(*n),
+		_ => None
+	}
+}
+
+fn compare(op: &str, left: &Value, right: &Value) -> Result<bool, String> {
+	let ordering = match (as_number(left), as_number(right), left, right) {
+		(Some(l), Some(r), _, _) => l.partial_cmp(&r),
+		(_, _, Value::String(l), Value::String(r)) => Some(l.as_bytes().cmp(&r.as_bytes())),
+		_ => None
+	};
+
+	match op {
+		"==" => Ok(ordering.map(|o| o.is_eq()).unwrap_or(left == right)),
+		"!=" => Ok(!ordering.map(|o| o.is_eq()).unwrap_or(left == right)),
+		_ => {
+			let ordering = ordering.ok_or(format!("Can't compare {} with {}", left.type_name(), right.type_name()))?;
+			Ok(match op {
+				"<" => ordering.is_lt(),
+				">" => ordering.is_gt(),
+				"<=" => ordering.is_le(),
+				_ => ordering.is_ge()
+			})
+		}
+	}
+}
+
+struct Renderer<'a> {
+	lua: &'a Lua,
+	folder: &'a Path,
+	context: Table,
+	/// Loop variables, innermost last
+	scopes: Vec<HashMap<String, Value>>,
+	/// Template whose definition of each block is used, the one lowest in the layout chain
+	blocks: HashMap<String, Rc<Template>>,
+	depth: usize,
+	out: String
+}
+
+impl<'a> Renderer<'a> {
+	fn error<T>(template: &Template, line: usize, message: String) -> Result<T, TemplateError> {
+		Err(TemplateError {
+			template: template.name.clone(),
+			line,
+			message
+		})
+	}
+
+	fn lookup(&self, path: &[String]) -> mlua::Result<Value> {
+		let first = &path[0];
+		let mut value = match self.scopes.iter().rev().find_map(|s| s.get(first)) {
+			Some(v) => v.clone(),
+			None => self.context.get(first.as_str())?
+		};
+
+		for field in &path[1..] {
+			value = match value {
+				Value::Table(t) => match field.parse::<i64>() {
+					Ok(idx) => t.get(idx)?,
+					Err(_) => t.get(field.as_str())?
+				},
+				_ => return Ok(Value::Nil)
+			};
+		}
+
+		Ok(value)
+	}
+
+	fn eval(&self, expr: &Expr) -> Result<Value, String> {
+		Ok(match expr {
+			Expr::Str(s) => Value::String(self.lua.create_string(s).map_err(|e| e.to_string())?),
+			Expr::Number(n) => Value::Number(*n),
+			Expr::Bool(b) => Value::Boolean(*b),
+			Expr::Nil => Value::Nil,
+			Expr::Path(path) => self.lookup(path).map_err(|e| e.to_string())?,
+			Expr::Not(e) => Value::Boolean(!is_truthy(&self.eval(e)?)),
+			Expr::And(l, r) => {
+				let left = self.eval(l)?;
+				if is_truthy(&left) { self.eval(r)? } else { left }
+			},
+			Expr::Or(l, r) => {
+				let left = self.eval(l)?;
+				if is_truthy(&left) { left } else { self.eval(r)? }
+			},
+			Expr::Compare(op, l, r) => Value::Boolean(compare(op, &self.eval(l)?, &self.eval(r)?)?)
+		})
+	}
+
+	fn output(&mut self, value: Value, filters: &[String]) -> Result<(), String> {
+		let mut text = match &value {
+			Value::Nil => String::new(),
+			Value::Table(t) if filters.iter().any(|f| f == "length") => t.raw_len().to_string(),
+			Value::String(s) if filters.iter().any(|f| f == "length") => s.to_str().map_err(|e| e.to_string())?.chars().count().to_string(),
+			Value::String(_) | Value::Integer(_) | Value::Number(_) | Value::Boolean(_) => value.to_string().map_err(|e| e.to_string())?,
+			other => return Err(format!("Can't output a {}", other.type_name()))
+		};
+
+		let mut raw = false;
+		for filter in filters {
+			match filter.as_str() {
+				"raw" => raw = true,
+				"upper" => text = text.to_uppercase(),
+				"lower" => text = text.to_lowercase(),
+				"trim" => text = text.trim().to_string(),
+				_ => {}
+			}
+		}
+
+		self.out.push_str(&if raw { text } else { escape_html(&text) });
+		Ok(())
+	}
+
+	/// Entries of a table, in sequence order for arrays and sorted by key otherwise
+	fn entries(table: &Table) -> mlua::Result<Vec<(Value, Value)>> {
+		let len = table.raw_len();
+		if len > 0 {
+			return (1..=len).map(|i| Ok((Value::Integer(i as i64), table.get(i)?))).collect();
+		}
+
+		let mut res: Vec<(Value, Value)> = table.pairs::<Value, Value>().collect::<mlua::Result<Vec<(Value, Value)>>>()?;
+		res.sort_by_key(|(k, _)| k.to_string().unwrap_or_default());
+		Ok(res)
+	}
+
+	fn render_nodes(&mut self, template: &Rc<Template>, nodes: &[Node]) -> Result<(), TemplateError> {
+		for node in nodes {
+			match node {
+				Node::Text(text) => self.out.push_str(text),
+				Node::Output { expr, filters, line } => {
+					let value = self.eval(expr).or_else(|e| Renderer::error(template, *line, e))?;
+					self.output(value, filters).or_else(|e| Renderer::error(template, *line, e))?;
+				},
+				Node::If { branches, otherwise } => {
+					let mut body = otherwise;
+					for (condition, nodes) in branches {
+						// Conditions are checked when parsed, evaluation only fails on Lua errors
+						if is_truthy(&self.eval(condition).or_else(|e| Renderer::error(template, 0, e))?) {
+							body = nodes;
+							break;
+						}
+					}
+					self.render_nodes(template, body)?;
+				},
+				Node::For { key, value, iterable, body, otherwise, line } => {
+					let entries = match self.eval(iterable).or_else(|e| Renderer::error(template, *line, e))? {
+						Value::Table(t) => Renderer::entries(&t).or_else(|e| Renderer::error(template, *line, e.to_string()))?,
+						Value::Nil => Vec::new(),
+						other => return Renderer::error(template, *line, format!("Can't loop over a {}", other.type_name()))
+					};
+
+					if entries.is_empty() {
+						self.render_nodes(template, otherwise)?;
+						continue;
+					}
+
+					let count = entries.len();
+					for (idx, (k, v)) in entries.into_iter().enumerate() {
+						let mut scope = HashMap::from([(value.clone(), v)]);
+						if let Some(key) = key {
+							scope.insert(key.clone(), k);
+						}
+
+						let info = (|| {
+							let info = self.lua.create_table()?;
+							info.set("index", idx + 1)?;
+							info.set("first", idx == 0)?;
+							info.set("last", idx + 1 == count)?;
+							Ok::<Table, mlua::Error>(info)
+						})().or_else(|e| Renderer::error(template, *line, e.to_string()))?;
+						scope.insert("loop".to_string(), Value::Table(info));
+
+						self.scopes.push(scope);
+						let res = self.render_nodes(template, body);
+						self.scopes.pop();
+						res?;
+					}
+				},
+				Node::Include { name, line } => {
+					let included = self.load(name, template, *line)?;
+					self.render(&included)?;
+					self.depth -= 1;
+				},
+				Node::Block { name } => {
+					let owner = self.blocks.get(name).cloned().unwrap_or(template.clone());
+					let body = &owner.blocks[name];
+					self.render_nodes(&owner, body)?;
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn load(&mut self, name: &str, from: &Template, line: usize) -> Result<Rc<Template>, TemplateError> {
+		self.depth += 1;
+		if self.depth > MAX_DEPTH {
+			return Renderer::error(from, line, format!("Too many nested templates including '{}'", name));
+		}
+
+		load(self.folder, name).map_err(|e| TemplateError {
+			template: from.name.clone(),
+			line,
+			message: e.to_string()
+		})
+	}
+
+	fn render(&mut self, template: &Rc<Template>) -> Result<(), TemplateError> {
+		let parent = match &template.extends {
+			Some(parent) => parent,
+			None => return self.render_nodes(template, &template.nodes)
+		};
+
+		// Only the blocks of a template extending a layout are rendered, inside the layout
+		for name in template.blocks.keys() {
+			self.blocks.entry(name.clone()).or_insert(template.clone());
+		}
+
+		let layout = self.load(parent, template, 0)?;
+		self.render(&layout)?;
+		self.depth -= 1;
+
+		Ok(())
+	}
+}
+
+/// Renders template `name` from `folder` with the values of `context`. Output is HTML-escaped
+/// unless it goes through the `raw` filter.
+pub fn render(lua: &Lua, folder: &Path, name: &str, context: Table) -> Result<String, TemplateError> {
+	let template = load(folder, name)?;
+	let mut renderer = Renderer {
+		lua,
+		folder,
+		context,
+		scopes: Vec::new(),
+		blocks: HashMap::new(),
+		depth: 0,
+		out: String::new()
+	};
+
+	renderer.render(&template)?;
+
+	Ok(renderer.out)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::fs;
+	use mlua::{Lua, Table};
+	use crate::template::{render, Template};
+
+	#[test]
+	pub fn test_templates() {
+		let folder = std::env::temp_dir().join(format!("jwx_template_test_{}", std::process::id()));
+		fs::create_dir_all(folder.join("parts")).unwrap();
+		fs::write(folder.join("layout.html"), "<title>{% block title %}Default{% endblock %}</title>\n{% block body %}{% endblock %}").unwrap();
+		fs::write(folder.join("parts/item.html"), "<li>{{ loop.index }}. {{ item.name | upper }}</li>").unwrap();
+		fs::write(folder.join("page.html"), concat!(
+			"{% extends \"layout.html\" %}\n",
+			"{% block body %}\n",
+			"{# ignored #}{% if user and user.admin %}Admin {{ user.name }}{% elif user %}{{ user.name }}{% else %}Guest{% endif %}\n",
+			"<ul>{% for item in items %}{% include \"parts/item.html\" %}{% else %}empty{% endfor %}</ul>\n",
+			"{% for k, v in attrs %}{{ k }}={{ v }};{% endfor %} {{ html }} {{ html | raw }} {{ items | length }}\n",
+			"{% endblock %}"
+		)).unwrap();
+		fs::write(folder.join("loop.html"), "{% include \"loop.html\" %}").unwrap();
+
+		let lua = Lua::new();
+		let context: Table = lua.load(r#"{
+			user = { name = "<bob>", admin = false },
+			items = { { name = "a" }, { name = "b" } },
+			attrs = { y = 2, x = 1 },
+			html = "<i>"
+		}"#).eval().unwrap();
+
+		let res = render(&lua, &folder, "page.html", context.clone()).unwrap();
+		assert_eq!(res, "<title>Default</title>\n&lt;bob&gt;<ul><li>1. A</li><li>2. B</li></ul>\nx=1;y=2; &lt;i&gt; <i> 2\n");
+
+		assert!(render(&lua, &folder, "loop.html", context.clone()).unwrap_err().message.contains("Too many nested templates"));
+		assert!(render(&lua, &folder, "../page.html", context.clone()).is_err());
+		assert_eq!(Template::compile("bad", "a\n{% if x %}").unwrap_err().line, 2);
+		assert!(Template::compile("bad", "{{ x | nope }}").is_err());
+
+		fs::remove_dir_all(&folder).unwrap();
+	}
+}
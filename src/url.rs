@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 
+#[allow(clippy::upper_case_acronyms)]
 pub struct URL {
 	pub uri: String,
 	pub queries: HashMap<String, String>
@@ -22,7 +23,7 @@ impl URL {
 		let uri = string[0..question_idx].to_string();
 		if question_idx == string.len() {
 			return Some(URL {
-				uri: uri,
+				uri,
 				queries: HashMap::new()
 			})
 		}
@@ -68,22 +69,77 @@ impl URL {
 			queries.insert(String::from(name), String::from(val));
 		};
 
-		return Some(URL {
-			uri: uri,
-			queries: queries
-		});
+		Some(URL {
+			uri,
+			queries
+		})
 	}
 }
 
+fn is_unreserved(c: u8) -> bool {
+	c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_' || c == b'~'
+}
+
+/// Percent-encodes every byte of `string` outside the RFC 3986 "unreserved" set, so the
+/// result is safe to use both as a path segment and as a query key or value.
+pub fn percent_encode(string: &str) -> String {
+	let mut res = String::with_capacity(string.len());
+	for b in string.bytes() {
+		if is_unreserved(b) {
+			res.push(b as char);
+		} else {
+			res.push_str(&format!("%{:02X}", b));
+		}
+	}
+
+	res
+}
+
+/// Decodes `%XX` sequences. Malformed sequences are kept as they are.
+pub fn percent_decode(string: &str) -> String {
+	let bytes = string.as_bytes();
+	let mut res: Vec<u8> = Vec::with_capacity(bytes.len());
+
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' && i + 2 < bytes.len()
+			&& bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() {
+			let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+			res.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+			i += 3;
+			continue;
+		}
+		res.push(bytes[i]);
+		i += 1;
+	}
+
+	String::from_utf8_lossy(&res).to_string()
+}
+
+/// Builds a `key=value&...` query string, percent-encoding keys and values.
+pub fn build_query(queries: &[(String, String)]) -> String {
+	let mut res = String::new();
+	for (k, v) in queries {
+		if !res.is_empty() {
+			res.push('&');
+		}
+		res.push_str(&percent_encode(k));
+		res.push('=');
+		res.push_str(&percent_encode(v));
+	}
+
+	res
+}
+
 impl Display for URL {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		let mut res = self.uri.clone();
 		if !self.queries.is_empty() {
-			res = res + "?";
+			res += "?";
 			let mut first = true;
 			for (k, v) in &self.queries {
 				if !first {
-					res = res + "&";
+					res += "&";
 				} else {
 					first = false;
 				}
@@ -98,6 +154,7 @@ impl Display for URL {
 
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_get_then_check)]
 mod tests {
 	use super::*;
 
@@ -149,4 +206,16 @@ mod tests {
 		assert!(url.queries.get("d").is_some());
 		assert_eq!(url.queries.get("d").unwrap(), "e");
 	}
+
+	#[test]
+	pub fn test_percent_encoding() {
+		assert_eq!(percent_encode("a b/c?d"), "a%20b%2Fc%3Fd");
+		assert_eq!(percent_encode("safe-._~"), "safe-._~");
+		assert_eq!(percent_decode("a%20b%2Fc%3Fd"), "a b/c?d");
+		assert_eq!(percent_decode("100%"), "100%");
+		assert_eq!(percent_decode("%zz"), "%zz");
+
+		let query = vec![("q".to_string(), "a&b".to_string()), ("n".to_string(), "1".to_string())];
+		assert_eq!(build_query(&query), "q=a%26b&n=1");
+	}
 }
\ No newline at end of file
@@ -1,8 +1,7 @@
-use std::ffi::{CString};
 use std::fs::File;
 use std::io::Error;
 use std::os::fd::{FromRawFd};
-use libc::{c_int, fork, mkfifo, pid_t};
+use libc::{c_int, fork, pid_t};
 
 pub enum ForkResult {
 	Parent(pid_t),
@@ -27,15 +26,111 @@ pub fn new_pipe() -> Result<(File, File), Error> {
 	Ok((unsafe { File::from_raw_fd(fds[0]) }, unsafe { File::from_raw_fd(fds[1]) }))
 }
 
-pub fn new_named_pipe(name: &str) -> Result<String, Error> {
-	let full_name = format!("/tmp/jwx_client_{name}");
-	let res: c_int = unsafe {
-		let cstr = CString::new(full_name.clone())?;
-		mkfifo(cstr.as_ptr(), 0o666)
-	};
+/// Converts days since the unix epoch to a (year, month, day) civil date
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let z = days + 719468;
+	let era = if z >= 0 { z } else { z - 146096 } / 146097;
+	let doe = z - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
 
-	match res {
-		0 => Ok(full_name),
-		_ => Err(Error::last_os_error())
+	(year, month, day)
+}
+
+const WEEK_DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+pub const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats a unix timestamp as an HTTP date (e.g. `Sun, 06 Nov 1994 08:49:37 GMT`)
+pub fn format_http_date(timestamp: i64) -> String {
+	let days = timestamp.div_euclid(86400);
+	let secs = timestamp.rem_euclid(86400);
+	let (year, month, day) = civil_from_days(days);
+
+	format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+		WEEK_DAYS[days.rem_euclid(7) as usize], day, MONTHS[(month - 1) as usize], year,
+		secs / 3600, (secs % 3600) / 60, secs % 60)
+}
+
+/// Escapes the characters that are special in HTML text and attribute values
+pub fn escape_html(string: &str) -> String {
+	let mut res = String::with_capacity(string.len());
+	for c in string.chars() {
+		match c {
+			'&' => res.push_str("&amp;"),
+			'<' => res.push_str("&lt;"),
+			'>' => res.push_str("&gt;"),
+			'"' => res.push_str("&quot;"),
+			'\'' => res.push_str("&#39;"),
+			c => res.push(c)
+		}
 	}
-}
\ No newline at end of file
+
+	res
+}
+
+/// Fills `buff` with bytes from the kernel's CSPRNG
+pub fn random_bytes(buff: &mut [u8]) -> Result<(), Error> {
+	let mut filled = 0;
+	while filled < buff.len() {
+		let res = unsafe { libc::getrandom(buff[filled..].as_mut_ptr() as *mut libc::c_void, buff.len() - filled, 0) };
+		if res < 0 {
+			let e = Error::last_os_error();
+			if e.kind() == std::io::ErrorKind::Interrupted {
+				continue;
+			}
+			return Err(e);
+		}
+		filled += res as usize;
+	}
+
+	Ok(())
+}
+
+/// `bytes` random bytes from the kernel's CSPRNG, as a hex string
+pub fn random_hex(bytes: usize) -> Result<String, Error> {
+	let mut buff = vec![0u8; bytes];
+	random_bytes(&mut buff)?;
+
+	Ok(to_hex(&buff))
+}
+
+/// Lowercase hex representation of `data`
+pub fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a hex string, in either case
+pub fn from_hex(string: &str) -> Option<Vec<u8>> {
+	// from_str_radix alone would let a `+` sign through
+	if !string.len().is_multiple_of(2) || !string.bytes().all(|c| c.is_ascii_hexdigit()) {
+		return None;
+	}
+
+	(0..string.len()).step_by(2)
+		.map(|i| u8::from_str_radix(string.get(i..i + 2)?, 16).ok())
+		.collect()
+}
+
+/// Compares two byte strings in time that doesn't depend on where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+
+	a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Random identifier used to match a failed request with its log entry
+pub fn new_request_id() -> String {
+	let mut buff = [0u8; 8];
+	if random_bytes(&mut buff).is_err() {
+		let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+		buff = (nanos as u64 ^ ((std::process::id() as u64) << 32)).to_ne_bytes();
+	}
+
+	buff.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use crate::behaviours::behaviour::ExecutionLimits;
+use crate::http::http_message::{HttpMessage, HttpVersion};
+use crate::http::http_response::{code_to_http_status, HttpResponse};
+
+/// How long past a limit the watchdog waits for the VM hook to stop the script by itself
+const HARD_LIMIT_GRACE: Duration = Duration::from_secs(1);
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// CPU time (in seconds) used by the whole process so far
+pub fn process_cpu_time() -> f64 {
+	let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+
+	ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9
+}
+
+fn set_rlimit(resource: libc::__rlimit_resource_t, soft: u64, hard: u64) {
+	let limit = libc::rlimit { rlim_cur: soft, rlim_max: hard };
+	if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+		log_error!("Watchdog", "setrlimit failed: {:?}", std::io::Error::last_os_error());
+	}
+}
+
+/// Caps the resources of the current (worker) process. The CPU cap only makes sense for a
+/// process handling a single request, so it's skipped for long-lived workers.
+pub fn apply_rlimits(limits: &ExecutionLimits, single_request: bool) {
+	if let Some(bytes) = limits.max_address_space {
+		set_rlimit(libc::RLIMIT_AS, bytes, bytes);
+	}
+
+	if let (Some(seconds), true) = (limits.max_cpu_time, single_request) {
+		// Backstop only: the watchdog should have ended the process before SIGXCPU does
+		let soft = seconds.ceil() as u64 + HARD_LIMIT_GRACE.as_secs() + 1;
+		set_rlimit(libc::RLIMIT_CPU, soft, soft + 1);
+	}
+}
+
+/// Writer that records whether anything has been sent yet, so the watchdog knows if it can
+/// still answer with an error response.
+pub struct TrackedWriter {
+	out: File,
+	started: Arc<AtomicBool>
+}
+
+impl TrackedWriter {
+	pub fn new(out: File, started: Arc<AtomicBool>) -> Self {
+		TrackedWriter {
+			out,
+			started
+		}
+	}
+}
+
+impl Write for TrackedWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.started.store(true, Ordering::SeqCst);
+		self.out.write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.out.flush()
+	}
+}
+
+/// Kills the worker when a request outlives its time or CPU limits, e.g. because it's stuck
+/// in native code the VM hook can't interrupt. The client gets a 504 (time) or 503 (CPU)
+/// if no response had been started. The time limit doesn't apply once the response has
+/// started: event streams and WebSockets are meant to stay open.
+pub struct Watchdog {
+	done: Sender<()>,
+	handle: JoinHandle<()>
+}
+
+impl Watchdog {
+	/// Returns `None` if `limits` has nothing the watchdog needs to enforce
+	pub fn start(limits: &ExecutionLimits, description: String, mut out: File, started: Arc<AtomicBool>, version: HttpVersion) -> Option<Watchdog> {
+		if limits.timeout.is_none() && limits.max_cpu_time.is_none() {
+			return None;
+		}
+
+		let deadline = limits.timeout.map(|t| Instant::now() + Duration::from_secs_f64(t) + HARD_LIMIT_GRACE);
+		let cpu_deadline = limits.max_cpu_time.map(|t| process_cpu_time() + t + HARD_LIMIT_GRACE.as_secs_f64());
+
+		let (done, done_recv) = channel::<()>();
+
+		let handle = thread::spawn(move || {
+			loop {
+				match done_recv.recv_timeout(CHECK_INTERVAL) {
+					Err(RecvTimeoutError::Timeout) => {},
+					_ => return
+				}
+
+				let code = if deadline.is_some_and(|d| Instant::now() >= d) && !started.load(Ordering::SeqCst) {
+					504
+				} else if cpu_deadline.is_some_and(|d| process_cpu_time() >= d) {
+					503
+				} else {
+					continue;
+				};
+
+				log_error!("Watchdog", "{} exceeded its {} limit, killing worker", description, if code == 504 { "time" } else { "CPU" });
+
+				if started.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+					let content = format!("{}: {}", code, code_to_http_status(code).unwrap_or("Error"));
+					let resp = HttpResponse::new(
+						code,
+						HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]),
+						content.into_bytes(),
+						version
+					);
+					_ = out.write_all(&resp.serialize());
+				}
+
+				unsafe { libc::_exit(1) };
+			}
+		});
+
+		Some(Watchdog {
+			done,
+			handle
+		})
+	}
+
+	pub fn stop(self) {
+		_ = self.done.send(());
+		_ = self.handle.join();
+	}
+}
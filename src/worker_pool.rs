@@ -0,0 +1,409 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+use libc::pid_t;
+use mlua::prelude::LuaResult;
+use mlua::{Table, Value};
+use crate::behaviours::behaviour_router::BehaviourRouter;
+use crate::dispatcher::{request_streams, serve_request};
+use crate::ipc::{read_message_with_fds, send_message_with_fds, IpcMessage, IpcMessageReceiver, IpcMessageSender};
+use crate::utils::{safe_fork, ForkResult};
+use crate::watchdog::apply_rlimits;
+
+const DEFAULT_MIN_WORKERS: usize = 2;
+const DEFAULT_MAX_WORKERS: usize = 16;
+const DEFAULT_MAX_REQUESTS: usize = 1000;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings of the worker pool, from `config_set_worker_pool`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+	pub min_workers: usize,
+	pub max_workers: usize,
+	/// Requests a worker serves before it is replaced
+	pub max_requests: usize,
+	/// Workers above `min_workers` that stay idle that long are stopped
+	pub idle_timeout: Duration
+}
+
+impl PoolConfig {
+	/// Reads the value of the `worker_pool` option: `true` for the defaults, or a table
+	/// `{ min, max, max_requests, idle_timeout }`. `None` (a process forked for every request)
+	/// if not set.
+	pub fn from_option(value: Value) -> LuaResult<Option<PoolConfig>> {
+		let table: Table = match value {
+			Value::Nil | Value::Boolean(false) => return Ok(None),
+			Value::Boolean(true) => return Ok(Some(PoolConfig::default())),
+			Value::Table(t) => t,
+			other => return Err(mlua::Error::RuntimeError(format!("worker_pool must be a boolean or a table, got {}", other.type_name())))
+		};
+
+		let config = PoolConfig {
+			min_workers: table.get::<Option<usize>>("min")?.unwrap_or(DEFAULT_MIN_WORKERS),
+			max_workers: table.get::<Option<usize>>("max")?.unwrap_or(DEFAULT_MAX_WORKERS),
+			max_requests: table.get::<Option<usize>>("max_requests")?.unwrap_or(DEFAULT_MAX_REQUESTS),
+			idle_timeout: match table.get::<Option<f64>>("idle_timeout")? {
+				Some(t) if t >= 0.0 => Duration::from_secs_f64(t),
+				Some(t) => return Err(mlua::Error::RuntimeError(format!("worker_pool idle_timeout must not be negative, got {}", t))),
+				None => DEFAULT_IDLE_TIMEOUT
+			}
+		};
+
+		if config.max_workers == 0 || config.min_workers > config.max_workers {
+			return Err(mlua::Error::RuntimeError(format!("worker_pool needs 0 <= min <= max and max > 0, got min = {} and max = {}", config.min_workers, config.max_workers)));
+		}
+
+		if config.max_requests == 0 {
+			return Err(mlua::Error::RuntimeError("worker_pool max_requests must be at least 1".to_string()));
+		}
+
+		Ok(Some(config))
+	}
+}
+
+impl Default for PoolConfig {
+	fn default() -> Self {
+		PoolConfig {
+			min_workers: DEFAULT_MIN_WORKERS,
+			max_workers: DEFAULT_MAX_WORKERS,
+			max_requests: DEFAULT_MAX_REQUESTS,
+			idle_timeout: DEFAULT_IDLE_TIMEOUT
+		}
+	}
+}
+
+/// A request received by the dispatcher, with the streams it is read from and answered to
+pub struct PendingRequest {
+	/// ID given by the listener
+	pub id: u64,
+	pub uri: String,
+	/// A WebSocket upgrade or an event stream, which holds its worker for the whole connection
+	pub streaming: bool,
+	pub input: File,
+	pub output: File
+}
+
+struct PoolWorker {
+	pid: pid_t,
+	socket: UnixStream,
+	busy: bool,
+	served: usize,
+	idle_since: Instant
+}
+
+/// Pre-forked workers, so requests don't wait for a fork. A worker forked from the loaded
+/// state serves requests one after the other, putting the behaviour and middlewares back in
+/// their loaded state after each (see `BehaviourRouter::restore`). A request whose state
+/// can't be restored, or whose endpoint limits the whole process, is the last one of its
+/// worker, which exits and is replaced. Requests arriving while
+/// every worker is busy and the pool is full wait in a queue. A worker exits once its socket
+/// is dropped.
+pub struct WorkerPool {
+	config: PoolConfig,
+	workers: Vec<PoolWorker>,
+	queue: VecDeque<PendingRequest>
+}
+
+/// True if a worker may go on serving requests after `uri`: the endpoint has no limit
+/// applied to the whole process
+fn keeps_worker(router: &BehaviourRouter, uri: &str) -> bool {
+	let limits = router.get(uri).map(|(_, b)| b.limits()).unwrap_or_default();
+	limits.max_address_space.is_none() && limits.max_cpu_time.is_none()
+}
+
+/// Serves the requests sent over `socket`, telling the dispatcher when each one is done
+/// (`Ok`), or that the worker is exiting after it (`Close`). Exits when the dispatcher goes
+/// away.
+fn run_worker(router: &BehaviourRouter, mut socket: UnixStream) -> std::io::Result<()> {
+	loop {
+		match read_message_with_fds(&mut socket) {
+			Ok((IpcMessage::Request { uri, .. }, fds)) => {
+				let reuse = keeps_worker(router, &uri);
+				if !reuse {
+					// The worker serves nothing else, its limits can be those of the endpoint
+					if let Some((_, b)) = router.get(&uri) {
+						apply_rlimits(&b.limits(), true);
+					}
+				}
+
+				let res = match request_streams(fds) {
+					Some((input, output)) => serve_request(router, input, output),
+					None => Err(std::io::Error::other("Request streams missing"))
+				};
+
+				if let Err(e) = res {
+					log_error!("WorkerPool", "Failed to serve {}: {:?}", uri, e);
+				}
+
+				if !reuse || !router.restore(&uri) {
+					socket.send_message(IpcMessage::Close)?;
+					break;
+				}
+
+				socket.send_message(IpcMessage::Ok)?;
+			},
+			Ok((IpcMessage::Close, _)) | Err(_) => break,
+			Ok(_) => {}
+		}
+	}
+
+	Ok(())
+}
+
+impl WorkerPool {
+	pub fn new(config: PoolConfig) -> Self {
+		WorkerPool {
+			config,
+			workers: Vec::new(),
+			queue: VecDeque::new()
+		}
+	}
+
+	/// Sockets of the workers, readable when a worker is done with its request or has exited
+	pub fn worker_fds(&self) -> Vec<RawFd> {
+		self.workers.iter().map(|w| w.socket.as_raw_fd()).collect()
+	}
+
+	/// Every descriptor held by the pool, which other processes forked by the dispatcher close
+	pub fn fds(&self) -> Vec<RawFd> {
+		let mut fds = self.worker_fds();
+		for request in &self.queue {
+			fds.push(request.input.as_raw_fd());
+			fds.push(request.output.as_raw_fd());
+		}
+
+		fds
+	}
+
+	/// Stops every worker, keeping the queued requests for the pool that replaces this one
+	pub fn into_queue(self) -> VecDeque<PendingRequest> {
+		self.queue
+	}
+
+	/// Queues the requests of the pool this one replaces, handed to workers by `maintain`
+	pub fn requeue(&mut self, requests: VecDeque<PendingRequest>) {
+		self.queue.extend(requests);
+	}
+
+	/// Forks a worker. It closes `inherited` and the descriptors of the pool.
+	fn spawn(&mut self, router: &BehaviourRouter, inherited: &[RawFd]) -> std::io::Result<usize> {
+		let (recv, send) = UnixStream::pair()?;
+		match safe_fork()? {
+			ForkResult::Child => {
+				drop(send);
+				for fd in inherited.iter().chain(self.fds().iter()) {
+					unsafe { libc::close(*fd) };
+				}
+
+				let res = run_worker(router, recv);
+				std::process::exit(if res.is_ok() { 0 } else { 1 });
+			},
+			ForkResult::Parent(pid) => {
+				log_debug!("WorkerPool", "Started worker {}", pid);
+				self.workers.push(PoolWorker {
+					pid,
+					socket: send,
+					busy: false,
+					served: 0,
+					idle_since: Instant::now()
+				});
+
+				Ok(self.workers.len() - 1)
+			}
+		}
+	}
+
+	/// Hands `request` to an idle worker, starting one if the pool isn't full, or queues it
+	pub fn dispatch(&mut self, request: PendingRequest, router: &BehaviourRouter, inherited: &[RawFd]) {
+		self.queue.push_back(request);
+		self.assign(router, inherited);
+	}
+
+	fn assign(&mut self, router: &BehaviourRouter, inherited: &[RawFd]) {
+		while !self.queue.is_empty() {
+			let idx = match self.workers.iter().position(|w| !w.busy) {
+				Some(idx) => idx,
+				None if self.workers.len() < self.config.max_workers => match self.spawn(router, inherited) {
+					Ok(idx) => idx,
+					Err(e) => {
+						log_error!("WorkerPool", "Can't start a worker: {:?}", e);
+						return;
+					}
+				},
+				None => return
+			};
+
+			let request = match self.queue.pop_front() {
+				Some(r) => r,
+				None => return
+			};
+
+			let worker = &mut self.workers[idx];
+			let fds = [request.input.as_raw_fd(), request.output.as_raw_fd()];
+			match send_message_with_fds(&mut worker.socket, IpcMessage::Request { id: request.id, uri: request.uri.clone(), streaming: false }, &fds) {
+				Ok(_) => {
+					worker.busy = true;
+					worker.served += 1;
+				},
+				Err(e) => {
+					log_warn!("WorkerPool", "Worker {} is unavailable: {:?}", worker.pid, e);
+					self.workers.remove(idx);
+					self.queue.push_front(request);
+				}
+			}
+		}
+	}
+
+	/// Reads the notifications of workers done with their request. Workers that served
+	/// `max_requests` requests or exited are removed.
+	pub fn collect(&mut self) {
+		let mut fds: Vec<libc::pollfd> = self.workers.iter()
+			.map(|w| libc::pollfd { fd: w.socket.as_raw_fd(), events: libc::POLLIN, revents: 0 })
+			.collect();
+
+		if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 0) } <= 0 {
+			return;
+		}
+
+		for (idx, fd) in fds.iter().enumerate().rev() {
+			if fd.revents == 0 {
+				continue;
+			}
+
+			let worker = &mut self.workers[idx];
+			match worker.socket.read_message() {
+				Ok(IpcMessage::Ok) => {
+					worker.busy = false;
+					worker.idle_since = Instant::now();
+					if worker.served >= self.config.max_requests {
+						log_debug!("WorkerPool", "Replacing worker {} after {} requests", worker.pid, worker.served);
+						self.workers.remove(idx);
+					}
+				},
+				Ok(IpcMessage::Close) => {
+					log_debug!("WorkerPool", "Worker {} exits after its request", worker.pid);
+					self.workers.remove(idx);
+				},
+				Ok(_) => {},
+				Err(_) => {
+					if worker.busy {
+						log_warn!("WorkerPool", "Worker {} exited while serving a request", worker.pid);
+					} else {
+						log_warn!("WorkerPool", "Worker {} exited", worker.pid);
+					}
+					self.workers.remove(idx);
+				}
+			}
+		}
+	}
+
+	/// Stops workers idle for too long, starts workers up to `min_workers` and hands them the
+	/// queued requests
+	pub fn maintain(&mut self, router: &BehaviourRouter, inherited: &[RawFd]) {
+		let mut extra = self.workers.len().saturating_sub(self.config.min_workers);
+		let idle_timeout = self.config.idle_timeout;
+		self.workers.retain(|w| {
+			if extra > 0 && !w.busy && w.idle_since.elapsed() >= idle_timeout {
+				log_debug!("WorkerPool", "Stopping idle worker {}", w.pid);
+				extra -= 1;
+				return false;
+			}
+
+			true
+		});
+
+		while self.workers.len() < self.config.min_workers {
+			if let Err(e) = self.spawn(router, inherited) {
+				log_error!("WorkerPool", "Can't start a worker: {:?}", e);
+				break;
+			}
+		}
+
+		self.assign(router, inherited);
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::fs;
+	use std::io::{Read, Write};
+	use std::time::Duration;
+	use mlua::{Lua, Value};
+	use crate::behaviours::behaviour::Behaviour;
+	use crate::behaviours::behaviour_router::BehaviourRouter;
+	use crate::behaviours::lua_behaviour::LuaBehaviour;
+	use crate::config::lua_config::{ConfigMgr, EndpointConfig};
+	use crate::http::http_message::HttpMessage;
+	use crate::http::http_request::HttpRequest;
+	use crate::utils::new_pipe;
+	use crate::worker_pool::{PendingRequest, PoolConfig, WorkerPool};
+
+	#[test]
+	pub fn test_pool_config() {
+		let lua = Lua::new();
+		assert!(PoolConfig::from_option(Value::Nil).unwrap().is_none());
+		assert_eq!(PoolConfig::from_option(Value::Boolean(true)).unwrap(), Some(PoolConfig::default()));
+
+		let config = PoolConfig::from_option(lua.load("{ min = 1, max = 4, max_requests = 50, idle_timeout = 2.5 }").eval().unwrap()).unwrap().unwrap();
+		assert_eq!((config.min_workers, config.max_workers, config.max_requests), (1, 4, 50));
+		assert_eq!(config.idle_timeout.as_millis(), 2500);
+
+		assert!(PoolConfig::from_option(lua.load("{ min = 5, max = 4 }").eval().unwrap()).is_err());
+	}
+
+	#[test]
+	pub fn test_snapshot_worker_reuse() {
+		let dir = std::env::temp_dir().join(format!("jwx_pool_test_{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let script = dir.join("count.lua");
+		fs::write(&script, r#"
+			require("jwx_library_main")
+			count = 1
+			function run_request()
+				count = count + 1
+				jwx.response:writeContent("count " .. count)
+			end
+		"#).unwrap();
+
+		let mut mgr = ConfigMgr::new(dir.to_str().unwrap());
+		mgr.add_library_folder(concat!(env!("CARGO_MANIFEST_DIR"), "/src/resources/lua/lib/?.lua"));
+		let behaviour = LuaBehaviour::new(&mgr, &EndpointConfig::new(script.to_str().unwrap())).unwrap();
+		let behaviours: HashMap<String, Box<dyn Behaviour>> = HashMap::from([("/count".to_string(), Box::new(behaviour) as Box<dyn Behaviour>)]);
+		let router = BehaviourRouter::new(behaviours, HashMap::new());
+
+		let mut pool = WorkerPool::new(PoolConfig { min_workers: 1, max_workers: 1, ..PoolConfig::default() });
+		let mut pids = Vec::new();
+		for id in 0..3 {
+			let (input, mut request) = new_pipe().unwrap();
+			let (mut response, output) = new_pipe().unwrap();
+			let data = HttpRequest::parse(b"GET /count HTTP/1.1\r\n\r\n").unwrap().serialize();
+			request.write_all(&(data.len() as u64).to_ne_bytes()).unwrap();
+			request.write_all(&data).unwrap();
+
+			pool.dispatch(PendingRequest { id, uri: "/count".to_string(), streaming: false, input, output }, &router, &[]);
+
+			// Every request starts from the loaded state
+			let mut res = String::new();
+			response.read_to_string(&mut res).unwrap();
+			assert!(res.ends_with("count 2"), "{}", res);
+
+			while pool.workers.first().is_some_and(|w| w.busy) {
+				pool.collect();
+				std::thread::sleep(Duration::from_millis(5));
+			}
+			assert_eq!(pool.workers.len(), 1);
+			pids.push(pool.workers[0].pid);
+		}
+
+		// One worker served all of them
+		assert!(pids.iter().all(|p| *p == pids[0]));
+
+		drop(pool);
+		unsafe { libc::waitpid(pids[0], std::ptr::null_mut(), 0) };
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}